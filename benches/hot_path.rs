@@ -0,0 +1,110 @@
+use std::io::Write;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use zstdp::compression::{CompressionOptions, CompressionType};
+use zstdp::proxy::headers::parse_response_headers;
+use zstdp::proxy::transfer::{decode_chunked_body, forward_chunked_body, ChunkedWriter};
+
+/// Compressible text standing in for a typical HTML/JSON response body.
+fn sample_body(len: usize) -> Vec<u8> {
+    let text = b"{\"id\": 1234, \"name\": \"zstdp\", \"tags\": [\"proxy\", \"compression\"]}\n";
+    text.iter().copied().cycle().take(len).collect()
+}
+
+fn chunked(body: &[u8], chunk_size: usize) -> Vec<u8> {
+    let mut writer = ChunkedWriter::new(Vec::new());
+    for chunk in body.chunks(chunk_size) {
+        writer.write_all(chunk).unwrap();
+        writer.flush().unwrap();
+    }
+    writer.finish(&[]).unwrap()
+}
+
+fn bench_header_parsing(c: &mut Criterion) {
+    let head = "HTTP/1.1 200 OK\r\n\
+        Content-Type: text/html; charset=utf-8\r\n\
+        Content-Length: 48213\r\n\
+        Cache-Control: public, max-age=600\r\n\
+        ETag: \"5f3c-1a2b3c\"\r\n\
+        Last-Modified: Tue, 01 Oct 2024 10:00:00 GMT\r\n\
+        Vary: Accept-Encoding\r\n\
+        Server: backend\r\n\
+        \r\n";
+    c.bench_function("parse_response_headers", |b| {
+        b.iter(|| parse_response_headers(black_box(head)))
+    });
+}
+
+fn bench_chunked(c: &mut Criterion) {
+    let body = sample_body(1024 * 1024);
+    let mut group = c.benchmark_group("chunked");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    // Copy into a reused buffer: `io::sink` would let `io::copy` skip the data entirely
+    let mut out = Vec::with_capacity(body.len() * 2);
+    for chunk_size in [1024, 16 * 1024] {
+        let encoded = chunked(&body, chunk_size);
+        group.bench_with_input(
+            BenchmarkId::new("forward", chunk_size),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    out.clear();
+                    forward_chunked_body(&mut &encoded[..], &mut out)
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("decode", chunk_size),
+            &encoded,
+            |b, encoded| {
+                b.iter(|| {
+                    out.clear();
+                    decode_chunked_body(&mut &encoded[..], &mut out)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let body = sample_body(256 * 1024);
+    let options = CompressionOptions {
+        zstd: 3,
+        brotli: 5,
+        gzip: 6,
+        zstd_workers: 0,
+        zstd_long: false,
+        zstd_window_log: None,
+        zstd_cpu_budget: None,
+        padding: None,
+    };
+    let mut group = c.benchmark_group("compression");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    for compression in [
+        CompressionType::Zstd,
+        CompressionType::Brotli,
+        CompressionType::Gzip,
+    ] {
+        // Same shape as the streaming response path: encoder into chunked framing, 16 KiB writes
+        group.bench_function(compression.to_string(), |b| {
+            b.iter(|| {
+                let chunked = ChunkedWriter::new(Vec::with_capacity(body.len()));
+                let mut encoder = options.encoder(chunked, compression).unwrap();
+                for chunk in body.chunks(16 * 1024) {
+                    encoder.write_all(chunk).unwrap();
+                }
+                encoder.finish().unwrap().finish(&[]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_header_parsing,
+    bench_chunked,
+    bench_compression
+);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+//! Embeds the archive named by `ZSTDP_EMBED_ARCHIVE`, if set, for `zstdp --embedded`.
+
+use std::env;
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(zstdp_embed)");
+    println!("cargo:rerun-if-env-changed=ZSTDP_EMBED_ARCHIVE");
+
+    if let Some(archive) = env::var_os("ZSTDP_EMBED_ARCHIVE") {
+        println!("cargo:rerun-if-changed={}", archive.to_string_lossy());
+        println!("cargo:rustc-cfg=zstdp_embed");
+    }
+}
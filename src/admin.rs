@@ -0,0 +1,175 @@
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::body_log::{self, BodyLogRule};
+use crate::log_error;
+use crate::request_target::{read_request_line, write_rejection, HeaderLines, RequestLimits};
+use crate::{capture, connections, metrics, stats};
+
+const DEFAULT_BODY_LOG_TTL: Duration = Duration::from_secs(300);
+const DEFAULT_BODY_LOG_MAX_BYTES: usize = 4096;
+/// Time an admin client has for each read and write, so that an idle one cannot hold its thread.
+const ADMIN_IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Admin requests handled at once; connections beyond that are answered with 503.
+const MAX_ADMIN_CONNECTIONS: usize = 8;
+
+static ADMIN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the admin API on its own listener, which should only be reachable by operators.
+///
+/// Endpoints:
+/// - `GET /body-logging` lists the active body logging rules
+/// - `POST /body-logging?route=<regex>[&ttl=<duration>][&max_bytes=<n>][&every=<n>]` logs the
+///   bodies of one in `every` requests matching `route` for `ttl`
+/// - `DELETE /body-logging` removes all rules
+/// - `GET /captures` lists the last exchanges kept with `--capture`
+/// - `DELETE /captures` forgets them
+/// - `GET /metrics` lists counters and gauges
+/// - `GET /connections` lists open connections per client address
+/// - `GET /stats/compression` reports compression ratio and CPU time per content type
+pub fn start_admin_server(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("Admin API listening on: {}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => accept(stream),
+                Err(e) => log_error!(e, "Failed to accept admin connection"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Handles `client` on a thread of its own, unless `MAX_ADMIN_CONNECTIONS` are already open.
+fn accept(mut client: TcpStream) {
+    let timeouts = client
+        .set_read_timeout(Some(ADMIN_IO_TIMEOUT))
+        .and_then(|()| client.set_write_timeout(Some(ADMIN_IO_TIMEOUT)));
+    if let Err(e) = timeouts {
+        return log_error!(e, "Failed to set up admin connection");
+    }
+    if ADMIN_CONNECTIONS.fetch_add(1, Ordering::Relaxed) >= MAX_ADMIN_CONNECTIONS {
+        ADMIN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        log::warn!("Too many admin connections, refusing one");
+        let _ = client.write_all(
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        return;
+    }
+    thread::spawn(move || {
+        if let Err(e) = handle_admin_request(client) {
+            log_error!(e, "Admin request failed");
+        }
+        ADMIN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+fn handle_admin_request(mut client: TcpStream) -> io::Result<()> {
+    // Admin requests are small, whatever the routes allow
+    let limits = RequestLimits::default();
+    let mut buf_reader = BufReader::new(&client);
+    let first_line = match read_request_line(&mut buf_reader, &limits) {
+        Ok(line) => line,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => return write_rejection(&client, &e),
+        Err(e) => return Err(e),
+    };
+
+    // Drain the headers; admin requests carry everything in the request line
+    let mut header_lines = HeaderLines::new(&limits);
+    loop {
+        match header_lines.next(&mut buf_reader) {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return write_rejection(&client, &e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut parts = first_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let target = parts.next().unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    log::info!("Admin request: {} {}", method, path);
+
+    let (status, body) = match (method, path) {
+        ("GET", "/body-logging") => ("200 OK", body_log::describe_rules()),
+        ("POST", "/body-logging") => match parse_body_log_rule(query) {
+            Ok(rule) => {
+                body_log::add_rule(rule);
+                ("200 OK", "OK\n".to_string())
+            }
+            Err(e) => ("400 Bad Request", format!("{}\n", e)),
+        },
+        ("DELETE", "/body-logging") => {
+            let count = body_log::clear_rules();
+            ("200 OK", format!("Removed {} rules\n", count))
+        }
+        ("GET", "/captures") => ("200 OK", capture::describe()),
+        ("DELETE", "/captures") => {
+            let count = capture::clear();
+            ("200 OK", format!("Removed {} captures\n", count))
+        }
+        ("GET", "/metrics") => ("200 OK", metrics::render()),
+        ("GET", "/connections") => ("200 OK", connections::describe()),
+        ("GET", "/stats/compression") => ("200 OK", stats::compression_report()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    client.write_all(format!("HTTP/1.1 {}\r\n", status).as_bytes())?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(format!("Content-Length: {}\r\n", body.len()).as_bytes())?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(body.as_bytes())
+}
+
+fn parse_body_log_rule(query: &str) -> Result<BodyLogRule, String> {
+    let mut pattern = None;
+    let mut ttl = DEFAULT_BODY_LOG_TTL;
+    let mut max_bytes = DEFAULT_BODY_LOG_MAX_BYTES;
+    let mut every = 1;
+
+    for (key, value) in parse_query(query) {
+        match key.as_str() {
+            "route" => {
+                pattern = Some(Regex::new(&value).map_err(|e| format!("Invalid route: {}", e))?)
+            }
+            "ttl" => {
+                ttl =
+                    humantime::parse_duration(&value).map_err(|e| format!("Invalid ttl: {}", e))?
+            }
+            "max_bytes" => {
+                max_bytes = value
+                    .parse()
+                    .map_err(|e| format!("Invalid max_bytes: {}", e))?
+            }
+            "every" => every = value.parse().map_err(|e| format!("Invalid every: {}", e))?,
+            _ => return Err(format!("Unknown parameter: {}", key)),
+        }
+    }
+
+    let pattern = pattern.ok_or("Missing route parameter")?;
+    Ok(BodyLogRule::new(pattern, ttl, max_bytes, every))
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+            (decode(key), decode(value))
+        })
+        .collect()
+}
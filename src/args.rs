@@ -1,34 +1,400 @@
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Parser, Subcommand};
 use regex::Regex;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::chaos::Fault;
+use crate::compression::{CompressionOptions, CompressionType, Padding, ZstdLevelPolicy};
+use crate::connections::OverLimit;
+use crate::file_serving::dotfiles::DotfilePolicy;
+use crate::header_case::HeaderCase;
+use crate::proxy::backend::{BackendAddr, Upstream};
+use crate::proxy::balancer::Balancing;
+use crate::proxy::cache_key::CacheKeyTemplate;
+use crate::proxy::connect::ConnectRule;
+use crate::proxy::remote_cache::RemoteCache;
+use crate::proxy::{BackendTimeouts, HostRewrite};
+use crate::request_target::{
+    HostPolicy, RequestLimits, DEFAULT_MAX_HEADERS, DEFAULT_MAX_HEADER_SIZE, DEFAULT_MAX_URI_LENGTH,
+};
+use crate::schedule::Window;
+use crate::slow_clients::ClientLimits;
+use crate::tunnel::TunnelLimits;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
-#[clap(group(ArgGroup::new("mode").required(true).args(&["forward", "serve"])))]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[clap(group(
+    ArgGroup::new("mode")
+        .required(true)
+        .multiple(true)
+        .args(&["forward", "serve", "embedded", "s3"])
+))]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(short, long, default_value = "127.0.0.1")]
     pub bind: String,
 
     #[arg(short, long, default_value = "9866")]
     pub port: u16,
 
-    #[arg(short, long)]
-    pub forward: Option<String>,
+    #[arg(long, value_name = "MODE", value_parser = parse_octal)]
+    pub socket_mode: Option<u32>,
 
     #[arg(short, long)]
+    pub forward: Option<Upstream>,
+
+    #[arg(short, long, conflicts_with_all = ["embedded", "s3"])]
     pub serve: Option<PathBuf>,
 
+    #[arg(long, conflicts_with = "s3")]
+    pub embedded: bool,
+
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        value_delimiter = ',',
+        default_value = "/api"
+    )]
+    pub proxy_path: Vec<String>,
+
+    #[arg(long)]
+    pub fastcgi_root: Option<PathBuf>,
+
+    #[arg(long, default_value = "index.php")]
+    pub fastcgi_index: String,
+
+    #[arg(long, value_name = "URL")]
+    pub s3: Option<String>,
+
+    #[arg(long, default_value = "us-east-1")]
+    pub s3_region: String,
+
+    #[arg(long)]
+    pub s3_cache_dir: Option<PathBuf>,
+
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    pub s3_cache_ttl: Duration,
+
     #[arg(short, long, default_value = "3")]
     pub zstd_level: i32,
 
     #[arg(short, long, default_value = "6")]
     pub gzip_level: u32,
 
+    #[arg(long, default_value = "5")]
+    pub brotli_level: u32,
+
+    #[arg(long, default_value = "0")]
+    pub zstd_workers: u32,
+
+    #[arg(long)]
+    pub zstd_long: bool,
+
+    #[arg(long, value_parser = clap::value_parser!(u32).range(10..=31))]
+    pub zstd_window_log: Option<u32>,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub zstd_cpu_budget: Option<Duration>,
+
+    #[arg(long, value_name = "MODE")]
+    pub pad_responses: Option<Padding>,
+
+    #[arg(long)]
+    pub adaptive_zstd: bool,
+
+    #[arg(long, default_value = "1")]
+    pub zstd_min_level: i32,
+
+    #[arg(long, default_value = "19")]
+    pub zstd_max_level: i32,
+
+    #[arg(long, default_value = "100ms", value_parser = humantime::parse_duration)]
+    pub flush_interval: Duration,
+
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub backend_connect_timeout: Option<Duration>,
+
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub backend_write_timeout: Option<Duration>,
+
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub backend_header_timeout: Option<Duration>,
+
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub backend_read_timeout: Option<Duration>,
+
+    #[arg(long)]
+    pub ignore_client_abort: bool,
+
+    #[arg(long)]
+    pub backend_proxy_protocol: bool,
+
+    #[arg(long, value_name = "HOST", default_value = "preserve")]
+    pub host_rewrite: HostRewrite,
+
+    #[arg(long)]
+    pub strict_response_headers: bool,
+
+    #[arg(long)]
+    pub h2c_passthrough: bool,
+
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub allow_response_header: Vec<String>,
+
+    #[arg(long, value_name = "HOST:PORT", value_delimiter = ',')]
+    pub connect_allow: Vec<ConnectRule>,
+
+    #[arg(long, value_name = "PATH")]
+    pub auth_token_file: Option<PathBuf>,
+    #[arg(long, value_name = "URL", conflicts_with = "auth_token_file")]
+    pub forward_auth: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_delimiter = ',',
+        default_value = "Authorization,Cookie"
+    )]
+    pub forward_auth_request_header: Vec<String>,
+
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub forward_auth_response_header: Vec<String>,
+
+    #[arg(long, value_name = "DURATION", default_value = "10m", value_parser = humantime::parse_duration)]
+    pub tunnel_idle_timeout: Duration,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub tunnel_max_lifetime: Option<Duration>,
+
+    #[arg(long, action = clap::ArgAction::Append)]
+    pub chaos: Vec<Fault>,
+
     #[arg(short = 'i', long, action = clap::ArgAction::Append)]
     pub bypass: Vec<String>,
 
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    pub secret_cookie: Vec<String>,
+
+    #[arg(long, value_name = "WINDOW", action = clap::ArgAction::Append)]
+    pub maintenance: Vec<Window>,
+
     #[arg(long)]
     pub spa: bool,
+
+    #[arg(long, value_delimiter = ',', default_value = "index.html")]
+    pub index: Vec<String>,
+
+    #[arg(long, action = clap::ArgAction::Append)]
+    pub save_data_skip: Vec<String>,
+
+    #[arg(long)]
+    pub admin_listen: Option<String>,
+
+    #[arg(long, value_name = "N")]
+    pub capture: Option<usize>,
+
+    #[arg(long, value_name = "BYTES", default_value = "1024")]
+    pub capture_body_bytes: usize,
+
+    #[arg(long)]
+    pub max_connections_per_client: Option<usize>,
+
+    #[arg(long, value_name = "ANSWER", default_value = "503")]
+    pub max_connections_answer: OverLimit,
+
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_URI_LENGTH)]
+    pub max_uri_length: usize,
+
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_HEADER_SIZE)]
+    pub max_header_size: usize,
+
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_HEADERS)]
+    pub max_headers: usize,
+
+    #[arg(long, value_name = "BYTES")]
+    pub max_body_size: Option<u64>,
+
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    pub allowed_hosts: Vec<String>,
+
+    #[arg(long, value_name = "HOST", requires = "allowed_hosts")]
+    pub default_host: Option<String>,
+
+    #[arg(long, value_name = "CASE", default_value = "preserve")]
+    pub header_case: HeaderCase,
+
+    #[arg(long, value_name = "ADDR")]
+    pub tls_passthrough: Option<String>,
+
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub stats_interval: Option<Duration>,
+
+    #[arg(long, value_name = "PATH")]
+    pub stats_file: Option<PathBuf>,
+
+    #[arg(long, value_name = "N", default_value = "100")]
+    pub header_warn_fields: usize,
+
+    #[arg(long, value_name = "BYTES", default_value = "32768")]
+    pub header_warn_bytes: usize,
+
+    #[arg(long, value_name = "BYTES", default_value = "8192")]
+    pub header_warn_field_bytes: usize,
+
+    #[arg(long, value_name = "BYTES")]
+    pub client_send_buffer: Option<usize>,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "60s")]
+    pub client_write_timeout: Duration,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "10s")]
+    pub client_idle_timeout: Duration,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "30s")]
+    pub client_header_timeout: Duration,
+
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, default_value = "60s")]
+    pub client_body_timeout: Duration,
+
+    #[arg(long, value_name = "IP")]
+    pub trust_force_encoding: Vec<IpAddr>,
+
+    #[arg(long)]
+    pub transcode_gzip: bool,
+
+    #[arg(long, value_name = "LIST", value_delimiter = ',')]
+    pub encodings: Option<Vec<CompressionType>>,
+
+    #[arg(long)]
+    pub shield: bool,
+
+    #[arg(long)]
+    pub content_digest: bool,
+
+    #[arg(long)]
+    pub manifest: Option<PathBuf>,
+
+    #[arg(long, value_name = "PATH")]
+    pub not_found_page: Option<String>,
+
+    #[arg(long, value_name = "PATH")]
+    pub error_page: Option<String>,
+
+    #[arg(long, value_name = "MODE", default_value = "ignore")]
+    pub hide_dotfiles: DotfilePolicy,
+
+    #[arg(long, value_name = "STRATEGY", default_value = "round-robin")]
+    pub lb_strategy: Balancing,
+
+    #[arg(long, value_name = "N", default_value = "3")]
+    pub max_fails: u32,
+
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    pub fail_timeout: Duration,
+
+    #[arg(long, default_value = "67108864")]
+    pub shield_max_size: usize,
+
+    #[arg(long, value_name = "URL")]
+    pub shield_remote: Option<RemoteCache>,
+
+    #[arg(long)]
+    pub shield_no_age: bool,
+
+    #[arg(long, value_name = "TEMPLATE", default_value = "host,path,query")]
+    pub cache_key: CacheKeyTemplate,
+
+    #[arg(long, value_name = "DIR")]
+    pub routes_dir: Option<PathBuf>,
+
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    pub routes_poll: Duration,
+
+    #[arg(long, value_name = "FILE")]
+    pub vhosts: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Zstd dictionary tools
+    Dict {
+        #[command(subcommand)]
+        command: DictCommand,
+    },
+    /// Generate HTTP load against a server, e.g. to compare builds of zstdp
+    Loadgen {
+        /// Server address as host:port
+        target: BackendAddr,
+
+        #[arg(long, default_value = "/")]
+        path: String,
+
+        #[arg(short = 'n', long, default_value = "1000")]
+        requests: usize,
+
+        #[arg(short, long, default_value = "8")]
+        concurrency: usize,
+
+        /// Extra request header, e.g. "Accept-Encoding: zstd"; repeatable
+        #[arg(short = 'H', long = "header")]
+        headers: Vec<String>,
+    },
+    /// Write pre-compressed siblings of every file in a directory, for file server mode
+    Precompress {
+        dir: PathBuf,
+
+        #[arg(long, value_delimiter = ',', default_value = "zstd,br,gzip")]
+        encodings: Vec<CompressionType>,
+
+        #[arg(short, long, default_value = "19")]
+        zstd_level: i32,
+
+        #[arg(short, long, default_value = "9")]
+        gzip_level: u32,
+
+        #[arg(long, default_value = "11")]
+        brotli_level: u32,
+
+        /// Files compressed in parallel [default: available CPUs]
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        /// Files smaller than this many bytes are left alone
+        #[arg(long, default_value = "256")]
+        min_size: u64,
+
+        /// Write a manifest of the pre-compressed files here, for `--manifest`
+        #[arg(short, long)]
+        manifest: Option<PathBuf>,
+    },
+    /// Show how a server would handle example requests, e.g. to check a configuration in CI
+    TestConfig {
+        /// Example requests: a request line and header lines each, separated by blank lines
+        fixtures: PathBuf,
+
+        /// The command line of the server, after `--`
+        #[arg(last = true, required = true)]
+        server_args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DictCommand {
+    /// Train a zstd dictionary from sample files or directories
+    Train {
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(long, default_value = "112640")]
+        max_size: usize,
+    },
 }
 
 pub fn should_bypass_compression(uri: &str, bypass_patterns: &[Regex]) -> bool {
@@ -37,7 +403,74 @@ pub fn should_bypass_compression(uri: &str, bypass_patterns: &[Regex]) -> bool {
 }
 
 impl Args {
-    pub fn listen_addr(self: &Self) -> String {
+    /// `bind:port`, or the `unix:` socket path given as the bind address.
+    pub fn listen_addr(&self) -> String {
+        if self.bind.starts_with("unix:") {
+            return self.bind.clone();
+        }
         format!("{}:{}", self.bind, self.port)
     }
+
+    pub fn backend_timeouts(&self) -> BackendTimeouts {
+        BackendTimeouts {
+            connect: self.backend_connect_timeout,
+            write: self.backend_write_timeout,
+            header: self.backend_header_timeout,
+            read: self.backend_read_timeout,
+        }
+    }
+
+    pub fn compression_options(&self) -> CompressionOptions {
+        CompressionOptions {
+            zstd: self.zstd_level,
+            brotli: self.brotli_level,
+            gzip: self.gzip_level,
+            zstd_workers: self.zstd_workers,
+            zstd_long: self.zstd_long,
+            zstd_window_log: self.zstd_window_log,
+            zstd_cpu_budget: self.zstd_cpu_budget,
+            padding: self.pad_responses,
+        }
+    }
+
+    pub fn request_limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_uri_length: self.max_uri_length,
+            max_header_size: self.max_header_size,
+            max_headers: self.max_headers,
+            max_body_size: self.max_body_size,
+            hosts: HostPolicy::new(&self.allowed_hosts, self.default_host.clone()),
+        }
+    }
+
+    /// The limits on client connections, with zero durations for no limit.
+    pub fn client_limits(&self) -> ClientLimits {
+        let limit = |timeout: Duration| Some(timeout).filter(|timeout| !timeout.is_zero());
+        ClientLimits {
+            send_buffer: self.client_send_buffer,
+            write_timeout: limit(self.client_write_timeout),
+            idle_timeout: limit(self.client_idle_timeout),
+            header_timeout: limit(self.client_header_timeout),
+            body_timeout: limit(self.client_body_timeout),
+        }
+    }
+
+    pub fn tunnel_limits(&self) -> TunnelLimits {
+        TunnelLimits::new(Some(self.tunnel_idle_timeout), self.tunnel_max_lifetime)
+    }
+
+    pub fn zstd_level_policy(&self) -> ZstdLevelPolicy {
+        ZstdLevelPolicy {
+            default: self.zstd_level,
+            min: self.zstd_min_level.min(self.zstd_level),
+            max: self.zstd_max_level.max(self.zstd_level),
+            adaptive: self.adaptive_zstd,
+        }
+    }
+}
+
+/// File permissions given in octal, as in `chmod`.
+fn parse_octal(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .map_err(|_| format!("Expected an octal mode such as 660, got '{}'", s))
 }
@@ -0,0 +1,317 @@
+//! Response bodies as the file server and the proxy send them: stored files, bytes in memory and
+//! streams read from a backend or a decoder. Copying a body as-is, cutting ranges out of it and
+//! compressing it on the way out are implemented once here for both modes.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::ClientStream;
+use crate::compression::{CompressionOptions, CompressionType, Decoder};
+use crate::context;
+use crate::file_serving::sendfile::send_file;
+use crate::proxy::headers::survives_compression;
+use crate::proxy::transfer::{
+    decode_chunked_body, forward_chunked_body, ChunkedWriter, IntervalFlushWriter,
+};
+use crate::slow_clients::{stalled, ClientWriter};
+use crate::stats::{record_compression, thread_cpu_time, CountingWriter};
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where the bytes of a response body come from.
+pub enum Body<'a> {
+    /// Bytes in memory, such as an archive member
+    Memory(Arc<[u8]>),
+    /// `length` bytes of a file from its current offset, sent with `sendfile(2)` where available
+    File { file: File, length: u64 },
+    /// A stream of `length` bytes, or read to its end if the length is unknown
+    Stream {
+        reader: Box<dyn Read + 'a>,
+        length: Option<u64>,
+    },
+    /// A stream in chunked transfer coding
+    Chunked(Box<dyn Read + 'a>),
+}
+
+/// How a body is compressed on the way out. Compressed bodies are sent chunked, as their length
+/// is only known at the end.
+pub struct Encoding<'a> {
+    /// `None` sends the content chunked but uncompressed
+    pub compression: CompressionType,
+    pub options: &'a CompressionOptions,
+    /// Encoding of the body as read, decoded before compressing (see `Decoder`)
+    pub content_encoding: Option<&'a str>,
+    /// End the body with a `Content-Digest` trailer
+    pub content_digest: bool,
+    /// How often compressed output is flushed to the client rather than left in the encoder
+    pub flush_interval: Option<Duration>,
+    /// Socket the body is read from, with nothing buffered in between. Output is also flushed
+    /// once `flush_interval` has passed while it is quiet, rather than only on the next write.
+    pub source: Option<RawFd>,
+    /// Content type the compression statistics are recorded under
+    pub mime_type: &'a str,
+}
+
+impl Body<'_> {
+    /// Length of the body as it is sent, if it is known before sending it.
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Body::Memory(data) => Some(data.len() as u64),
+            Body::File { length, .. } => Some(*length),
+            Body::Stream { length, .. } => *length,
+            Body::Chunked(_) => None,
+        }
+    }
+
+    /// Copies the body as-is to `out`, chunked bodies with their chunked framing.
+    pub fn copy_to<W: Write>(self, out: &mut W) -> io::Result<()> {
+        let out = &mut BodyWriter(out);
+        match self {
+            Body::Memory(data) => out.write_all(&data),
+            Body::Chunked(mut reader) => forward_chunked_body(&mut reader, out).map(drop),
+            body => body.read_into(out, None).map(drop),
+        }
+    }
+
+    /// Sends the body as-is to `client`, without copying files through userspace where possible.
+    pub fn send(self, client: &mut ClientStream) -> io::Result<()> {
+        match self {
+            Body::File { file, length } => {
+                match send_file(&file, client, length).map_err(stalled)? {
+                    Some(sent) => context::count_body_bytes(sent),
+                    None => {
+                        let out = &mut BodyWriter(ClientWriter::new(client));
+                        copy_in_chunks(&mut file.take(length), out)?;
+                    }
+                }
+                Ok(())
+            }
+            body => body.copy_to(&mut ClientWriter::new(client)),
+        }
+    }
+
+    /// Sends `length` bytes from `start` to `client`. Only stored bodies can be cut into ranges.
+    pub fn send_range(
+        &mut self,
+        client: &mut ClientStream,
+        start: u64,
+        length: u64,
+    ) -> io::Result<()> {
+        match self {
+            Body::Memory(data) => BodyWriter(ClientWriter::new(client))
+                .write_all(&data[start as usize..(start + length) as usize]),
+            Body::File { file, .. } => {
+                file.seek(SeekFrom::Start(start))?;
+                match send_file(file, client, length).map_err(stalled)? {
+                    Some(sent) => context::count_body_bytes(sent),
+                    None => {
+                        let out = &mut BodyWriter(ClientWriter::new(client));
+                        copy_in_chunks(&mut file.take(length), out)?;
+                    }
+                }
+                Ok(())
+            }
+            Body::Stream { .. } | Body::Chunked(_) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Ranges of streamed bodies cannot be sent",
+            )),
+        }
+    }
+
+    /// Compresses the content of the body into a chunked body on `out`, and records the
+    /// compression in the statistics. Trailers of chunked bodies follow it, apart from those that
+    /// only held for the body as it was encoded.
+    pub fn encode<W: Write>(self, out: W, encoding: &Encoding) -> io::Result<()> {
+        let body = BufWriter::new(BodyWriter(out));
+        let chunked_writer = if encoding.content_digest {
+            ChunkedWriter::with_digest(body)
+        } else {
+            ChunkedWriter::new(body)
+        };
+        if encoding.compression == CompressionType::None {
+            let mut writer = Decoder::new(chunked_writer, encoding.content_encoding);
+            let trailers = self.read_into(&mut writer, encoding.quiet_source())?;
+            return writer.finish()?.finish(&trailers).map(drop);
+        }
+
+        let cpu_start = thread_cpu_time();
+        let encoder = encoding
+            .options
+            .encoder(CountingWriter::new(chunked_writer), encoding.compression)?;
+        let flush_interval = encoding.flush_interval.unwrap_or(Duration::MAX);
+        let mut writer = Decoder::new(
+            CountingWriter::new(IntervalFlushWriter::new(encoder, flush_interval)),
+            encoding.content_encoding,
+        );
+        let trailers = self.read_into(&mut writer, encoding.quiet_source())?;
+
+        let decoded = writer.finish()?;
+        let bytes_in = decoded.count();
+        let body_writer = decoded.into_inner().into_inner().finish()?;
+        let bytes_out = body_writer.count();
+        let mut chunked_writer = body_writer.into_inner();
+        if let Some(padding) = encoding.options.padding {
+            padding.write(&mut chunked_writer, encoding.compression, bytes_out)?;
+        }
+        chunked_writer.finish(&trailers)?;
+        record_compression(
+            encoding.mime_type,
+            bytes_in,
+            bytes_out,
+            thread_cpu_time() - cpu_start,
+        );
+        Ok(())
+    }
+
+    /// Writes the content of the body to `writer`, without any chunked framing, and returns the
+    /// trailers that survive compression. With a `quiet` socket and interval, streams are read
+    /// through `FlushWhenQuiet`.
+    fn read_into<W: Write>(
+        self,
+        writer: &mut W,
+        quiet: Option<(RawFd, Duration)>,
+    ) -> io::Result<Vec<(String, String)>> {
+        let writer = RefCell::new(writer);
+        let watched = |reader| FlushWhenQuiet::wrap(reader, quiet, &writer);
+        let out = &mut SharedWriter(&writer);
+        match self {
+            Body::Memory(data) => out.write_all(&data)?,
+            Body::File { file, length } => drop(copy_in_chunks(&mut file.take(length), out)?),
+            Body::Stream {
+                reader,
+                length: Some(length),
+            } => drop(copy_in_chunks(&mut watched(reader).take(length), out)?),
+            Body::Stream {
+                reader,
+                length: None,
+            } => drop(copy_in_chunks(&mut watched(reader), out)?),
+            Body::Chunked(reader) => {
+                let mut trailers = decode_chunked_body(&mut watched(reader), out)?;
+                trailers.retain(|(name, _)| survives_compression(name));
+                return Ok(trailers);
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+impl Encoding<'_> {
+    /// The socket to watch and how long it may stay quiet before output is flushed, if both are
+    /// known.
+    fn quiet_source(&self) -> Option<(RawFd, Duration)> {
+        Some((self.source?, self.flush_interval?))
+    }
+}
+
+/// Writes to a writer that a `FlushWhenQuiet` reader flushes between writes.
+struct SharedWriter<'w, W: Write>(&'w RefCell<W>);
+
+impl<W: Write> Write for SharedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+/// Reads a body from `socket` through `reader`, but before waiting on a quiet socket with output
+/// read more than `interval` ago still unflushed, flushes `writer`: a backend that sends a little
+/// and then thinks for a while must not keep that little in the encoder until it sends more.
+struct FlushWhenQuiet<'w, R: Read, W: Write> {
+    reader: R,
+    socket: RawFd,
+    writer: &'w RefCell<W>,
+    interval: Duration,
+    /// When the oldest data that may not have been flushed yet was read
+    unflushed_since: Option<Instant>,
+}
+
+impl<'w, W: Write> FlushWhenQuiet<'w, Box<dyn Read + 'w>, W> {
+    /// `reader`, watched if there is a `quiet` socket and interval.
+    fn wrap(
+        reader: Box<dyn Read + 'w>,
+        quiet: Option<(RawFd, Duration)>,
+        writer: &'w RefCell<W>,
+    ) -> Box<dyn Read + 'w> {
+        match quiet {
+            Some((socket, interval)) => Box::new(FlushWhenQuiet {
+                reader,
+                socket,
+                writer,
+                interval,
+                unflushed_since: None,
+            }),
+            None => reader,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Read for FlushWhenQuiet<'_, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(since) = self.unflushed_since {
+            let wait = self.interval.saturating_sub(since.elapsed());
+            if !readable_within(self.socket, wait)? {
+                self.writer.borrow_mut().flush()?;
+                self.unflushed_since = None;
+            }
+        }
+        let n = self.reader.read(buf)?;
+        if n > 0 {
+            self.unflushed_since.get_or_insert_with(Instant::now);
+        }
+        Ok(n)
+    }
+}
+
+/// Whether `socket` has something to read, or has been closed, within `timeout`.
+pub fn readable_within(socket: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd: socket,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+    loop {
+        match unsafe { libc::poll(&mut poll, 1, timeout) } {
+            -1 if io::Error::last_os_error().kind() == ErrorKind::Interrupted => continue,
+            -1 => return Err(io::Error::last_os_error()),
+            ready => return Ok(ready > 0),
+        }
+    }
+}
+
+/// Counts the bytes written through it as body bytes of the current connection.
+struct BodyWriter<W: Write>(W);
+
+impl<W: Write> Write for BodyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        context::count_body_bytes(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn copy_in_chunks<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
@@ -0,0 +1,213 @@
+use regex::Regex;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::capture::{BodySample, CapturedExchange};
+use crate::header_stats::Direction;
+
+/// Routes whose request and response bodies are currently being logged.
+static RULES: Mutex<Vec<BodyLogRule>> = Mutex::new(Vec::new());
+
+/// A temporary request to log bodies of the requests matching `pattern`.
+pub struct BodyLogRule {
+    pub pattern: Regex,
+    pub expires: Instant,
+    /// Bytes of each body to log at most
+    pub max_bytes: usize,
+    /// Log one in this many matching requests
+    pub every: u64,
+    matched: u64,
+}
+
+impl BodyLogRule {
+    pub fn new(pattern: Regex, ttl: Duration, max_bytes: usize, every: u64) -> Self {
+        Self {
+            pattern,
+            expires: Instant::now() + ttl,
+            max_bytes,
+            every: every.max(1),
+            matched: 0,
+        }
+    }
+}
+
+pub fn add_rule(rule: BodyLogRule) {
+    log::info!(
+        "Logging bodies for '{}' (1 in {}, up to {} bytes) for {:?}",
+        rule.pattern,
+        rule.every,
+        rule.max_bytes,
+        rule.expires.saturating_duration_since(Instant::now())
+    );
+    lock_rules().push(rule);
+}
+
+pub fn clear_rules() -> usize {
+    let mut rules = lock_rules();
+    let count = rules.len();
+    rules.clear();
+    count
+}
+
+/// One line per active rule, for the admin API.
+pub fn describe_rules() -> String {
+    let mut rules = lock_rules();
+    rules.retain(|r| r.expires > Instant::now());
+    rules
+        .iter()
+        .map(|r| {
+            format!(
+                "{} every={} max_bytes={} remaining={}s\n",
+                r.pattern,
+                r.every,
+                r.max_bytes,
+                r.expires
+                    .saturating_duration_since(Instant::now())
+                    .as_secs()
+            )
+        })
+        .collect()
+}
+
+/// Returns how many bytes of the bodies of `uri` to log, if this request is sampled.
+pub fn sample_limit(uri: &str) -> Option<usize> {
+    let mut rules = lock_rules();
+    rules.retain(|r| r.expires > Instant::now());
+    let rule = rules.iter_mut().find(|r| r.pattern.is_match(uri))?;
+    rule.matched += 1;
+    ((rule.matched - 1) % rule.every == 0).then_some(rule.max_bytes)
+}
+
+fn lock_rules() -> std::sync::MutexGuard<'static, Vec<BodyLogRule>> {
+    RULES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Wraps a reader or writer and logs the first bytes that pass through it once dropped, or keeps
+/// them in the capture of the exchange.
+pub struct Sampled<T> {
+    inner: T,
+    sample: Option<Sample>,
+}
+
+struct Sample {
+    label: String,
+    /// Bytes to log, unless only captured
+    log_bytes: Option<usize>,
+    max_bytes: usize,
+    captured: Vec<u8>,
+    total: u64,
+    capture: Option<(Arc<CapturedExchange>, Direction)>,
+}
+
+impl<T> Sampled<T> {
+    /// Samples the traffic through `inner` when `max_bytes` is set, otherwise passes it through.
+    pub fn new(inner: T, label: impl Into<String>, max_bytes: Option<usize>) -> Self {
+        let sample = max_bytes.map(|max_bytes| Sample {
+            label: label.into(),
+            log_bytes: Some(max_bytes),
+            max_bytes,
+            captured: Vec::new(),
+            total: 0,
+            capture: None,
+        });
+        Self { inner, sample }
+    }
+
+    /// Also keeps the first bytes in `exchange`, if it is captured, as its `direction` body.
+    pub fn capturing(
+        mut self,
+        exchange: Option<&Arc<CapturedExchange>>,
+        direction: Direction,
+    ) -> Self {
+        let Some(exchange) = exchange else {
+            return self;
+        };
+        let sample = self.sample.get_or_insert_with(|| Sample {
+            label: String::new(),
+            log_bytes: None,
+            max_bytes: 0,
+            captured: Vec::new(),
+            total: 0,
+            capture: None,
+        });
+        sample.max_bytes = sample.max_bytes.max(exchange.body_bytes());
+        sample.capture = Some((Arc::clone(exchange), direction));
+        self
+    }
+
+    fn record(&mut self, data: &[u8]) {
+        if let Some(sample) = &mut self.sample {
+            let room = sample.max_bytes.saturating_sub(sample.captured.len());
+            sample
+                .captured
+                .extend_from_slice(&data[..room.min(data.len())]);
+            sample.total += data.len() as u64;
+        }
+    }
+}
+
+impl<T: Read> Read for Sampled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Sampled<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> Drop for Sampled<T> {
+    fn drop(&mut self) {
+        let Some(sample) = self.sample.take() else {
+            return;
+        };
+        if let Some(log_bytes) = sample.log_bytes {
+            let logged = &sample.captured[..log_bytes.min(sample.captured.len())];
+            let truncated = if sample.total > logged.len() as u64 {
+                " (truncated)"
+            } else {
+                ""
+            };
+            if is_binary(logged) {
+                log::info!("{} body: <binary, {} bytes>", sample.label, sample.total);
+            } else {
+                log::info!(
+                    "{} body ({} bytes){}: {:?}",
+                    sample.label,
+                    sample.total,
+                    truncated,
+                    String::from_utf8_lossy(logged)
+                );
+            }
+        }
+        if let Some((exchange, direction)) = sample.capture {
+            let body = BodySample {
+                data: sample.captured,
+                total: sample.total,
+            };
+            exchange.set_body(direction, body);
+        }
+    }
+}
+
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    if data.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(data) {
+        Ok(_) => false,
+        // A multi-byte character cut off by the size cap is still text
+        Err(e) => e.error_len().is_some(),
+    }
+}
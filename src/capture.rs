@@ -0,0 +1,231 @@
+//! The last proxied exchanges, kept in memory for the admin API.
+//!
+//! With `--capture N`, the heads of the last `N` requests to backends and FastCGI applications
+//! and of their responses are kept along with the first `--capture-body-bytes` of each body, so
+//! that operators can see what just happened during an incident without turning on debug logs.
+//! Exchanges are added as soon as their request is read, so those still in progress show too.
+//! Values of headers that carry credentials are left out.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::body_log::is_binary;
+use crate::header_stats::Direction;
+
+/// Headers whose values are replaced in captures.
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+];
+
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+
+struct Ring {
+    capacity: usize,
+    body_bytes: usize,
+    exchanges: VecDeque<Arc<CapturedExchange>>,
+    /// Number of the next exchange
+    next: u64,
+}
+
+/// Keeps the last `capacity` exchanges, with up to `body_bytes` of each body.
+pub fn enable(capacity: usize, body_bytes: usize) {
+    log::info!(
+        "Capturing the last {} exchanges, with up to {} bytes of each body",
+        capacity,
+        body_bytes
+    );
+    *lock() = Some(Ring {
+        capacity,
+        body_bytes,
+        exchanges: VecDeque::with_capacity(capacity),
+        next: 1,
+    });
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Ring>> {
+    RING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts capturing a request, if captures are enabled.
+pub fn start(
+    client: Option<IpAddr>,
+    method: &str,
+    uri: &str,
+    host: Option<&str>,
+    headers: &[(String, String)],
+) -> Option<Arc<CapturedExchange>> {
+    let mut ring = lock();
+    let ring = ring.as_mut().filter(|ring| ring.capacity > 0)?;
+    let mut request_headers = Vec::with_capacity(headers.len() + 1);
+    if let Some(host) = host {
+        request_headers.push(("Host".to_string(), host.to_string()));
+    }
+    request_headers.extend(headers.iter().map(redact));
+    let exchange = Arc::new(CapturedExchange {
+        number: ring.next,
+        time: SystemTime::now(),
+        client,
+        request_line: format!("{} {}", method, uri),
+        request_headers,
+        body_bytes: ring.body_bytes,
+        state: Mutex::new(State::default()),
+    });
+    ring.next += 1;
+    if ring.exchanges.len() == ring.capacity {
+        ring.exchanges.pop_front();
+    }
+    ring.exchanges.push_back(Arc::clone(&exchange));
+    Some(exchange)
+}
+
+/// The captured exchanges, oldest first, for the admin API.
+pub fn describe() -> String {
+    let exchanges: Vec<_> = match lock().as_ref() {
+        Some(ring) => ring.exchanges.iter().cloned().collect(),
+        None => return "Captures are disabled; start zstdp with --capture <N>\n".to_string(),
+    };
+    exchanges
+        .iter()
+        .map(|exchange| exchange.describe())
+        .collect()
+}
+
+/// Forgets the captured exchanges, returning how many there were.
+pub fn clear() -> usize {
+    lock()
+        .as_mut()
+        .map_or(0, |ring| std::mem::take(&mut ring.exchanges).len())
+}
+
+/// A request to a backend and, once known, its response.
+pub struct CapturedExchange {
+    number: u64,
+    time: SystemTime,
+    client: Option<IpAddr>,
+    request_line: String,
+    request_headers: Vec<(String, String)>,
+    /// Bytes of each body to keep
+    body_bytes: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    request_body: Option<BodySample>,
+    status_line: Option<String>,
+    response_headers: Vec<(String, String)>,
+    response_body: Option<BodySample>,
+    /// How the exchange ended, when not with a response from the backend
+    note: Option<String>,
+}
+
+/// The first bytes of a body and its full length.
+pub struct BodySample {
+    pub data: Vec<u8>,
+    pub total: u64,
+}
+
+impl CapturedExchange {
+    /// Bytes of each body to keep.
+    pub fn body_bytes(&self) -> usize {
+        self.body_bytes
+    }
+
+    pub fn set_response(&self, status_line: &str, headers: &[(String, String)]) {
+        let mut state = self.lock();
+        state.status_line = Some(status_line.to_string());
+        state.response_headers = headers.iter().map(redact).collect();
+    }
+
+    pub fn set_body(&self, direction: Direction, mut body: BodySample) {
+        body.data.truncate(self.body_bytes);
+        let mut state = self.lock();
+        match direction {
+            Direction::Request => state.request_body = Some(body),
+            Direction::Response => state.response_body = Some(body),
+        }
+    }
+
+    /// Records how the exchange ended when the backend did not answer it.
+    pub fn note(&self, note: impl Into<String>) {
+        self.lock().note = Some(note.into());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn describe(&self) -> String {
+        let state = self.lock();
+        let mut out = format!(
+            "#{} {} {} {}\n",
+            self.number,
+            humantime::format_rfc3339_seconds(self.time),
+            self.client
+                .map_or_else(|| "-".to_string(), |client| client.to_string()),
+            self.request_line
+        );
+        for (name, value) in &self.request_headers {
+            let _ = writeln!(out, "> {}: {}", name, value);
+        }
+        describe_body(&mut out, '>', state.request_body.as_ref());
+        match &state.status_line {
+            Some(status_line) => {
+                let _ = writeln!(out, "< {}", status_line);
+                for (name, value) in &state.response_headers {
+                    let _ = writeln!(out, "< {}: {}", name, value);
+                }
+                describe_body(&mut out, '<', state.response_body.as_ref());
+            }
+            None => {
+                let _ = writeln!(
+                    out,
+                    "< {}",
+                    state.note.as_deref().unwrap_or("(no response yet)")
+                );
+            }
+        }
+        out.push('\n');
+        out
+    }
+}
+
+fn describe_body(out: &mut String, marker: char, body: Option<&BodySample>) {
+    let Some(body) = body.filter(|body| body.total > 0) else {
+        return;
+    };
+    let truncated = if body.total > body.data.len() as u64 {
+        " (truncated)"
+    } else {
+        ""
+    };
+    if is_binary(&body.data) {
+        let _ = writeln!(out, "{} <binary body, {} bytes>", marker, body.total);
+    } else {
+        let _ = writeln!(
+            out,
+            "{} body ({} bytes){}: {:?}",
+            marker,
+            body.total,
+            truncated,
+            String::from_utf8_lossy(&body.data)
+        );
+    }
+}
+
+fn redact((name, value): &(String, String)) -> (String, String) {
+    if REDACTED_HEADERS
+        .iter()
+        .any(|redacted| name.eq_ignore_ascii_case(redacted))
+    {
+        (name.clone(), format!("<{} bytes>", value.len()))
+    } else {
+        (name.clone(), value.clone())
+    }
+}
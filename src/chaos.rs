@@ -0,0 +1,147 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A fault injected into a share (`rate`, between 0 and 1) of proxied requests, for testing how
+/// clients cope with a misbehaving proxy. Written as `delay:<rate>:<duration>`,
+/// `truncate:<rate>`, `error:<rate>` or `drop:<rate>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Wait before reading the backend response
+    Delay { rate: f64, duration: Duration },
+    /// Cut the response body off part way and close the connection
+    Truncate { rate: f64 },
+    /// Answer with a random 5xx instead of contacting the backend
+    Error { rate: f64 },
+    /// Close the connection without answering
+    Drop { rate: f64 },
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let kind = parts.next().unwrap_or("");
+        let rate = parts
+            .next()
+            .ok_or_else(|| format!("Missing rate in fault '{}'", s))?
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid rate in fault '{}': {}", s, e))?;
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(format!("Rate must be between 0 and 1 in fault '{}'", s));
+        }
+
+        let fault = match kind {
+            "delay" => {
+                let duration = parts
+                    .next()
+                    .ok_or_else(|| format!("Missing duration in fault '{}'", s))?;
+                let duration = humantime::parse_duration(duration)
+                    .map_err(|e| format!("Invalid duration in fault '{}': {}", s, e))?;
+                Fault::Delay { rate, duration }
+            }
+            "truncate" => Fault::Truncate { rate },
+            "error" => Fault::Error { rate },
+            "drop" => Fault::Drop { rate },
+            _ => return Err(format!("Unknown fault '{}'", kind)),
+        };
+
+        if parts.next().is_some() {
+            return Err(format!("Too many fields in fault '{}'", s));
+        }
+        Ok(fault)
+    }
+}
+
+/// The faults picked for one request.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChaosPlan {
+    pub delay: Option<Duration>,
+    /// Fraction of the response body to send before cutting it off
+    pub truncate_at: Option<f64>,
+    pub error_status: Option<u16>,
+    pub drop: bool,
+}
+
+impl ChaosPlan {
+    pub fn roll(faults: &[Fault]) -> Self {
+        let mut plan = ChaosPlan::default();
+        for fault in faults {
+            match *fault {
+                Fault::Delay { rate, duration } if chance(rate) => plan.delay = Some(duration),
+                Fault::Truncate { rate } if chance(rate) => plan.truncate_at = Some(random()),
+                Fault::Error { rate } if chance(rate) => {
+                    const STATUSES: [u16; 4] = [500, 502, 503, 504];
+                    plan.error_status = Some(STATUSES[(random_u64() % 4) as usize]);
+                }
+                Fault::Drop { rate } if chance(rate) => plan.drop = true,
+                _ => {}
+            }
+        }
+        if plan.delay.is_some()
+            || plan.truncate_at.is_some()
+            || plan.error_status.is_some()
+            || plan.drop
+        {
+            log::warn!("Chaos: injecting {:?}", plan);
+        }
+        plan
+    }
+}
+
+/// Fails every write once `limit` bytes have gone through, leaving the response cut short.
+pub struct TruncatingWriter<W: Write> {
+    inner: W,
+    remaining: Option<u64>,
+}
+
+impl<W: Write> TruncatingWriter<W> {
+    pub fn new(inner: W, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+        }
+    }
+}
+
+impl<W: Write> Write for TruncatingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(remaining) = self.remaining else {
+            return self.inner.write(buf);
+        };
+        if remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Chaos: truncated response",
+            ));
+        }
+        let len = buf.len().min(remaining as usize);
+        let written = self.inner.write(&buf[..len])?;
+        self.remaining = Some(remaining - written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn chance(rate: f64) -> bool {
+    random() < rate
+}
+
+/// A uniformly distributed number in `[0, 1)`.
+fn random() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
@@ -0,0 +1,272 @@
+//! Client connections, accepted over TCP or, with `--bind unix:PATH`, on a Unix socket that a
+//! front proxy on the same host talks to.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::header_case;
+use crate::slow_clients;
+
+/// Where clients connect.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listens on `addr`, a `host:port` pair or `unix:` followed by a socket path. A socket file
+    /// left behind by a server that is gone is replaced, and `mode` sets its permissions.
+    pub fn bind(addr: &str, mode: Option<u32>) -> io::Result<Self> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => bind_unix(Path::new(path), mode),
+            None => TcpListener::bind(addr).map(Listener::Tcp),
+        }
+    }
+
+    /// Waits for the next client.
+    pub fn accept(&self) -> io::Result<ClientStream> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, _)| ClientStream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| ClientStream::Unix(stream)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Another server is listening on {}", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                log::info!("Removing stale socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path, _mode: Option<u32>) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+/// A connection from a client.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// Address of the client. Clients on a Unix socket share the same host, and count as
+    /// `127.0.0.1` for per-client limits and trusted addresses.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peer_addr(),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        }
+    }
+
+    /// Address the client connected to, `127.0.0.1` on a Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        }
+    }
+
+    /// Scheme of the requests read from the connection. zstdp only reads plain HTTP: connections
+    /// that open with TLS are relayed to the `--tls-passthrough` terminator, and the requests it
+    /// decrypts come back on plain connections of their own.
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            ClientStream::Tcp(_) => "http",
+            #[cfg(unix)]
+            ClientStream::Unix(_) => "http",
+        }
+    }
+
+    /// The TCP connection, for options that only TCP has.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            ClientStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => None,
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            ClientStream::Tcp(stream) => stream.try_clone().map(ClientStream::Tcp),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.try_clone().map(ClientStream::Unix),
+        }
+    }
+
+    /// Reads into `buf` without consuming what was read.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peek(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => peek_unix(stream, buf),
+        }
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read_timeout(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.read_timeout(),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write_timeout(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.write_timeout(),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+/// `UnixStream::peek` is not stable yet.
+#[cfg(unix)]
+fn peek_unix(stream: &UnixStream, buf: &mut [u8]) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        // SAFETY: the buffer is valid for `buf.len()` bytes and the descriptor is owned by
+        // `stream`, borrowed for the whole call.
+        let n = unsafe {
+            libc::recv(
+                stream.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                libc::MSG_PEEK,
+            )
+        };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+impl Read for &ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let client: &ClientStream = self;
+        slow_clients::read_head(client, || match client {
+            ClientStream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => (&*stream).read(buf),
+        })
+    }
+}
+
+impl Write for &ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => header_case::write(&mut &*stream, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => header_case::write(&mut &*stream, buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for ClientStream {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        match self {
+            ClientStream::Tcp(stream) => stream.as_raw_fd(),
+            ClientStream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
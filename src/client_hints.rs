@@ -0,0 +1,92 @@
+use regex::Regex;
+
+use crate::args::should_bypass_compression;
+
+/// Hints the server knows how to act on, as they are advertised in `Accept-CH`.
+const LINK_HINTS: &[&str] = &["Save-Data", "ECT", "Downlink", "RTT"];
+
+/// Client hints sent with a request (`Save-Data`, network hints and any `Sec-CH-*` header).
+#[derive(Debug, Default, Clone)]
+pub struct ClientHints {
+    values: Vec<(String, String)>,
+}
+
+impl ClientHints {
+    pub fn from_headers(headers: &[(String, String)]) -> Self {
+        let values = headers
+            .iter()
+            .filter_map(|(k, v)| {
+                let key = k.to_lowercase();
+                let is_hint = key.starts_with("sec-ch-")
+                    || LINK_HINTS.iter().any(|h| h.eq_ignore_ascii_case(&key))
+                    || key == "device-memory";
+                is_hint.then(|| (key, v.trim().trim_matches('"').to_string()))
+            })
+            .collect::<Vec<_>>();
+
+        if !values.is_empty() {
+            log::debug!("Client hints: {:?}", values);
+        }
+
+        Self { values }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn save_data(&self) -> bool {
+        self.get("save-data")
+            .map(|v| v.eq_ignore_ascii_case("on"))
+            .unwrap_or(false)
+    }
+
+    /// Effective connection type, as reported by the Network Information API.
+    pub fn ect(&self) -> Option<String> {
+        self.get("ect").map(|v| v.to_lowercase())
+    }
+
+    /// Downlink bandwidth estimate in Mbps.
+    pub fn downlink(&self) -> Option<f64> {
+        self.get("downlink").and_then(|v| v.parse().ok())
+    }
+
+    /// Round-trip time estimate in milliseconds.
+    pub fn rtt(&self) -> Option<u32> {
+        self.get("rtt").and_then(|v| v.parse().ok())
+    }
+}
+
+/// What the server does with client hints and which ones it asks for.
+#[derive(Debug, Default)]
+pub struct ClientHintPolicy {
+    /// Ask for the network hints used to pick an adaptive zstd level.
+    pub request_link_hints: bool,
+    /// Paths that are not served to clients sending `Save-Data: on`.
+    pub save_data_skip: Vec<Regex>,
+}
+
+impl ClientHintPolicy {
+    pub fn should_skip(&self, hints: &ClientHints, uri: &str) -> bool {
+        hints.save_data() && should_bypass_compression(uri, &self.save_data_skip)
+    }
+
+    /// Headers advertising the hints we use, and declaring the responses that depend on them.
+    pub fn response_headers(&self) -> Vec<(String, String)> {
+        let mut headers = Vec::new();
+        if self.request_link_hints {
+            headers.push(("Accept-CH".to_string(), LINK_HINTS.join(", ")));
+        } else if !self.save_data_skip.is_empty() {
+            headers.push(("Accept-CH".to_string(), "Save-Data".to_string()));
+        }
+        headers
+    }
+
+    /// Request headers whose value can change the response body.
+    pub fn vary(&self) -> Option<&'static str> {
+        (!self.save_data_skip.is_empty()).then_some("Save-Data")
+    }
+}
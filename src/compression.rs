@@ -1,8 +1,21 @@
+use brotli::CompressorWriter as BrotliEncoder;
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder};
+use flate2::Compression as GzipCompression;
 use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+use zstd::stream::write::Encoder as ZstdEncoder;
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+use crate::chaos::random_u64;
+use crate::client_hints::ClientHints;
+use crate::metrics::ZSTD_BUDGET_DOWNGRADES;
+use crate::stats::thread_cpu_time;
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum CompressionType {
     Zstd,
+    Brotli,
     Gzip,
     None,
 }
@@ -11,36 +24,131 @@ impl fmt::Display for CompressionType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             CompressionType::Zstd => write!(f, "zstd"),
+            CompressionType::Brotli => write!(f, "br"),
             CompressionType::Gzip => write!(f, "gzip"),
             CompressionType::None => write!(f, "none"),
         }
     }
 }
 
+impl FromStr for CompressionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "zstd" => Ok(CompressionType::Zstd),
+            "br" | "brotli" => Ok(CompressionType::Brotli),
+            "gzip" => Ok(CompressionType::Gzip),
+            _ => Err(format!("Unknown encoding '{}'", s)),
+        }
+    }
+}
+
+impl CompressionType {
+    /// File name suffix of pre-compressed files in this encoding.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionType::Zstd => ".zst",
+            CompressionType::Brotli => ".br",
+            CompressionType::Gzip => ".gz",
+            CompressionType::None => "",
+        }
+    }
+}
+
+/// Encodings used when none are configured, most preferred first.
+pub const DEFAULT_ENCODINGS: &[CompressionType] = &[
+    CompressionType::Zstd,
+    CompressionType::Brotli,
+    CompressionType::Gzip,
+];
+
+/// The encodings a response may use, best first.
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct AcceptedCompression {
-    pub supports_zstd: bool,
-    pub supports_gzip: bool,
+    /// Padded with `CompressionType::None`
+    ranked: [CompressionType; 3],
+}
+
+impl AcceptedCompression {
+    pub fn any(&self) -> bool {
+        self.best() != CompressionType::None
+    }
+
+    /// Accepts `compression` alone, or nothing but identity for `CompressionType::None`.
+    pub fn only(compression: CompressionType) -> Self {
+        AcceptedCompression {
+            ranked: [compression, CompressionType::None, CompressionType::None],
+        }
+    }
+
+    /// The encoding to respond with, or `CompressionType::None` for identity.
+    pub fn best(&self) -> CompressionType {
+        self.ranked[0]
+    }
+
+    /// The accepted encodings, best first.
+    pub fn preferred(&self) -> impl Iterator<Item = CompressionType> {
+        self.ranked
+            .into_iter()
+            .take_while(|&compression| compression != CompressionType::None)
+    }
 }
 
 impl fmt::Display for AcceptedCompression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "zstd: {}, gzip: {}",
-            self.supports_zstd, self.supports_gzip
-        )
+        if !self.any() {
+            return write!(f, "none");
+        }
+        let names: Vec<String> = self.preferred().map(|c| c.to_string()).collect();
+        write!(f, "{}", names.join(", "))
     }
 }
 
-pub fn determine_compression(accept_encoding: &str) -> AcceptedCompression {
-    let lowercase_ae = accept_encoding.to_lowercase();
-    let encodings: Vec<&str> = lowercase_ae.split(',').map(|s| s.trim()).collect();
+/// Ranks the `encodings` offered by the server, most preferred first, by the q-values of the
+/// client's `Accept-Encoding`. Encodings the client values equally keep the server's order.
+pub fn determine_compression(
+    accept_encoding: &str,
+    encodings: &[CompressionType],
+) -> AcceptedCompression {
+    let mut wildcard = 0.0;
+    let mut qualities = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim().to_lowercase();
+        // A malformed q-value counts as 1, as if it were missing
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match coding.as_str() {
+            "*" => wildcard = quality,
+            "zstd" => qualities.push((CompressionType::Zstd, quality)),
+            "br" => qualities.push((CompressionType::Brotli, quality)),
+            "gzip" | "x-gzip" => qualities.push((CompressionType::Gzip, quality)),
+            _ => {}
+        }
+    }
+
+    let mut ranked: Vec<(CompressionType, f32)> = encodings
+        .iter()
+        .map(|&compression| {
+            let quality = qualities
+                .iter()
+                .find(|(listed, _)| *listed == compression)
+                .map_or(wildcard, |&(_, quality)| quality);
+            (compression, quality)
+        })
+        .filter(|&(_, quality)| quality > 0.0)
+        .collect();
+    // Stable, so that ties keep the server's order
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
 
-    let compression = AcceptedCompression {
-        supports_zstd: encodings.iter().any(|&e| e == "zstd"),
-        supports_gzip: encodings.iter().any(|&e| e == "gzip"),
-    };
+    let mut compression = AcceptedCompression::only(CompressionType::None);
+    for (slot, (encoding, _)) in compression.ranked.iter_mut().zip(ranked) {
+        *slot = encoding;
+    }
 
     log::debug!(
         "Determined compression support from '{}': {}",
@@ -50,3 +158,551 @@ pub fn determine_compression(accept_encoding: &str) -> AcceptedCompression {
 
     compression
 }
+
+/// Request header with which a trusted CDN edge pins the encoding of a response, so that it only
+/// has to cache one variant per value it normalizes `Accept-Encoding` to.
+pub const FORCE_ENCODING_HEADER: &str = "x-zstdp-force-encoding";
+
+/// Parses a pinned encoding: `zstd`, `br`, `gzip` or `identity`.
+pub fn parse_forced_encoding(value: &str) -> Option<CompressionType> {
+    match value.trim().to_lowercase().as_str() {
+        "zstd" => Some(CompressionType::Zstd),
+        "br" => Some(CompressionType::Brotli),
+        "gzip" => Some(CompressionType::Gzip),
+        "identity" => Some(CompressionType::None),
+        _ => {
+            log::warn!("Ignoring unknown forced encoding '{}'", value);
+            None
+        }
+    }
+}
+
+/// How responses are compressed on the fly, shared by both modes.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct CompressionOptions {
+    pub zstd: i32,
+    pub brotli: u32,
+    pub gzip: u32,
+    /// Zstd worker threads per response; 0 compresses on the calling thread
+    pub zstd_workers: u32,
+    /// Zstd long-distance matching
+    pub zstd_long: bool,
+    /// Zstd window size as a power of two
+    pub zstd_window_log: Option<u32>,
+    /// CPU time a response may spend in zstd before the rest is compressed at
+    /// `ZSTD_FALLBACK_LEVEL`
+    pub zstd_cpu_budget: Option<Duration>,
+    /// Padding appended to zstd and gzip bodies, so that their length tells less about the content
+    pub padding: Option<Padding>,
+}
+
+/// How much padding compressed bodies get, against attacks that guess secrets in a response from
+/// how well it compresses (BREACH and the like).
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Padding {
+    /// Up to the next multiple of this many bytes
+    Bucket(u64),
+    /// A random number of bytes, up to this many
+    Random(u64),
+}
+
+/// Smallest zstd skippable frame: a magic number and the length of its content.
+const SKIPPABLE_FRAME_HEADER: u64 = 8;
+/// Smallest gzip member: a header with a comment, its terminator, an empty deflate block and the
+/// trailer.
+const EMPTY_GZIP_MEMBER: u64 = 10 + 1 + 2 + 8;
+
+impl Padding {
+    /// Bytes of padding for a body of `length` bytes in `compression`, enough for the padding to
+    /// be valid data for decoders to skip, or 0.
+    fn amount(&self, length: u64, compression: CompressionType) -> u64 {
+        let minimum = match compression {
+            CompressionType::Zstd => SKIPPABLE_FRAME_HEADER,
+            CompressionType::Gzip => EMPTY_GZIP_MEMBER,
+            CompressionType::Brotli | CompressionType::None => return 0,
+        };
+        match *self {
+            Padding::Bucket(size) => {
+                let mut amount = (size - length % size) % size;
+                while amount != 0 && amount < minimum {
+                    amount += size;
+                }
+                amount
+            }
+            Padding::Random(max) if max >= minimum => minimum + random_u64() % (max - minimum + 1),
+            Padding::Random(_) => 0,
+        }
+    }
+
+    /// Appends padding to a body of `length` bytes in `compression`: a zstd skippable frame or an
+    /// empty gzip member whose comment fills the space, both of which decoders skip. Brotli has no
+    /// room after its last block, so brotli bodies are not padded.
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        compression: CompressionType,
+        length: u64,
+    ) -> io::Result<()> {
+        let amount = self.amount(length, compression);
+        if amount == 0 {
+            return Ok(());
+        }
+        match compression {
+            CompressionType::Zstd => {
+                let content = amount - SKIPPABLE_FRAME_HEADER;
+                out.write_all(&0x184D_2A50u32.to_le_bytes())?;
+                out.write_all(&(content as u32).to_le_bytes())?;
+                io::copy(&mut io::repeat(0).take(content), out)?;
+            }
+            CompressionType::Gzip => {
+                let comment = amount - EMPTY_GZIP_MEMBER;
+                // Magic, deflate, FCOMMENT, no time, no extra flags, unknown OS
+                out.write_all(&[0x1f, 0x8b, 8, 0x10, 0, 0, 0, 0, 0, 255])?;
+                io::copy(&mut io::repeat(b' ').take(comment), out)?;
+                // End of the comment, a final empty fixed-Huffman block (BFINAL and BTYPE 01,
+                // then the end-of-block code), CRC-32 and length of nothing
+                out.write_all(&[0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0])?;
+            }
+            CompressionType::Brotli | CompressionType::None => {}
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("Expected bucket:<bytes> or random:<bytes>, got '{}'", s);
+        let (mode, bytes) = s.split_once(':').ok_or_else(expected)?;
+        let bytes = bytes
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|&bytes| bytes > 0 && bytes <= u32::MAX as u64)
+            .ok_or_else(expected)?;
+        match mode.trim() {
+            "bucket" => Ok(Padding::Bucket(bytes)),
+            "random" => Ok(Padding::Random(bytes)),
+            _ => Err(expected()),
+        }
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::Bucket(size) => write!(f, "bucket:{}", size),
+            Padding::Random(max) => write!(f, "random:{}", max),
+        }
+    }
+}
+
+/// Largest zstd window (8 MB) that HTTP clients are required to decode, per RFC 8878.
+pub const ZSTD_HTTP_MAX_WINDOW_LOG: u32 = 23;
+
+/// Highest zstd level whose default window fits in `ZSTD_HTTP_MAX_WINDOW_LOG`; the levels above
+/// it use windows of up to 128 MB.
+const ZSTD_HTTP_MAX_DEFAULT_LEVEL: i32 = 19;
+
+/// Level zstd responses drop to once they have used up their CPU budget.
+const ZSTD_FALLBACK_LEVEL: i32 = 1;
+
+// Brotli encoder buffer size and window log (22 is brotli's own default)
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_LGWIN: u32 = 22;
+
+impl CompressionOptions {
+    /// Builds a streaming encoder producing `compression` into `writer`.
+    pub fn encoder<W: Write>(
+        &self,
+        writer: W,
+        compression: CompressionType,
+    ) -> io::Result<Encoder<W>> {
+        match compression {
+            CompressionType::Zstd => Ok(Encoder::Zstd {
+                encoder: Some(self.zstd_encoder(writer, self.zstd)?),
+                budget: self
+                    .zstd_cpu_budget
+                    .filter(|_| self.zstd > ZSTD_FALLBACK_LEVEL),
+                options: *self,
+            }),
+            CompressionType::Brotli => Ok(Encoder::Brotli(Box::new(BrotliEncoder::new(
+                writer,
+                BROTLI_BUFFER_SIZE,
+                self.brotli,
+                BROTLI_LGWIN,
+            )))),
+            CompressionType::Gzip => Ok(Encoder::Gzip(GzEncoder::new(
+                writer,
+                GzipCompression::new(self.gzip),
+            ))),
+            CompressionType::None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No encoder for uncompressed responses",
+            )),
+        }
+    }
+
+    /// A zstd frame at `level` with the configured workers, long-distance matching and window.
+    fn zstd_encoder<W: Write>(&self, writer: W, level: i32) -> io::Result<ZstdEncoder<'static, W>> {
+        let mut encoder = ZstdEncoder::new(writer, level)?;
+        if self.zstd_workers > 0 {
+            encoder.multithread(self.zstd_workers)?;
+        }
+        if self.zstd_long {
+            encoder.long_distance_matching(true)?;
+        }
+        // Long-distance matching and the highest levels would otherwise raise the window past
+        // what clients accept
+        let window_log = self
+            .zstd_window_log
+            .or((self.zstd_long || level > ZSTD_HTTP_MAX_DEFAULT_LEVEL)
+                .then_some(ZSTD_HTTP_MAX_WINDOW_LOG));
+        if let Some(window_log) = window_log {
+            encoder.window_log(window_log)?;
+        }
+        Ok(encoder)
+    }
+}
+
+/// A streaming compressor for any of the supported encodings.
+pub enum Encoder<W: Write> {
+    Zstd {
+        /// `None` only after ending a frame failed
+        encoder: Option<ZstdEncoder<'static, W>>,
+        /// CPU time left at the configured level. Once it runs out, the frame is ended and the
+        /// rest of the response goes in a new frame at `ZSTD_FALLBACK_LEVEL`, which clients decode
+        /// as a continuation of the same stream.
+        budget: Option<Duration>,
+        /// What the new frame is built with, apart from its level
+        options: CompressionOptions,
+    },
+    Brotli(Box<BrotliEncoder<W>>),
+    Gzip(GzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed stream and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Zstd { encoder, .. } => live_zstd(encoder)?.finish(),
+            Encoder::Brotli(mut encoder) => {
+                // `into_inner` finishes the stream but swallows write errors, so surface those
+                // first
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            Encoder::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Zstd {
+                encoder,
+                budget,
+                options,
+            } => {
+                let Some(left) = *budget else {
+                    return live_zstd(encoder.as_mut())?.write(buf);
+                };
+                let start = thread_cpu_time();
+                let written = live_zstd(encoder.as_mut())?.write(buf)?;
+                *budget = left.checked_sub(thread_cpu_time().saturating_sub(start));
+                if budget.is_none() {
+                    log::debug!(
+                        "Zstd CPU budget used up, going on at level {}",
+                        ZSTD_FALLBACK_LEVEL
+                    );
+                    ZSTD_BUDGET_DOWNGRADES.increment();
+                    let writer = live_zstd(encoder.take())?.finish()?;
+                    *encoder = Some(options.zstd_encoder(writer, ZSTD_FALLBACK_LEVEL)?);
+                }
+                Ok(written)
+            }
+            Encoder::Brotli(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Zstd { encoder, .. } => live_zstd(encoder.as_mut())?.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+fn live_zstd<T>(encoder: Option<T>) -> io::Result<T> {
+    encoder.ok_or_else(|| io::Error::other("Zstd stream already failed"))
+}
+
+/// Whether an upstream `Content-Encoding` can be decoded and re-encoded as zstd.
+pub fn is_transcodable(content_encoding: &str) -> bool {
+    matches!(content_encoding, "gzip" | "x-gzip" | "deflate")
+}
+
+/// A streaming decompressor for upstream encodings that can be transcoded.
+pub enum Decoder<W: Write> {
+    Gzip(GzDecoder<W>),
+    Deflate(ZlibDecoder<W>),
+    Identity(W),
+}
+
+impl<W: Write> Decoder<W> {
+    /// Decodes `content_encoding` (`gzip`, `x-gzip` or `deflate`); anything else passes through.
+    pub fn new(inner: W, content_encoding: Option<&str>) -> Self {
+        match content_encoding {
+            Some("gzip" | "x-gzip") => Decoder::Gzip(GzDecoder::new(inner)),
+            Some("deflate") => Decoder::Deflate(ZlibDecoder::new(inner)),
+            _ => Decoder::Identity(inner),
+        }
+    }
+
+    /// Checks that the compressed stream ended properly and returns the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(decoder) => decoder.finish(),
+            Decoder::Identity(inner) => Ok(inner),
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.write(buf),
+            Decoder::Deflate(decoder) => decoder.write(buf),
+            Decoder::Identity(inner) => inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.flush(),
+            Decoder::Deflate(decoder) => decoder.flush(),
+            Decoder::Identity(inner) => inner.flush(),
+        }
+    }
+}
+
+/// How the zstd level is chosen for a given client.
+///
+/// With `adaptive` disabled every client gets `default`. Otherwise the `Save-Data`, `ECT`,
+/// `Downlink` and `RTT` client hints move the level towards `max` for slow links (where the
+/// extra CPU buys the most transfer time) and towards `min` for fast ones.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct ZstdLevelPolicy {
+    pub default: i32,
+    pub min: i32,
+    pub max: i32,
+    pub adaptive: bool,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum LinkSpeed {
+    Slow,
+    Fast,
+    Unknown,
+}
+
+impl ZstdLevelPolicy {
+    pub fn level_for(&self, hints: &ClientHints) -> i32 {
+        if !self.adaptive {
+            return self.default;
+        }
+
+        let level = match estimate_link_speed(hints) {
+            LinkSpeed::Slow => self.max,
+            LinkSpeed::Fast => self.min,
+            LinkSpeed::Unknown => self.default,
+        };
+        log::debug!("Adaptive zstd level: {}", level);
+        level
+    }
+}
+
+fn estimate_link_speed(hints: &ClientHints) -> LinkSpeed {
+    if hints.save_data() {
+        return LinkSpeed::Slow;
+    }
+
+    match hints.ect().as_deref() {
+        Some("slow-2g") | Some("2g") | Some("3g") => return LinkSpeed::Slow,
+        _ => {}
+    }
+
+    if let Some(downlink) = hints.downlink() {
+        if downlink < 1.5 {
+            return LinkSpeed::Slow;
+        } else if downlink >= 10.0 {
+            return LinkSpeed::Fast;
+        }
+    }
+
+    if let Some(rtt) = hints.rtt() {
+        if rtt >= 300 {
+            return LinkSpeed::Slow;
+        } else if rtt <= 25 {
+            return LinkSpeed::Fast;
+        }
+    }
+
+    LinkSpeed::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> CompressionOptions {
+        CompressionOptions {
+            zstd: 3,
+            brotli: 5,
+            gzip: 6,
+            zstd_workers: 0,
+            zstd_long: false,
+            zstd_window_log: None,
+            zstd_cpu_budget: None,
+            padding: None,
+        }
+    }
+
+    /// Text that compresses, but not so well that compressing it takes no time.
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                b"abcdefgh  \n"[(state % 11) as usize]
+            })
+            .collect()
+    }
+
+    fn zstd_frames(mut compressed: &[u8]) -> usize {
+        let mut frames = 0;
+        while !compressed.is_empty() {
+            let size = zstd::zstd_safe::find_frame_compressed_size(compressed).unwrap();
+            compressed = &compressed[size..];
+            frames += 1;
+        }
+        frames
+    }
+
+    /// Compresses `input` with a CPU budget that runs out at once, and returns the frames and
+    /// what they decode to within `window_log`.
+    fn compress_over_budget(
+        options: CompressionOptions,
+        input: &[u8],
+        window_log: u32,
+    ) -> (usize, Vec<u8>) {
+        let options = CompressionOptions {
+            zstd_cpu_budget: Some(Duration::ZERO),
+            ..options
+        };
+        let mut encoder = options.encoder(Vec::new(), CompressionType::Zstd).unwrap();
+        let mut chunks = input.chunks(64 * 1024);
+        for chunk in chunks.by_ref() {
+            encoder.write_all(chunk).unwrap();
+            if matches!(encoder, Encoder::Zstd { budget: None, .. }) {
+                break;
+            }
+        }
+        assert!(matches!(encoder, Encoder::Zstd { budget: None, .. }));
+        for chunk in chunks {
+            encoder.write_all(chunk).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoder = zstd::stream::read::Decoder::new(&compressed[..]).unwrap();
+        decoder.window_log_max(window_log).unwrap();
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        (zstd_frames(&compressed), decoded)
+    }
+
+    fn padded(padding: Padding, compression: CompressionType, body: &[u8]) -> Vec<u8> {
+        let mut encoder = options().encoder(Vec::new(), compression).unwrap();
+        encoder.write_all(body).unwrap();
+        let mut compressed = encoder.finish().unwrap();
+        let length = compressed.len() as u64;
+        padding.write(&mut compressed, compression, length).unwrap();
+        compressed
+    }
+
+    #[test]
+    fn padded_gzip_is_a_multiple_of_the_bucket_and_gunzips_to_the_body() {
+        let body = sample(10_000);
+        for bucket in [1, 21, 512, 4096] {
+            let padded = padded(Padding::Bucket(bucket), CompressionType::Gzip, &body);
+            assert_eq!(padded.len() as u64 % bucket, 0, "bucket {}", bucket);
+            let mut decoded = Vec::new();
+            flate2::read::MultiGzDecoder::new(&padded[..])
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert!(decoded == body, "bucket {}", bucket);
+        }
+    }
+
+    #[test]
+    fn padded_zstd_is_a_multiple_of_the_bucket_and_decodes_to_the_body() {
+        let body = sample(10_000);
+        for bucket in [1, 8, 512, 4096] {
+            let padded = padded(Padding::Bucket(bucket), CompressionType::Zstd, &body);
+            assert_eq!(padded.len() as u64 % bucket, 0, "bucket {}", bucket);
+            assert!(zstd::stream::decode_all(&padded[..]).unwrap() == body);
+        }
+    }
+
+    #[test]
+    fn random_padding_stays_within_its_bounds() {
+        let body = sample(1000);
+        let unpadded = padded(Padding::Bucket(1), CompressionType::Gzip, &body).len();
+        for _ in 0..20 {
+            let padded = padded(Padding::Random(100), CompressionType::Gzip, &body);
+            let amount = padded.len() - unpadded;
+            assert!(
+                (EMPTY_GZIP_MEMBER as usize..=100).contains(&amount),
+                "{}",
+                amount
+            );
+        }
+        let brotli = padded(Padding::Random(100), CompressionType::Brotli, &body);
+        assert_eq!(
+            brotli,
+            padded(Padding::Bucket(1), CompressionType::Brotli, &body)
+        );
+    }
+
+    #[test]
+    fn spent_cpu_budget_goes_on_in_a_second_frame_within_the_http_window() {
+        let input = sample(1 << 20);
+        let options = CompressionOptions {
+            zstd: 22,
+            ..options()
+        };
+        let (frames, decoded) = compress_over_budget(options, &input, ZSTD_HTTP_MAX_WINDOW_LOG);
+        assert_eq!(frames, 2);
+        assert!(decoded == input);
+    }
+
+    #[test]
+    fn second_frame_keeps_the_configured_window() {
+        let input = sample(1 << 20);
+        let options = CompressionOptions {
+            zstd: 19,
+            zstd_window_log: Some(12),
+            ..options()
+        };
+        let (frames, decoded) = compress_over_budget(options, &input, 12);
+        assert_eq!(frames, 2);
+        assert!(decoded == input);
+    }
+}
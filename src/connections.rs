@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::metrics::{OPEN_CONNECTIONS, REJECTED_CONNECTIONS};
+
+/// Open connections per client address.
+static OPEN: Mutex<Option<HashMap<IpAddr, usize>>> = Mutex::new(None);
+
+/// How connections over `--max-connections-per-client` are turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverLimit {
+    /// `429 Too Many Requests`
+    TooManyRequests,
+    /// `503 Service Unavailable`
+    #[default]
+    Unavailable,
+    /// Closed without an answer, which spares a thread for each of them
+    Drop,
+}
+
+impl FromStr for OverLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "429" => Ok(OverLimit::TooManyRequests),
+            "503" => Ok(OverLimit::Unavailable),
+            "drop" => Ok(OverLimit::Drop),
+            _ => Err(format!("Expected 429, 503 or drop, got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for OverLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OverLimit::TooManyRequests => "429",
+            OverLimit::Unavailable => "503",
+            OverLimit::Drop => "drop",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Holds one connection slot for a client until dropped.
+pub struct ConnectionSlot {
+    ip: IpAddr,
+}
+
+/// Takes a connection slot for `ip`, unless it already holds `max_per_client` of them.
+pub fn acquire(ip: IpAddr, max_per_client: Option<usize>) -> Option<ConnectionSlot> {
+    let mut open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+    let count = open.get_or_insert_with(HashMap::new).entry(ip).or_insert(0);
+
+    if max_per_client.is_some_and(|max| *count >= max) {
+        REJECTED_CONNECTIONS.increment();
+        log::warn!("Rejecting connection from {}: {} already open", ip, count);
+        return None;
+    }
+
+    *count += 1;
+    OPEN_CONNECTIONS.increment();
+    Some(ConnectionSlot { ip })
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(open) = open.as_mut() {
+            if let Some(count) = open.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&self.ip);
+                }
+            }
+        }
+        OPEN_CONNECTIONS.decrement();
+    }
+}
+
+/// `address count` lines for every client with open connections, busiest first.
+pub fn describe() -> String {
+    let open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+    let mut clients: Vec<_> = open.iter().flatten().collect();
+    clients.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    clients
+        .into_iter()
+        .map(|(ip, count)| format!("{} {}\n", ip, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(198, 51, 100, last))
+    }
+
+    fn line_of(ip: IpAddr) -> Option<String> {
+        describe()
+            .lines()
+            .find(|line| line.split(' ').next() == Some(&ip.to_string()))
+            .map(str::to_string)
+    }
+
+    #[test]
+    fn clients_hold_no_more_than_their_share() {
+        let first = acquire(ip(1), Some(2)).unwrap();
+        let second = acquire(ip(1), Some(2)).unwrap();
+        assert!(acquire(ip(1), Some(2)).is_none());
+        // Other clients have slots of their own
+        let other = acquire(ip(2), Some(2)).unwrap();
+
+        drop(first);
+        let third = acquire(ip(1), Some(2)).unwrap();
+        assert!(acquire(ip(1), Some(2)).is_none());
+        drop((second, third, other));
+        assert!(acquire(ip(1), Some(2)).is_some());
+    }
+
+    #[test]
+    fn unlimited_clients_are_still_counted() {
+        let slots: Vec<_> = (0..5).map(|_| acquire(ip(3), None).unwrap()).collect();
+        let busy = acquire(ip(4), None).unwrap();
+        assert_eq!(line_of(ip(3)).as_deref(), Some("198.51.100.3 5"));
+        assert_eq!(line_of(ip(4)).as_deref(), Some("198.51.100.4 1"));
+        let listed = describe();
+        let position = |ip: IpAddr| listed.find(&format!("{} ", ip)).unwrap();
+        assert!(position(ip(3)) < position(ip(4)));
+
+        drop(slots);
+        drop(busy);
+        assert_eq!(line_of(ip(3)), None);
+        assert_eq!(line_of(ip(4)), None);
+    }
+
+    #[test]
+    fn answers_parse_from_their_names() {
+        assert_eq!(OverLimit::default(), OverLimit::Unavailable);
+        for answer in [
+            OverLimit::TooManyRequests,
+            OverLimit::Unavailable,
+            OverLimit::Drop,
+        ] {
+            assert_eq!(answer.to_string().parse::<OverLimit>(), Ok(answer));
+        }
+        assert!("404".parse::<OverLimit>().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! What is known about a client connection while it is handled: who opened it, where it was
+//! routed, how the response was encoded and how much of its body was sent.
+//!
+//! Each connection is handled on a thread of its own, which makes its context current so that
+//! log lines and the code deep inside the handlers can reach it without passing it around.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::compression::CompressionType;
+use crate::header_case::HeadRewriter;
+use crate::route::RouteConfig;
+use crate::slow_clients::ClientLimits;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<ConnectionContext>>> = const { RefCell::new(None) };
+}
+
+pub struct ConnectionContext {
+    /// Sequential id, shown on every log line about the connection
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub route: Arc<RouteConfig>,
+    pub client_limits: ClientLimits,
+    pub started: Instant,
+    /// Content coding of the response, once its head is sent
+    encoding: Mutex<Option<CompressionType>>,
+    /// Bytes sent after the response head, including any chunked framing
+    body_bytes: AtomicU64,
+    /// How far the response head has been written, for `--header-case`
+    head: Mutex<HeadRewriter>,
+    /// When the request head must have arrived by, while it is read
+    head_deadline: Mutex<Option<Instant>>,
+}
+
+impl ConnectionContext {
+    pub fn new(
+        peer: SocketAddr,
+        route: Arc<RouteConfig>,
+        client_limits: ClientLimits,
+    ) -> Arc<Self> {
+        Arc::new(ConnectionContext {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            route,
+            client_limits,
+            started: Instant::now(),
+            encoding: Mutex::new(None),
+            body_bytes: AtomicU64::new(0),
+            head: Mutex::new(HeadRewriter::default()),
+            head_deadline: Mutex::new(None),
+        })
+    }
+
+    /// Makes this the context of the current thread until the guard is dropped.
+    pub fn enter(self: &Arc<Self>) -> Entered {
+        CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(self)));
+        Entered(())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn encoding(&self) -> Option<CompressionType> {
+        *self.encoding.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn body_bytes(&self) -> u64 {
+        self.body_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn head_rewriter(&self) -> MutexGuard<'_, HeadRewriter> {
+        self.head.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn head_deadline(&self) -> Option<Instant> {
+        *self.head_deadline.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_head_deadline(&self, deadline: Option<Instant>) {
+        *self.head_deadline.lock().unwrap_or_else(|e| e.into_inner()) = deadline;
+    }
+}
+
+impl fmt::Display for ConnectionContext {
+    /// How the response went, for the line logged when it is done.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.elapsed())?;
+        if let Some(encoding) = self.encoding() {
+            write!(f, ", {}", encoding)?;
+        }
+        write!(f, ", {} body bytes", self.body_bytes())
+    }
+}
+
+/// Clears the context of the current thread when dropped.
+pub struct Entered(());
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        let _ = CURRENT.try_with(|current| current.borrow_mut().take());
+    }
+}
+
+/// The context of the connection the current thread handles, if any.
+pub fn current() -> Option<Arc<ConnectionContext>> {
+    CURRENT
+        .try_with(|current| current.borrow().clone())
+        .ok()
+        .flatten()
+}
+
+/// Id of the connection the current thread handles, for log lines.
+pub fn current_id() -> Option<u64> {
+    CURRENT
+        .try_with(|current| current.borrow().as_ref().map(|context| context.id))
+        .ok()
+        .flatten()
+}
+
+/// Records the content coding of the response to the current connection.
+pub fn record_encoding(encoding: CompressionType) {
+    if let Some(context) = current() {
+        *context.encoding.lock().unwrap_or_else(|e| e.into_inner()) = Some(encoding);
+    }
+}
+
+/// Adds `n` bytes to the body sent on the current connection.
+pub fn count_body_bytes(n: u64) {
+    let _ = CURRENT.try_with(|current| {
+        if let Some(context) = current.borrow().as_ref() {
+            context.body_bytes.fetch_add(n, Ordering::Relaxed);
+        }
+    });
+}
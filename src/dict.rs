@@ -0,0 +1,60 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Trains a zstd dictionary from every file under `inputs` and writes it to `output`.
+pub fn train(inputs: &[PathBuf], output: &Path, max_size: usize) -> io::Result<()> {
+    let start_time = Instant::now();
+
+    let mut samples = Vec::new();
+    for input in inputs {
+        collect_files(input, &mut samples)?;
+    }
+    if samples.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No sample files found",
+        ));
+    }
+
+    let total_bytes: u64 = samples
+        .iter()
+        .filter_map(|p| fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+    log::info!(
+        "Training dictionary of up to {} bytes from {} files ({} bytes)",
+        max_size,
+        samples.len(),
+        total_bytes
+    );
+
+    let dictionary = zstd::dict::from_files(&samples, max_size)?;
+    fs::write(output, &dictionary)?;
+
+    log::info!(
+        "Wrote {} byte dictionary to {} in {:?}",
+        dictionary.len(),
+        output.display(),
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+/// Appends every file under `path` (or `path` itself, if it is a file) to `files`, in sorted order.
+pub(crate) fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_file() {
+        files.push(path.to_path_buf());
+    } else if metadata.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+//! Routes discovered at runtime from a directory of route files, so that orchestration systems can
+//! add and remove backends without restarting zstdp.
+//!
+//! Each `*.route` file defines one route as `key = value` lines, with `#` comments:
+//!
+//! ```text
+//! host = api.example.com
+//! path = /v1
+//! forward = 10.0.0.5:3000
+//! ```
+//!
+//! `host` (optional) and `path` (a prefix, `/` by default) select the requests; exactly one of
+//! `forward` and `serve` says where they go, as the command-line options of the same name do.
+//! A FastCGI backend also needs `fastcgi-root`, and `encodings`, `lb-strategy`, `host-rewrite`,
+//! `backend-proxy-protocol`, `strict-response-headers`, `allow-response-header`,
+//! `connect-allow`, `h2c-passthrough` and `secret-cookie` override the options of the same name.
+//! `bypass` and `maintenance` lines, one pattern or window each, replace those of the command
+//! line, and `active` lines limit the route to their windows (see `schedule`). Other options,
+//! apart from `--manifest`, are inherited from the command line. Requests matching no route go to
+//! the routes given on the command line.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::args::Args;
+use crate::proxy::shield::Shield;
+use crate::route::RouteConfig;
+use crate::router::{self, Route};
+
+/// Routes in the order they are tried, see `router::sort`.
+static ROUTES: Mutex<Vec<Arc<Route>>> = Mutex::new(Vec::new());
+
+/// The routes discovered so far.
+pub fn routes() -> Vec<Arc<Route>> {
+    ROUTES.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Loads a route file, named after the file without its `.route` extension.
+fn load(path: &Path, args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<Route> {
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let content = fs::read_to_string(path)?;
+    let lines = content.lines().enumerate().map(|(i, line)| (i + 1, line));
+    parse(&name, None, lines, args, shield).map_err(|reason| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), reason),
+        )
+    })
+}
+
+/// Builds the route `name` from `key = value` lines given with their line numbers, as found in
+/// route files. `host` applies unless a `host` line overrides it, and backend responses go to
+/// `shield` if it is given.
+pub(crate) fn parse<'a>(
+    name: &str,
+    mut host: Option<String>,
+    lines: impl Iterator<Item = (usize, &'a str)>,
+    args: &Args,
+    shield: Option<&Arc<Shield>>,
+) -> Result<Route, String> {
+    let mut args = args.clone();
+    args.forward = None;
+    args.serve = None;
+    args.s3 = None;
+    args.embedded = false;
+    args.fastcgi_root = None;
+    // The manifest describes the directory given on the command line
+    args.manifest = None;
+    let mut prefix = "/".to_string();
+    let mut bypass_set = false;
+    let mut maintenance_set = false;
+    let mut active = Vec::new();
+    for (number, line) in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| format!("line {}: expected key = value", number))?;
+        match key {
+            "host" => host = Some(value.to_string()),
+            "path" => prefix = value.to_string(),
+            "forward" => {
+                args.forward = Some(
+                    value
+                        .parse()
+                        .map_err(|e| format!("line {}: {}", number, e))?,
+                )
+            }
+            "serve" => args.serve = Some(PathBuf::from(value)),
+            "fastcgi-root" => args.fastcgi_root = Some(PathBuf::from(value)),
+            "lb-strategy" => {
+                args.lb_strategy = value
+                    .parse()
+                    .map_err(|e| format!("line {}: {}", number, e))?
+            }
+            "host-rewrite" => {
+                args.host_rewrite = value
+                    .parse()
+                    .map_err(|e| format!("line {}: {}", number, e))?
+            }
+            "backend-proxy-protocol" => {
+                args.backend_proxy_protocol = value
+                    .parse()
+                    .map_err(|_| format!("line {}: expected true or false", number))?
+            }
+            "h2c-passthrough" => {
+                args.h2c_passthrough = value
+                    .parse()
+                    .map_err(|_| format!("line {}: expected true or false", number))?
+            }
+            "strict-response-headers" => {
+                args.strict_response_headers = value
+                    .parse()
+                    .map_err(|_| format!("line {}: expected true or false", number))?
+            }
+            "allow-response-header" => {
+                args.allow_response_header = value.split(',').map(str::to_string).collect()
+            }
+            "bypass" => {
+                // Each line adds a pattern to those of the route, which replace the command line's
+                if !bypass_set {
+                    args.bypass.clear();
+                    bypass_set = true;
+                }
+                args.bypass.push(value.to_string())
+            }
+            "active" => active.push(
+                value
+                    .parse()
+                    .map_err(|e| format!("line {}: {}", number, e))?,
+            ),
+            "maintenance" => {
+                // Like bypass lines, they replace the windows of the command line
+                if !maintenance_set {
+                    args.maintenance.clear();
+                    maintenance_set = true;
+                }
+                args.maintenance.push(
+                    value
+                        .parse()
+                        .map_err(|e| format!("line {}: {}", number, e))?,
+                )
+            }
+            "connect-allow" => {
+                args.connect_allow = value
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("line {}: {}", number, e))?
+            }
+            "secret-cookie" => args.secret_cookie = value.split(',').map(str::to_string).collect(),
+            "encodings" => {
+                args.encodings = Some(
+                    value
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(|e| format!("line {}: {}", number, e))?,
+                )
+            }
+            _ => return Err(format!("line {}: unknown key '{}'", number, key)),
+        }
+    }
+    if args.forward.is_some() == args.serve.is_some() {
+        return Err("expected either forward or serve".to_string());
+    }
+
+    let config = RouteConfig::from_args(&args, shield).map_err(|e| e.to_string())?;
+    Ok(Route::new(name, host, &prefix, Arc::new(config)).with_schedule(active))
+}
+
+/// Loads the routes in `dir` once, without watching it.
+pub fn load_dir(dir: &Path, args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<()> {
+    apply(load_all(&scan(dir)?, args, shield));
+    Ok(())
+}
+
+/// Loads the routes in `dir`, then checks it for changes every `interval` and applies them.
+pub fn watch(
+    dir: PathBuf,
+    args: &Args,
+    shield: Option<&Arc<Shield>>,
+    interval: Duration,
+) -> io::Result<()> {
+    let mut snapshot = scan(&dir)?;
+    apply(load_all(&snapshot, args, shield));
+    log::info!("Watching {} for routes", dir.display());
+
+    let args = args.clone();
+    let shield = shield.cloned();
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match scan(&dir) {
+            Ok(current) if current != snapshot => {
+                apply(load_all(&current, &args, shield.as_ref()));
+                snapshot = current;
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to read routes from {}: {}", dir.display(), e),
+        }
+    });
+    Ok(())
+}
+
+/// The route files in `dir` with their modification times and sizes, to notice changes.
+fn scan(dir: &Path) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "route") {
+            continue;
+        }
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_file() {
+            files.push((path, metadata.modified()?, metadata.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Loads every route file, skipping invalid ones so that a bad file cannot take the others down.
+fn load_all(
+    files: &[(PathBuf, SystemTime, u64)],
+    args: &Args,
+    shield: Option<&Arc<Shield>>,
+) -> Vec<Arc<Route>> {
+    files
+        .iter()
+        .filter_map(|(path, _, _)| match load(path, args, shield) {
+            Ok(route) => Some(Arc::new(route)),
+            Err(e) => {
+                log::warn!("Skipping route: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn apply(mut routes: Vec<Arc<Route>>) {
+    router::sort(&mut routes);
+    router::log_routes(&routes);
+    let mut current = ROUTES.lock().unwrap_or_else(|e| e.into_inner());
+    for old in current.iter() {
+        if !routes.iter().any(|route| route.name == old.name) {
+            log::info!("Route {} removed", old.name);
+        }
+    }
+    *current = routes;
+}
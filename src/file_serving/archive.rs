@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use mime_guess::from_path;
+use percent_encoding::percent_decode_str;
+
+use super::conditional::Validators;
+use super::spa::SpaConfig;
+use super::{cache_headers, compress_in_memory, Body, FileResponse};
+use crate::compression::{AcceptedCompression, CompressionOptions, CompressionType};
+
+/// A file inside an archive.
+struct Member {
+    data: Arc<[u8]>,
+    modified: Option<SystemTime>,
+}
+
+/// Frames of zstd-compressed archives start with these bytes.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[cfg(zstdp_embed)]
+static EMBEDDED: Option<&[u8]> = Some(include_bytes!(env!("ZSTDP_EMBED_ARCHIVE")));
+#[cfg(not(zstdp_embed))]
+static EMBEDDED: Option<&[u8]> = None;
+
+/// Members compressed on the fly, by name and encoding.
+type CompressedCache = HashMap<(String, CompressionType), Arc<[u8]>>;
+
+/// A `.tar` or `.tar.zst` bundle of static files, unpacked into memory once at startup and served
+/// without touching the filesystem. Members compressed on the fly are cached, since the archive
+/// never changes.
+pub struct Archive {
+    /// Members by their path inside the archive, without a leading `/`
+    members: HashMap<String, Member>,
+    compressed: Mutex<CompressedCache>,
+}
+
+impl Archive {
+    /// Whether `path` names an archive that `load` understands.
+    pub fn is_archive(path: &Path) -> bool {
+        let name = path.to_string_lossy().to_lowercase();
+        name.ends_with(".tar") || name.ends_with(".tar.zst") || name.ends_with(".tzst")
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if path.to_string_lossy().to_lowercase().ends_with(".tar") {
+            Self::from_tar(file, &path.display().to_string())
+        } else {
+            Self::from_tar(zstd::Decoder::new(file)?, &path.display().to_string())
+        }
+    }
+
+    /// The archive built into the binary with `ZSTDP_EMBED_ARCHIVE`, if any.
+    pub fn embedded() -> io::Result<Option<Self>> {
+        let Some(bytes) = EMBEDDED else {
+            return Ok(None);
+        };
+        let archive = if bytes.starts_with(&ZSTD_MAGIC) {
+            Self::from_tar(zstd::Decoder::new(bytes)?, "the embedded archive")?
+        } else {
+            Self::from_tar(bytes, "the embedded archive")?
+        };
+        Ok(Some(archive))
+    }
+
+    /// Unpacks a tar stream, naming it `source` in the log.
+    fn from_tar<R: Read>(reader: R, source: &str) -> io::Result<Self> {
+        let start_time = Instant::now();
+
+        let mut members = HashMap::new();
+        let mut total_bytes = 0;
+        for entry in tar::Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = member_name(&entry.path()?);
+            if name.is_empty() {
+                continue;
+            }
+            let modified = entry
+                .header()
+                .mtime()
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            let mut data = Vec::with_capacity(entry.size() as usize);
+            entry.read_to_end(&mut data)?;
+            total_bytes += data.len();
+            members.insert(
+                name,
+                Member {
+                    data: data.into(),
+                    modified,
+                },
+            );
+        }
+
+        log::info!(
+            "Loaded {} files ({} bytes) from {} in {:?}",
+            members.len(),
+            total_bytes,
+            source,
+            start_time.elapsed()
+        );
+        Ok(Self {
+            members,
+            compressed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the archive has a member at `request_path`.
+    pub fn contains(&self, request_path: &str) -> bool {
+        self.members
+            .contains_key(&member_name(Path::new(request_path)))
+    }
+
+    /// The archive counterpart of `serve_file`: resolves `request_path` to a member, preferring
+    /// pre-compressed siblings in the archive, then a cached or freshly compressed copy.
+    pub fn serve(
+        &self,
+        request_path: &str,
+        accepted_compression: AcceptedCompression,
+        options: &CompressionOptions,
+        should_bypass: bool,
+        spa_config: Option<&SpaConfig>,
+        index_files: &[String],
+    ) -> io::Result<Option<FileResponse>> {
+        let path_without_query = request_path.split('?').next().unwrap_or(request_path);
+        let decoded = percent_decode_str(path_without_query)
+            .decode_utf8()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let name = member_name(Path::new(decoded.as_ref()));
+        let Some(name) = self.resolve(name, spa_config, index_files) else {
+            log::debug!("No archive member for {}", request_path);
+            return Ok(None);
+        };
+        let member = &self.members[&name];
+        log::debug!("Serving archive member {}", name);
+
+        let mime_type = from_path(&name).first_or_octet_stream().to_string();
+        let headers = cache_headers(Path::new(&name), index_files);
+
+        let candidates: Vec<CompressionType> = if should_bypass {
+            Vec::new()
+        } else {
+            accepted_compression.preferred().collect()
+        };
+
+        // Pre-compressed siblings inside the archive come first, as on disk
+        for &compression in &candidates {
+            let sibling = format!("{}{}", name, compression.extension());
+            if let Some(precompressed) = self.members.get(&sibling) {
+                return Ok(Some(FileResponse {
+                    validators: Validators::for_content(
+                        precompressed.data.len() as u64,
+                        precompressed.modified,
+                        compression,
+                        false,
+                    ),
+                    body: Body::Memory(Arc::clone(&precompressed.data)),
+                    encoded: false,
+                    precompressed: true,
+                    mime_type,
+                    compression,
+                    headers,
+                }));
+            }
+        }
+
+        let (data, compression) = match candidates.first() {
+            Some(&compression) => (
+                self.compress(&name, member, compression, options, &mime_type)?,
+                compression,
+            ),
+            None => (Arc::clone(&member.data), CompressionType::None),
+        };
+        Ok(Some(FileResponse {
+            validators: Validators::for_content(
+                member.data.len() as u64,
+                member.modified,
+                compression,
+                compression != CompressionType::None,
+            ),
+            body: Body::Memory(data),
+            encoded: false,
+            precompressed: false,
+            mime_type,
+            compression,
+            headers,
+        }))
+    }
+
+    /// Maps a cleaned request path to a member: directories to their first index file, and
+    /// unknown non-asset paths to the SPA index.
+    fn resolve(
+        &self,
+        name: String,
+        spa_config: Option<&SpaConfig>,
+        index_files: &[String],
+    ) -> Option<String> {
+        if self.members.contains_key(&name) {
+            return Some(name);
+        }
+
+        let index = index_names(&name, index_files).find(|index| self.members.contains_key(index));
+        if index.is_some() {
+            return index;
+        }
+
+        let spa_config = spa_config?;
+        if spa_config.is_static_file(Path::new(&name)) {
+            return None;
+        }
+        index_names("", index_files).find(|index| self.members.contains_key(index))
+    }
+
+    /// `member` compressed with `compression`, from the cache if it was compressed before. Cached
+    /// copies keep the level of the request that compressed them.
+    fn compress(
+        &self,
+        name: &str,
+        member: &Member,
+        compression: CompressionType,
+        options: &CompressionOptions,
+        mime_type: &str,
+    ) -> io::Result<Arc<[u8]>> {
+        let key = (name.to_string(), compression);
+        if let Some(data) = self.cache().get(&key) {
+            return Ok(Arc::clone(data));
+        }
+
+        // Concurrent misses may compress the same member twice; the copies are interchangeable
+        let data: Arc<[u8]> =
+            compress_in_memory(&member.data, compression, options, mime_type)?.into();
+        log::debug!(
+            "Cached {} of {} ({} -> {} bytes)",
+            compression,
+            name,
+            member.data.len(),
+            data.len()
+        );
+
+        self.cache().insert(key, Arc::clone(&data));
+        Ok(data)
+    }
+
+    fn cache(&self) -> MutexGuard<'_, CompressedCache> {
+        self.compressed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The names of the index files of directory `name`, in order of preference.
+pub(super) fn index_names<'a>(
+    name: &'a str,
+    index_files: &'a [String],
+) -> impl Iterator<Item = String> + 'a {
+    index_files.iter().map(move |index| match name {
+        "" => index.clone(),
+        name => format!("{}/{}", name, index),
+    })
+}
+
+/// The member name for a path inside the archive or a request path: its normal components
+/// joined by `/`, dropping roots, `.` and `..` as `sanitize_path` does.
+pub(super) fn member_name(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+    parts.join("/")
+}
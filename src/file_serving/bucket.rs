@@ -0,0 +1,642 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use mime_guess::from_path;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+
+use super::archive::{index_names, member_name};
+use super::conditional::Validators;
+use super::spa::SpaConfig;
+use super::{cache_headers, Body, FileResponse};
+use crate::compression::{AcceptedCompression, CompressionType};
+use crate::precompress::{self, write_atomically, write_atomically_with};
+use crate::proxy::backend::BackendAddr;
+use crate::proxy::cache_key::{CacheKeyTemplate, KeyedRequest};
+use crate::proxy::headers::parse_response_headers;
+use crate::proxy::transfer::{decode_chunked_body, read_response_head};
+use crate::proxy::BackendTimeouts;
+use crate::stats::CountingWriter;
+
+/// SHA-256 of an empty payload, the body of every request we sign.
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Characters left alone in URI path segments by SigV4 canonicalization.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Access keys for signing requests, from the standard `AWS_*` environment variables.
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Option<Self> {
+        Some(Self {
+            access_key: env::var("AWS_ACCESS_KEY_ID").ok()?,
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Objects kept on local disk, served without asking the bucket for `ttl` after they were last
+/// fetched or revalidated.
+struct ObjectCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+/// What the bucket answered for a key.
+enum Fetched {
+    Object {
+        body: ObjectBody,
+        etag: Option<String>,
+        last_modified: Option<SystemTime>,
+    },
+    NotModified,
+    Missing,
+}
+
+/// An S3-compatible bucket served in file server mode. Objects are fetched with path-style,
+/// SigV4-signed GET requests over plain HTTP, and go through the same negotiation and compression
+/// as files on disk.
+pub struct Bucket {
+    endpoint: BackendAddr,
+    bucket: String,
+    /// Key prefix that request paths are appended to, empty or ending in `/`
+    prefix: String,
+    region: String,
+    credentials: Option<Credentials>,
+    cache: Option<ObjectCache>,
+    timeouts: BackendTimeouts,
+}
+
+impl Bucket {
+    /// A bucket given as `http://host[:port]/bucket[/prefix]`. Requests are anonymous unless
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` are set.
+    pub fn new(
+        url: &str,
+        region: &str,
+        cache: Option<(PathBuf, Duration)>,
+        timeouts: BackendTimeouts,
+    ) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid bucket URL '{}': {}", url, reason),
+            )
+        };
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => {
+                return Err(invalid(
+                    "HTTPS endpoints need a local TLS proxy, as zstdp only speaks plain HTTP",
+                ))
+            }
+            None => return Err(invalid("expected http://host[:port]/bucket[/prefix]")),
+        };
+        let (authority, path) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("missing bucket name"))?;
+        let authority = if authority.starts_with('[') || authority.matches(':').count() == 1 {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        let endpoint = authority.parse::<BackendAddr>().map_err(|e| invalid(&e))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(invalid("missing bucket name"));
+        }
+        let prefix = match prefix.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+
+        let credentials = Credentials::from_env();
+        if credentials.is_none() {
+            log::info!("No AWS credentials in the environment, sending anonymous requests");
+        }
+        let cache = match cache {
+            Some((dir, ttl)) => {
+                fs::create_dir_all(&dir)?;
+                Some(ObjectCache { dir, ttl })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            endpoint,
+            bucket: bucket.to_string(),
+            prefix,
+            region: region.to_string(),
+            credentials,
+            cache,
+            timeouts,
+        })
+    }
+
+    /// Whether there is an object at `request_path`. Failures to reach the bucket count as no.
+    pub fn contains(&self, request_path: &str) -> bool {
+        let name = member_name(Path::new(request_path));
+        matches!(self.object(&name), Ok(Some(_)))
+    }
+
+    /// The bucket counterpart of `serve_file`: resolves `request_path` to an object, falling back
+    /// to the index files below it and then to the SPA index.
+    pub fn serve(
+        &self,
+        request_path: &str,
+        accepted_compression: AcceptedCompression,
+        should_bypass: bool,
+        spa_config: Option<&SpaConfig>,
+        index_files: &[String],
+    ) -> io::Result<Option<FileResponse>> {
+        let path_without_query = request_path.split('?').next().unwrap_or(request_path);
+        let decoded = percent_decode_str(path_without_query)
+            .decode_utf8()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let name = member_name(Path::new(decoded.as_ref()));
+
+        let mut candidates = Vec::new();
+        if !name.is_empty() && !decoded.ends_with('/') {
+            candidates.push(name.clone());
+        }
+        candidates.extend(index_names(&name, index_files));
+        if let Some(spa_config) = spa_config {
+            if !spa_config.is_static_file(Path::new(&name)) {
+                candidates.extend(index_names("", index_files));
+            }
+        }
+
+        for name in candidates {
+            if let Some(object) = self.object(&name)? {
+                log::debug!("Serving object {}{}", self.prefix, name);
+                let headers = cache_headers(Path::new(&name), index_files);
+                return self
+                    .respond(&name, object, headers, accepted_compression, should_bypass)
+                    .map(Some);
+            }
+        }
+        log::debug!("No object for {}", request_path);
+        Ok(None)
+    }
+
+    fn respond(
+        &self,
+        name: &str,
+        object: StoredObject,
+        headers: Vec<(String, String)>,
+        accepted_compression: AcceptedCompression,
+        should_bypass: bool,
+    ) -> io::Result<FileResponse> {
+        let mime_type = from_path(name).first_or_octet_stream().to_string();
+
+        let compression = if should_bypass {
+            CompressionType::None
+        } else {
+            accepted_compression.best()
+        };
+        let on_the_fly = compression != CompressionType::None;
+        let length = object.length();
+        let validators = Validators::for_content(
+            length.unwrap_or(0),
+            object.last_modified,
+            compression,
+            on_the_fly,
+        );
+
+        let (body, encoded) = match object.content {
+            // Objects of unknown length are sent chunked, whether compressed or not
+            Content::Stream(body) => (body.into_body(), on_the_fly || length.is_none()),
+            Content::Cached { file, length } => (Body::File { file, length }, on_the_fly),
+        };
+        Ok(FileResponse {
+            body,
+            encoded,
+            precompressed: false,
+            mime_type,
+            compression,
+            validators,
+            headers,
+        })
+    }
+
+    /// The object at `name` below the prefix, from the local cache while it is fresh.
+    fn object(&self, name: &str) -> io::Result<Option<StoredObject>> {
+        let key = format!("{}{}", self.prefix, name);
+        let Some(cache) = &self.cache else {
+            return Ok(match self.get(&key, None)? {
+                Fetched::Object {
+                    body,
+                    last_modified,
+                    ..
+                } => Some(StoredObject {
+                    content: Content::Stream(body),
+                    last_modified,
+                }),
+                Fetched::NotModified | Fetched::Missing => None,
+            });
+        };
+
+        let (data_path, meta_path) = cache.paths(&self.cache_key(&key));
+        let meta = fs::read_to_string(&meta_path)
+            .ok()
+            .map(|m| CacheMeta::parse(&m));
+        // A copy that is not the one its metadata describes is fetched again, whatever its age
+        let meta = match meta {
+            Some(meta) if !meta.describes(&data_path) => {
+                log::debug!(
+                    "Cached copy of {} does not match its metadata, evicting it",
+                    key
+                );
+                cache.evict(&data_path, &meta_path)?;
+                None
+            }
+            meta => meta,
+        };
+        let fresh = fs::metadata(&meta_path)
+            .and_then(|m| m.modified())
+            .is_ok_and(|validated| validated.elapsed().unwrap_or(Duration::ZERO) < cache.ttl);
+
+        match &meta {
+            Some(meta) if fresh => return cache.open(&data_path, meta).map(Some),
+            _ => {}
+        }
+        match self.get(&key, meta.as_ref())? {
+            Fetched::NotModified => {
+                let meta = meta.unwrap_or_default();
+                // Rewriting the metadata restarts the TTL
+                write_atomically(&meta_path, meta.to_string().as_bytes())?;
+                log::debug!("Revalidated cached {}", key);
+                cache.open(&data_path, &meta).map(Some)
+            }
+            Fetched::Object {
+                body,
+                etag,
+                last_modified,
+            } => {
+                // Replace the copy in one rename so concurrent requests never read half of it
+                let length = write_atomically_with(&data_path, |file| body.copy_to(file))?;
+                let meta = CacheMeta {
+                    etag,
+                    last_modified,
+                    length: Some(length),
+                };
+                write_atomically(&meta_path, meta.to_string().as_bytes())?;
+                log::debug!("Cached {} ({} bytes)", key, length);
+                cache.open(&data_path, &meta).map(Some)
+            }
+            Fetched::Missing => {
+                cache.evict(&data_path, &meta_path)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The path-style request target of `key`, percent-encoded as SigV4 canonicalizes it.
+    fn uri(&self, key: &str) -> String {
+        format!("/{}/{}", self.bucket, key)
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// The key the local copy of `key` is stored under: that of a GET of the object in the
+    /// shield's scheme, as the copy is the unencoded response to one.
+    fn cache_key(&self, key: &str) -> String {
+        let host = self.endpoint.host_header();
+        let request = KeyedRequest {
+            host: Some(&host),
+            uri: &self.uri(key),
+            headers: &[],
+            compression: CompressionType::None,
+            forced: true,
+        };
+        CacheKeyTemplate::default()
+            .key(&request)
+            .unwrap_or_else(|| key.to_string())
+    }
+
+    /// Sends a signed GET for `key`, conditional on the validators of the `cached` copy if given.
+    fn get(&self, key: &str, cached: Option<&CacheMeta>) -> io::Result<Fetched> {
+        let uri = self.uri(key);
+        let host = self.endpoint.host_header();
+
+        let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", uri, host);
+        for (name, value) in self.sign(&uri, &host, SystemTime::now()) {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        match cached.map(|meta| (&meta.etag, meta.last_modified)) {
+            Some((Some(etag), _)) => {
+                request.push_str(&format!("If-None-Match: {}\r\n", etag));
+            }
+            Some((None, Some(modified))) => {
+                request.push_str(&format!(
+                    "If-Modified-Since: {}\r\n",
+                    httpdate::fmt_http_date(modified)
+                ));
+            }
+            _ => {}
+        }
+        request.push_str("Connection: close\r\n\r\n");
+
+        let mut server = self.timeouts.connect(&self.endpoint)?;
+        server.write_all(request.as_bytes())?;
+        let head = read_response_head(&mut server, self.timeouts.header, self.timeouts.read)?;
+        let head = String::from_utf8_lossy(&head);
+        let (status_line, headers) = parse_response_headers(&head);
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .unwrap_or(0);
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        match status {
+            200 => {
+                let body = ObjectBody {
+                    length: header("content-length").and_then(|v| v.parse::<u64>().ok()),
+                    chunked: header("transfer-encoding")
+                        .is_some_and(|v| v.to_lowercase().contains("chunked")),
+                    server,
+                };
+                log::debug!("Fetching {} ({:?} bytes)", key, body.length);
+                Ok(Fetched::Object {
+                    body,
+                    etag: header("etag").map(str::to_string),
+                    last_modified: header("last-modified")
+                        .and_then(|v| httpdate::parse_http_date(v).ok()),
+                })
+            }
+            304 => Ok(Fetched::NotModified),
+            404 => Ok(Fetched::Missing),
+            // Without `s3:ListBucket`, missing keys are reported as access denied
+            403 => {
+                log::warn!("Access to {} denied by the bucket", key);
+                Ok(Fetched::Missing)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Bucket answered '{}' for {}", status_line, key),
+            )),
+        }
+    }
+
+    /// The headers that authenticate a GET of `uri` with AWS Signature Version 4, or just the
+    /// payload hash for anonymous requests.
+    fn sign(&self, uri: &str, host: &str, now: SystemTime) -> Vec<(&'static str, String)> {
+        let timestamp = amz_date(now);
+        let mut headers = vec![
+            ("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        let Some(credentials) = &self.credentials else {
+            return headers;
+        };
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let mut canonical_headers = format!("host:{}\n", host);
+        let mut signed_headers = vec!["host"];
+        for (name, value) in &headers {
+            canonical_headers.push_str(&format!("{}:{}\n", name, value));
+            signed_headers.push(name);
+        }
+        let signed_headers = signed_headers.join(";");
+        let canonical_request = format!(
+            "GET\n{}\n\n{}\n{}\n{}",
+            uri, canonical_headers, signed_headers, EMPTY_PAYLOAD_SHA256
+        );
+
+        let date = &timestamp[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = [date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", credentials.secret_key).into_bytes(),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        headers.push((
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key, scope, signed_headers, signature
+            ),
+        ));
+        headers
+    }
+}
+
+/// Deletes the temporary files that fetches interrupted by a crash left in the object cache at
+/// `dir`, including the `.tmp` files of versions that did not name them after their process.
+pub fn remove_orphans(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            match fs::remove_file(&path) {
+                Ok(()) => log::info!("Removed {}, left by an interrupted fetch", path.display()),
+                Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+    }
+    precompress::remove_orphans_under(dir)
+}
+
+impl ObjectCache {
+    /// Cached objects are named by the hash of their key, so that any key maps to one flat file.
+    fn paths(&self, key: &str) -> (PathBuf, PathBuf) {
+        let name = hex(&Sha256::digest(key.as_bytes()));
+        (
+            self.dir.join(&name),
+            self.dir.join(format!("{}.meta", name)),
+        )
+    }
+
+    /// Deletes a cached copy and its metadata.
+    fn evict(&self, data_path: &Path, meta_path: &Path) -> io::Result<()> {
+        for path in [data_path, meta_path] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&self, data_path: &Path, meta: &CacheMeta) -> io::Result<StoredObject> {
+        let file = File::open(data_path)?;
+        let length = file.metadata()?.len();
+        Ok(StoredObject {
+            content: Content::Cached { file, length },
+            last_modified: meta.last_modified,
+        })
+    }
+}
+
+/// The `ETag` and `Last-Modified` of a cached object and the length of its copy, one per line.
+#[derive(Default)]
+struct CacheMeta {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+    length: Option<u64>,
+}
+
+impl CacheMeta {
+    fn parse(contents: &str) -> Self {
+        let mut lines = contents.lines();
+        let mut next = || lines.next().filter(|line| !line.is_empty());
+        Self {
+            etag: next().map(str::to_string),
+            last_modified: next().and_then(|v| httpdate::parse_http_date(v).ok()),
+            length: next().and_then(|v| v.parse().ok()),
+        }
+    }
+
+    /// Whether the copy at `data_path` is the one fetched along with this metadata, rather than
+    /// missing, cut short or replaced by a fetch that has not written its metadata yet. Copies of
+    /// versions that did not record their length are never taken for it.
+    fn describes(&self, data_path: &Path) -> bool {
+        fs::metadata(data_path).is_ok_and(|data| Some(data.len()) == self.length)
+    }
+}
+
+impl std::fmt::Display for CacheMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.etag.as_deref().unwrap_or(""))?;
+        match self.last_modified {
+            Some(modified) => writeln!(f, "{}", httpdate::fmt_http_date(modified))?,
+            None => writeln!(f)?,
+        }
+        match self.length {
+            Some(length) => writeln!(f, "{}", length),
+            None => writeln!(f),
+        }
+    }
+}
+
+/// An object fetched from the bucket, streamed from it or in the local cache.
+struct StoredObject {
+    content: Content,
+    last_modified: Option<SystemTime>,
+}
+
+enum Content {
+    Stream(ObjectBody),
+    Cached { file: File, length: u64 },
+}
+
+impl StoredObject {
+    fn length(&self) -> Option<u64> {
+        match &self.content {
+            Content::Stream(body) => body.length,
+            Content::Cached { length, .. } => Some(*length),
+        }
+    }
+}
+
+/// The body of an object still to be read from the bucket, framed by `Content-Length`, chunked
+/// encoding or the end of the connection. It is never held in memory whole.
+struct ObjectBody {
+    server: TcpStream,
+    length: Option<u64>,
+    chunked: bool,
+}
+
+impl ObjectBody {
+    /// The body as it is sent on to the client.
+    fn into_body(self) -> Body<'static> {
+        if self.chunked {
+            Body::Chunked(Box::new(self.server))
+        } else {
+            Body::Stream {
+                reader: Box::new(self.server),
+                length: self.length,
+            }
+        }
+    }
+
+    /// Copies the content of the body to `out`, and returns its size.
+    fn copy_to<W: Write>(mut self, out: &mut W) -> io::Result<u64> {
+        if self.chunked {
+            let mut counter = CountingWriter::new(out);
+            // Trailers say nothing the cached copy needs
+            decode_chunked_body(&mut self.server, &mut counter)?;
+            return Ok(counter.count());
+        }
+        let Some(length) = self.length else {
+            return io::copy(&mut self.server, out);
+        };
+        let copied = io::copy(&mut (&mut self.server).take(length), out)?;
+        if copied < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Bucket closed the connection before the end of the object",
+            ));
+        }
+        Ok(copied)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `time` as an ISO 8601 basic timestamp, e.g. `20261016T101022Z`.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
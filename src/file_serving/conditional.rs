@@ -0,0 +1,204 @@
+use std::fs::Metadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::compression::CompressionType;
+
+/// The validators of one representation of a file, which conditional request headers
+/// (`If-None-Match`, `If-Modified-Since` and `If-Range`) are checked against.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Validators derived from the size and modification time of a file. Stored bytes get a
+    /// strong entity tag, with the encoding of pre-compressed files appended; output compressed
+    /// on the fly gets a weak one, since its bytes depend on the compression settings.
+    pub fn new(metadata: &Metadata, compression: CompressionType, on_the_fly: bool) -> Self {
+        Self::for_content(
+            metadata.len(),
+            metadata.modified().ok(),
+            compression,
+            on_the_fly,
+        )
+    }
+
+    /// Like `new`, for content that is not a file of its own, such as an archive member.
+    pub fn for_content(
+        length: u64,
+        last_modified: Option<SystemTime>,
+        compression: CompressionType,
+        on_the_fly: bool,
+    ) -> Self {
+        let modified_nanos = last_modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_nanos());
+        let tag = match compression {
+            CompressionType::None => format!("{:x}-{:x}", length, modified_nanos),
+            compression => format!("{:x}-{:x}-{}", length, modified_nanos, compression),
+        };
+        let etag = if on_the_fly {
+            format!("W/\"{}\"", tag)
+        } else {
+            format!("\"{}\"", tag)
+        };
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    /// Weak validators only promise equivalent content, not the same bytes.
+    pub fn is_weak(&self) -> bool {
+        self.etag.starts_with("W/")
+    }
+
+    /// Whether a GET can be answered with 304 Not Modified. As RFC 9110 requires,
+    /// `If-Modified-Since` only counts when there is no `If-None-Match`.
+    pub fn not_modified(
+        &self,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> bool {
+        match (if_none_match, if_modified_since) {
+            (Some(value), _) => self.matches_any(value),
+            (None, Some(value)) => self.unmodified_since(value),
+            (None, None) => false,
+        }
+    }
+
+    /// Whether the validator in an `If-Range` header still matches, so that the requested ranges
+    /// may be sent. Entity tags only match exactly, and weak tags never do; dates must equal
+    /// `Last-Modified`.
+    pub fn if_range(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') || value.starts_with("W/") {
+            return !value.starts_with("W/") && !self.is_weak() && value == self.etag;
+        }
+        match (httpdate::parse_http_date(value), self.last_modified) {
+            (Ok(date), Some(modified)) => whole_seconds(date) == whole_seconds(modified),
+            _ => false,
+        }
+    }
+
+    /// `ETag` and `Last-Modified` response headers.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![("ETag".to_string(), self.etag.clone())];
+        if let Some(modified) = self.last_modified {
+            headers.push((
+                "Last-Modified".to_string(),
+                httpdate::fmt_http_date(modified),
+            ));
+        }
+        headers
+    }
+
+    /// Whether an `If-None-Match` header lists our entity tag, using weak comparison.
+    fn matches_any(&self, value: &str) -> bool {
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        value.trim() == "*"
+            || value
+                .split(',')
+                .any(|tag| opaque(tag) == opaque(&self.etag))
+    }
+
+    /// Whether the file has not changed since the date in an `If-Modified-Since` header.
+    /// Invalid dates are ignored.
+    fn unmodified_since(&self, value: &str) -> bool {
+        match (httpdate::parse_http_date(value.trim()), self.last_modified) {
+            (Ok(date), Some(modified)) => whole_seconds(modified) <= whole_seconds(date),
+            _ => false,
+        }
+    }
+}
+
+/// HTTP dates have a resolution of one second.
+fn whole_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)
+    }
+
+    fn stored() -> Validators {
+        Validators::for_content(4096, Some(modified()), CompressionType::None, false)
+    }
+
+    #[test]
+    fn entity_tags_tell_representations_apart() {
+        let stored = stored();
+        assert!(!stored.is_weak());
+        let precompressed =
+            Validators::for_content(4096, Some(modified()), CompressionType::Zstd, false);
+        assert!(precompressed.etag.ends_with("-zstd\""));
+        assert_ne!(precompressed.etag, stored.etag);
+        let on_the_fly =
+            Validators::for_content(4096, Some(modified()), CompressionType::Zstd, true);
+        assert!(on_the_fly.is_weak());
+        assert_eq!(on_the_fly.etag, format!("W/{}", precompressed.etag));
+        let changed = Validators::for_content(4097, Some(modified()), CompressionType::None, false);
+        assert_ne!(changed.etag, stored.etag);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly_and_wins_over_dates() {
+        let stored = stored();
+        let etag = stored.etag.clone();
+        assert!(stored.not_modified(Some(&etag), None));
+        assert!(stored.not_modified(Some(&format!("\"other\", W/{}", etag)), None));
+        assert!(stored.not_modified(Some(" * "), None));
+        assert!(!stored.not_modified(Some("\"other\""), None));
+        let later = httpdate::fmt_http_date(modified() + Duration::from_secs(60));
+        assert!(!stored.not_modified(Some("\"other\""), Some(&later)));
+        assert!(!stored.not_modified(None, None));
+    }
+
+    #[test]
+    fn if_modified_since_works_in_whole_seconds() {
+        let stored = stored();
+        let same = httpdate::fmt_http_date(modified());
+        let earlier = httpdate::fmt_http_date(modified() - Duration::from_secs(1));
+        assert!(stored.not_modified(None, Some(&same)));
+        assert!(!stored.not_modified(None, Some(&earlier)));
+        assert!(!stored.not_modified(None, Some("yesterday")));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match_or_the_exact_date() {
+        let stored = stored();
+        assert!(stored.if_range(&stored.etag));
+        assert!(!stored.if_range(&format!("W/{}", stored.etag)));
+        assert!(!stored.if_range("\"other\""));
+        assert!(stored.if_range(&httpdate::fmt_http_date(modified())));
+        let later = httpdate::fmt_http_date(modified() + Duration::from_secs(1));
+        assert!(!stored.if_range(&later));
+
+        let weak = Validators::for_content(4096, Some(modified()), CompressionType::Zstd, true);
+        assert!(!weak.if_range(&weak.etag));
+    }
+
+    #[test]
+    fn headers_carry_both_validators() {
+        let headers = stored().headers();
+        assert_eq!(headers[0], ("ETag".to_string(), stored().etag));
+        assert_eq!(
+            headers[1],
+            (
+                "Last-Modified".to_string(),
+                "Tue, 14 Nov 2023 22:13:20 GMT".to_string()
+            )
+        );
+        let undated = Validators::for_content(1, None, CompressionType::None, false);
+        assert_eq!(undated.headers().len(), 1);
+    }
+}
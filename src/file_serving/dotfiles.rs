@@ -0,0 +1,123 @@
+use std::fmt;
+use std::io;
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// Directory that stays reachable under every policy, as ACME challenges and other
+/// well-known URIs (RFC 8615) live there.
+const WELL_KNOWN: &str = ".well-known";
+
+/// What happens to requests for paths with a component starting with a dot, such as `/.git/config`
+/// or `/.env`, which are rarely meant to be public.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DotfilePolicy {
+    /// Serve them like any other file
+    Allow,
+    /// Answer 403 Forbidden
+    Deny,
+    /// Answer 404 Not Found, as if they did not exist
+    #[default]
+    Ignore,
+}
+
+impl DotfilePolicy {
+    /// Whether `path`, a decoded request path, names a hidden file or a file in a hidden directory.
+    pub fn hides(&self, path: &Path) -> bool {
+        *self != DotfilePolicy::Allow
+            && path.components().any(|c| match c {
+                Component::Normal(part) => part
+                    .to_str()
+                    .is_some_and(|part| part.starts_with('.') && part != WELL_KNOWN),
+                _ => false,
+            })
+    }
+
+    /// Checks `path` against the policy: `Ok(true)` when it may be served, `Ok(false)` when it is
+    /// to be treated as missing, and a `PermissionDenied` error when it is forbidden.
+    pub fn admits(&self, path: &Path) -> io::Result<bool> {
+        if !self.hides(path) {
+            return Ok(true);
+        }
+        log::debug!("Hidden path requested: {}", path.display());
+        match self {
+            DotfilePolicy::Deny => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Hidden path {}", path.display()),
+            )),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl FromStr for DotfilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(DotfilePolicy::Allow),
+            "deny" => Ok(DotfilePolicy::Deny),
+            "ignore" => Ok(DotfilePolicy::Ignore),
+            _ => Err(format!(
+                "Expected allow, deny or ignore for hidden paths, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DotfilePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DotfilePolicy::Allow => "allow",
+            DotfilePolicy::Deny => "deny",
+            DotfilePolicy::Ignore => "ignore",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_files_and_directories_are_hidden() {
+        let policy = DotfilePolicy::default();
+        for path in ["/.env", "/.git/config", "/app/.cache/x.js", ".htpasswd"] {
+            assert!(policy.hides(Path::new(path)), "{}", path);
+        }
+        for path in ["/", "/index.html", "/a.b/c", "/../x", "/./x", "/x./y"] {
+            assert!(!policy.hides(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn well_known_stays_reachable_but_not_what_hides_below_it() {
+        let policy = DotfilePolicy::Deny;
+        assert!(!policy.hides(Path::new("/.well-known/acme-challenge/token")));
+        assert!(policy.hides(Path::new("/.well-known/.secret")));
+        assert!(policy.hides(Path::new("/.well-known-not/x")));
+    }
+
+    #[test]
+    fn each_policy_answers_its_own_way() {
+        let path = Path::new("/.git/HEAD");
+        assert!(DotfilePolicy::Allow.admits(path).unwrap());
+        assert!(!DotfilePolicy::Ignore.admits(path).unwrap());
+        let e = DotfilePolicy::Deny.admits(path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(DotfilePolicy::Deny.admits(Path::new("/ok.txt")).unwrap());
+    }
+
+    #[test]
+    fn policies_parse_from_their_names() {
+        for policy in [
+            DotfilePolicy::Allow,
+            DotfilePolicy::Deny,
+            DotfilePolicy::Ignore,
+        ] {
+            assert_eq!(policy.to_string().parse::<DotfilePolicy>(), Ok(policy));
+        }
+        assert!("hide".parse::<DotfilePolicy>().is_err());
+    }
+}
@@ -0,0 +1,32 @@
+/// Pages sent as the bodies of error responses instead of their plain-text reason phrase. Pages
+/// are request paths within the served directory or archive, so they are resolved, pre-compressed
+/// and compressed like any other file.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    /// Sent with 404 Not Found
+    pub not_found: Option<String>,
+    /// Sent with 500 Internal Server Error
+    pub server_error: Option<String>,
+}
+
+impl ErrorPages {
+    /// Pages used when none are given and the served root has them, as in nginx setups.
+    pub const DEFAULT_NOT_FOUND: &'static str = "/404.html";
+    pub const DEFAULT_SERVER_ERROR: &'static str = "/50x.html";
+
+    /// The configured pages, falling back to the default pages for which `exists` holds.
+    pub fn new(
+        not_found: Option<&str>,
+        server_error: Option<&str>,
+        exists: impl Fn(&str) -> bool,
+    ) -> Self {
+        let page = |configured: Option<&str>, default: &str| match configured {
+            Some(path) => Some(format!("/{}", path.trim_start_matches('/'))),
+            None => exists(default).then(|| default.to_string()),
+        };
+        Self {
+            not_found: page(not_found, Self::DEFAULT_NOT_FOUND),
+            server_error: page(server_error, Self::DEFAULT_SERVER_ERROR),
+        }
+    }
+}
@@ -1,44 +1,51 @@
-use path_utils::{find_precompressed, sanitize_path};
-use regex::Regex;
+use path_utils::{
+    find_precompressed, find_zstd_only, is_stale, open_file, report_stale, sanitize_path,
+};
 use std::io::ErrorKind;
 
 use crate::{
-    args::should_bypass_compression,
-    compression::{determine_compression, AcceptedCompression},
+    client_hints::ClientHints,
+    compression::{
+        determine_compression, parse_forced_encoding, AcceptedCompression, CompressionOptions,
+        FORCE_ENCODING_HEADER,
+    },
+    route::{RouteConfig, ServeDir},
 };
 
 use super::*;
-use std::net::TcpStream;
+use std::time::Duration;
 
+use super::conditional::Validators;
+use super::range::{parse_range, ByteRange, Multipart, RangeRequest};
 use super::spa::SpaConfig;
+use crate::body::Encoding;
+use crate::client::ClientStream;
+use crate::context;
+use crate::metrics::{
+    FILE_RESPONSES_COMPRESSED, FILE_RESPONSES_IDENTITY, FILE_RESPONSES_PRECOMPRESSED,
+    PRECOMPRESSED_STALE,
+};
+use crate::slow_clients::ClientWriter;
+
+/// Longest a client may stall a decompressed body, whose decoder holds its window buffer until the
+/// response is done.
+const DECOMPRESS_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub fn serve_file(
-    base_dir: &Path,
+    dir: &ServeDir,
     request_path: &str,
     accepted_compression: AcceptedCompression,
-    zstd_level: i32,
-    gzip_level: u32,
-    bypass_patterns: &[Regex],
+    options: &CompressionOptions,
+    should_bypass: bool,
     spa_config: Option<&SpaConfig>,
 ) -> io::Result<Option<FileResponse>> {
+    let base_dir = dir.root.as_path();
+    let manifest = dir.manifest.as_ref();
     log::debug!("Received request for path: {}", request_path);
     log::trace!("Base directory: {}", base_dir.display());
-    log::trace!(
-        "Accepted compression - zstd: {}, gzip: {}",
-        accepted_compression.supports_zstd,
-        accepted_compression.supports_gzip
-    );
-
-    // Check if request should bypass compression
-    let should_bypass = should_bypass_compression(request_path, bypass_patterns);
-    if should_bypass {
-        log::debug!(
-            "Path '{}' matches bypass pattern, skipping compression",
-            request_path
-        );
-    }
+    log::trace!("Accepted compression - {}", accepted_compression);
 
-    let path = match sanitize_path(base_dir, request_path)? {
+    let path = match sanitize_path(base_dir, request_path, dir.dotfiles)? {
         Some(p) => {
             log::debug!("Sanitized path: {}", p.display());
             p
@@ -49,17 +56,23 @@ pub fn serve_file(
         }
     };
 
+    // Directories resolve to their first index file that exists
+    let index_of = |dir_path: &Path| {
+        let index_files = &dir.index_files;
+        let index = index_files
+            .iter()
+            .find(|index| dir_path.join(index).is_file())
+            .unwrap_or(&index_files[0]);
+        dir_path.join(index)
+    };
+
     // Handle SPA routing
     let final_path = if path.is_dir() {
-        if let Some(spa_config) = spa_config {
-            path.join(&spa_config.index_path)
-        } else {
-            path.join("index.html")
-        }
+        index_of(&path)
     } else if let Some(spa_config) = spa_config {
         if !spa_config.is_static_file(&path) && !path.exists() {
-            // For SPA routes that don't exist as files, serve index.html
-            base_dir.join(&spa_config.index_path)
+            // For SPA routes that don't exist as files, serve the index of the root
+            index_of(base_dir)
         } else {
             path
         }
@@ -69,57 +82,92 @@ pub fn serve_file(
 
     log::debug!("Final resolved path: {}", final_path.display());
 
-    // Set appropriate cache headers based on whether it's index.html
-    let is_index = final_path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .map(|n| n.to_lowercase() == "index.html")
-        .unwrap_or(false);
-
-    let cache_headers = if is_index {
-        vec![
-            (
-                "Cache-Control".to_string(),
-                "no-cache, no-store, must-revalidate".to_string(),
-            ),
-            ("Pragma".to_string(), "no-cache".to_string()),
-            ("Expires".to_string(), "0".to_string()),
-        ]
-    } else {
-        vec![(
-            "Cache-Control".to_string(),
-            "public, max-age=31536000".to_string(),
-        )]
-    };
+    let cache_headers = cache_headers(&final_path, &dir.index_files);
 
     // First try to find any pre-compressed version
-    if let Some(precompressed) = find_precompressed(base_dir, &final_path, accepted_compression)? {
+    let precompressed = find_precompressed(base_dir, &final_path, accepted_compression, manifest)?;
+    if let Some(precompressed) = precompressed {
         log::debug!(
             "Using pre-compressed file: {} with compression {:?}",
             precompressed.path.display(),
             precompressed.compression
         );
 
-        let mut content = Vec::new();
-        File::open(&precompressed.path)?.read_to_end(&mut content)?;
-
-        let mime_type = from_path(&final_path).first_or_octet_stream().to_string();
+        match open_file(base_dir, &precompressed.path) {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                if !metadata.is_file() {
+                    log::warn!(
+                        "Pre-compressed path is not a regular file: {}",
+                        precompressed.path.display()
+                    );
+                    return Ok(None);
+                }
+                if is_stale(&metadata, &final_path) {
+                    // Served from the file itself until the siblings are written again
+                    PRECOMPRESSED_STALE.increment();
+                    report_stale(&precompressed.path);
+                } else {
+                    let length = metadata.len();
+                    let mime_type = from_path(&final_path).first_or_octet_stream().to_string();
 
-        return Ok(Some(FileResponse {
-            content,
-            mime_type,
-            compression: precompressed.compression,
-            headers: cache_headers,
-        }));
+                    return Ok(Some(FileResponse {
+                        body: Body::File { file, length },
+                        encoded: false,
+                        precompressed: true,
+                        mime_type,
+                        compression: precompressed.compression,
+                        validators: Validators::new(&metadata, precompressed.compression, false),
+                        headers: cache_headers,
+                    }));
+                }
+            }
+            // Only a stale manifest lists files that are gone
+            Err(e) if e.kind() == ErrorKind::NotFound => log::warn!(
+                "Pre-compressed file {} listed in the manifest is missing",
+                precompressed.path.display()
+            ),
+            Err(e) => return Err(e),
+        }
     }
 
     // If no pre-compressed file exists, check if original file exists
     if !final_path.exists() {
+        // Only the zstd sibling may be kept, which clients without zstd get decompressed
+        if let Some((file, metadata)) = find_zstd_only(base_dir, &final_path, manifest)? {
+            let compression = if should_bypass {
+                CompressionType::None
+            } else {
+                accepted_compression
+                    .preferred()
+                    .find(|&compression| compression != CompressionType::Zstd)
+                    .unwrap_or(CompressionType::None)
+            };
+            log::debug!(
+                "Decompressing the zstd sibling of {} for {:?}",
+                final_path.display(),
+                compression
+            );
+            return Ok(Some(FileResponse {
+                body: Body::Stream {
+                    reader: Box::new(zstd::stream::read::Decoder::new(file)?),
+                    length: None,
+                },
+                encoded: true,
+                precompressed: false,
+                mime_type: from_path(&final_path).first_or_octet_stream().to_string(),
+                compression,
+                validators: Validators::new(&metadata, compression, true),
+                headers: cache_headers,
+            }));
+        }
         log::debug!("File not found: {}", final_path.display());
         return Ok(None);
     }
 
-    let metadata = fs::metadata(&final_path)?;
+    // The metadata of the open file, so that what is checked is what is sent
+    let file = open_file(base_dir, &final_path)?;
+    let metadata = file.metadata()?;
     if !metadata.is_file() {
         log::warn!(
             "Path exists but is not a regular file: {}",
@@ -128,82 +176,330 @@ pub fn serve_file(
         return Ok(None);
     }
 
-    // Read original file
-    let mut content = Vec::new();
-    File::open(&final_path)?.read_to_end(&mut content)?;
-
     let mime_type = from_path(&final_path).first_or_octet_stream().to_string();
 
-    // Compress if needed
-    let (final_content, compression) = if should_bypass {
-        (content, CompressionType::None)
-    } else if accepted_compression.supports_zstd {
-        log::debug!("Compressing with zstd level {}", zstd_level);
-        let mut encoder = ZstdEncoder::new(Vec::new(), zstd_level)?;
-        encoder.write_all(&content)?;
-        (encoder.finish()?, CompressionType::Zstd)
-    } else if accepted_compression.supports_gzip {
-        log::debug!("Compressing with gzip level {}", gzip_level);
-        let mut encoder = GzEncoder::new(Vec::new(), GzipCompression::new(gzip_level));
-        encoder.write_all(&content)?;
-        (encoder.finish()?, CompressionType::Gzip)
+    // Compress while sending if needed
+    let compression = if should_bypass {
+        CompressionType::None
     } else {
-        (content, CompressionType::None)
+        accepted_compression.best()
     };
+    match compression {
+        CompressionType::Zstd => log::debug!("Compressing with zstd level {}", options.zstd),
+        CompressionType::Brotli => log::debug!("Compressing with brotli level {}", options.brotli),
+        CompressionType::Gzip => log::debug!("Compressing with gzip level {}", options.gzip),
+        CompressionType::None => {}
+    }
 
     Ok(Some(FileResponse {
-        content: final_content,
+        body: Body::File {
+            file,
+            length: metadata.len(),
+        },
+        encoded: compression != CompressionType::None,
+        precompressed: false,
         mime_type,
         compression,
+        validators: Validators::new(&metadata, compression, compression != CompressionType::None),
         headers: cache_headers,
     }))
 }
 
+/// Writes the `Content-Length` or `Transfer-Encoding` header, ends the header block and sends the
+/// body: as stored, or compressed on the fly if `encoded` is set, ending with a `Content-Digest`
+/// trailer if `content_digest` is set.
+fn write_body(
+    client: &mut ClientStream,
+    response: FileResponse,
+    options: &CompressionOptions,
+    content_digest: bool,
+) -> io::Result<()> {
+    end_head(client, response.stored_length(), content_digest)?;
+    if !response.encoded {
+        return response.body.send(client);
+    }
+    // Decoders hold their window buffer until the response is done, unless clients already get
+    // less time
+    let longer = |timeout: Duration| timeout > DECOMPRESS_WRITE_TIMEOUT;
+    if matches!(response.body, Body::Stream { .. }) && client.write_timeout()?.is_none_or(longer) {
+        client.set_write_timeout(Some(DECOMPRESS_WRITE_TIMEOUT))?;
+    }
+    let encoding = Encoding {
+        compression: response.compression,
+        options,
+        content_encoding: None,
+        content_digest,
+        flush_interval: None,
+        source: None,
+        mime_type: &response.mime_type,
+    };
+    response.body.encode(ClientWriter::new(client), &encoding)
+}
+
+/// Writes the framing headers of a body of `stored_length`, or a chunked one, and ends the header
+/// block. Responses to HEAD stop here.
+fn end_head(
+    client: &mut ClientStream,
+    stored_length: Option<u64>,
+    content_digest: bool,
+) -> io::Result<()> {
+    match stored_length {
+        Some(length) => {
+            client.write_all(format!("Content-Length: {}\r\n", length).as_bytes())?;
+        }
+        None => {
+            client.write_all(b"Transfer-Encoding: chunked\r\n")?;
+            if content_digest {
+                client.write_all(b"Trailer: Content-Digest\r\n")?;
+            }
+        }
+    }
+    client.write_all(b"\r\n")
+}
+
+/// Sends the requested ranges of a stored body as a 206 body: a single range as-is, several as
+/// `multipart/byteranges`. Ends the header block, which must not have a `Content-Type` yet.
+fn write_ranges(
+    client: &mut ClientStream,
+    mut body: Body,
+    ranges: &[ByteRange],
+    mime_type: &str,
+) -> io::Result<()> {
+    let length = body.length().unwrap_or(0);
+    let mut send_range = |client: &mut ClientStream, range: &ByteRange| {
+        body.send_range(client, range.start, range.len())
+    };
+    if let [range] = ranges {
+        client.write_all(format!("Content-Type: {}\r\n", mime_type).as_bytes())?;
+        client
+            .write_all(format!("Content-Range: {}\r\n", range.content_range(length)).as_bytes())?;
+        client.write_all(format!("Content-Length: {}\r\n", range.len()).as_bytes())?;
+        client.write_all(b"\r\n")?;
+        return send_range(client, range);
+    }
+
+    let multipart = Multipart::new(mime_type, length);
+    client.write_all(format!("Content-Type: {}\r\n", multipart.content_type()).as_bytes())?;
+    client
+        .write_all(format!("Content-Length: {}\r\n", multipart.body_length(ranges)).as_bytes())?;
+    client.write_all(b"\r\n")?;
+    for range in ranges {
+        client.write_all(multipart.part_head(range).as_bytes())?;
+        send_range(client, range)?;
+    }
+    client.write_all(multipart.tail().as_bytes())
+}
+
+/// Answers a GET or HEAD request for `request_path` under `dir`. HEAD gets the same head as GET
+/// would, without the body or any compression work, except that files GET would compress on the
+/// fly are described uncompressed so that their `Content-Length` is known.
 pub fn handle_file_request(
-    mut client: TcpStream,
-    base_dir: &Path,
-    request: &str,
+    mut client: ClientStream,
+    route: &RouteConfig,
+    dir: &ServeDir,
+    method: &str,
+    request_path: &str,
     headers: &[(String, String)],
-    zstd_level: i32,
-    gzip_level: u32,
-    bypass_patterns: &[Regex],
-    spa_config: Option<&SpaConfig>,
 ) -> io::Result<()> {
+    let head_request = method.eq_ignore_ascii_case("HEAD");
+
     let accept_encoding = headers
         .iter()
         .find(|(k, _)| k.to_lowercase() == "accept-encoding")
         .map(|(_, v)| v.as_str())
         .unwrap_or("");
 
-    let compression = determine_compression(accept_encoding);
+    let forced_encoding = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(FORCE_ENCODING_HEADER))
+        .and_then(|(_, v)| parse_forced_encoding(v));
+    let compression = match forced_encoding {
+        Some(forced) => {
+            log::debug!("Encoding pinned to {}", forced);
+            AcceptedCompression::only(forced)
+        }
+        None => determine_compression(accept_encoding, route.compression.file_encodings()),
+    };
+    let hints = ClientHints::from_headers(headers);
+    let options = route.compression.options_for(&hints);
+    let hint_policy = &route.client_hints;
+
+    let vary = match hint_policy.vary() {
+        Some(hint) => format!("Accept-Encoding, {}", hint),
+        None => "Accept-Encoding".to_string(),
+    };
+
+    if hint_policy.should_skip(&hints, request_path) {
+        log::debug!("Skipping '{}' for Save-Data client", request_path);
+        client.write_all(b"HTTP/1.1 204 No Content\r\n")?;
+        client.write_all(format!("Vary: {}\r\n", vary).as_bytes())?;
+        client.write_all(b"Content-Length: 0\r\n")?;
+        client.write_all(b"\r\n")?;
+        return Ok(());
+    }
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
 
-    let request_path = request.split_whitespace().nth(1).unwrap_or("/");
+    let should_bypass = route.compression.bypasses(request_path);
+    let error_page = |page: Option<&str>| {
+        let page = page?;
+        let bypass = route.compression.bypasses(page);
+        find(dir, page, compression, &options, bypass, None)
+            .inspect_err(|e| log::warn!("Failed to load error page {}: {}", page, e))
+            .ok()
+            .flatten()
+    };
 
-    match serve_file(
-        base_dir,
+    let found = match find(
+        dir,
         request_path,
         compression,
-        zstd_level,
-        gzip_level,
-        bypass_patterns, // Pass bypass_patterns
-        spa_config,
-    )? {
-        Some(response) => {
-            client.write_all(b"HTTP/1.1 200 OK\r\n")?;
-            client.write_all(format!("Content-Type: {}\r\n", response.mime_type).as_bytes())?;
+        &options,
+        should_bypass,
+        dir.spa.as_ref(),
+    ) {
+        Ok(found) => found,
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            send_error(
+                &mut client,
+                "403 Forbidden",
+                None,
+                &vary,
+                head_request,
+                &options,
+                route.compression.content_digest,
+            )?;
+            return Err(e);
+        }
+        Err(e) => {
+            send_error(
+                &mut client,
+                "500 Internal Server Error",
+                error_page(dir.error_pages.server_error.as_deref()),
+                &vary,
+                head_request,
+                &options,
+                route.compression.content_digest,
+            )?;
+            return Err(e);
+        }
+    };
+
+    match found {
+        Some(mut response) => {
+            // The length of a body compressed on the fly is only known once it is compressed, so
+            // HEAD describes the file as stored, with its length, rather than leave clients
+            // without one
+            if head_request {
+                response.send_as_stored()?;
+            }
+            let not_modified = response
+                .validators
+                .not_modified(header("if-none-match"), header("if-modified-since"));
+            if not_modified {
+                log::debug!("{} is not modified, answering 304", request_path);
+                client.write_all(b"HTTP/1.1 304 Not Modified\r\n")?;
+                client.write_all(format!("Vary: {}\r\n", vary).as_bytes())?;
+                for (key, value) in response
+                    .validators
+                    .headers()
+                    .iter()
+                    .chain(&response.headers)
+                {
+                    client.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
+                }
+                client.write_all(b"\r\n")?;
+                return Ok(());
+            }
+
+            // Ranges are only defined for GET
+            let range = match header("range").filter(|_| !head_request) {
+                Some(range) => {
+                    // Ranges are cut from the stored bytes, whose validators If-Range has to match
+                    let stored = match &response.body {
+                        Body::File { file, .. } if response.encoded => {
+                            Validators::new(&file.metadata()?, CompressionType::None, false)
+                        }
+                        _ => response.validators.clone(),
+                    };
+                    match header("if-range") {
+                        Some(if_range) if !stored.if_range(if_range) => {
+                            log::debug!("If-Range does not match, sending the whole file");
+                            None
+                        }
+                        _ => Some(range),
+                    }
+                }
+                None => None,
+            };
 
+            // Offsets refer to the file as stored, so ranges are never compressed on the fly
+            if range.is_some() {
+                response.send_as_stored()?;
+            }
+            // Archive members compressed on the fly are sent whole, as their bytes may change, and
+            // so are objects streamed from a bucket, which cannot be cut
+            let streamed = matches!(response.body, Body::Stream { .. } | Body::Chunked(_));
+            let ranges = match (response.stored_length(), range) {
+                (Some(length), Some(range)) if !response.validators.is_weak() && !streamed => {
+                    parse_range(range, length)
+                }
+                _ => RangeRequest::Full,
+            };
+
+            match &ranges {
+                RangeRequest::Full => client.write_all(b"HTTP/1.1 200 OK\r\n")?,
+                RangeRequest::Partial(_) => {
+                    client.write_all(b"HTTP/1.1 206 Partial Content\r\n")?
+                }
+                RangeRequest::Unsatisfiable => {
+                    let length = response.stored_length().unwrap_or(0);
+                    client.write_all(b"HTTP/1.1 416 Range Not Satisfiable\r\n")?;
+                    client
+                        .write_all(format!("Content-Range: bytes */{}\r\n", length).as_bytes())?;
+                    client.write_all(b"Content-Length: 0\r\n")?;
+                    client.write_all(b"\r\n")?;
+                    return Ok(());
+                }
+            }
+            record_source(&response);
+            if matches!(ranges, RangeRequest::Full) {
+                client.write_all(format!("Content-Type: {}\r\n", response.mime_type).as_bytes())?;
+            }
+            client.write_all(b"Accept-Ranges: bytes\r\n")?;
+            for (key, value) in response.validators.headers() {
+                client.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
+            }
+
+            if response.compression != CompressionType::None {
+                context::record_encoding(response.compression);
+            }
             match response.compression {
                 CompressionType::Zstd => {
                     client.write_all(b"Content-Encoding: zstd\r\n")?;
                 }
+                CompressionType::Brotli => {
+                    client.write_all(b"Content-Encoding: br\r\n")?;
+                }
                 CompressionType::Gzip => {
                     client.write_all(b"Content-Encoding: gzip\r\n")?;
                 }
                 CompressionType::None => {}
             }
 
-            // Write cache and security headers
-            for (key, value) in response.headers {
+            client.write_all(format!("Vary: {}\r\n", vary).as_bytes())?;
+
+            // Write cache, client hint and security headers
+            for (key, value) in response
+                .headers
+                .iter()
+                .chain(&hint_policy.response_headers())
+            {
                 client.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
             }
 
@@ -212,19 +508,133 @@ pub fn handle_file_request(
             client.write_all(b"X-Frame-Options: DENY\r\n")?;
             client.write_all(b"X-XSS-Protection: 1; mode=block\r\n")?;
 
-            client
-                .write_all(format!("Content-Length: {}\r\n", response.content.len()).as_bytes())?;
-            client.write_all(b"\r\n")?;
-            client.write_all(&response.content)?;
-            Ok(())
+            match ranges {
+                RangeRequest::Partial(ranges) => {
+                    write_ranges(&mut client, response.body, &ranges, &response.mime_type)
+                }
+                _ if head_request => end_head(
+                    &mut client,
+                    response.stored_length(),
+                    route.compression.content_digest,
+                ),
+                _ => write_body(
+                    &mut client,
+                    response,
+                    &options,
+                    route.compression.content_digest,
+                ),
+            }
         }
         None => {
-            client.write_all(b"HTTP/1.1 404 Not Found\r\n")?;
-            client.write_all(b"Content-Type: text/plain\r\n")?;
-            client.write_all(b"Content-Length: 9\r\n")?;
-            client.write_all(b"\r\n")?;
-            client.write_all(b"Not Found")?;
+            send_error(
+                &mut client,
+                "404 Not Found",
+                error_page(dir.error_pages.not_found.as_deref()),
+                &vary,
+                head_request,
+                &options,
+                route.compression.content_digest,
+            )?;
             Err(io::Error::new(ErrorKind::NotFound, "File not found"))
         }
     }
 }
+
+/// Looks `request_path` up in the archive, bucket or directory served by `dir`.
+fn find(
+    dir: &ServeDir,
+    request_path: &str,
+    accepted_compression: AcceptedCompression,
+    options: &CompressionOptions,
+    should_bypass: bool,
+    spa_config: Option<&SpaConfig>,
+) -> io::Result<Option<FileResponse>> {
+    // Directories apply the policy in `sanitize_path`
+    if dir.archive.is_some() || dir.bucket.is_some() {
+        let path_without_query = request_path.split('?').next().unwrap_or(request_path);
+        let decoded = percent_decode_str(path_without_query).decode_utf8_lossy();
+        if !dir.dotfiles.admits(Path::new(decoded.as_ref()))? {
+            return Ok(None);
+        }
+    }
+    match (&dir.archive, &dir.bucket) {
+        (Some(archive), _) => archive.serve(
+            request_path,
+            accepted_compression,
+            options,
+            should_bypass,
+            spa_config,
+            &dir.index_files,
+        ),
+        (None, Some(bucket)) => bucket.serve(
+            request_path,
+            accepted_compression,
+            should_bypass,
+            spa_config,
+            &dir.index_files,
+        ),
+        (None, None) => serve_file(
+            dir,
+            request_path,
+            accepted_compression,
+            options,
+            should_bypass,
+            spa_config,
+        ),
+    }
+}
+
+/// Counts where the body of a response comes from, so that operators can tell how often the
+/// pre-compressed siblings spare compressing on the fly.
+fn record_source(response: &FileResponse) {
+    let counter = if response.precompressed {
+        &FILE_RESPONSES_PRECOMPRESSED
+    } else if response.compression != CompressionType::None {
+        &FILE_RESPONSES_COMPRESSED
+    } else {
+        &FILE_RESPONSES_IDENTITY
+    };
+    counter.increment();
+}
+
+/// Answers with `status`, sending the error page if there is one and the reason phrase as plain
+/// text otherwise. Error pages are compressed like other files but never cached.
+fn send_error(
+    client: &mut ClientStream,
+    status: &str,
+    page: Option<FileResponse>,
+    vary: &str,
+    head_request: bool,
+    options: &CompressionOptions,
+    content_digest: bool,
+) -> io::Result<()> {
+    client.write_all(format!("HTTP/1.1 {}\r\n", status).as_bytes())?;
+    let Some(mut page) = page else {
+        let reason = status.split_once(' ').map_or(status, |(_, reason)| reason);
+        client.write_all(b"Content-Type: text/plain\r\n")?;
+        client.write_all(format!("Content-Length: {}\r\n", reason.len()).as_bytes())?;
+        client.write_all(b"\r\n")?;
+        if !head_request {
+            client.write_all(reason.as_bytes())?;
+            context::count_body_bytes(reason.len() as u64);
+        }
+        return Ok(());
+    };
+
+    if head_request {
+        page.send_as_stored()?;
+    }
+    client.write_all(format!("Content-Type: {}\r\n", page.mime_type).as_bytes())?;
+    if page.compression != CompressionType::None {
+        client.write_all(format!("Content-Encoding: {}\r\n", page.compression).as_bytes())?;
+        context::record_encoding(page.compression);
+    }
+    client.write_all(format!("Vary: {}\r\n", vary).as_bytes())?;
+    client.write_all(b"Cache-Control: no-cache\r\n")?;
+    client.write_all(b"X-Content-Type-Options: nosniff\r\n")?;
+    if head_request {
+        end_head(client, page.stored_length(), content_digest)
+    } else {
+        write_body(client, page, options, content_digest)
+    }
+}
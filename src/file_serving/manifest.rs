@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compression::CompressionType;
+use crate::precompress::write_atomically;
+
+/// The pre-compressed siblings that exist for each file under a directory, as written by
+/// `zstdp precompress`. Loaded at startup, it saves the file server from probing for `.zst`, `.br`
+/// and `.gz` siblings on every request.
+///
+/// One line per file: its path relative to the directory, a tab and its encodings separated by
+/// commas, e.g. `assets/app.js\tzstd,br,gzip`.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    files: HashMap<PathBuf, Vec<CompressionType>>,
+}
+
+impl Manifest {
+    pub fn insert(&mut self, rel_path: PathBuf, encodings: Vec<CompressionType>) {
+        self.files.insert(rel_path, encodings);
+    }
+
+    /// Encodings with a pre-compressed sibling of `rel_path`; empty for unlisted files.
+    pub fn encodings(&self, rel_path: &Path) -> &[CompressionType] {
+        self.files.get(rel_path).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut manifest = Manifest::default();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", path.display(), number + 1, reason),
+                )
+            };
+            let (rel_path, encodings) = line
+                .split_once('\t')
+                .ok_or_else(|| invalid("expected a path and encodings".to_string()))?;
+            let encodings = encodings
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?;
+            manifest.insert(PathBuf::from(rel_path), encodings);
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the manifest to `path`, replacing any previous one in a single rename.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut entries: Vec<_> = self.files.iter().filter(|(_, e)| !e.is_empty()).collect();
+        entries.sort_by_key(|(rel_path, _)| *rel_path);
+
+        let mut contents = String::from("# zstdp precompressed manifest\n");
+        for (rel_path, encodings) in entries {
+            let encodings: Vec<_> = encodings.iter().map(|e| e.to_string()).collect();
+            contents.push_str(&format!(
+                "{}\t{}\n",
+                rel_path.display(),
+                encodings.join(",")
+            ));
+        }
+
+        write_atomically(path, contents.as_bytes())
+    }
+}
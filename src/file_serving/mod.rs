@@ -1,27 +1,118 @@
+pub mod archive;
+pub mod bucket;
+pub mod conditional;
+pub mod dotfiles;
+pub mod error_pages;
 pub mod handlers;
+pub mod manifest;
 mod path_utils;
+pub(crate) mod range;
+pub(crate) mod sendfile;
 pub mod spa;
 
-use flate2::write::GzEncoder;
-use flate2::Compression as GzipCompression;
 use mime_guess::from_path;
 use percent_encoding::percent_decode_str;
 use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use zstd::stream::write::Encoder as ZstdEncoder;
 
-use crate::compression::{AcceptedCompression, CompressionType};
+use crate::body::Body;
+use crate::compression::{AcceptedCompression, CompressionOptions, CompressionType};
 use crate::logging::LoggingExt;
+use crate::stats::{record_compression, thread_cpu_time};
+use conditional::Validators;
 
 pub struct PrecompressedFile {
     pub path: PathBuf,
     pub compression: CompressionType,
 }
 
+/// `data` compressed with `compression`, counted in the compression statistics.
+pub(crate) fn compress_in_memory(
+    data: &[u8],
+    compression: CompressionType,
+    options: &CompressionOptions,
+    mime_type: &str,
+) -> io::Result<Vec<u8>> {
+    let cpu_start = thread_cpu_time();
+    let mut encoder = options.encoder(Vec::new(), compression)?;
+    encoder.write_all(data)?;
+    let mut compressed = encoder.finish()?;
+    let bytes_out = compressed.len() as u64;
+    record_compression(
+        mime_type,
+        data.len() as u64,
+        bytes_out,
+        thread_cpu_time() - cpu_start,
+    );
+    if let Some(padding) = options.padding {
+        padding.write(&mut compressed, compression, bytes_out)?;
+    }
+    Ok(compressed)
+}
+
+/// Cache headers for a file: index files are always revalidated, everything else is cached for a
+/// year.
+pub fn cache_headers(path: &Path, index_files: &[String]) -> Vec<(String, String)> {
+    let is_index = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| {
+            index_files
+                .iter()
+                .any(|index| index.eq_ignore_ascii_case(n))
+        })
+        .unwrap_or(false);
+
+    if is_index {
+        vec![
+            (
+                "Cache-Control".to_string(),
+                "no-cache, no-store, must-revalidate".to_string(),
+            ),
+            ("Pragma".to_string(), "no-cache".to_string()),
+            ("Expires".to_string(), "0".to_string()),
+        ]
+    } else {
+        vec![(
+            "Cache-Control".to_string(),
+            "public, max-age=31536000".to_string(),
+        )]
+    }
+}
+
 pub struct FileResponse {
-    pub content: Vec<u8>,
+    pub body: Body<'static>,
+    /// The body is compressed with `compression` while it is sent, rather than stored that way
+    pub encoded: bool,
+    /// The body is a pre-compressed sibling of the file, rather than compressed by zstdp
+    pub precompressed: bool,
     pub mime_type: String,
     pub compression: CompressionType,
+    pub validators: Validators,
     pub headers: Vec<(String, String)>,
 }
+
+impl FileResponse {
+    /// Length of bodies that are sent as-is.
+    pub fn stored_length(&self) -> Option<u64> {
+        if self.encoded {
+            None
+        } else {
+            self.body.length()
+        }
+    }
+
+    /// Sends a stored file as it is stored rather than compressed on the fly, with the validators
+    /// of the file. Other bodies are left alone.
+    pub fn send_as_stored(&mut self) -> io::Result<()> {
+        if let Body::File { file, .. } = &self.body {
+            if self.encoded {
+                self.validators = Validators::new(&file.metadata()?, CompressionType::None, false);
+                self.encoded = false;
+                self.compression = CompressionType::None;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,21 @@
+use super::dotfiles::DotfilePolicy;
+use super::manifest::Manifest;
 use super::*;
 use crate::log_error;
+use std::collections::HashSet;
+use std::sync::Mutex;
 use std::time::Instant;
 
-pub fn sanitize_path(base_dir: &Path, request_path: &str) -> io::Result<Option<PathBuf>> {
+/// Stale siblings already warned about.
+static WARNED_STALE: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Maps `request_path` to a path under `base_dir`, or `None` if it would escape it or `dotfiles`
+/// hides it. Fails with `PermissionDenied` for hidden paths that `dotfiles` forbids.
+pub fn sanitize_path(
+    base_dir: &Path,
+    request_path: &str,
+    dotfiles: DotfilePolicy,
+) -> io::Result<Option<PathBuf>> {
     let start_time = Instant::now();
     log::debug!(
         "Sanitizing path - base: {}, request: {}",
@@ -26,6 +39,10 @@ pub fn sanitize_path(base_dir: &Path, request_path: &str) -> io::Result<Option<P
         .collect::<PathBuf>();
     log::debug!("Cleaned path: {}", cleaned_path.display());
 
+    if !dotfiles.admits(&cleaned_path)? {
+        return Ok(None);
+    }
+
     let requested_path = base_dir.join(&cleaned_path);
 
     match fs::canonicalize(&requested_path) {
@@ -55,6 +72,11 @@ pub fn sanitize_path(base_dir: &Path, request_path: &str) -> io::Result<Option<P
                 Ok(None)
             }
         }
+        // Names longer than the file system allows cannot exist
+        Err(e) if e.kind() == io::ErrorKind::InvalidFilename => {
+            log::debug!("Path name too long: {}", e);
+            Ok(None)
+        }
         Err(e) => {
             log_error!(
                 e,
@@ -65,15 +87,86 @@ pub fn sanitize_path(base_dir: &Path, request_path: &str) -> io::Result<Option<P
     }
 }
 
+/// Opens a file under `base_dir` to serve it, closing the window between `sanitize_path` and the
+/// open: a symlink swapped in since is only followed if it still leads into `base_dir`, and the
+/// file opened must be the one now at its path. Fails with `PermissionDenied` otherwise.
+pub fn open_file(base_dir: &Path, path: &Path) -> io::Result<File> {
+    let (file, opened) = match open_no_follow(path) {
+        // Index files and pre-compressed siblings are joined to a sanitized path and may be links
+        Err(e) if is_symlink_error(&e) => {
+            let target = fs::canonicalize(path)?;
+            if !target.starts_with(base_dir) {
+                log::warn!("Symlink escapes base directory: {}", path.display());
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} links outside the served directory", path.display()),
+                ));
+            }
+            (open_no_follow(&target)?, target)
+        }
+        result => (result?, path.to_path_buf()),
+    };
+
+    if !same_file(&file.metadata()?, &fs::symlink_metadata(&opened)?) {
+        log::warn!("File changed while being opened: {}", opened.display());
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} changed while being opened", opened.display()),
+        ));
+    }
+    Ok(file)
+}
+
+/// Opens `path` read-only without following a symlink in its last component. A terminal never
+/// becomes the controlling one, and a FIFO does not block the open; neither is a regular file,
+/// so callers turn them away after opening.
+#[cfg(unix)]
+fn open_no_follow(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn open_no_follow(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+#[cfg(unix)]
+fn is_symlink_error(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ELOOP)
+}
+
+#[cfg(not(unix))]
+fn is_symlink_error(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
 pub fn find_precompressed(
     base_dir: &Path,
     path: &Path,
     accepted_compression: AcceptedCompression,
+    manifest: Option<&Manifest>,
 ) -> io::Result<Option<PrecompressedFile>> {
     let start_time = Instant::now();
     log::debug!("Looking for pre-compressed version of: {}", path.display());
 
-    if !accepted_compression.supports_zstd && !accepted_compression.supports_gzip {
+    if !accepted_compression.any() {
         log::debug!("No compression requested, skipping pre-compressed check");
         return Ok(None);
     }
@@ -85,19 +178,26 @@ pub fn find_precompressed(
     })?;
 
     // Try all supported compression types in order of preference
-    let mut possible_compressions = Vec::new();
-    if accepted_compression.supports_zstd {
-        possible_compressions.push((CompressionType::Zstd, ".zst"));
-    }
-    if accepted_compression.supports_gzip {
-        possible_compressions.push((CompressionType::Gzip, ".gz"));
-    }
+    for compression_type in accepted_compression.preferred() {
+        let compressed_path = base_dir.join(Path::new(&format!(
+            "{}{}",
+            rel_path.display(),
+            compression_type.extension()
+        )));
 
-    // Check each possible compression type
-    for (compression_type, extension) in possible_compressions {
-        let compressed_path = base_dir.join(Path::new(&format!("{}{}", rel_path.display(), extension)));
-        log::debug!("Checking compressed path: {}", compressed_path.display());
+        // The manifest already knows which siblings exist
+        if let Some(manifest) = manifest {
+            if manifest.encodings(rel_path).contains(&compression_type) {
+                log::debug!("Manifest lists {}", compressed_path.display());
+                return Ok(Some(PrecompressedFile {
+                    path: compressed_path,
+                    compression: compression_type,
+                }));
+            }
+            continue;
+        }
 
+        log::debug!("Checking compressed path: {}", compressed_path.display());
         if compressed_path.exists() {
             let metadata = fs::metadata(&compressed_path)?;
             if metadata.is_file() {
@@ -117,3 +217,65 @@ pub fn find_precompressed(
     log::debug!("No pre-compressed file found in {:?}", start_time.elapsed());
     Ok(None)
 }
+
+/// Whether a pre-compressed sibling with `metadata` was written before `path` last changed, and
+/// so may hold an older version of it. Siblings of files that are missing are never stale.
+pub fn is_stale(metadata: &fs::Metadata, path: &Path) -> bool {
+    let Ok(written) = metadata.modified() else {
+        return false;
+    };
+    fs::metadata(path)
+        .and_then(|source| source.modified())
+        .is_ok_and(|changed| changed > written)
+}
+
+/// Logs that the sibling at `path` is stale: with a warning the first time, and at debug level
+/// after that, as every request for its file finds it again until the siblings are rewritten.
+pub fn report_stale(path: &Path) {
+    let first = WARNED_STALE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashSet::new)
+        .insert(path.to_path_buf());
+    if first {
+        log::warn!(
+            "Pre-compressed file {} is older than its file, ignoring it",
+            path.display()
+        );
+    } else {
+        log::debug!("Ignoring stale pre-compressed file {}", path.display());
+    }
+}
+
+/// The zstd sibling of `path` when `path` itself is missing, as in deployments that keep only
+/// pre-compressed files, along with its metadata.
+pub fn find_zstd_only(
+    base_dir: &Path,
+    path: &Path,
+    manifest: Option<&Manifest>,
+) -> io::Result<Option<(File, fs::Metadata)>> {
+    if let Some(manifest) = manifest {
+        let listed = path.strip_prefix(base_dir).is_ok_and(|rel_path| {
+            manifest
+                .encodings(rel_path)
+                .contains(&CompressionType::Zstd)
+        });
+        if !listed {
+            return Ok(None);
+        }
+    }
+
+    let sibling = PathBuf::from(format!(
+        "{}{}",
+        path.display(),
+        CompressionType::Zstd.extension()
+    ));
+    match open_file(base_dir, &sibling) {
+        Ok(file) => {
+            let metadata = file.metadata()?;
+            Ok(metadata.is_file().then_some((file, metadata)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
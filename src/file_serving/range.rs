@@ -0,0 +1,253 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Range requests with more ranges than this are answered with the whole file.
+const MAX_RANGES: usize = 32;
+
+/// An inclusive range of byte offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// The `Content-Range` value of this range within a representation of `total` bytes.
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// What to send for a request, given its `Range` header.
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// No usable `Range` header: send the whole representation
+    Full,
+    /// Send these ranges, sorted and without overlaps
+    Partial(Vec<ByteRange>),
+    /// None of the ranges overlaps the representation
+    Unsatisfiable,
+}
+
+/// Interprets a `Range` header for a representation of `length` bytes. Headers that are not
+/// valid byte ranges are ignored, as RFC 9110 requires.
+pub fn parse_range(value: &str, length: u64) -> RangeRequest {
+    let Some((unit, specs)) = value.split_once('=') else {
+        return RangeRequest::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (first.trim(), last.trim()) {
+            // The last `n` bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) => Some(ByteRange {
+                    start: length.saturating_sub(n),
+                    end: length.wrapping_sub(1),
+                }),
+                Err(_) => return RangeRequest::Full,
+            },
+            (first, last) => {
+                let Ok(start) = first.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                let end = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(end) if end >= start => end,
+                        _ => return RangeRequest::Full,
+                    },
+                };
+                Some(ByteRange {
+                    start,
+                    end: end.min(length.wrapping_sub(1)),
+                })
+            }
+        };
+        ranges.extend(range.filter(|r| length > 0 && r.start < length));
+        if ranges.len() > MAX_RANGES {
+            log::debug!("Ignoring Range header with more than {} ranges", MAX_RANGES);
+            return RangeRequest::Full;
+        }
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    // Overlapping and adjacent ranges are sent once
+    ranges.sort();
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => merged.push(range),
+        }
+    }
+    RangeRequest::Partial(merged)
+}
+
+/// A `multipart/byteranges` body, for responses with more than one range.
+pub struct Multipart {
+    boundary: String,
+    content_type: String,
+    total: u64,
+}
+
+impl Multipart {
+    /// Parts of a `total` byte representation of type `content_type`, split by a random boundary.
+    pub fn new(content_type: &str, total: u64) -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(total);
+        Self {
+            boundary: format!("zstdp-{:016x}", hasher.finish()),
+            content_type: content_type.to_string(),
+            total,
+        }
+    }
+
+    /// The `Content-Type` of the whole response.
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// The boundary and headers that come before the bytes of `range`.
+    pub fn part_head(&self, range: &ByteRange) -> String {
+        format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            self.boundary,
+            self.content_type,
+            range.content_range(self.total)
+        )
+    }
+
+    /// The closing boundary after the last part.
+    pub fn tail(&self) -> String {
+        format!("\r\n--{}--\r\n", self.boundary)
+    }
+
+    /// Length of the whole body, for `Content-Length`.
+    pub fn body_length(&self, ranges: &[ByteRange]) -> u64 {
+        ranges
+            .iter()
+            .map(|range| self.part_head(range).len() as u64 + range.len())
+            .sum::<u64>()
+            + self.tail().len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(pairs: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            pairs
+                .iter()
+                .map(|&(start, end)| ByteRange { start, end })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn single_ranges_are_clamped_to_the_representation() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ranges(&[(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), ranges(&[(900, 999)]));
+        assert_eq!(parse_range("bytes=900-5000", 1000), ranges(&[(900, 999)]));
+        assert_eq!(parse_range("bytes=-100", 1000), ranges(&[(900, 999)]));
+        assert_eq!(parse_range("bytes=-5000", 1000), ranges(&[(0, 999)]));
+        assert_eq!(parse_range(" Bytes = 1-2 ", 1000), ranges(&[(1, 2)]));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(
+            parse_range("bytes=500-599,0-99,50-149,150-199,-10", 1000),
+            ranges(&[(0, 199), (500, 599), (990, 999)])
+        );
+        assert_eq!(parse_range("bytes=0-0,-1,0-", 10), ranges(&[(0, 9)]));
+    }
+
+    #[test]
+    fn unusable_headers_ask_for_the_whole_representation() {
+        for value in [
+            "",
+            "bytes",
+            "items=0-1",
+            "bytes=a-b",
+            "bytes=5-1",
+            "bytes=1",
+            "bytes=-x",
+        ] {
+            assert_eq!(parse_range(value, 1000), RangeRequest::Full, "{:?}", value);
+        }
+        let many: Vec<String> = (0..=MAX_RANGES)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .collect();
+        assert_eq!(
+            parse_range(&format!("bytes={}", many.join(",")), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+        assert_eq!(
+            parse_range("bytes=2000-3000,10-20", 1000),
+            ranges(&[(10, 20)])
+        );
+    }
+
+    #[test]
+    fn multipart_bodies_have_the_length_they_announce() {
+        let content = b"0123456789abcdefghij";
+        let parts = [
+            ByteRange { start: 0, end: 3 },
+            ByteRange { start: 10, end: 19 },
+        ];
+        let multipart = Multipart::new("text/plain", content.len() as u64);
+        let mut body = String::new();
+        for range in &parts {
+            body.push_str(&multipart.part_head(range));
+            body.push_str(
+                std::str::from_utf8(&content[range.start as usize..=range.end as usize]).unwrap(),
+            );
+        }
+        body.push_str(&multipart.tail());
+
+        assert_eq!(multipart.body_length(&parts), body.len() as u64);
+        let boundary = multipart
+            .content_type()
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-3/20\r\n\r\n\
+                 0123\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 10-19/20\
+                 \r\n\r\nabcdefghij\r\n--{0}--\r\n",
+                boundary
+            )
+        );
+    }
+}
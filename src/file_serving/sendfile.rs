@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io;
+
+use crate::client::ClientStream;
+
+/// Sends `length` bytes of `file`, starting at its current offset, straight to `client` without
+/// copying them through userspace.
+///
+/// Returns `Ok(None)` when zero-copy transfer is not available for this pair of descriptors and
+/// nothing has been sent, so the caller can fall back to a buffered copy.
+#[cfg(target_os = "linux")]
+pub fn send_file(file: &File, client: &ClientStream, length: u64) -> io::Result<Option<u64>> {
+    use std::os::unix::io::AsRawFd;
+
+    // Linux transfers at most this many bytes per call
+    const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+    let mut sent = 0;
+    while sent < length {
+        let count = (length - sent).min(MAX_SENDFILE) as usize;
+        // SAFETY: both descriptors are owned by live objects borrowed for the whole call, and a
+        // null offset makes the kernel use and advance the file's own offset.
+        let n = unsafe {
+            libc::sendfile(
+                client.as_raw_fd(),
+                file.as_raw_fd(),
+                std::ptr::null_mut(),
+                count,
+            )
+        };
+
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if sent == 0 => {
+                    log::debug!("sendfile unavailable ({}), falling back to copy", e);
+                    return Ok(None);
+                }
+                _ => return Err(e),
+            }
+        }
+        if n == 0 {
+            break;
+        }
+        sent += n as u64;
+    }
+
+    log::debug!("Sent {} bytes with sendfile", sent);
+    Ok(Some(sent))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(_file: &File, _client: &ClientStream, _length: u64) -> io::Result<Option<u64>> {
+    Ok(None)
+}
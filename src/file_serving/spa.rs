@@ -1,9 +1,9 @@
 use std::collections::HashSet;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 #[derive(Debug, Clone)]
+/// Single-page app routing: paths that are neither files nor assets get the index of the root.
 pub struct SpaConfig {
-    pub index_path: PathBuf,
     pub static_extensions: HashSet<String>,
 }
 
@@ -18,10 +18,7 @@ impl Default for SpaConfig {
             static_extensions.insert(ext.to_string());
         }
 
-        Self {
-            index_path: PathBuf::from("index.html"),
-            static_extensions,
-        }
+        Self { static_extensions }
     }
 }
 
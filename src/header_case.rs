@@ -0,0 +1,304 @@
+//! Casing of the header names in responses.
+//!
+//! Header names are case-insensitive, but some legacy clients compare them as they are spelled.
+//! By default (`preserve`) zstdp writes its own headers in Title-Case and keeps the spelling of
+//! backends for theirs. With `--header-case title` or `lower`, the name of every header in a
+//! response head is rewritten as the head is written to the client, whichever code path produced
+//! it, interim `100` and `103` heads as well as the final one that follows them: `title` gives
+//! `Content-Type` and `X-Request-Id` (with the usual spellings of `ETag`, `WWW-Authenticate` and
+//! a few others), `lower` gives `content-type`. Bodies, trailers and tunneled bytes are never
+//! touched.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::context;
+
+/// Names whose customary spelling is not plain Title-Case.
+const SPECIAL_NAMES: &[&str] = &[
+    "ETag",
+    "WWW-Authenticate",
+    "Content-MD5",
+    "X-XSS-Protection",
+    "X-UA-Compatible",
+    "TE",
+    "DNT",
+    "SourceMap",
+];
+
+/// How header names are spelled in responses.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum HeaderCase {
+    /// As each header was spelled by zstdp or the backend
+    #[default]
+    Preserve,
+    /// `Content-Type`
+    Title,
+    /// `content-type`
+    Lower,
+}
+
+impl FromStr for HeaderCase {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(HeaderCase::Preserve),
+            "title" => Ok(HeaderCase::Title),
+            "lower" => Ok(HeaderCase::Lower),
+            _ => Err(format!("Expected preserve, title or lower, got '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for HeaderCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HeaderCase::Preserve => "preserve",
+            HeaderCase::Title => "title",
+            HeaderCase::Lower => "lower",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl HeaderCase {
+    fn spell<'a>(self, name: &'a str) -> Cow<'a, str> {
+        match self {
+            HeaderCase::Preserve => Cow::Borrowed(name),
+            HeaderCase::Lower => Cow::Owned(name.to_ascii_lowercase()),
+            HeaderCase::Title => match SPECIAL_NAMES
+                .iter()
+                .find(|special| special.eq_ignore_ascii_case(name))
+            {
+                Some(special) => Cow::Borrowed(special),
+                None => Cow::Owned(title_case(name)),
+            },
+        }
+    }
+}
+
+fn title_case(name: &str) -> String {
+    let mut capitalize = true;
+    name.chars()
+        .map(|c| {
+            let c = if capitalize {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            };
+            capitalize = c == '-';
+            c
+        })
+        .collect()
+}
+
+/// Where a connection's writes are in its response head.
+#[derive(Default)]
+pub struct HeadRewriter {
+    state: State,
+    /// The line being written, until it is complete
+    line: Vec<u8>,
+    /// Whether the head being written is an interim one, which another head follows
+    interim: bool,
+}
+
+#[derive(Default, PartialEq)]
+enum State {
+    /// Nothing written yet; a response head starts with `HTTP/`
+    #[default]
+    Start,
+    StatusLine,
+    Headers,
+    /// The head is over, or what was written is not a response head
+    Done,
+}
+
+impl HeadRewriter {
+    /// Takes the bytes written next, and returns those to send now, with the header names in
+    /// them rewritten. Incomplete lines are held until the rest of them is written.
+    fn feed(&mut self, policy: HeaderCase, mut buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(buf.len());
+        while !buf.is_empty() && self.state != State::Done {
+            if self.state == State::Start {
+                let needed = (b"HTTP/".len() - self.line.len()).min(buf.len());
+                self.line.extend_from_slice(&buf[..needed]);
+                buf = &buf[needed..];
+                if !b"HTTP/".starts_with(&self.line) {
+                    self.state = State::Done;
+                    out.append(&mut self.line);
+                } else if self.line.len() == b"HTTP/".len() {
+                    self.state = State::StatusLine;
+                }
+                continue;
+            }
+            let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+                self.line.extend_from_slice(buf);
+                return out;
+            };
+            self.line.extend_from_slice(&buf[..=end]);
+            buf = &buf[end + 1..];
+            let line = std::mem::take(&mut self.line);
+            match self.state {
+                State::StatusLine => {
+                    self.state = State::Headers;
+                    self.interim = is_interim(&line);
+                    out.extend_from_slice(&line);
+                }
+                _ if line.trim_ascii().is_empty() => {
+                    self.state = if self.interim {
+                        State::Start
+                    } else {
+                        State::Done
+                    };
+                    out.extend_from_slice(&line);
+                }
+                _ => match line.iter().position(|&b| b == b':') {
+                    Some(colon) => {
+                        let name = String::from_utf8_lossy(&line[..colon]);
+                        out.extend_from_slice(policy.spell(&name).as_bytes());
+                        out.extend_from_slice(&line[colon..]);
+                    }
+                    None => out.extend_from_slice(&line),
+                },
+            }
+        }
+        out.extend_from_slice(buf);
+        out
+    }
+}
+
+/// Whether a status line is that of an informational response other than `101 Switching
+/// Protocols`, after which the bytes are no longer HTTP.
+fn is_interim(status_line: &[u8]) -> bool {
+    let mut fields = status_line.split(|b| b.is_ascii_whitespace());
+    match fields.nth(1) {
+        Some([b'1', rest @ ..]) => rest != b"01",
+        _ => false,
+    }
+}
+
+/// Writes `buf` to `out`, rewriting the header names of the response head it is part of as the
+/// route of the current connection asks. Writes outside of a connection's context, and all of
+/// them with `preserve`, go straight through.
+pub fn write<W: Write>(out: &mut W, buf: &[u8]) -> io::Result<usize> {
+    let Some(context) = context::current() else {
+        return out.write(buf);
+    };
+    let policy = context.route.header_case;
+    if policy == HeaderCase::Preserve {
+        return out.write(buf);
+    }
+    let mut head = context.head_rewriter();
+    if head.state == State::Done {
+        drop(head);
+        return out.write(buf);
+    }
+    let rewritten = head.feed(policy, buf);
+    out.write_all(&rewritten)?;
+    Ok(buf.len())
+}
+
+/// A whole response written outside of a connection's context, with its header names spelled as
+/// `policy` asks.
+pub fn rewrite(response: &[u8], policy: HeaderCase) -> Cow<'_, [u8]> {
+    if policy == HeaderCase::Preserve {
+        return Cow::Borrowed(response);
+    }
+    let mut head = HeadRewriter::default();
+    let mut rewritten = head.feed(policy, response);
+    rewritten.append(&mut head.line);
+    Cow::Owned(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(policy: HeaderCase, writes: &[&[u8]]) -> Vec<u8> {
+        let mut head = HeadRewriter::default();
+        let mut out = Vec::new();
+        for buf in writes {
+            out.extend(head.feed(policy, buf));
+        }
+        out.append(&mut head.line);
+        out
+    }
+
+    #[test]
+    fn title_case_follows_customary_spellings() {
+        assert_eq!(HeaderCase::Title.spell("content-type"), "Content-Type");
+        assert_eq!(HeaderCase::Title.spell("X-REQUEST-ID"), "X-Request-Id");
+        assert_eq!(HeaderCase::Title.spell("etag"), "ETag");
+        assert_eq!(
+            HeaderCase::Title.spell("www-authenticate"),
+            "WWW-Authenticate"
+        );
+        assert_eq!(HeaderCase::Lower.spell("ETag"), "etag");
+        assert_eq!(HeaderCase::Preserve.spell("eTaG"), "eTaG");
+    }
+
+    #[test]
+    fn only_header_names_of_the_head_are_rewritten() {
+        let response = b"HTTP/1.1 200 OK\r\ncontent-TYPE: Text/Plain\r\n\r\nContent-Type: body";
+        assert_eq!(
+            rewrite(response, HeaderCase::Title).as_ref(),
+            b"HTTP/1.1 200 OK\r\nContent-Type: Text/Plain\r\n\r\nContent-Type: body"
+        );
+        assert_eq!(
+            rewrite(response, HeaderCase::Lower).as_ref(),
+            b"HTTP/1.1 200 OK\r\ncontent-type: Text/Plain\r\n\r\nContent-Type: body"
+        );
+    }
+
+    #[test]
+    fn heads_split_across_writes_are_rewritten() {
+        let out = feed_all(
+            HeaderCase::Lower,
+            &[
+                b"HT",
+                b"TP/1.1 204 No Content\r\nX-Re",
+                b"quest-Id: 7\r",
+                b"\n\r\n",
+            ],
+        );
+        assert_eq!(out, b"HTTP/1.1 204 No Content\r\nx-request-id: 7\r\n\r\n");
+    }
+
+    #[test]
+    fn final_head_after_interim_ones_is_rewritten() {
+        let out = feed_all(
+            HeaderCase::Lower,
+            &[
+                b"HTTP/1.1 103 Early Hints\r\nLink: </app.css>\r\n\r\n",
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n",
+                b"OK",
+            ],
+        );
+        assert_eq!(
+            out,
+            b"HTTP/1.1 103 Early Hints\r\nlink: </app.css>\r\n\r\n\
+              HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nOK"
+        );
+    }
+
+    #[test]
+    fn bytes_after_switching_protocols_are_left_alone() {
+        let out = feed_all(
+            HeaderCase::Lower,
+            &[b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\nHTTP/1.1 X: Y\n"],
+        );
+        assert_eq!(
+            out,
+            b"HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\n\r\nHTTP/1.1 X: Y\n"
+        );
+    }
+
+    #[test]
+    fn writes_that_are_not_a_response_head_pass_through() {
+        let out = feed_all(HeaderCase::Title, &[b"HTX", b"content-type: x\r\n"]);
+        assert_eq!(out, b"HTXcontent-type: x\r\n");
+    }
+}
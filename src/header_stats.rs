@@ -0,0 +1,91 @@
+use std::sync::Mutex;
+
+use crate::metrics::{
+    Counter, REQUEST_HEADER_ANOMALIES, REQUEST_HEADER_BYTES, REQUEST_HEADER_FIELDS,
+    REQUEST_HEADER_SETS, RESPONSE_HEADER_ANOMALIES, RESPONSE_HEADER_BYTES, RESPONSE_HEADER_FIELDS,
+    RESPONSE_HEADER_SETS,
+};
+
+/// Sizes above which a set of headers is logged as unusual. Huge cookies and hundreds of
+/// headers are cheap to send but often explain slow or memory-hungry requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeaderThresholds {
+    /// Number of header fields
+    pub fields: usize,
+    /// Size of all header fields together, in bytes
+    pub bytes: usize,
+    /// Size of a single header field, in bytes
+    pub field_bytes: usize,
+}
+
+static THRESHOLDS: Mutex<Option<HeaderThresholds>> = Mutex::new(None);
+
+/// Sets the sizes above which headers are logged as unusual.
+pub fn configure(thresholds: HeaderThresholds) {
+    *THRESHOLDS.lock().unwrap_or_else(|e| e.into_inner()) = Some(thresholds);
+}
+
+/// Which side sent the headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+impl Direction {
+    fn counters(&self) -> [&'static Counter; 4] {
+        match self {
+            Direction::Request => [
+                &REQUEST_HEADER_SETS,
+                &REQUEST_HEADER_FIELDS,
+                &REQUEST_HEADER_BYTES,
+                &REQUEST_HEADER_ANOMALIES,
+            ],
+            Direction::Response => [
+                &RESPONSE_HEADER_SETS,
+                &RESPONSE_HEADER_FIELDS,
+                &RESPONSE_HEADER_BYTES,
+                &RESPONSE_HEADER_ANOMALIES,
+            ],
+        }
+    }
+}
+
+/// Adds the headers of a request or response for `uri` to the metrics, and logs them if they
+/// exceed the thresholds.
+pub fn record(direction: Direction, uri: &str, headers: &[(String, String)]) {
+    // As sent on the wire, `name: value` and CRLF
+    let size = |(name, value): &(String, String)| name.len() + value.len() + 4;
+    let bytes: usize = headers.iter().map(size).sum();
+    let largest = headers.iter().max_by_key(|header| size(header));
+
+    let [sets, fields, total_bytes, anomalies] = direction.counters();
+    sets.add(1);
+    fields.add(headers.len() as u64);
+    total_bytes.add(bytes as u64);
+
+    let Some(thresholds) = *THRESHOLDS.lock().unwrap_or_else(|e| e.into_inner()) else {
+        return;
+    };
+    let largest_bytes = largest.map_or(0, size);
+    if headers.len() <= thresholds.fields
+        && bytes <= thresholds.bytes
+        && largest_bytes <= thresholds.field_bytes
+    {
+        return;
+    }
+    anomalies.increment();
+    let side = match direction {
+        Direction::Request => "Request for",
+        Direction::Response => "Response to",
+    };
+    log::warn!(
+        "{} {} has {} headers of {} bytes, the largest being {} ({} bytes)",
+        side,
+        uri,
+        headers.len(),
+        bytes,
+        largest.map_or("none", |(name, _)| name.as_str()),
+        largest_bytes
+    );
+}
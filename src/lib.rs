@@ -0,0 +1,38 @@
+//! The proxy and file server behind the `zstdp` binary, exposed as a library so that benchmarks
+//! can exercise the hot paths directly. Only the modules the binary and the benchmarks use are
+//! public; the rest are internal and may change at any time.
+
+pub(crate) mod admin;
+pub mod args;
+pub(crate) mod body;
+pub(crate) mod body_log;
+pub(crate) mod capture;
+pub(crate) mod chaos;
+pub(crate) mod client;
+pub(crate) mod client_hints;
+pub mod compression;
+pub(crate) mod connections;
+pub(crate) mod context;
+pub mod dict;
+pub(crate) mod discovery;
+pub(crate) mod file_serving;
+pub(crate) mod header_case;
+pub(crate) mod header_stats;
+pub mod loadgen;
+pub mod logging;
+pub(crate) mod metrics;
+pub(crate) mod patterns;
+pub mod precompress;
+pub mod proxy;
+pub(crate) mod request_target;
+pub(crate) mod route;
+pub(crate) mod router;
+pub(crate) mod schedule;
+pub mod server;
+pub(crate) mod slow_clients;
+pub(crate) mod stats;
+pub(crate) mod stats_file;
+pub mod test_config;
+pub(crate) mod tls_passthrough;
+pub(crate) mod tunnel;
+pub(crate) mod vhosts;
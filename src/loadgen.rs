@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::proxy::backend::BackendAddr;
+
+/// The outcome of one request.
+struct Sample {
+    latency: Duration,
+    /// Status code, or `None` if the request failed
+    status: Option<u16>,
+    /// Bytes received after the response head
+    body_bytes: u64,
+}
+
+/// Sends `requests` GET requests for `path` to `target`, `concurrency` at a time and each on its
+/// own connection, then logs throughput and latency percentiles.
+pub fn run(
+    target: &BackendAddr,
+    path: &str,
+    requests: usize,
+    concurrency: usize,
+    headers: &[String],
+) -> io::Result<()> {
+    let request = build_request(target, path, headers);
+    let remaining = Arc::new(AtomicUsize::new(requests));
+    log::info!(
+        "Sending {} requests for {} to {}, {} at a time",
+        requests,
+        path,
+        target,
+        concurrency
+    );
+
+    let start_time = Instant::now();
+    let workers: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            let target = target.clone();
+            let request = request.clone();
+            let remaining = Arc::clone(&remaining);
+            thread::spawn(move || {
+                let mut samples = Vec::new();
+                while remaining
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok()
+                {
+                    samples.push(send(&target, &request));
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::with_capacity(requests);
+    for worker in workers {
+        let worker_samples = worker
+            .join()
+            .map_err(|_| io::Error::other("Load generator thread panicked"))?;
+        samples.extend(worker_samples);
+    }
+    report(&mut samples, start_time.elapsed());
+    Ok(())
+}
+
+fn build_request(target: &BackendAddr, path: &str, headers: &[String]) -> Vec<u8> {
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n",
+        path,
+        target.host_header()
+    );
+    for header in headers {
+        request.push_str(header);
+        request.push_str("\r\n");
+    }
+    request.push_str("Connection: close\r\n\r\n");
+    request.into_bytes()
+}
+
+fn send(target: &BackendAddr, request: &[u8]) -> Sample {
+    let start_time = Instant::now();
+    let result = (|| {
+        let mut stream = target.connect()?;
+        stream.write_all(request)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        Ok::<_, io::Error>(response)
+    })();
+
+    let latency = start_time.elapsed();
+    match result {
+        Ok(response) => {
+            let head_end = response
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+                .map_or(response.len(), |p| p + 4);
+            let status = String::from_utf8_lossy(&response[..head_end])
+                .split_whitespace()
+                .nth(1)
+                .and_then(|code| code.parse().ok());
+            Sample {
+                latency,
+                status,
+                body_bytes: (response.len() - head_end) as u64,
+            }
+        }
+        Err(e) => {
+            log::debug!("Request failed: {}", e);
+            Sample {
+                latency,
+                status: None,
+                body_bytes: 0,
+            }
+        }
+    }
+}
+
+fn report(samples: &mut [Sample], elapsed: Duration) {
+    if samples.is_empty() {
+        log::info!("No requests sent");
+        return;
+    }
+    samples.sort_by_key(|s| s.latency);
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100].latency;
+
+    let mut statuses = BTreeMap::new();
+    for sample in samples.iter() {
+        *statuses.entry(sample.status).or_insert(0) += 1;
+    }
+    let body_bytes: u64 = samples.iter().map(|s| s.body_bytes).sum();
+
+    log::info!(
+        "{} requests in {:?}: {:.1} req/s, {:.2} MiB/s of bodies",
+        samples.len(),
+        elapsed,
+        samples.len() as f64 / elapsed.as_secs_f64(),
+        body_bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    log::info!(
+        "Latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(50),
+        percentile(90),
+        percentile(99),
+        percentile(100)
+    );
+    for (status, count) in statuses {
+        match status {
+            Some(status) => log::info!("  {}: {}", status, count),
+            None => log::warn!("  failed: {}", count),
+        }
+    }
+}
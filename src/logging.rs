@@ -1,15 +1,25 @@
 use env_logger::Builder;
 use log::LevelFilter;
+use std::borrow::Cow;
 use std::io::Write;
 use std::path::Path;
 use std::time::SystemTime;
 
+use crate::context;
+
+/// Longest request target written to the access log as it is; longer ones are cut short.
+const LOGGED_TARGET_LENGTH: usize = 1024;
+
 pub fn setup_logging() {
     Builder::new()
         .filter_level(LevelFilter::Info) // Set default level
         .parse_env("RUST_LOG") // Allow override through env var
         .format(|buf, record| {
             let timestamp = SystemTime::now();
+            let connection = match context::current_id() {
+                Some(id) => format!("#{} ", id),
+                None => String::new(),
+            };
 
             if atty::is(atty::Stream::Stderr) {
                 // Terminal output with colors
@@ -23,10 +33,11 @@ pub fn setup_logging() {
 
                 writeln!(
                     buf,
-                    "{}{:>5}\x1B[0m [{}] {} - {}:{}",
+                    "{}{:>5}\x1B[0m [{}] {}{} - {}:{}",
                     level_color,
                     record.level(),
                     humantime::format_rfc3339_millis(timestamp),
+                    connection,
                     record.args(),
                     record.file().unwrap_or("unknown"),
                     record.line().unwrap_or(0)
@@ -35,9 +46,10 @@ pub fn setup_logging() {
                 // Plain output for non-terminal
                 writeln!(
                     buf,
-                    "{:>5} [{}] {} - {}:{}",
+                    "{:>5} [{}] {}{} - {}:{}",
                     record.level(),
                     humantime::format_rfc3339_millis(timestamp),
+                    connection,
                     record.args(),
                     record.file().unwrap_or("unknown"),
                     record.line().unwrap_or(0)
@@ -47,23 +59,40 @@ pub fn setup_logging() {
         .init();
 }
 
+/// `target` as the access log shows it: cut short on a character boundary if it is long, with its
+/// full length.
+pub fn clip(target: &str) -> Cow<'_, str> {
+    if target.len() <= LOGGED_TARGET_LENGTH {
+        return Cow::Borrowed(target);
+    }
+    let end = (0..=LOGGED_TARGET_LENGTH)
+        .rev()
+        .find(|&end| target.is_char_boundary(end))
+        .unwrap_or(0);
+    Cow::Owned(format!("{}... ({} bytes)", &target[..end], target.len()))
+}
+
 #[macro_export]
 macro_rules! log_request {
     ($request:expr) => {{
-        let parts: Vec<&str> = $request.trim().split_whitespace().collect();
+        let parts: Vec<&str> = $request.split_whitespace().collect();
         if parts.len() >= 2 {
-            log::info!("→ {} {}", parts[0], parts[1])
+            log::info!("→ {} {}", parts[0], $crate::logging::clip(parts[1]))
         } else {
-            log::info!("→ Invalid request format: {}", $request.trim())
+            log::info!(
+                "→ Invalid request format: {}",
+                $crate::logging::clip($request.trim())
+            )
         }
     }};
 }
 
 #[macro_export]
 macro_rules! log_response {
-    ($status:expr, $duration:expr) => {
-        log::info!("← {} ({:?})", $status, $duration)
-    };
+    ($context:expr, $status:expr) => {{
+        $crate::metrics::REQUESTS_SERVED.add(1);
+        log::info!("← {} ({})", $status, $context)
+    }};
 }
 
 #[macro_export]
@@ -1,37 +1,162 @@
 use clap::Parser;
 use std::io;
 
-mod args;
-mod compression;
-mod file_serving;
-mod logging;
-mod proxy;
-mod server;
-
-use args::Args;
-use logging::setup_logging;
-use server::start_server;
+use zstdp::args::{Args, Command, DictCommand};
+use zstdp::compression::{CompressionOptions, ZSTD_HTTP_MAX_WINDOW_LOG};
+use zstdp::logging::setup_logging;
+use zstdp::proxy::backend::Upstream;
+use zstdp::proxy::balancer::Balancing;
+use zstdp::server::start_server;
+use zstdp::{dict, loadgen, precompress, test_config};
 
 fn main() -> io::Result<()> {
     setup_logging();
 
     let args = Args::parse();
+
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Dict {
+                command:
+                    DictCommand::Train {
+                        inputs,
+                        output,
+                        max_size,
+                    },
+            } => dict::train(inputs, output, *max_size),
+            Command::Loadgen {
+                target,
+                path,
+                requests,
+                concurrency,
+                headers,
+            } => loadgen::run(target, path, *requests, *concurrency, headers),
+            Command::Precompress {
+                dir,
+                encodings,
+                zstd_level,
+                gzip_level,
+                brotli_level,
+                jobs,
+                min_size,
+                manifest,
+            } => {
+                let options = CompressionOptions {
+                    zstd: *zstd_level,
+                    brotli: *brotli_level,
+                    gzip: *gzip_level,
+                    zstd_workers: 0,
+                    zstd_long: false,
+                    zstd_window_log: None,
+                    zstd_cpu_budget: None,
+                    padding: None,
+                };
+                let jobs = jobs
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+                precompress::run(
+                    dir,
+                    encodings,
+                    options,
+                    jobs,
+                    *min_size,
+                    manifest.as_deref(),
+                )
+            }
+            Command::TestConfig {
+                fixtures,
+                server_args,
+            } => test_config::run(fixtures, server_args),
+        };
+    }
+
     log::info!("Starting server with configuration:");
     log::info!("  Listen address: {}", args.listen_addr());
 
+    let files = match (&args.serve, &args.s3) {
+        (Some(dir), _) => Some(format!("directory: {}", dir.display())),
+        (None, Some(url)) => Some(format!("bucket: {}", url)),
+        (None, None) => args.embedded.then(|| "the embedded archive".to_string()),
+    };
     if let Some(addr) = &args.forward {
-        log::info!("  Mode: Proxy");
+        match &files {
+            Some(files) => {
+                log::info!("  Mode: File Server and Proxy");
+                log::info!("  Serving {}", files);
+                log::info!("  Proxied paths: {}", args.proxy_path.join(", "));
+            }
+            None => log::info!("  Mode: Proxy"),
+        }
         log::info!("  Forward address: {}", addr);
+        if let Upstream::Http(backends) = addr {
+            log::info!("  Load balancing: {}", args.lb_strategy);
+            if backends.weighted() && args.lb_strategy == Balancing::RoundRobin {
+                log::warn!("Backend weights are ignored by round-robin balancing");
+            }
+            if args.max_fails > 0 {
+                log::info!(
+                    "  Failing backends: left out for {:?} after {} failures in a row",
+                    args.fail_timeout,
+                    args.max_fails
+                );
+            }
+        }
         log::info!("  Zstd compression level: {}", args.zstd_level);
-    } else if let Some(dir) = &args.serve {
+        log_zstd_settings(&args);
+        if !args.chaos.is_empty() {
+            log::warn!("  Chaos mode: injecting {:?}", args.chaos);
+        }
+    } else {
         log::info!("  Mode: File Server");
-        log::info!("  Serving directory: {}", dir.display());
+        log::info!(
+            "  Serving {}",
+            files.as_deref().unwrap_or("the embedded archive")
+        );
         log::info!(
             "  Compression levels - Zstd: {}, Gzip: {}",
             args.zstd_level,
             args.gzip_level
         );
+        log_zstd_settings(&args);
     }
 
     start_server(args)
 }
+
+fn log_zstd_settings(args: &Args) {
+    if args.zstd_long {
+        log::info!("  Zstd long-distance matching enabled");
+    }
+    if let Some(window_log) = args.zstd_window_log {
+        log::info!("  Zstd window log: {}", window_log);
+        if window_log > ZSTD_HTTP_MAX_WINDOW_LOG {
+            log::warn!(
+                "Zstd window log {} exceeds {}, which browsers may refuse to decode",
+                window_log,
+                ZSTD_HTTP_MAX_WINDOW_LOG
+            );
+        }
+    }
+    if args.zstd_workers > 0 {
+        log::info!("  Zstd worker threads: {}", args.zstd_workers);
+    }
+    if let Some(budget) = args.zstd_cpu_budget {
+        log::info!(
+            "  Zstd CPU budget per response: {}",
+            humantime::format_duration(budget)
+        );
+        if args.zstd_workers > 0 {
+            log::warn!("Zstd worker threads are not counted against the zstd CPU budget");
+        }
+    }
+    if let Some(padding) = args.pad_responses {
+        log::info!("  Padding of compressed responses: {}", padding);
+    }
+    if args.adaptive_zstd {
+        let policy = args.zstd_level_policy();
+        log::info!(
+            "  Adaptive zstd level: {} (slow clients) to {} (fast clients)",
+            policy.max,
+            policy.min
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A process-wide event counter.
+pub struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Adds `n` to the counter.
+    pub fn add(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Increments the counter and logs its new value.
+    pub fn increment(&self) -> u64 {
+        let value = self.value.fetch_add(1, Ordering::Relaxed) + 1;
+        log::debug!("{} = {}", self.name, value);
+        value
+    }
+}
+
+/// A process-wide value that goes up and down, such as a number of open connections.
+pub struct Gauge {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub static BACKEND_CONNECT_TIMEOUTS: Counter = Counter::new("backend_connect_timeouts");
+pub static BACKEND_HEADER_TIMEOUTS: Counter = Counter::new("backend_header_timeouts");
+pub static BACKEND_READ_TIMEOUTS: Counter = Counter::new("backend_read_timeouts");
+pub static BACKEND_WRITE_TIMEOUTS: Counter = Counter::new("backend_write_timeouts");
+pub static BACKEND_RETRIES: Counter = Counter::new("backend_retries");
+pub static CLIENT_ABORTS: Counter = Counter::new("client_aborts");
+pub static CLIENT_WRITE_TIMEOUTS: Counter = Counter::new("client_write_timeouts");
+pub static CLIENT_IDLE_TIMEOUTS: Counter = Counter::new("client_idle_timeouts");
+pub static CLIENT_HEADER_TIMEOUTS: Counter = Counter::new("client_header_timeouts");
+pub static REJECTED_CONNECTIONS: Counter = Counter::new("rejected_connections");
+pub static FILE_RESPONSES_PRECOMPRESSED: Counter = Counter::new("file_responses_precompressed");
+pub static PRECOMPRESSED_STALE: Counter = Counter::new("precompressed_stale");
+pub static FILE_RESPONSES_COMPRESSED: Counter =
+    Counter::new("file_responses_compressed_on_the_fly");
+pub static FILE_RESPONSES_IDENTITY: Counter = Counter::new("file_responses_identity");
+pub static TLS_PASSTHROUGH_CONNECTIONS: Counter = Counter::new("tls_passthrough_connections");
+pub static CONNECT_TUNNELS: Counter = Counter::new("connect_tunnels");
+pub static FORWARD_AUTH_DENIALS: Counter = Counter::new("forward_auth_denials");
+pub static TOKEN_AUTH_DENIALS: Counter = Counter::new("token_auth_denials");
+pub static UPGRADED_CONNECTIONS: Counter = Counter::new("upgraded_connections");
+pub static H2C_PASSTHROUGH_CONNECTIONS: Counter = Counter::new("h2c_passthrough_connections");
+pub static MAINTENANCE_RESPONSES: Counter = Counter::new("maintenance_responses");
+pub static ZSTD_BUDGET_DOWNGRADES: Counter = Counter::new("zstd_budget_downgrades");
+pub static SECRET_COOKIE_BYPASSES: Counter = Counter::new("secret_cookie_bypasses");
+pub static REQUEST_HEADER_SETS: Counter = Counter::new("request_header_sets");
+pub static REQUEST_HEADER_FIELDS: Counter = Counter::new("request_header_fields");
+pub static REQUEST_HEADER_BYTES: Counter = Counter::new("request_header_bytes");
+pub static REQUEST_HEADER_ANOMALIES: Counter = Counter::new("request_header_anomalies");
+pub static RESPONSE_HEADER_SETS: Counter = Counter::new("response_header_sets");
+pub static RESPONSE_HEADER_FIELDS: Counter = Counter::new("response_header_fields");
+pub static RESPONSE_HEADER_BYTES: Counter = Counter::new("response_header_bytes");
+pub static RESPONSE_HEADER_ANOMALIES: Counter = Counter::new("response_header_anomalies");
+
+/// Requests answered, whatever the status.
+pub static REQUESTS_SERVED: Counter = Counter::new("requests_served");
+
+pub static OPEN_CONNECTIONS: Gauge = Gauge::new("open_connections");
+
+static COUNTERS: &[&Counter] = &[
+    &REQUESTS_SERVED,
+    &BACKEND_CONNECT_TIMEOUTS,
+    &BACKEND_HEADER_TIMEOUTS,
+    &BACKEND_READ_TIMEOUTS,
+    &BACKEND_WRITE_TIMEOUTS,
+    &BACKEND_RETRIES,
+    &CLIENT_ABORTS,
+    &CLIENT_WRITE_TIMEOUTS,
+    &CLIENT_IDLE_TIMEOUTS,
+    &CLIENT_HEADER_TIMEOUTS,
+    &REJECTED_CONNECTIONS,
+    &FILE_RESPONSES_PRECOMPRESSED,
+    &PRECOMPRESSED_STALE,
+    &FILE_RESPONSES_COMPRESSED,
+    &FILE_RESPONSES_IDENTITY,
+    &TLS_PASSTHROUGH_CONNECTIONS,
+    &CONNECT_TUNNELS,
+    &FORWARD_AUTH_DENIALS,
+    &TOKEN_AUTH_DENIALS,
+    &UPGRADED_CONNECTIONS,
+    &H2C_PASSTHROUGH_CONNECTIONS,
+    &MAINTENANCE_RESPONSES,
+    &ZSTD_BUDGET_DOWNGRADES,
+    &SECRET_COOKIE_BYPASSES,
+    &REQUEST_HEADER_SETS,
+    &REQUEST_HEADER_FIELDS,
+    &REQUEST_HEADER_BYTES,
+    &REQUEST_HEADER_ANOMALIES,
+    &RESPONSE_HEADER_SETS,
+    &RESPONSE_HEADER_FIELDS,
+    &RESPONSE_HEADER_BYTES,
+    &RESPONSE_HEADER_ANOMALIES,
+];
+
+static GAUGES: &[&Gauge] = &[&OPEN_CONNECTIONS];
+
+/// The counters as `(name, value)` pairs, for saving them.
+pub fn counters() -> impl Iterator<Item = (&'static str, u64)> {
+    COUNTERS.iter().map(|c| (c.name, c.get()))
+}
+
+/// The counter called `name`, if there is one.
+pub fn counter(name: &str) -> Option<&'static Counter> {
+    COUNTERS.iter().copied().find(|c| c.name == name)
+}
+
+/// All metrics as `name value` lines, for the admin API.
+pub fn render() -> String {
+    let counters = counters();
+    let gauges = GAUGES.iter().map(|g| (g.name, g.get()));
+    counters
+        .chain(gauges)
+        .map(|(name, value)| format!("{} {}\n", name, value))
+        .collect()
+}
@@ -0,0 +1,65 @@
+//! URI patterns given on the command line, either inline or, as `@path`, read from a file with one
+//! pattern per line. Blank lines and lines starting with `#` are skipped; a pattern that starts
+//! with `#` or `@` is written with a backslash, as `\#` or `\@`.
+
+use std::fs;
+use std::io;
+
+use regex::Regex;
+
+/// Compiles the patterns given with `flag`. Errors and warnings name the flag occurrence or the
+/// file and line each pattern came from.
+pub fn compile(values: &[String], flag: &str) -> io::Result<Vec<Regex>> {
+    let mut patterns = Vec::new();
+    for (index, value) in values.iter().enumerate() {
+        let Some(path) = value.strip_prefix('@') else {
+            patterns.push(compile_one(value, &format!("{} #{}", flag, index + 1))?);
+            continue;
+        };
+        let content = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read {} patterns from {}: {}", flag, path, e),
+            )
+        })?;
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            patterns.push(compile_one(line, &format!("{}:{}", path, number + 1))?);
+        }
+    }
+    Ok(patterns)
+}
+
+fn compile_one(pattern: &str, source: &str) -> io::Result<Regex> {
+    let regex = Regex::new(pattern).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid pattern from {}: {}", source, e),
+        )
+    })?;
+
+    // Patterns are searched for anywhere in the URI, so one that matches the empty string
+    // matches every URI
+    if regex.is_match("") {
+        log::warn!("Pattern '{}' from {} matches every URI", pattern, source);
+    } else if is_bare_extension(pattern) {
+        log::warn!(
+            "Pattern '{}' from {} also matches inside URIs, such as .json for \\.js; \
+             anchor it as '{}$'",
+            pattern,
+            source,
+            pattern
+        );
+    }
+    Ok(regex)
+}
+
+/// Whether `pattern` is a file extension such as `\.js` without an anchor.
+fn is_bare_extension(pattern: &str) -> bool {
+    pattern
+        .strip_prefix("\\.")
+        .is_some_and(|ext| !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+}
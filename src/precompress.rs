@@ -0,0 +1,260 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::compression::{CompressionOptions, CompressionType};
+use crate::dict::collect_files;
+use crate::file_serving::manifest::Manifest;
+
+/// Ends the names of the temporary files that siblings, manifests and cached objects are written
+/// to before they are renamed into place, after the id of the process writing them.
+const TEMP_SUFFIX: &str = ".zstdp-tmp";
+
+/// Tells apart the temporary files of writes running at once in one process.
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Totals for one encoding across a run.
+#[derive(Default, Clone, Copy)]
+struct EncodingTotals {
+    files: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// Writes a `.zst`, `.br` and/or `.gz` sibling of every file under `dir` smaller than the file
+/// itself, compressing `jobs` files at a time from a single read of each. Siblings newer than
+/// their file are kept as they are. If `manifest` is given, the siblings are listed there for
+/// the file server to load with `--manifest`.
+pub fn run(
+    dir: &Path,
+    encodings: &[CompressionType],
+    options: CompressionOptions,
+    jobs: usize,
+    min_size: u64,
+    manifest: Option<&Path>,
+) -> io::Result<()> {
+    let start_time = Instant::now();
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    remove_orphans(&mut files);
+    files.retain(|path| {
+        let is_sibling = [
+            CompressionType::Zstd,
+            CompressionType::Brotli,
+            CompressionType::Gzip,
+        ]
+        .iter()
+        .any(|c| path.to_string_lossy().ends_with(c.extension()));
+        !is_sibling && Some(path.as_path()) != manifest
+    });
+    log::info!(
+        "Precompressing {} files under {} with {} workers",
+        files.len(),
+        dir.display(),
+        jobs
+    );
+
+    let files = Arc::new(files);
+    let next = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(Manifest::default()));
+    let totals = Arc::new(Mutex::new(vec![EncodingTotals::default(); encodings.len()]));
+
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let (files, next, results, totals) = (
+                Arc::clone(&files),
+                Arc::clone(&next),
+                Arc::clone(&results),
+                Arc::clone(&totals),
+            );
+            let encodings = encodings.to_vec();
+            let dir = dir.to_path_buf();
+            thread::spawn(move || -> io::Result<()> {
+                while let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let kept = precompress_file(path, &encodings, &options, min_size, &totals)?;
+                    let rel_path = path.strip_prefix(&dir).unwrap_or(path).to_path_buf();
+                    results
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(rel_path, kept);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| io::Error::other("Precompress worker panicked"))??;
+    }
+
+    let totals = totals.lock().unwrap_or_else(|e| e.into_inner());
+    for (encoding, totals) in encodings.iter().zip(totals.iter()) {
+        log::info!(
+            "  {}: {} files, {} -> {} bytes",
+            encoding,
+            totals.files,
+            totals.bytes_in,
+            totals.bytes_out
+        );
+    }
+
+    if let Some(manifest_path) = manifest {
+        let manifest = results.lock().unwrap_or_else(|e| e.into_inner());
+        manifest.save(manifest_path)?;
+        log::info!("Wrote manifest to {}", manifest_path.display());
+    }
+
+    log::info!("Precompressed in {:?}", start_time.elapsed());
+    Ok(())
+}
+
+/// Compresses one file into each encoding, returning the encodings whose sibling now exists.
+fn precompress_file(
+    path: &Path,
+    encodings: &[CompressionType],
+    options: &CompressionOptions,
+    min_size: u64,
+    totals: &Mutex<Vec<EncodingTotals>>,
+) -> io::Result<Vec<CompressionType>> {
+    let metadata = fs::metadata(path)?;
+    if metadata.len() < min_size {
+        log::debug!(
+            "Skipping {}: smaller than {} bytes",
+            path.display(),
+            min_size
+        );
+        return Ok(Vec::new());
+    }
+    let modified = metadata.modified()?;
+
+    let mut data = None;
+    let mut kept = Vec::new();
+    for (i, &encoding) in encodings.iter().enumerate() {
+        let sibling = PathBuf::from(format!("{}{}", path.display(), encoding.extension()));
+        let up_to_date = fs::metadata(&sibling)
+            .and_then(|m| m.modified())
+            .is_ok_and(|sibling_modified| sibling_modified >= modified);
+        if up_to_date {
+            log::debug!("Keeping up-to-date {}", sibling.display());
+            kept.push(encoding);
+            continue;
+        }
+
+        // Read the file once for all of its encodings
+        let data = match &mut data {
+            Some(data) => data,
+            None => data.insert(fs::read(path)?),
+        };
+        let mut encoder = options.encoder(Vec::new(), encoding)?;
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+
+        if compressed.len() >= data.len() {
+            log::debug!("Skipping {}: does not shrink", sibling.display());
+            match fs::remove_file(&sibling) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => continue,
+            }
+        }
+
+        write_atomically(&sibling, &compressed)?;
+        log::debug!(
+            "Wrote {} ({} -> {} bytes)",
+            sibling.display(),
+            data.len(),
+            compressed.len()
+        );
+
+        let mut totals = totals.lock().unwrap_or_else(|e| e.into_inner());
+        totals[i].files += 1;
+        totals[i].bytes_in += data.len() as u64;
+        totals[i].bytes_out += compressed.len() as u64;
+        kept.push(encoding);
+    }
+    Ok(kept)
+}
+
+/// Writes `contents` to `path` through a temporary file of this process next to it, synced to
+/// disk and then renamed over `path`, so that servers and concurrent runs never see half of the
+/// file, even after a crash.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomically_with(path, |file| file.write_all(contents))
+}
+
+/// Like `write_atomically`, for contents that `write` streams to the temporary file.
+pub(crate) fn write_atomically_with<T>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(
+        ".{}.{}{}",
+        NEXT_TEMP.fetch_add(1, Ordering::Relaxed),
+        std::process::id(),
+        TEMP_SUFFIX
+    ));
+    let tmp_path = PathBuf::from(tmp_path);
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        let written = write(&mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(written)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Deletes the temporary files under `dir` that writes interrupted by a crash left behind, as a
+/// server does for the directory it serves when it starts.
+pub fn remove_orphans_under(dir: &Path) -> io::Result<()> {
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    remove_orphans(&mut files);
+    Ok(())
+}
+
+/// Takes the temporary files out of `files`, deleting those whose process is gone: runs that
+/// crashed or were killed leave them behind. Those of runs still going are left to them.
+fn remove_orphans(files: &mut Vec<PathBuf>) {
+    files.retain(|path| {
+        let name = path.to_string_lossy();
+        let Some(stem) = name.strip_suffix(TEMP_SUFFIX) else {
+            return true;
+        };
+        let pid = stem.rsplit_once('.').and_then(|(_, pid)| pid.parse().ok());
+        if pid.is_some_and(is_running) {
+            log::debug!("Leaving {} to the run writing it", path.display());
+            return false;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => log::info!("Removed {}, left by an interrupted write", path.display()),
+            Err(e) => log::warn!("Failed to remove {}: {}", path.display(), e),
+        }
+        false
+    });
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists and may be signaled.
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
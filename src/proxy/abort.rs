@@ -0,0 +1,132 @@
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::client::ClientStream;
+use crate::metrics::CLIENT_ABORTS;
+
+/// How long the watcher waits on the client socket before checking whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Watches the client while a response is being produced and shuts the backend connection down
+/// as soon as the client goes away, instead of downloading and compressing a response nobody
+/// will receive. Dropping it stops the watch.
+///
+/// A client that half-closes its side of the connection is treated as gone.
+pub struct DisconnectWatch {
+    done: Arc<AtomicBool>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl DisconnectWatch {
+    pub fn start(client: &ClientStream, server: &TcpStream) -> io::Result<Self> {
+        let done = Arc::new(AtomicBool::new(false));
+        let client = client.try_clone()?;
+        let server = server.try_clone()?;
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let watching = Arc::clone(&done);
+        let aborting = Arc::clone(&aborted);
+        thread::spawn(move || {
+            while !watching.load(Ordering::Relaxed) {
+                match client_state(&client) {
+                    Ok(ClientState::Connected) => {}
+                    Ok(ClientState::Sending) => thread::sleep(POLL_INTERVAL),
+                    Ok(ClientState::Gone) | Err(_) => {
+                        if !watching.load(Ordering::Relaxed) {
+                            CLIENT_ABORTS.increment();
+                            aborting.store(true, Ordering::Relaxed);
+                            log::info!("Client disconnected, aborting backend request");
+                            let _ = server.shutdown(Shutdown::Both);
+                        }
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Self { done, aborted })
+    }
+
+    /// Whether the client disconnected and the backend request was aborted.
+    pub fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for DisconnectWatch {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+
+enum ClientState {
+    /// Nothing happened within the poll interval
+    Connected,
+    /// The client has data (e.g. a request body) waiting to be read
+    Sending,
+    /// The client closed or reset the connection
+    Gone,
+}
+
+#[cfg(unix)]
+fn client_state(client: &ClientStream) -> io::Result<ClientState> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = client.as_raw_fd();
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `pollfd` is a valid, exclusively borrowed array of one element.
+    let ready = unsafe { libc::poll(&mut pollfd, 1, POLL_INTERVAL.as_millis() as libc::c_int) };
+    if ready < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::Interrupted => Ok(ClientState::Connected),
+            _ => Err(e),
+        };
+    }
+    if ready == 0 {
+        return Ok(ClientState::Connected);
+    }
+    if pollfd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+        return Ok(ClientState::Gone);
+    }
+
+    // Readable: either data or end of stream. Peek without blocking, since the request body
+    // upload may consume the data first.
+    let mut byte = 0u8;
+    // SAFETY: the buffer is one valid byte and the descriptor is owned by `client`.
+    let n = unsafe {
+        libc::recv(
+            fd,
+            &mut byte as *mut u8 as *mut libc::c_void,
+            1,
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    match n {
+        0 => Ok(ClientState::Gone),
+        n if n > 0 => Ok(ClientState::Sending),
+        _ => {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => {
+                    Ok(ClientState::Connected)
+                }
+                _ => Ok(ClientState::Gone),
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn client_state(_client: &ClientStream) -> io::Result<ClientState> {
+    thread::sleep(POLL_INTERVAL);
+    Ok(ClientState::Connected)
+}
@@ -0,0 +1,102 @@
+//! Authorization of client requests, in either mode.
+//!
+//! An `AuthProvider` decides whether each request may be served before it goes any further. It
+//! answers the requests it refuses itself, with whatever response suits it (a `401` with a
+//! challenge, a redirect to a login page), and may set headers on those it allows, such as the id
+//! of the signed-in user; clients cannot send those headers themselves, as theirs are always
+//! removed. Static bearer tokens (`token_auth`) and forward authentication (`forward_auth`) are
+//! the providers zstdp builds from its command line; others, such as a check of a JWT, plug in by
+//! implementing the trait.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+use crate::client::ClientStream;
+use crate::route::RouteConfig;
+
+use super::transfer::ForwardedRequest;
+
+/// The request a provider is asked about.
+pub struct Subject<'a> {
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub host: Option<&'a str>,
+    pub headers: &'a [(String, String)],
+    pub peer: IpAddr,
+    /// Scheme of the connection the request arrived on, whatever the request claims
+    pub scheme: &'static str,
+}
+
+/// Decides whether requests may be served.
+pub trait AuthProvider: fmt::Display + Send + Sync {
+    /// Checks `subject`, and returns the headers to set on it once allowed. Refused requests are
+    /// answered on `client` and fail with `PermissionDenied`, carrying a `Denied` so that
+    /// `denied_status` finds the status they were answered with; when the provider itself fails,
+    /// the client is answered with 502 or 504 and the error is of another kind.
+    fn check(
+        &self,
+        client: &mut ClientStream,
+        subject: &Subject,
+    ) -> io::Result<Vec<(String, String)>>;
+
+    /// Headers only the provider may set on requests.
+    fn response_headers(&self) -> &[String];
+}
+
+/// A request a provider did not allow, answered with the provider's own response.
+#[derive(Debug)]
+pub struct Denied {
+    status: String,
+}
+
+impl Denied {
+    /// A refusal answered with `status`, such as `401 Unauthorized`.
+    pub fn error(status: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            Denied {
+                status: status.to_string(),
+            },
+        )
+    }
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request not authorized ({})", self.status)
+    }
+}
+
+impl Error for Denied {}
+
+/// Checks `request` with the provider of `route`, if it has one, and sets the headers the
+/// provider allowed it with. Errors are those of `AuthProvider::check`.
+pub fn authorize(
+    route: &RouteConfig,
+    client: &mut ClientStream,
+    request: &mut ForwardedRequest,
+) -> io::Result<()> {
+    let Some(auth) = &route.auth else {
+        return Ok(());
+    };
+    let subject = Subject {
+        method: &request.method,
+        uri: &request.uri,
+        host: request.host.as_deref(),
+        headers: &request.headers,
+        peer: client.peer_addr()?.ip(),
+        scheme: client.scheme(),
+    };
+    let allowed = auth.check(client, &subject)?;
+    request.replace_headers(auth.response_headers(), allowed);
+    Ok(())
+}
+
+/// The status line a request refused by `AuthProvider::check` was answered with.
+pub fn denied_status(e: &io::Error) -> Option<&str> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Denied>())
+        .map(|denied| denied.status.as_str())
+}
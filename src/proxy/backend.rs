@@ -0,0 +1,189 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::balancer::Backends;
+use super::fastcgi::FastCgiAddr;
+
+/// Where `--forward` sends requests: an HTTP backend, or a FastCGI application server given as
+/// `fastcgi:host:port` or `fastcgi:unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    Http(Backends),
+    FastCgi(FastCgiAddr),
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("fastcgi:") {
+            Some(addr) => addr.parse().map(Upstream::FastCgi),
+            None => s.parse().map(Upstream::Http),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Http(addr) => write!(f, "{}", addr),
+            Upstream::FastCgi(addr) => write!(f, "fastcgi:{}", addr),
+        }
+    }
+}
+
+/// A backend address given as `host:port`, `ipv4:port`, `[ipv6]:port` or `[ipv6%zone]:port`,
+/// where the zone of a link-local address is an interface name or index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackendAddr {
+    host: BackendHost,
+    port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BackendHost {
+    Name(String),
+    Ip(IpAddr),
+    ScopedV6 { ip: Ipv6Addr, zone: String },
+}
+
+impl BackendAddr {
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        TcpStream::connect(&self.socket_addrs()?[..])
+    }
+
+    /// Connects like `connect`, giving up on each address after `timeout`.
+    pub fn connect_timeout(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No address for {}", self))
+        }))
+    }
+
+    fn socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match &self.host {
+            BackendHost::Name(name) => Ok((name.as_str(), self.port).to_socket_addrs()?.collect()),
+            BackendHost::Ip(ip) => Ok(vec![SocketAddr::new(*ip, self.port)]),
+            BackendHost::ScopedV6 { ip, zone } => {
+                let scope_id = resolve_zone(zone)?;
+                Ok(vec![SocketAddr::V6(SocketAddrV6::new(
+                    *ip, self.port, 0, scope_id,
+                ))])
+            }
+        }
+    }
+
+    /// The value of a `Host` header addressing this backend. Zone identifiers are only meaningful
+    /// to the local host, so they are left out.
+    pub fn host_header(&self) -> String {
+        let host = match &self.host {
+            BackendHost::Name(name) => name.clone(),
+            BackendHost::Ip(IpAddr::V4(ip)) => ip.to_string(),
+            BackendHost::Ip(IpAddr::V6(ip)) | BackendHost::ScopedV6 { ip, .. } => {
+                format!("[{}]", ip)
+            }
+        };
+        format!("{}:{}", host, self.port)
+    }
+}
+
+impl FromStr for BackendAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, port) = rest
+                .split_once("]:")
+                .ok_or_else(|| format!("Expected [address]:port, got '{}'", s))?;
+            (host, port)
+        } else {
+            if s.matches(':').count() > 1 {
+                return Err(format!(
+                    "IPv6 addresses must be enclosed in brackets, e.g. [::1]:8080, got '{}'",
+                    s
+                ));
+            }
+            s.rsplit_once(':')
+                .ok_or_else(|| format!("Expected host:port, got '{}'", s))?
+        };
+
+        let port = port
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid port '{}': {}", port, e))?;
+
+        let host = if s.starts_with('[') {
+            // Zone identifiers may be percent-encoded as in URIs (RFC 6874)
+            match host.split_once('%') {
+                Some((ip, zone)) => {
+                    let zone = match zone.strip_prefix("25") {
+                        Some(decoded) if !decoded.is_empty() => decoded,
+                        _ => zone,
+                    };
+                    let ip = ip
+                        .parse::<Ipv6Addr>()
+                        .map_err(|e| format!("Invalid IPv6 address '{}': {}", ip, e))?;
+                    if zone.is_empty() {
+                        return Err(format!("Empty zone identifier in '{}'", s));
+                    }
+                    BackendHost::ScopedV6 {
+                        ip,
+                        zone: zone.to_string(),
+                    }
+                }
+                None => BackendHost::Ip(IpAddr::V6(
+                    host.parse::<Ipv6Addr>()
+                        .map_err(|e| format!("Invalid IPv6 address '{}': {}", host, e))?,
+                )),
+            }
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            BackendHost::Ip(ip)
+        } else if host.is_empty() {
+            return Err(format!("Missing host in '{}'", s));
+        } else {
+            BackendHost::Name(host.to_string())
+        };
+
+        Ok(BackendAddr { host, port })
+    }
+}
+
+impl fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            BackendHost::ScopedV6 { ip, zone } => write!(f, "[{}%{}]:{}", ip, zone, self.port),
+            _ => write!(f, "{}", self.host_header()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn resolve_zone(zone: &str) -> io::Result<u32> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    let name =
+        std::ffi::CString::new(zone).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is a valid NUL-terminated string that outlives the call.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown network interface '{}'", zone),
+        )),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn resolve_zone(zone: &str) -> io::Result<u32> {
+    zone.parse::<u32>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Zone must be numeric"))
+}
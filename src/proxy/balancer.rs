@@ -0,0 +1,340 @@
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::backend::BackendAddr;
+
+/// How connections are spread over several backends.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Balancing {
+    /// Each backend in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest connections in flight for its weight
+    LeastConn,
+    /// Each backend in turn, as many times as its weight, interleaved
+    Weighted,
+}
+
+impl FromStr for Balancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "least-conn" => Ok(Balancing::LeastConn),
+            "weighted" => Ok(Balancing::Weighted),
+            _ => Err(format!(
+                "Expected round-robin, least-conn or weighted, got '{}'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Balancing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Balancing::RoundRobin => "round-robin",
+            Balancing::LeastConn => "least-conn",
+            Balancing::Weighted => "weighted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+struct Member {
+    addr: BackendAddr,
+    weight: u32,
+    in_flight: AtomicUsize,
+    /// Failures since the last success
+    failures: AtomicU32,
+    /// Until when the backend is left out after failing too often
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Member {
+    fn new(addr: BackendAddr, weight: u32) -> Self {
+        Member {
+            addr,
+            weight,
+            in_flight: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+        }
+    }
+
+    fn ejected(&self, now: Instant) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some_and(|until| now < until)
+    }
+}
+
+/// When backends that keep failing are left out, as in nginx: after `max_fails` consecutive
+/// failures, for `fail_timeout`. A `max_fails` of 0 never leaves a backend out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ejection {
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+}
+
+impl Default for Ejection {
+    fn default() -> Self {
+        Ejection {
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// HTTP backends given as a comma-separated list of addresses, each optionally followed by
+/// `=weight`, which share connections according to a `Balancing` strategy.
+#[derive(Debug)]
+pub struct Backends {
+    members: Vec<Member>,
+    strategy: Balancing,
+    ejection: Ejection,
+    next: AtomicUsize,
+    /// Current weights of the smooth weighted round-robin, as in nginx
+    current: Mutex<Vec<i64>>,
+}
+
+impl Backends {
+    fn new(members: Vec<Member>, strategy: Balancing, ejection: Ejection) -> Self {
+        Backends {
+            current: Mutex::new(vec![0; members.len()]),
+            members,
+            strategy,
+            ejection,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// These backends, balanced with `strategy`.
+    pub fn with_strategy(mut self, strategy: Balancing) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// These backends, leaving out failing ones as `ejection` says.
+    pub fn with_ejection(mut self, ejection: Ejection) -> Self {
+        self.ejection = ejection;
+        self
+    }
+
+    /// Whether any backend has a weight other than 1.
+    pub fn weighted(&self) -> bool {
+        self.members.iter().any(|member| member.weight != 1)
+    }
+
+    /// The backend for the next connection, counted as in flight until the lease is dropped.
+    pub fn pick(&self) -> Lease<'_> {
+        self.pick_other(&[]).unwrap_or_else(|| self.lease(0))
+    }
+
+    /// The backend for a connection that `tried` failed to answer, if any other is left.
+    pub fn pick_other(&self, tried: &[BackendAddr]) -> Option<Lease<'_>> {
+        let now = Instant::now();
+        let untried: Vec<usize> = (0..self.members.len())
+            .filter(|&i| !tried.contains(&self.members[i].addr))
+            .collect();
+        // Backends that were left out are only used when nothing else is left
+        let healthy: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|&i| !self.members[i].ejected(now))
+            .collect();
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let count = candidates.len();
+        let turn = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = match self.strategy {
+            Balancing::RoundRobin => candidates[turn % count],
+            Balancing::LeastConn => {
+                // Start at a different backend each time, so that ties are shared out
+                let load = |i: usize| {
+                    let member = &self.members[i];
+                    (
+                        member.in_flight.load(Ordering::Relaxed),
+                        member.weight as usize,
+                    )
+                };
+                (0..count)
+                    .map(|offset| candidates[(turn + offset) % count])
+                    .reduce(|best, i| {
+                        let (best_flight, best_weight) = load(best);
+                        let (flight, weight) = load(i);
+                        if flight * best_weight < best_flight * weight {
+                            i
+                        } else {
+                            best
+                        }
+                    })
+                    .unwrap_or(candidates[0])
+            }
+            Balancing::Weighted => {
+                let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+                let mut best = candidates[0];
+                let mut total = 0;
+                for &i in &candidates {
+                    let weight = self.members[i].weight as i64;
+                    current[i] += weight;
+                    total += weight;
+                    if current[i] > current[best] {
+                        best = i;
+                    }
+                }
+                current[best] -= total;
+                best
+            }
+        };
+        Some(self.lease(index))
+    }
+
+    fn lease(&self, index: usize) -> Lease<'_> {
+        self.members[index]
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed);
+        Lease {
+            backends: self,
+            index,
+        }
+    }
+}
+
+/// A backend picked for a connection.
+pub struct Lease<'a> {
+    backends: &'a Backends,
+    index: usize,
+}
+
+impl Lease<'_> {
+    fn member(&self) -> &Member {
+        &self.backends.members[self.index]
+    }
+
+    /// Records that the backend answered.
+    pub fn succeeded(&self) {
+        self.member().failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Records that the backend could not be reached or did not answer, leaving it out for a
+    /// while once it has failed too often in a row.
+    pub fn failed(&self) {
+        let Ejection {
+            max_fails,
+            fail_timeout,
+        } = self.backends.ejection;
+        let member = self.member();
+        let failures = member.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if max_fails == 0 || failures < max_fails || self.backends.members.len() < 2 {
+            return;
+        }
+        member.failures.store(0, Ordering::Relaxed);
+        *member
+            .ejected_until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now() + fail_timeout);
+        log::warn!(
+            "Leaving out backend {} for {:?} after {} failures in a row",
+            member.addr,
+            fail_timeout,
+            failures
+        );
+    }
+}
+
+impl Deref for Lease<'_> {
+    type Target = BackendAddr;
+
+    fn deref(&self) -> &BackendAddr {
+        &self.member().addr
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.member().in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Backends {
+    /// A copy with its own turns, counts and failures, starting over at the first backend.
+    fn clone(&self) -> Self {
+        let members = self
+            .members
+            .iter()
+            .map(|member| Member::new(member.addr.clone(), member.weight))
+            .collect();
+        Backends::new(members, self.strategy, self.ejection)
+    }
+}
+
+impl PartialEq for Backends {
+    fn eq(&self, other: &Self) -> bool {
+        self.strategy == other.strategy
+            && self.ejection == other.ejection
+            && self.members.len() == other.members.len()
+            && self
+                .members
+                .iter()
+                .zip(&other.members)
+                .all(|(a, b)| a.addr == b.addr && a.weight == b.weight)
+    }
+}
+
+impl FromStr for Backends {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let members = s
+            .split(',')
+            .map(|backend| {
+                let backend = backend.trim();
+                let (addr, weight) = match backend.rsplit_once('=') {
+                    Some((addr, weight)) => {
+                        let weight = weight
+                            .parse::<u32>()
+                            .ok()
+                            .filter(|&weight| weight > 0)
+                            .ok_or_else(|| {
+                                format!("Invalid weight '{}' in '{}'", weight, backend)
+                            })?;
+                        (addr, weight)
+                    }
+                    None => (backend, 1),
+                };
+                Ok(Member::new(addr.parse()?, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Backends::new(
+            members,
+            Balancing::default(),
+            Ejection::default(),
+        ))
+    }
+}
+
+impl fmt::Display for Backends {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, member) in self.members.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", member.addr)?;
+            if member.weight != 1 {
+                write!(f, "={}", member.weight)?;
+            }
+        }
+        Ok(())
+    }
+}
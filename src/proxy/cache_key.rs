@@ -0,0 +1,355 @@
+//! Keys of the origin shield, local and remote.
+//!
+//! A key is made of the parts of a request that `--cache-key` names, normalized so that
+//! requests a backend cannot tell apart share an entry, and of the encoding of the response,
+//! which is never left out: the response body depends on it, and a response stored for one
+//! `Accept-Encoding` must never reach a client that sent another.
+//!
+//! Percent-escapes of unreserved characters are decoded and the others uppercased, which RFC 3986
+//! (section 6.2.2) makes equivalent for every URI. Dot segments and repeated slashes are left
+//! alone, as backends do not all resolve them alike. The file server's bucket cache keys its
+//! copies the same way, as the unencoded responses to GETs of their objects. Validators are not
+//! part of any key, as every cache only learns them from the response it stores; entries are
+//! revalidated or checked with them instead.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::compression::CompressionType;
+
+/// A part of a request that goes into its key.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    /// The `Host`, ignoring case
+    Host,
+    Path,
+    /// The query string as sent
+    Query,
+    /// The query parameters in sorted order, so that `?a=1&b=2` and `?b=2&a=1` share an entry
+    SortedQuery,
+    /// The value of a request header, by lowercase name
+    Header(String),
+}
+
+/// Which parts of a request its key is made of, given as comma-separated `host`, `path`,
+/// `query` or `sorted-query` and `header:NAME`. The path is required.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheKeyTemplate {
+    parts: Vec<Part>,
+}
+
+impl Default for CacheKeyTemplate {
+    fn default() -> Self {
+        CacheKeyTemplate {
+            parts: vec![Part::Host, Part::Path, Part::Query],
+        }
+    }
+}
+
+impl FromStr for CacheKeyTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            let part = match part {
+                "host" => Part::Host,
+                "path" => Part::Path,
+                "query" => Part::Query,
+                "sorted-query" => Part::SortedQuery,
+                _ => match part.strip_prefix("header:") {
+                    Some(name) if !name.is_empty() => Part::Header(name.to_lowercase()),
+                    _ => {
+                        return Err(format!(
+                            "Expected host, path, query, sorted-query or header:NAME, got '{}'",
+                            part
+                        ))
+                    }
+                },
+            };
+            if parts.contains(&part) {
+                return Err(format!("'{}' is listed twice", s));
+            }
+            parts.push(part);
+        }
+        if !parts.contains(&Part::Path) {
+            return Err("The cache key needs the path".to_string());
+        }
+        if parts.contains(&Part::Query) && parts.contains(&Part::SortedQuery) {
+            return Err("Expected query or sorted-query, not both".to_string());
+        }
+        Ok(CacheKeyTemplate { parts })
+    }
+}
+
+impl fmt::Display for CacheKeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Host => "host".to_string(),
+                Part::Path => "path".to_string(),
+                Part::Query => "query".to_string(),
+                Part::SortedQuery => "sorted-query".to_string(),
+                Part::Header(name) => format!("header:{}", name),
+            })
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// What a key is computed from.
+pub struct KeyedRequest<'a> {
+    pub host: Option<&'a str>,
+    /// The request target in origin form
+    pub uri: &'a str,
+    /// Request headers, in any case
+    pub headers: &'a [(String, String)],
+    /// Encoding of the response zstdp sends
+    pub compression: CompressionType,
+    /// The encoding zstdp pinned for a trusted client, in which case the backend is asked for an
+    /// unencoded response whatever the client accepts
+    pub forced: bool,
+}
+
+impl CacheKeyTemplate {
+    /// The key of `request`, or `None` for targets that are not paths, such as `*`.
+    pub fn key(&self, request: &KeyedRequest) -> Option<String> {
+        if !request.uri.starts_with('/') {
+            return None;
+        }
+        let (path, query) = match request.uri.split_once('?') {
+            Some((path, query)) => (path, Some(query).filter(|query| !query.is_empty())),
+            None => (request.uri, None),
+        };
+
+        let mut key = encoding_part(request);
+        key.push(' ');
+        // Hosts have no `/` and paths start with one, so the two cannot run into each other
+        if self.parts.contains(&Part::Host) {
+            key.push_str(&request.host.unwrap_or("").to_lowercase());
+        }
+        key.push_str(&normalize_escapes(path));
+        for part in &self.parts {
+            match (part, query) {
+                (Part::Query, Some(query)) => {
+                    key.push('?');
+                    key.push_str(&normalize_escapes(query));
+                }
+                (Part::SortedQuery, Some(query)) => {
+                    let mut params: Vec<String> = query
+                        .split('&')
+                        .filter(|param| !param.is_empty())
+                        .map(normalize_escapes)
+                        .collect();
+                    params.sort();
+                    key.push('?');
+                    key.push_str(&params.join("&"));
+                }
+                (Part::Header(name), _) => {
+                    let values: Vec<&str> = request
+                        .headers
+                        .iter()
+                        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .map(|(_, v)| v.trim())
+                        .collect();
+                    // Quoted, so that values cannot pass for other parts
+                    if values.is_empty() {
+                        key.push_str(&format!(" {}", name));
+                    } else {
+                        key.push_str(&format!(" {}={:?}", name, values.join(", ")));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(key)
+    }
+
+    /// Whether the key tells apart requests that differ in header `name`, so that responses
+    /// varying on it may be stored.
+    pub fn varies_on(&self, name: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Header(keyed) if keyed.eq_ignore_ascii_case(name)))
+    }
+}
+
+/// The encoding of the response and the `Accept-Encoding` the backend saw, as backends may answer
+/// with an encoding of their own that zstdp passes through.
+fn encoding_part(request: &KeyedRequest) -> String {
+    if request.forced {
+        return format!("{}/identity", request.compression);
+    }
+    let mut codings: Vec<String> = request
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("accept-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .and_then(|(_, value)| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality == 1.0 {
+                Some(coding)
+            } else {
+                Some(format!("{};q={}", coding, quality))
+            }
+        })
+        .collect();
+    codings.sort();
+    codings.dedup();
+    if codings.is_empty() {
+        codings.push("identity".to_string());
+    }
+    format!("{}/{}", request.compression, codings.join(","))
+}
+
+/// Decodes the percent-escapes of unreserved characters and uppercases the others.
+fn normalize_escapes(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit) => {
+                std::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                out.push(byte as char);
+                i += 3;
+            }
+            Some(byte) => {
+                out.push_str(&format!("%{:02X}", byte));
+                i += 3;
+            }
+            None => {
+                // Not an escape; copy the character whole
+                let c = s[i..].chars().next().unwrap_or_default();
+                out.push(c);
+                i += c.len_utf8();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(template: &str, uri: &str, headers: &[(&str, &str)]) -> Option<String> {
+        let template: CacheKeyTemplate = template.parse().unwrap();
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        template.key(&KeyedRequest {
+            host: Some("Example.COM"),
+            uri,
+            headers: &headers,
+            compression: CompressionType::Zstd,
+            forced: false,
+        })
+    }
+
+    #[test]
+    fn templates_name_each_part_once_and_need_the_path() {
+        let template: CacheKeyTemplate = "host, path,sorted-query,header:X-Tenant".parse().unwrap();
+        assert_eq!(
+            template.to_string(),
+            "host,path,sorted-query,header:x-tenant"
+        );
+        assert!(template.varies_on("X-TENANT"));
+        assert!(!template.varies_on("Cookie"));
+        assert_eq!(CacheKeyTemplate::default().to_string(), "host,path,query");
+        for invalid in [
+            "host,query",
+            "path,path",
+            "path,query,sorted-query",
+            "path,header:",
+            "path,cookie",
+        ] {
+            assert!(invalid.parse::<CacheKeyTemplate>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn equivalent_targets_share_a_key() {
+        let plain = key("host,path,query", "/a~b/%2F?x=A", &[]);
+        assert_eq!(
+            plain.as_deref(),
+            Some("zstd/identity example.com/a~b/%2F?x=A")
+        );
+        assert_eq!(key("host,path,query", "/a%7eb/%2f?x=%41", &[]), plain);
+        assert_ne!(key("host,path,query", "/a~b//%2F?x=A", &[]), plain);
+        assert_eq!(
+            key("path", "/a?x=1", &[]).as_deref(),
+            Some("zstd/identity /a")
+        );
+        assert_eq!(key("path,query", "/a?", &[]), key("path,query", "/a", &[]));
+        assert_eq!(key("path", "*", &[]), None);
+    }
+
+    #[test]
+    fn sorted_queries_ignore_parameter_order() {
+        assert_eq!(
+            key("path,sorted-query", "/s?b=2&a=1&", &[]),
+            key("path,sorted-query", "/s?a=1&b=2", &[])
+        );
+        assert_ne!(
+            key("path,query", "/s?b=2&a=1", &[]),
+            key("path,query", "/s?a=1&b=2", &[])
+        );
+    }
+
+    #[test]
+    fn header_parts_are_quoted_and_missing_headers_count() {
+        assert_eq!(
+            key("path,header:x-tenant", "/", &[("X-Tenant", " a b ")]).as_deref(),
+            Some("zstd/identity / x-tenant=\"a b\"")
+        );
+        assert_eq!(
+            key("path,header:x-tenant", "/", &[]).as_deref(),
+            Some("zstd/identity / x-tenant")
+        );
+        assert_ne!(
+            key("path,header:x-tenant", "/", &[("X-Tenant", "")]),
+            key("path,header:x-tenant", "/", &[])
+        );
+    }
+
+    #[test]
+    fn keys_carry_the_encoding_and_what_the_client_accepted() {
+        let accepting = |value| key("path", "/", &[("Accept-Encoding", value)]);
+        assert_eq!(
+            accepting("zstd, GZIP;q=1.0, br;q=0.5").as_deref(),
+            Some("zstd/br;q=0.5,gzip,zstd /")
+        );
+        assert_eq!(accepting("gzip, zstd"), accepting("zstd,gzip,gzip"));
+        assert_ne!(accepting("gzip, zstd"), accepting("zstd"));
+
+        let headers = vec![("Accept-Encoding".to_string(), "zstd, br".to_string())];
+        let forced = CacheKeyTemplate::default().key(&KeyedRequest {
+            host: None,
+            uri: "/",
+            headers: &headers,
+            compression: CompressionType::Gzip,
+            forced: true,
+        });
+        assert_eq!(forced.as_deref(), Some("gzip/identity /"));
+    }
+}
@@ -0,0 +1,154 @@
+//! `CONNECT` tunnels, for clients that take zstdp for a forward proxy to reach TLS servers.
+//!
+//! A `CONNECT host:port` request is never sent to the backend, which would not know what to make
+//! of it. Destinations matching `--connect-allow` are connected to directly and the client is
+//! answered with `200 Connection Established`, after which bytes are relayed both ways unchanged;
+//! other destinations are answered with `403 Forbidden`, and with no `--connect-allow` every
+//! `CONNECT` is.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::client::ClientStream;
+use crate::metrics::CONNECT_TUNNELS;
+use crate::request_target::write_bad_request;
+use crate::tunnel::{tunnel_connection, TunnelLimits};
+
+use super::backend::BackendAddr;
+use super::handlers::{write_bad_gateway, write_gateway_timeout};
+use super::transfer::{is_timeout, ForwardedRequest};
+use super::BackendTimeouts;
+
+/// Destinations clients may open tunnels to, given as `HOST:PORT`. The host is a name or address,
+/// `*.example.com` for the subdomains of a domain, or `*` for any; the port is a number or `*`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectRule {
+    /// Lowercase, without the brackets of an IPv6 address
+    host: String,
+    /// `None` for any port
+    port: Option<u16>,
+}
+
+impl ConnectRule {
+    fn allows(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|allowed| allowed != port) {
+            return false;
+        }
+        if self.host == "*" {
+            return true;
+        }
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            None => host == self.host,
+        }
+    }
+}
+
+impl FromStr for ConnectRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || {
+            format!(
+                "Expected HOST:PORT, with * for any host or port, got '{}'",
+                s
+            )
+        };
+        let (host, port) = s.trim().rsplit_once(':').ok_or_else(expected)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = host.strip_prefix("*.").unwrap_or(host);
+        if name.is_empty() || (name.contains('*') && host != "*") {
+            return Err(expected());
+        }
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| expected())?),
+        };
+        Ok(ConnectRule {
+            host: host.to_lowercase(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ConnectRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.host.contains(':'), self.port) {
+            (true, Some(port)) => write!(f, "[{}]:{}", self.host, port),
+            (true, None) => write!(f, "[{}]:*", self.host),
+            (false, Some(port)) => write!(f, "{}:{}", self.host, port),
+            (false, None) => write!(f, "{}:*", self.host),
+        }
+    }
+}
+
+/// Answers a `CONNECT` request: opens a tunnel to its target if `rules` allow it, and relays the
+/// connection until the destination closes it. Malformed targets are answered with 400 and
+/// returned as `InvalidInput`, refused ones with 403 and returned as `PermissionDenied`.
+pub fn tunnel(
+    client: &mut ClientStream,
+    request: ForwardedRequest,
+    rules: &[ConnectRule],
+    timeouts: &BackendTimeouts,
+    limits: TunnelLimits,
+) -> io::Result<()> {
+    let target = request.uri.as_str();
+    let destination = target.rsplit_once(':').and_then(|(host, port)| {
+        let port = port.parse::<u16>().ok()?;
+        Some((target.parse::<BackendAddr>().ok()?, host, port))
+    });
+    let Some((addr, host, port)) = destination else {
+        write_bad_request(client)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid CONNECT target {}", target),
+        ));
+    };
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase();
+    if !rules.iter().any(|rule| rule.allows(&host, port)) {
+        write_forbidden(client)?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("CONNECT to {} is not allowed", target),
+        ));
+    }
+
+    let mut server = match timeouts.connect(&addr) {
+        Ok(server) => server,
+        Err(e) if is_timeout(&e) => {
+            write_gateway_timeout(client)?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+        }
+        Err(e) => {
+            log::warn!("Failed to open tunnel to {}: {}", target, e);
+            write_bad_gateway(client)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    };
+    // Tunnels idle as long as the two ends like
+    server.set_write_timeout(None)?;
+    client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+    // Bytes the client sent right after the request, without waiting for the answer
+    if let Some(early) = request.body {
+        early.copy_to(&mut io::empty(), &mut server)?;
+    }
+
+    CONNECT_TUNNELS.increment();
+    log::debug!("Tunnel to {} open", target);
+    tunnel_connection(client, server, limits)
+}
+
+fn write_forbidden(client: &mut ClientStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 403 Forbidden\r\n")?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 9\r\n")?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Forbidden")
+}
@@ -0,0 +1,600 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use percent_encoding::percent_decode_str;
+
+use super::auth;
+use super::backend::BackendAddr;
+use super::handlers::{
+    write_bad_gateway, write_content_too_large, write_gateway_timeout, Relay, ResponseHead,
+};
+use super::transfer::{is_timeout, read_request, MAX_RESPONSE_HEAD_SIZE};
+use super::BackendTimeouts;
+use crate::body_log::Sampled;
+use crate::client::ClientStream;
+use crate::file_serving::handlers::handle_file_request;
+use crate::header_stats::Direction;
+use crate::metrics::{BACKEND_CONNECT_TIMEOUTS, BACKEND_WRITE_TIMEOUTS};
+use crate::request_target::write_bad_request;
+use crate::route::{FastCgiApp, RouteConfig};
+use crate::slow_clients::ClientWriter;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+/// Requests are never multiplexed, so every connection carries request 1.
+const REQUEST_ID: u16 = 1;
+const MAX_RECORD_CONTENT: usize = u16::MAX as usize;
+
+/// A FastCGI application server, such as PHP-FPM, listening on TCP or a Unix socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FastCgiAddr {
+    Tcp(BackendAddr),
+    Unix(PathBuf),
+}
+
+impl FastCgiAddr {
+    fn connect(&self, timeouts: &BackendTimeouts) -> io::Result<Connection> {
+        match self {
+            FastCgiAddr::Tcp(addr) => timeouts.connect(addr).map(Connection::Tcp),
+            #[cfg(unix)]
+            FastCgiAddr::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_write_timeout(timeouts.write)?;
+                Ok(Connection::Unix(stream))
+            }
+            #[cfg(not(unix))]
+            FastCgiAddr::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+impl FromStr for FastCgiAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("Missing socket path in '{}'", s)),
+            Some(path) => Ok(FastCgiAddr::Unix(PathBuf::from(path))),
+            None => s.parse().map(FastCgiAddr::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for FastCgiAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FastCgiAddr::Tcp(addr) => write!(f, "{}", addr),
+            FastCgiAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// What a request path maps to under the document root.
+#[derive(Debug, PartialEq)]
+enum Resolved {
+    /// A file other than a script, served by the file server
+    Static,
+    /// A script run by the application, with the rest of the path as `PATH_INFO`
+    Script {
+        script_name: String,
+        path_info: String,
+    },
+}
+
+impl FastCgiApp {
+    /// Maps `request_path` the way a typical PHP setup does: `.php` scripts (with optional path
+    /// info) run as they are, other existing files are served statically, directories run their
+    /// index script, and everything else goes to the index script at the root, as front
+    /// controllers expect.
+    fn resolve(&self, request_path: &str) -> Resolved {
+        let root = &self.static_files.root;
+        let decoded = percent_decode_str(request_path).decode_utf8_lossy();
+        let parts: Vec<_> = Path::new(decoded.as_ref())
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            })
+            .collect();
+        let path = format!("/{}", parts.join("/"));
+
+        // The file server answers hidden paths as its policy says
+        if self.static_files.dotfiles.hides(Path::new(&path)) {
+            return Resolved::Static;
+        }
+
+        let script = |script_name: String, path_info: &str| Resolved::Script {
+            script_name,
+            path_info: path_info.to_string(),
+        };
+
+        if let Some(end) = path
+            .match_indices(".php")
+            .map(|(i, _)| i + 4)
+            .find(|&end| path[end..].is_empty() || path[end..].starts_with('/'))
+        {
+            if root.join(&path[1..end]).is_file() {
+                return script(path[..end].to_string(), &path[end..]);
+            }
+        } else {
+            let target = root.join(&path[1..]);
+            if target.is_file() {
+                return Resolved::Static;
+            }
+            let index = target.join(&self.index);
+            if target.is_dir() && index.is_file() {
+                let dir = path.trim_end_matches('/');
+                return script(format!("{}/{}", dir, self.index), "");
+            }
+        }
+
+        if root.join(&self.index).is_file() {
+            script(format!("/{}", self.index), "")
+        } else {
+            Resolved::Static
+        }
+    }
+}
+
+/// Answers a request with a FastCGI application, or with the file server for static files under
+/// its document root. The application's output is compressed like a backend response.
+pub fn handle_fastcgi_connection(
+    mut client: ClientStream,
+    route: &RouteConfig,
+    app: &FastCgiApp,
+) -> io::Result<()> {
+    let start_time = Instant::now();
+    let local_addr = client.local_addr()?;
+    let peer_addr = client.peer_addr()?;
+    let trust_forced_encoding = route.trusts_forced_encoding(peer_addr.ip());
+    let mut request = read_request(
+        &mut client,
+        &route.requests,
+        trust_forced_encoding,
+        route.compression.backend_encodings(),
+    )?;
+    auth::authorize(route, &mut client, &mut request)?;
+
+    let (path, query) = request
+        .uri
+        .split_once('?')
+        .unwrap_or((request.uri.as_str(), ""));
+    let (script_name, path_info) = match app.resolve(path) {
+        Resolved::Static => {
+            log::debug!("Serving {} as a static file", path);
+            let (method, uri) = (request.method.clone(), request.uri.clone());
+            return handle_file_request(
+                client,
+                route,
+                &app.static_files,
+                &method,
+                &uri,
+                &request.headers,
+            );
+        }
+        Resolved::Script {
+            script_name,
+            path_info,
+        } => (script_name, path_info),
+    };
+    log::debug!("Running {} for {}", script_name, path);
+
+    let root = app.static_files.root.display().to_string();
+    let host = request
+        .host
+        .clone()
+        .unwrap_or_else(|| local_addr.to_string());
+    let server_name = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name.to_string(),
+        _ => host.clone(),
+    };
+    let mut params = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "zstdp".to_string()),
+        ("SERVER_PROTOCOL", "HTTP/1.1".to_string()),
+        ("SERVER_NAME", server_name),
+        ("SERVER_ADDR", local_addr.ip().to_string()),
+        ("SERVER_PORT", local_addr.port().to_string()),
+        ("REMOTE_ADDR", peer_addr.ip().to_string()),
+        ("REMOTE_PORT", peer_addr.port().to_string()),
+        ("REQUEST_METHOD", request.method.clone()),
+        ("REQUEST_URI", request.uri.clone()),
+        ("QUERY_STRING", query.to_string()),
+        ("DOCUMENT_ROOT", root.clone()),
+        ("DOCUMENT_URI", script_name.clone()),
+        ("SCRIPT_FILENAME", format!("{}{}", root, script_name)),
+        ("SCRIPT_NAME", script_name),
+        ("HTTP_HOST", host),
+    ];
+    if !path_info.is_empty() {
+        params.push(("PATH_TRANSLATED", format!("{}{}", root, path_info)));
+        params.push(("PATH_INFO", path_info));
+    }
+    // Applications expect CONTENT_LENGTH, so a chunked body is read before the request is sent
+    if let Some(body) = request.body.take_if(|body| body.is_chunked()) {
+        let body = match body.into_buffered(&mut client) {
+            Ok(body) => body,
+            Err(e) if e.kind() == io::ErrorKind::FileTooLarge => {
+                log::debug!("Rejecting request: {}", e);
+                write_content_too_large(&mut client)?;
+                return Err(e);
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::debug!("Rejecting request with a malformed chunked body: {}", e);
+                write_bad_request(&client)?;
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            Err(e) => return Err(e),
+        };
+        params.push(("CONTENT_LENGTH", body.length().unwrap_or(0).to_string()));
+        request.body = Some(body);
+    }
+    let mut http_headers = Vec::new();
+    for (name, value) in &request.headers {
+        let name = name.to_uppercase().replace('-', "_");
+        match name.as_str() {
+            "CONTENT_TYPE" => params.push(("CONTENT_TYPE", value.clone())),
+            "CONTENT_LENGTH" => params.push(("CONTENT_LENGTH", value.clone())),
+            // `HTTP_PROXY` would be taken for proxy settings by many applications (httpoxy)
+            "PROXY" => {}
+            // Applications get the body without its chunked framing
+            "TRANSFER_ENCODING" => {}
+            // A pinned response is compressed here, so the application must not compress it
+            "ACCEPT_ENCODING" if request.forced_encoding.is_some() => {}
+            _ => http_headers.push((format!("HTTP_{}", name), value.clone())),
+        }
+    }
+
+    let timeouts = app.policy.timeouts;
+    let mut server = match app.addr.connect(&timeouts) {
+        Ok(server) => server,
+        Err(e) if is_timeout(&e) => {
+            BACKEND_CONNECT_TIMEOUTS.increment();
+            log::warn!("Connecting to FastCGI server {} timed out", app.addr);
+            write_gateway_timeout(&mut client)?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+        }
+        Err(e) => {
+            log::error!("Failed to connect to FastCGI server {}: {}", app.addr, e);
+            write_bad_gateway(&mut client)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    };
+    log::debug!("Connected to FastCGI server in {:?}", start_time.elapsed());
+
+    let mut encoded_params = Vec::new();
+    for (name, value) in params
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(http_headers.iter().map(|(n, v)| (n.as_str(), v.as_str())))
+    {
+        encode_param(&mut encoded_params, name, value);
+    }
+    let sent = (|| {
+        let mut records = BufWriter::new(&mut server);
+        write_record(
+            &mut records,
+            BEGIN_REQUEST,
+            &[&RESPONDER.to_be_bytes()[..], &[0; 6]].concat(),
+        )?;
+        write_stream(&mut records, PARAMS, &encoded_params)?;
+        write_record(&mut records, PARAMS, &[])?;
+        if let Some(body) = request.body.take() {
+            let mut stdin = StreamWriter::new(&mut records, STDIN);
+            let length = body.copy_to(&mut client, &mut stdin)?;
+            stdin.flush()?;
+            log::debug!("Sent request body of {} bytes", length);
+        }
+        write_record(&mut records, STDIN, &[])?;
+        records.flush()
+    })();
+    match sent {
+        Err(e) if is_timeout(&e) => {
+            BACKEND_WRITE_TIMEOUTS.increment();
+            log::warn!(
+                "Sending the request to FastCGI server {} timed out",
+                app.addr
+            );
+            write_gateway_timeout(&mut client)?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+        }
+        sent => sent?,
+    }
+
+    let relay = Relay::for_request(route, &app.policy, &request);
+    server.set_read_timeout(timeouts.header.or(timeouts.read))?;
+    let mut stdout = BufReader::new(Stdout::new(server));
+    let response = match read_cgi_head(&mut stdout) {
+        Ok(head) => head,
+        Err(e) if is_timeout(&e) => {
+            log::warn!("FastCGI server {} timed out: {}", app.addr, e);
+            write_gateway_timeout(&mut client)?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+        }
+        Err(e) => {
+            log::warn!("Invalid response from FastCGI server {}: {}", app.addr, e);
+            write_bad_gateway(&mut client)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    };
+    log::debug!("← {} from FastCGI server", response.status_line);
+    response.record_headers(&request.uri);
+    response.record_capture(&request);
+    let response = relay.restrict(response);
+    stdout.get_ref().inner.set_read_timeout(timeouts.read)?;
+    let mut stdout =
+        Sampled::new(stdout, "", None).capturing(request.captured.as_ref(), Direction::Response);
+
+    let mut out = ClientWriter::new(&mut client);
+    // Records are read ahead into `stdout`, so its socket does not tell when it is quiet
+    relay.respond(&mut out, &mut stdout, None, &response, &app.addr, None)?;
+    log::debug!("← Completed FastCGI request in {:?}", start_time.elapsed());
+    Ok(())
+}
+
+/// Reads the CGI response head from the application's output and turns it into an HTTP response
+/// head: the `Status` header becomes the status line, and responses of unknown length are ended
+/// by closing the connection.
+fn read_cgi_head<R: BufRead>(stdout: &mut R) -> io::Result<ResponseHead> {
+    let mut status = None;
+    let mut headers = Vec::new();
+    let mut head_size = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if stdout.read_until(b'\n', &mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "FastCGI output ended before the end of its headers",
+            ));
+        }
+        head_size += line.len();
+        if head_size > MAX_RESPONSE_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "FastCGI response head exceeds {} bytes",
+                    MAX_RESPONSE_HEAD_SIZE
+                ),
+            ));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid FastCGI response header '{}'", line),
+            ));
+        };
+        if name.trim().eq_ignore_ascii_case("status") {
+            status = Some(value.trim().to_string());
+        } else {
+            headers.push(format!("{}: {}\r\n", name.trim(), value.trim()));
+        }
+    }
+
+    let has_header = |name: &str| {
+        headers
+            .iter()
+            .any(|h| h.to_lowercase().starts_with(&format!("{}:", name)))
+    };
+    let status = match status {
+        Some(status) if status.contains(' ') => status,
+        Some(code) => format!("{} ", code),
+        None if has_header("location") => "302 Found".to_string(),
+        None => "200 OK".to_string(),
+    };
+    let mut raw = format!("HTTP/1.1 {}\r\n", status);
+    for header in &headers {
+        raw.push_str(header);
+    }
+    if !has_header("content-length") {
+        raw.push_str("Connection: close\r\n");
+    }
+    raw.push_str("\r\n");
+    Ok(ResponseHead::parse(raw.into_bytes()))
+}
+
+/// The application's `FCGI_STDOUT` stream, ending with its `FCGI_END_REQUEST` record.
+/// `FCGI_STDERR` output is logged.
+struct Stdout {
+    inner: Connection,
+    /// Bytes left in the current `FCGI_STDOUT` record
+    remaining: usize,
+    padding: usize,
+    done: bool,
+}
+
+impl Stdout {
+    fn new(inner: Connection) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            padding: 0,
+            done: false,
+        }
+    }
+
+    fn skip_padding(&mut self) -> io::Result<()> {
+        let mut padding = [0; 255];
+        self.inner.read_exact(&mut padding[..self.padding])
+    }
+}
+
+impl Read for Stdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done && !buf.is_empty() {
+            if self.remaining > 0 {
+                let limit = buf.len().min(self.remaining);
+                let n = self.inner.read(&mut buf[..limit])?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "FastCGI connection closed inside a record",
+                    ));
+                }
+                self.remaining -= n;
+                if self.remaining == 0 {
+                    self.skip_padding()?;
+                }
+                return Ok(n);
+            }
+
+            let mut header = [0; 8];
+            self.inner.read_exact(&mut header)?;
+            let kind = header[1];
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            self.padding = header[6] as usize;
+            if kind == STDOUT {
+                self.remaining = length;
+                if length == 0 {
+                    self.skip_padding()?;
+                }
+                continue;
+            }
+
+            let mut content = vec![0; length];
+            self.inner.read_exact(&mut content)?;
+            self.skip_padding()?;
+            match kind {
+                STDERR if !content.is_empty() => log::warn!(
+                    "FastCGI stderr: {}",
+                    String::from_utf8_lossy(&content).trim_end()
+                ),
+                END_REQUEST => {
+                    if content.get(4).is_some_and(|&status| status != 0) {
+                        log::warn!("FastCGI request ended with protocol status {}", content[4]);
+                    }
+                    self.done = true;
+                }
+                _ => {}
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// Splits everything written to it into records of one stream type.
+struct StreamWriter<W: Write> {
+    inner: W,
+    kind: u8,
+}
+
+impl<W: Write> StreamWriter<W> {
+    fn new(inner: W, kind: u8) -> Self {
+        Self { inner, kind }
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(MAX_RECORD_CONTENT);
+        if length > 0 {
+            write_record(&mut self.inner, self.kind, &buf[..length])?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_stream<W: Write>(out: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    StreamWriter::new(out, kind).write_all(content)
+}
+
+fn write_record<W: Write>(out: &mut W, kind: u8, content: &[u8]) -> io::Result<()> {
+    let [id_high, id_low] = REQUEST_ID.to_be_bytes();
+    let [length_high, length_low] = (content.len() as u16).to_be_bytes();
+    // Padding keeps records 8-byte aligned, as the specification recommends
+    let padding = (8 - content.len() % 8) % 8;
+    out.write_all(&[
+        VERSION,
+        kind,
+        id_high,
+        id_low,
+        length_high,
+        length_low,
+        padding as u8,
+        0,
+    ])?;
+    out.write_all(content)?;
+    out.write_all(&[0; 8][..padding])
+}
+
+/// Appends a name-value pair in the FastCGI encoding: each length in one byte below 128, in
+/// four bytes with the high bit set otherwise.
+fn encode_param(out: &mut Vec<u8>, name: &str, value: &str) {
+    for length in [name.len(), value.len()] {
+        if length < 128 {
+            out.push(length as u8);
+        } else {
+            out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
@@ -0,0 +1,407 @@
+//! Forward authentication: an external service decides whether each request may be served.
+//!
+//! With `--forward-auth http://host:port/path`, every request is first described to the service
+//! in a `GET` of that path, carrying the request's `--forward-auth-request-header` headers
+//! (`Authorization` and `Cookie` by default) along with `X-Forwarded-Method`,
+//! `X-Forwarded-Proto`, `X-Forwarded-Host`, `X-Forwarded-Uri` and `X-Forwarded-For`, which zstdp
+//! sets from the request and the connection it arrived on, never from the client's own headers.
+//! A `2xx` answer lets the request through, with the `--forward-auth-response-header` headers of
+//! the answer, such as the id of the signed-in user, set on it; clients cannot send those headers
+//! themselves, as theirs are always removed. Any other answer, a `401`, a `403` or a redirect to
+//! a login page, is relayed to the client as the service sent it, with a `Content-Length` of its
+//! own, and the request goes no further. A service that cannot be reached, answers with something
+//! other than HTTP or denies with a body over 64 KiB is answered with `502 Bad Gateway` or
+//! `504 Gateway Timeout`, so that no request is served unchecked.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::client::ClientStream;
+use crate::metrics::FORWARD_AUTH_DENIALS;
+
+use super::auth::{AuthProvider, Denied, Subject};
+use super::backend::BackendAddr;
+use super::handlers::{write_bad_gateway, write_gateway_timeout};
+use super::headers::{append_raw_headers, parse_response_headers};
+use super::transfer::{decode_chunked_body, is_timeout, read_response_head};
+use super::BackendTimeouts;
+
+/// Largest body of a denial relayed to the client.
+const MAX_DENIAL_BODY: u64 = 64 * 1024;
+
+/// The authorization service and the headers exchanged with it.
+pub struct ForwardAuth {
+    addr: BackendAddr,
+    /// Path and query the service is asked at
+    path: String,
+    /// Request headers sent to the service
+    request_headers: Vec<String>,
+    /// Headers of the service's answer set on the request it allowed
+    response_headers: Vec<String>,
+    timeouts: BackendTimeouts,
+}
+
+impl fmt::Display for ForwardAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.addr.host_header(), self.path)
+    }
+}
+
+impl ForwardAuth {
+    /// A service given as `http://host[:port][/path]`.
+    pub fn new(
+        url: &str,
+        request_headers: &[String],
+        response_headers: &[String],
+        timeouts: BackendTimeouts,
+    ) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid forward auth URL '{}': {}", url, reason),
+            )
+        };
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None if url.starts_with("https://") => {
+                return Err(invalid(
+                    "HTTPS services need a local TLS proxy, as zstdp only speaks plain HTTP",
+                ))
+            }
+            None => return Err(invalid("expected http://host[:port][/path]")),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(end) => rest.split_at(end),
+            None => (rest, ""),
+        };
+        let authority = if authority.starts_with('[') || authority.matches(':').count() == 1 {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        let addr = authority.parse::<BackendAddr>().map_err(|e| invalid(&e))?;
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        Ok(ForwardAuth {
+            addr,
+            path,
+            request_headers: request_headers.to_vec(),
+            response_headers: response_headers.to_vec(),
+            timeouts,
+        })
+    }
+
+    /// Sends the subrequest and reads the head of the answer.
+    fn ask(&self, subject: &Subject) -> io::Result<(Vec<u8>, TcpStream)> {
+        let mut server = self.timeouts.connect(&self.addr)?;
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\n",
+            self.path,
+            self.addr.host_header()
+        );
+        request.push_str(&format!("X-Forwarded-Method: {}\r\n", subject.method));
+        request.push_str(&format!("X-Forwarded-Proto: {}\r\n", subject.scheme));
+        if let Some(host) = subject.host {
+            request.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        request.push_str(&format!("X-Forwarded-Uri: {}\r\n", subject.uri));
+        request.push_str(&format!("X-Forwarded-For: {}\r\n", subject.peer));
+        for (name, value) in subject.headers {
+            if self
+                .request_headers
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(name))
+            {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        server.write_all(request.as_bytes())?;
+
+        let head = read_response_head(&mut server, self.timeouts.header, self.timeouts.read)?;
+        if !head.starts_with(b"HTTP/") || !head.ends_with(b"\r\n\r\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Malformed response from the authorization service",
+            ));
+        }
+        Ok((head, server))
+    }
+}
+
+impl AuthProvider for ForwardAuth {
+    /// Asks the service about `subject`. Denied requests are answered with the service's
+    /// response; failures of the service are answered with 502 or 504 and fail with
+    /// `InvalidData` or `TimedOut`.
+    fn check(
+        &self,
+        client: &mut ClientStream,
+        subject: &Subject,
+    ) -> io::Result<Vec<(String, String)>> {
+        let (head, mut server) = match self.ask(subject) {
+            Ok(answer) => answer,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut || is_timeout(&e) => {
+                log::warn!("Authorization service {} timed out: {}", self.addr, e);
+                write_gateway_timeout(client)?;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+            }
+            Err(e) => {
+                log::warn!("Authorization service {} failed: {}", self.addr, e);
+                write_bad_gateway(client)?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+        let head_str = String::from_utf8_lossy(&head);
+        let (status_line, headers) = parse_response_headers(&head_str);
+        let status = status_line
+            .split_once(' ')
+            .map(|(_, status)| status.trim())
+            .unwrap_or_default();
+        if status.starts_with('2') {
+            log::debug!("Request allowed by the authorization service ({})", status);
+            // Spelled as configured, since the answer's names were lowercased when parsed
+            return Ok(self
+                .response_headers
+                .iter()
+                .flat_map(|name| {
+                    headers
+                        .iter()
+                        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+                        .map(|(_, v)| (name.clone(), v.clone()))
+                })
+                .collect());
+        }
+
+        let body = match read_denial_body(&mut server, &headers) {
+            Ok(body) => body,
+            Err(e) => {
+                log::warn!(
+                    "Unusable denial from authorization service {}: {}",
+                    self.addr,
+                    e
+                );
+                write_bad_gateway(client)?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        };
+        FORWARD_AUTH_DENIALS.increment();
+        log::debug!("Request denied by the authorization service ({})", status);
+        // The body was read whole, so it is framed anew rather than as the service sent it
+        let head: String = head_str
+            .split_inclusive("\r\n")
+            .filter(|line| {
+                !line.split_once(':').is_some_and(|(name, _)| {
+                    ["content-length", "transfer-encoding", "connection"]
+                        .iter()
+                        .any(|framing| name.trim().eq_ignore_ascii_case(framing))
+                })
+            })
+            .collect();
+        let extra = [
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Connection".to_string(), "close".to_string()),
+        ];
+        client.write_all(&append_raw_headers(head.as_bytes(), &extra))?;
+        if !subject.method.eq_ignore_ascii_case("HEAD") {
+            client.write_all(&body)?;
+        }
+        Err(Denied::error(status))
+    }
+
+    fn response_headers(&self) -> &[String] {
+        &self.response_headers
+    }
+}
+
+/// Reads the body of a denial whole, without its framing. A body over `MAX_DENIAL_BODY`, or one
+/// cut short, fails with `InvalidData`.
+fn read_denial_body(server: &mut TcpStream, headers: &[(String, String)]) -> io::Result<Vec<u8>> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.trim())
+    };
+    let too_large = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Denial body larger than {} bytes", MAX_DENIAL_BODY),
+        )
+    };
+    let mut body = Vec::new();
+    let chunked = header("transfer-encoding").is_some_and(|v| v.to_lowercase().contains("chunked"));
+    if chunked {
+        // Chunk framing takes little room next to the content, so a body cut at twice the limit
+        // was too large anyway
+        decode_chunked_body(&mut server.take(MAX_DENIAL_BODY * 2), &mut body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    } else if let Some(length) = header("content-length") {
+        let length = length.parse::<u64>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid Content-Length {:?}", length),
+            )
+        })?;
+        if length > MAX_DENIAL_BODY {
+            return Err(too_large());
+        }
+        body.resize(length as usize, 0);
+        server.read_exact(&mut body)?;
+    } else {
+        // The service was asked to close the connection, so a body of unknown length ends there
+        server.take(MAX_DENIAL_BODY + 1).read_to_end(&mut body)?;
+    }
+    if body.len() as u64 > MAX_DENIAL_BODY {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use std::os::unix::net::UnixStream;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use crate::proxy::auth::denied_status;
+
+    /// A service that answers one request with `answer`, and hands back the head of the request.
+    fn service(answer: &[u8]) -> (ForwardAuth, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let answer = answer.to_vec();
+        let asked = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(&stream);
+            while reader.read_line(&mut head).unwrap() > 2 {}
+            stream.write_all(&answer).unwrap();
+            head
+        });
+        let timeout = Some(Duration::from_secs(5));
+        let timeouts = BackendTimeouts {
+            connect: timeout,
+            write: timeout,
+            header: timeout,
+            read: timeout,
+        };
+        let auth = ForwardAuth::new(
+            &url,
+            &["Authorization".to_string()],
+            &["X-User".to_string()],
+            timeouts,
+        )
+        .unwrap();
+        (auth, asked)
+    }
+
+    /// Checks a request with `headers` against a service answering `answer`, and returns the
+    /// outcome, the head the service was asked with and what the client was sent.
+    fn check(
+        answer: &[u8],
+        headers: &[(&str, &str)],
+    ) -> (io::Result<Vec<(String, String)>>, String, String) {
+        let (auth, asked) = service(answer);
+        let headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut client = ClientStream::Unix(ours);
+        let subject = Subject {
+            method: "GET",
+            uri: "/private?page=2",
+            host: Some("example.com"),
+            headers: &headers,
+            peer: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)),
+            scheme: "http",
+        };
+        let result = auth.check(&mut client, &subject);
+        drop(client);
+        let mut sent = String::new();
+        theirs.read_to_string(&mut sent).unwrap();
+        (result, asked.join().unwrap(), sent)
+    }
+
+    #[test]
+    fn allowed_requests_get_the_response_headers() {
+        let answer =
+            b"HTTP/1.1 200 OK\r\nX-User: alice\r\nX-Other: no\r\nContent-Length: 0\r\n\r\n";
+        let (result, _, sent) = check(answer, &[("Authorization", "Bearer t")]);
+        assert_eq!(
+            result.unwrap(),
+            [("X-User".to_string(), "alice".to_string())]
+        );
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn the_service_is_told_about_the_request_and_its_connection() {
+        let answer = b"HTTP/1.1 204 No Content\r\n\r\n";
+        let headers = [
+            ("Authorization", "Bearer t"),
+            ("Cookie", "session=1"),
+            ("X-Forwarded-Proto", "https"),
+        ];
+        let (_, asked, _) = check(answer, &headers);
+        let lines: Vec<&str> = asked.lines().collect();
+        assert_eq!(lines[0], "GET /auth HTTP/1.1");
+        for line in [
+            "X-Forwarded-Method: GET",
+            "X-Forwarded-Proto: http",
+            "X-Forwarded-Host: example.com",
+            "X-Forwarded-Uri: /private?page=2",
+            "X-Forwarded-For: 192.0.2.7",
+            "Authorization: Bearer t",
+        ] {
+            assert!(lines.contains(&line), "{} not in {:?}", line, lines);
+        }
+        assert!(!asked.contains("https"));
+        assert!(!asked.contains("Cookie"));
+    }
+
+    #[test]
+    fn denials_are_relayed_with_their_body() {
+        let answer = b"HTTP/1.1 302 Found\r\nLocation: /login\r\nContent-Length: 5\r\n\r\nlogin";
+        let (result, _, sent) = check(answer, &[]);
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(denied_status(&e), Some("302 Found"));
+        assert_eq!(
+            sent,
+            "HTTP/1.1 302 Found\r\nLocation: /login\r\nContent-Length: 5\r\n\
+             Connection: close\r\n\r\nlogin"
+        );
+    }
+
+    #[test]
+    fn chunked_denials_are_sent_with_a_length() {
+        let answer = b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       4\r\nno e\r\n5\r\nntry!\r\n0\r\n\r\n";
+        let (result, _, sent) = check(answer, &[]);
+        assert_eq!(denied_status(&result.unwrap_err()), Some("403 Forbidden"));
+        assert_eq!(
+            sent,
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 9\r\nConnection: close\r\n\r\nno entry!"
+        );
+    }
+
+    #[test]
+    fn denials_too_large_to_relay_are_answered_with_502() {
+        let answer = format!(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: {}\r\n\r\n",
+            MAX_DENIAL_BODY + 1
+        );
+        let (result, _, sent) = check(answer.as_bytes(), &[]);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(sent.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", sent);
+    }
+}
@@ -0,0 +1,66 @@
+//! gRPC through zstdp.
+//!
+//! gRPC compresses its messages itself, ends its responses with trailers carrying `grpc-status`
+//! and, in its native form, runs over HTTP/2, which zstdp does not speak. Responses with an
+//! `application/grpc` content type, the `grpc-web` variants included, are therefore passed
+//! through as the backend sent them: never compressed again, and with their trailers. With
+//! `--h2c-passthrough`, connections that open with the HTTP/2 connection preface, as gRPC clients
+//! without TLS do, are relayed byte for byte to a backend instead of being read as malformed
+//! HTTP/1.1 requests. The relay happens before any request is read, so the requests inside skip
+//! every per-request policy: request normalization, authentication, the origin shield, body
+//! limits and the access log, which only records the connection.
+
+use std::io;
+
+use crate::client::ClientStream;
+use crate::metrics::H2C_PASSTHROUGH_CONNECTIONS;
+use crate::route::ProxyPolicy;
+use crate::tunnel::tunnel_connection;
+
+use super::backend::BackendAddr;
+use super::proxy_protocol;
+use super::transfer::is_timeout;
+
+/// The first bytes of an HTTP/2 connection with prior knowledge (RFC 9113, section 3.4).
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Whether `content_type` is one of gRPC's, such as `application/grpc+proto` or
+/// `application/grpc-web-text`.
+pub fn is_grpc(content_type: &str) -> bool {
+    content_type
+        .trim()
+        .get(.."application/grpc".len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("application/grpc"))
+}
+
+/// Whether the client opened with the HTTP/2 preface, without consuming it. The start of the
+/// preface is enough, as no HTTP/1.1 request begins with `PRI `.
+pub fn opens_with_preface(client: &ClientStream) -> io::Result<bool> {
+    let mut first = [0; PREFACE.len()];
+    let peeked = client.peek(&mut first)?;
+    Ok(peeked >= 4 && first[..peeked] == PREFACE[..peeked])
+}
+
+/// Relays an HTTP/2 connection to `forward` as it is, after a PROXY protocol header if `policy`
+/// asks for one. Nothing of the route's per-request policy applies to what is relayed. Backends
+/// that cannot be reached fail with `TimedOut` or `InvalidData`, as the client cannot be told in
+/// HTTP/1.1.
+pub fn relay(client: &ClientStream, forward: &BackendAddr, policy: &ProxyPolicy) -> io::Result<()> {
+    let mut server = match policy.timeouts.connect(forward) {
+        Ok(server) => server,
+        Err(e) if is_timeout(&e) => return Err(io::Error::new(io::ErrorKind::TimedOut, e)),
+        Err(e) => {
+            log::warn!("Failed to relay HTTP/2 connection to {}: {}", forward, e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    };
+    if policy.proxy_protocol {
+        proxy_protocol::write_header(&mut server, Some(client))?;
+    }
+    // Streams idle as long as the two ends like
+    server.set_write_timeout(None)?;
+
+    H2C_PASSTHROUGH_CONNECTIONS.increment();
+    log::debug!("Relaying HTTP/2 connection to {} as is", forward);
+    tunnel_connection(client, server, policy.tunnel)
+}
@@ -1,145 +1,1063 @@
-use io::BufWriter;
-use regex::Regex;
+use io::BufReader;
 
-use crate::args::should_bypass_compression;
-use crate::logging::LoggingExt;
+use crate::body::{Body, Encoding};
+use crate::body_log::Sampled;
+use crate::chaos::{ChaosPlan, TruncatingWriter};
+use crate::client_hints::ClientHints;
+use crate::compression::{is_transcodable, CompressionOptions, CompressionType};
+use crate::context;
+use crate::header_stats::{self, Direction};
+use crate::log_request;
+use crate::logging::{Loggable, LoggingExt};
+use crate::metrics::{
+    BACKEND_CONNECT_TIMEOUTS, BACKEND_HEADER_TIMEOUTS, BACKEND_READ_TIMEOUTS, BACKEND_RETRIES,
+    BACKEND_WRITE_TIMEOUTS, UPGRADED_CONNECTIONS,
+};
+use crate::request_target::{write_rejection, HeaderLines, RequestLimits};
+use crate::route::{ProxyPolicy, RouteConfig};
+use crate::slow_clients::{is_body_timeout, BodyTimeout, ClientWriter};
+use crate::tunnel::tunnel_connection;
 
-use super::headers::parse_response_headers;
-use super::transfer::{forward_chunked_body, forward_request};
+use super::abort::DisconnectWatch;
+use super::backend::BackendAddr;
+use super::balancer::Backends;
+use super::headers::{
+    append_raw_headers, append_vary, parse_response_headers, survives_compression, weaken_etag,
+    HeaderAllowList, BODY_FRAMING_HEADERS,
+};
+use super::shield::{self, Capture, Entry, Fetch, Freshness, Lookup};
+use super::transfer::{
+    is_timeout, read_request, read_response_head, ForwardedRequest, PendingBody, Upload,
+};
 use super::*;
+use std::os::fd::{AsRawFd, RawFd};
 use std::sync::Arc;
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Body length assumed when truncating a response of unknown length.
+const CHAOS_TRUNCATE_UNKNOWN_LENGTH: usize = 16 * 1024;
+
+/// Content types of responses that go on for as long as the backend has something to say, whose
+/// parts have to reach the client as soon as they are sent.
+const STREAMING_TYPES: &[&str] = &["text/event-stream", "application/x-ndjson"];
 
 pub fn handle_proxy_connection(
-    mut client: TcpStream,
-    forward: &str,
-    zstd_level: i32,
-    bypass_patterns: Arc<Vec<Regex>>,
+    mut client: ClientStream,
+    route: &RouteConfig,
+    backends: &Backends,
+    policy: &ProxyPolicy,
 ) -> io::Result<()> {
     let start_time = Instant::now();
+    let lease = backends.pick();
+    let forward: &BackendAddr = &lease;
     log::debug!("→ New proxy connection to {}", forward);
+    let timeouts = policy.timeouts;
+    let chaos = ChaosPlan::roll(&policy.chaos);
+
+    if chaos.drop {
+        return Ok(());
+    }
+    if let Some(status) = chaos.error_status {
+        return write_chaos_error(&mut client, &route.requests, status);
+    }
+    if policy.h2c_passthrough && grpc::opens_with_preface(&client)? {
+        log_request!("PRI * HTTP/2.0");
+        return grpc::relay(&client, forward, policy);
+    }
 
-    let mut server = TcpStream::connect(forward).map_err(|e| {
-        log::error!("Failed to connect to backend {}: {}", forward, e);
-        e
-    })?;
-    log::debug!("Connected to backend server in {:?}", start_time.elapsed());
+    // Read the request before connecting, so that the shield can answer it on its own
+    let trust_forced_encoding = route.trusts_forced_encoding(client.peer_addr()?.ip());
+    let mut request = read_request(
+        &mut client,
+        &route.requests,
+        trust_forced_encoding,
+        route.compression.backend_encodings(),
+    )?;
+    auth::authorize(route, &mut client, &mut request)?;
+    if request.method.eq_ignore_ascii_case("CONNECT") {
+        note(&request, "Tunneled");
+        return connect::tunnel(
+            &mut client,
+            request,
+            &policy.connect_allow,
+            &timeouts,
+            policy.tunnel,
+        );
+    }
+    let uri = &request.uri;
+    let relay = Relay::for_request(route, policy, &request);
+    let compression = relay.compression;
 
-    // Forward request to server
-    let (_headers, supports_zstd, uri) = forward.log_operation("forward_request", || {
-        forward_request(&mut client, &mut server.try_clone()?)
-    })?;
+    let key = policy
+        .shield
+        .as_ref()
+        .and_then(|shield| shield::key(&request, backends, compression, &shield.key));
+    let fetch = match (&policy.shield, key) {
+        (Some(shield), Some(key)) => match shield.store.lookup(&key, timeouts.until_response()) {
+            Lookup::Fresh(entry) => {
+                note(&request, "Answered from the shield (HIT)");
+                return write_entry(&mut client, &entry, "HIT", &request);
+            }
+            Lookup::Stale { entry, revalidate } => {
+                if revalidate {
+                    let head = request.head_for(&policy.host_rewrite.host_for(&request, forward));
+                    let forward = forward.clone();
+                    let relay = relay.clone();
+                    let stale = Arc::clone(&entry);
+                    let policy = policy.clone();
+                    thread::spawn(move || refresh(key, &forward, &head, &relay, &policy, &stale));
+                }
+                note(&request, "Answered from the shield (STALE)");
+                return write_entry(&mut client, &entry, "STALE", &request);
+            }
+            Lookup::Miss(fetch) => Some(fetch),
+            Lookup::Pass => None,
+        },
+        _ => None,
+    };
 
-    // Check if request should bypass compression
-    let should_bypass = should_bypass_compression(&uri, &bypass_patterns);
-    if should_bypass {
-        log::debug!("URI '{}' matches bypass pattern, skipping compression", uri);
+    // Idempotent requests without a body can be sent again when a backend fails before
+    // answering, since nothing has reached the client yet
+    let mut body = request.body.take();
+    let retryable = body.is_none() && is_idempotent(&request.method);
+    let mut lease = lease;
+    let mut tried = Vec::new();
+    let exchange = loop {
+        let failure =
+            match Exchange::start(&client, &lease, &request, body.take(), policy, chaos.delay) {
+                Ok(exchange) => {
+                    lease.succeeded();
+                    break exchange;
+                }
+                Err(failure) if failure.watch.as_ref().is_some_and(DisconnectWatch::aborted) => {
+                    return Err(failure.error);
+                }
+                Err(failure) => failure,
+            };
+        lease.failed();
+        tried.push(BackendAddr::clone(&lease));
+        let next = match backends.pick_other(&tried) {
+            Some(next) if retryable => next,
+            _ => {
+                let forward: &BackendAddr = &lease;
+                note(
+                    &request,
+                    format!("No response from {}: {}", forward, failure.error),
+                );
+                return fail_exchange(
+                    &mut client,
+                    fetch.as_ref(),
+                    forward,
+                    failure.step,
+                    failure.error,
+                );
+            }
+        };
+        BACKEND_RETRIES.increment();
+        log::warn!(
+            "Backend {} failed, retrying {} {} on {}: {}",
+            *lease,
+            request.method,
+            uri,
+            *next,
+            failure.error
+        );
+        lease = next;
+    };
+    let forward: &BackendAddr = &lease;
+    let Exchange {
+        mut server,
+        head,
+        _upload,
+        _watch,
+    } = exchange;
+
+    // Relay interim responses (e.g. 100 Continue, 103 Early Hints) until the final one
+    let mut head = head;
+    let response = loop {
+        let response = ResponseHead::parse(head);
+        log::debug!("← {} from backend", response.status_line);
+        if !is_interim(response.status()) {
+            break response;
+        }
+        client.write_all(&relay.restrict(response).raw)?;
+        head = match read_response_head(&mut server, timeouts.header, timeouts.read) {
+            Ok(head) => head,
+            Err(e) => {
+                note(&request, format!("No response from {}: {}", forward, e));
+                return fail_exchange(&mut client, fetch.as_ref(), forward, Step::Receive, e);
+            }
+        };
+    };
+    response.record_headers(uri);
+    response.record_capture(&request);
+    let response = relay.restrict(response);
+
+    // The backend took over the connection, for a WebSocket or another protocol
+    if response.status() == 101 {
+        if let Some(fetch) = fetch {
+            fetch.pass();
+        }
+        drop(_upload);
+        drop(_watch);
+        relay.write_head_as_is(&mut client, &response)?;
+        UPGRADED_CONNECTIONS.increment();
+        log::debug!("Connection to {} upgraded, relaying it as is", forward);
+        return tunnel_connection(&client, server, policy.tunnel);
     }
 
-    // Read response headers
-    let mut response_headers = Vec::new();
-    let mut byte = [0u8; 1];
-    while let Ok(1) = server.read(&mut byte) {
-        response_headers.push(byte[0]);
-        if response_headers.ends_with(b"\r\n\r\n") {
-            break;
+    if response.status() >= 500 {
+        if let Some(entry) = fetch.as_ref().and_then(Fetch::stale_if_error) {
+            log::warn!(
+                "Backend {} answered {}, serving stale response",
+                forward,
+                response.status()
+            );
+            return entry.write_to(&mut client, "STALE");
         }
     }
+    // Bypassed responses are sent as they come, so their length tells up front whether they fit
+    // in the shield; larger ones, typically big downloads, stream through without a copy
+    let max_entry_size = policy
+        .shield
+        .as_ref()
+        .map_or(0, |shield| shield.store.max_entry_size());
+    let too_large = relay.bypass
+        && response
+            .header("content-length")
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .is_some_and(|length| length > max_entry_size);
+    // Streams may never end, and requests collapsed onto them would wait as long
+    let fetch = fetch.and_then(|fetch| {
+        let template = &policy.shield.as_ref()?.key;
+        match Freshness::of_response(response.status(), &response.headers, template) {
+            Some(freshness) if !too_large && !response.is_streaming() => Some((fetch, freshness)),
+            _ => {
+                fetch.pass();
+                None
+            }
+        }
+    });
 
-    let response_headers_str = String::from_utf8_lossy(&response_headers).to_string();
-    let (status_line, headers) = parse_response_headers(&response_headers_str);
-    log::debug!("← {} from backend", status_line);
+    let label = format!("Response to {}", uri);
+    let source = server.as_raw_fd();
+    let mut server = Sampled::new(server, label, request.body_sample)
+        .capturing(request.captured.as_ref(), Direction::Response);
+    let mut out = Capture::new(
+        ClientWriter::new(&mut client),
+        fetch.is_some().then_some(max_entry_size),
+    );
+    let relayed = relay.respond(
+        &mut out,
+        &mut server,
+        Some(source),
+        &response,
+        forward,
+        chaos.truncate_at,
+    );
+    if relayed.as_ref().is_err_and(is_timeout) {
+        BACKEND_READ_TIMEOUTS.increment();
+    }
+    relayed?;
 
-    // Check compression and encoding properties
-    let current_encoding = headers
-        .iter()
-        .find(|(k, _)| k.to_lowercase() == "content-encoding")
-        .map(|(_, v)| v.to_lowercase());
+    if let Some((fetch, freshness)) = fetch {
+        match out.into_copy() {
+            Some(copy) => fetch.store(copy, freshness),
+            None => fetch.pass(),
+        }
+    }
 
-    let is_already_compressed = current_encoding.is_some();
-    let is_chunked = headers.iter().any(|(k, v)| {
-        k.to_lowercase() == "transfer-encoding" && v.to_lowercase().contains("chunked")
-    });
+    log::debug!("← Completed proxy request in {:?}", start_time.elapsed());
 
-    let content_length = headers
-        .iter()
-        .find(|(k, _)| k.to_lowercase() == "content-length")
-        .and_then(|(_, v)| v.parse::<usize>().ok());
+    Ok(())
+}
 
-    log::debug!(
-        "Response properties - compressed: {}, chunked: {}, length: {:?}",
-        is_already_compressed,
-        is_chunked,
-        content_length
-    );
+/// A request sent to a backend, up to the head of the first response.
+struct Exchange {
+    server: TcpStream,
+    head: Vec<u8>,
+    _upload: Option<Upload>,
+    _watch: Option<DisconnectWatch>,
+}
 
-    if is_already_compressed || should_bypass {
-        forward.log_operation("forward_compressed", || {
-            // Forward headers and body as-is
-            client.write_all(&response_headers)?;
+/// The steps of an exchange with a backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Connect,
+    Send,
+    Receive,
+}
 
-            if is_chunked {
-                forward_chunked_body(&mut server.try_clone()?, &mut client)
-            } else if let Some(length) = content_length {
-                io::copy(&mut server.take(length as u64), &mut client)?;
-                Ok(())
+/// Why an exchange ended without a response head.
+struct Failure {
+    step: Step,
+    error: io::Error,
+    /// Watches the client once the request is sent, to tell whether it gave up
+    watch: Option<DisconnectWatch>,
+}
+
+impl Failure {
+    fn new(step: Step, error: io::Error) -> Self {
+        Failure {
+            step,
+            error,
+            watch: None,
+        }
+    }
+}
+
+impl Exchange {
+    /// Connects to `forward`, sends it the request and its `body` and reads the first response
+    /// head.
+    fn start(
+        client: &ClientStream,
+        forward: &BackendAddr,
+        request: &ForwardedRequest,
+        body: Option<PendingBody>,
+        policy: &ProxyPolicy,
+        delay: Option<Duration>,
+    ) -> Result<Self, Failure> {
+        let start_time = Instant::now();
+        let timeouts = policy.timeouts;
+        let mut server = timeouts.connect(forward).map_err(|e| {
+            log::error!("Failed to connect to backend {}: {}", forward, e);
+            Failure::new(Step::Connect, e)
+        })?;
+        log::debug!("Connected to backend server in {:?}", start_time.elapsed());
+
+        // Forward request to server
+        let sent = (|| {
+            forward.log_operation("forward_request", || {
+                if policy.proxy_protocol {
+                    proxy_protocol::write_header(&mut server, Some(client))?;
+                }
+                let host = policy.host_rewrite.host_for(request, forward);
+                server.write_all(&request.head_for(&host))?;
+                server.flush()
+            })?;
+            let upload = match body {
+                Some(body) => {
+                    let label = format!("Request to {}", request.uri);
+                    Some(body.spawn_upload(
+                        client,
+                        &server,
+                        label,
+                        request.body_sample,
+                        request.captured.as_ref(),
+                    )?)
+                }
+                None => None,
+            };
+            let watch = if policy.ignore_client_abort {
+                None
             } else {
-                io::copy(&mut server, &mut client)?;
-                Ok(())
+                Some(DisconnectWatch::start(client, &server)?)
+            };
+            Ok((upload, watch))
+        })();
+        let (upload, watch) = sent.map_err(|e| Failure::new(Step::Send, e))?;
+
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+
+        let result = read_response_head(&mut server, timeouts.header, timeouts.read);
+        let upload = match upload {
+            // Without a response, the backend may have been cut off from a body that grew too
+            // large or stalled, which is then the failure to report
+            Some(upload) if !result.as_ref().is_ok_and(|head| !head.is_empty()) => {
+                match upload.finish() {
+                    Err(e) if e.kind() == io::ErrorKind::FileTooLarge || is_body_timeout(&e) => {
+                        return Err(Failure {
+                            step: Step::Send,
+                            error: e,
+                            watch,
+                        });
+                    }
+                    _ => None,
+                }
             }
-        })?;
-    } else {
-        forward.log_operation("forward_with_compression", || {
-            let mut modified_headers = headers.clone();
-            if supports_zstd {
-                modified_headers.retain(|(k, _)| k.to_lowercase() != "content-length");
-                modified_headers.push(("Content-Encoding".to_string(), "zstd".to_string()));
-                modified_headers.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+            upload => upload,
+        };
+        match result {
+            Ok(head) => Ok(Exchange {
+                server,
+                head,
+                _upload: upload,
+                _watch: watch,
+            }),
+            Err(error) => Err(Failure {
+                step: Step::Receive,
+                error,
+                watch,
+            }),
+        }
+    }
+}
+
+/// Answers the client after `forward` failed at `step` to send a response head: with 413 if the
+/// request body was too large and 408 if it stalled, with a stale copy if the shield has one,
+/// otherwise with 504 on timeouts and 502 when the backend refused the connection, closed it or
+/// answered with something other than HTTP.
+fn fail_exchange(
+    client: &mut ClientStream,
+    fetch: Option<&Fetch>,
+    forward: &BackendAddr,
+    step: Step,
+    e: io::Error,
+) -> io::Result<()> {
+    if e.kind() == io::ErrorKind::FileTooLarge {
+        log::debug!("Rejecting request: {}", e);
+        write_content_too_large(client)?;
+        return Err(e);
+    }
+    if is_body_timeout(&e) {
+        log::debug!("Rejecting request: {}", e);
+        let e = io::Error::new(io::ErrorKind::InvalidInput, BodyTimeout);
+        write_rejection(&*client, &e)?;
+        return Err(e);
+    }
+    if let Some(entry) = fetch.and_then(Fetch::stale_if_error) {
+        log::warn!("Backend {} failed, serving stale response: {}", forward, e);
+        return entry.write_to(client, "STALE");
+    }
+    if is_timeout(&e) {
+        match step {
+            Step::Connect => &BACKEND_CONNECT_TIMEOUTS,
+            Step::Send => &BACKEND_WRITE_TIMEOUTS,
+            Step::Receive if e.kind() == io::ErrorKind::TimedOut => &BACKEND_HEADER_TIMEOUTS,
+            Step::Receive => &BACKEND_READ_TIMEOUTS,
+        }
+        .increment();
+        log::warn!("Backend {} timed out: {}", forward, e);
+        write_gateway_timeout(client)?;
+        return Err(io::Error::new(io::ErrorKind::TimedOut, e));
+    }
+    match step {
+        // Errors while sending may come from reading the client's request body
+        Step::Send if e.kind() != io::ErrorKind::InvalidData => Err(e),
+        Step::Connect => {
+            write_bad_gateway(client)?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        _ => {
+            log::warn!("No valid response from backend {}: {}", forward, e);
+            write_bad_gateway(client)?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+/// Answers `request` from a shield entry, with the range it asks for if there is one.
+fn write_entry(
+    client: &mut ClientStream,
+    entry: &Entry,
+    status: &str,
+    request: &ForwardedRequest,
+) -> io::Result<()> {
+    match request.header("range") {
+        Some(range) => entry.write_range_to(client, status, range, request.header("if-range")),
+        None => entry.write_to(client, status),
+    }
+}
+
+/// Records in the capture of `request`, if any, how it was answered without a backend response.
+fn note(request: &ForwardedRequest, note: impl Into<String>) {
+    if let Some(captured) = &request.captured {
+        captured.note(note);
+    }
+}
+
+/// Methods that can be sent again without changing their effect, see RFC 9110 section 9.2.2.
+fn is_idempotent(method: &str) -> bool {
+    matches!(
+        method,
+        "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE"
+    )
+}
+
+/// Fetches a stale shield entry again while clients keep being served the stale copy.
+fn refresh(
+    key: String,
+    forward: &BackendAddr,
+    request_head: &[u8],
+    relay: &Relay,
+    policy: &ProxyPolicy,
+    stale: &Entry,
+) {
+    let Some(shield) = &policy.shield else {
+        return;
+    };
+    let timeouts = policy.timeouts;
+    let fetched = (|| {
+        let mut server = timeouts.connect(forward)?;
+        if policy.proxy_protocol {
+            proxy_protocol::write_header(&mut server, None)?;
+        }
+        server.write_all(request_head)?;
+        let response = ResponseHead::parse(read_response_head(
+            &mut server,
+            timeouts.header,
+            timeouts.read,
+        )?);
+        // Keep serving the stale copy rather than replacing it with an error
+        if response.status() >= 500 {
+            return Err(io::Error::other(response.status_line));
+        }
+        let freshness = Freshness::of_response(response.status(), &response.headers, &shield.key);
+        let Some(freshness) = freshness else {
+            return Ok(None);
+        };
+        let mut out = Capture::new(io::sink(), Some(shield.store.max_entry_size()));
+        let source = server.as_raw_fd();
+        relay.respond(
+            &mut out,
+            &mut server,
+            Some(source),
+            &response,
+            forward,
+            None,
+        )?;
+        Ok::<_, io::Error>(out.into_copy().map(|copy| (copy, freshness)))
+    })();
+
+    match fetched {
+        Ok(response) => {
+            log::debug!("Revalidated {}", key);
+            shield.store.replace(&key, response);
+        }
+        Err(e) => {
+            log::warn!("Failed to revalidate {}: {}", key, e);
+            stale.refresh_failed();
+        }
+    }
+}
+
+/// Informational responses that precede the final response. `101 Switching Protocols` is final.
+fn is_interim(status: u16) -> bool {
+    (100..200).contains(&status) && status != 101
+}
+
+/// A backend response head, raw and parsed.
+pub(super) struct ResponseHead {
+    raw: Vec<u8>,
+    pub(super) status_line: String,
+    /// Headers with lowercase names
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    pub(super) fn parse(raw: Vec<u8>) -> Self {
+        let text = String::from_utf8_lossy(&raw).to_string();
+        let (status_line, headers) = parse_response_headers(&text);
+        ResponseHead {
+            status_line: status_line.to_string(),
+            headers,
+            raw,
+        }
+    }
+
+    /// The head without the headers `allowed` leaves out.
+    fn restrict(self, allowed: &HeaderAllowList) -> Self {
+        ResponseHead::parse(allowed.filter_raw(&self.raw))
+    }
+
+    /// Adds the headers to the metrics, flagging unusual ones.
+    pub(super) fn record_headers(&self, uri: &str) {
+        header_stats::record(Direction::Response, uri, &self.headers);
+    }
+
+    /// Adds the head to the capture of the exchange, if it is captured.
+    pub(super) fn record_capture(&self, request: &ForwardedRequest) {
+        if let Some(captured) = &request.captured {
+            captured.set_response(&self.status_line, &self.headers);
+        }
+    }
+
+    /// Whether the response is a stream of events rather than a document, by its content type or
+    /// the `X-Accel-Buffering: no` header that asks proxies not to hold it back.
+    fn is_streaming(&self) -> bool {
+        let content_type = self.header("content-type").unwrap_or("");
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        STREAMING_TYPES
+            .iter()
+            .any(|streaming| mime.eq_ignore_ascii_case(streaming))
+            || self
+                .header("x-accel-buffering")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("no"))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The name of a header as the backend spelled it, if the backend sent it.
+    fn spelling(&self, name: &str) -> Option<&str> {
+        let text = std::str::from_utf8(&self.raw).ok()?;
+        text.split("\r\n")
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(k, _)| k.trim())
+            .find(|k| k.eq_ignore_ascii_case(name))
+    }
+
+    fn status(&self) -> u16 {
+        self.status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// How backend responses are turned into responses for the client.
+#[derive(Clone)]
+pub(super) struct Relay {
+    compression: CompressionType,
+    options: CompressionOptions,
+    flush_interval: Duration,
+    /// The request matched a bypass pattern
+    bypass: bool,
+    transcode_gzip: bool,
+    content_digest: bool,
+    /// The response to a HEAD request has no body, whatever its headers say
+    head_request: bool,
+    hint_headers: Vec<(String, String)>,
+    /// Backend headers forwarded in strict mode, or `None` to forward all of them
+    allowed_headers: Option<HeaderAllowList>,
+}
+
+impl Relay {
+    pub(super) fn for_request(
+        route: &RouteConfig,
+        policy: &ProxyPolicy,
+        request: &ForwardedRequest,
+    ) -> Self {
+        let accepted_compression = request.accepted_compression;
+
+        let compression = if let Some(forced) = request.forced_encoding {
+            log::debug!("Encoding pinned to {}", forced);
+            forced
+        } else {
+            accepted_compression.best()
+        };
+
+        Relay {
+            compression,
+            options: route
+                .compression
+                .options_for(&ClientHints::from_headers(&request.headers)),
+            flush_interval: route.compression.flush_interval,
+            bypass: route.compression.bypasses(&request.uri)
+                || route.compression.guards_secrets(&request.headers),
+            transcode_gzip: route.compression.transcode_gzip,
+            content_digest: route.compression.content_digest,
+            head_request: request.method.eq_ignore_ascii_case("HEAD"),
+            hint_headers: route.client_hints.response_headers(),
+            allowed_headers: policy.allowed_response_headers.clone(),
+        }
+    }
+
+    /// The response head as it may reach the client: in strict mode, with only the allowed
+    /// headers left.
+    pub(super) fn restrict(&self, response: ResponseHead) -> ResponseHead {
+        match &self.allowed_headers {
+            Some(allowed) => response.restrict(allowed),
+            None => response,
+        }
+    }
+
+    /// Writes the backend's response head unchanged, apart from the client hint headers.
+    fn write_head_as_is<W: Write>(&self, out: &mut W, response: &ResponseHead) -> io::Result<()> {
+        if self.hint_headers.is_empty() {
+            out.write_all(&response.raw)
+        } else {
+            out.write_all(&append_raw_headers(&response.raw, &self.hint_headers))
+        }
+    }
+
+    /// Writes the response head for a body compressed with `self.compression`: without the
+    /// backend's length and encoding, which describe the original body, and announcing only the
+    /// trailers that are forwarded after the compressed one. A strong `ETag` is made weak, as it
+    /// no longer names the bytes sent; every other header is forwarded with its value unchanged,
+    /// in particular the freshness information (`Last-Modified`, `Age`, `Expires`,
+    /// `Cache-Control`) that clients and caches rely on.
+    fn write_compressed_head<W: Write>(
+        &self,
+        out: &mut W,
+        response: &ResponseHead,
+    ) -> io::Result<()> {
+        let compression = self.compression;
+        let mut modified_headers = response.headers.clone();
+        if compression != CompressionType::None {
+            let mut trailers: Vec<&str> = response
+                .headers
+                .iter()
+                .filter(|(k, _)| k == "trailer")
+                .flat_map(|(_, v)| v.split(','))
+                .map(str::trim)
+                .filter(|name| !name.is_empty() && survives_compression(name))
+                .collect();
+            if self.content_digest {
+                trailers.push("Content-Digest");
+            }
+            let trailers = trailers.join(", ");
+            modified_headers.retain(|(k, _)| !BODY_FRAMING_HEADERS.contains(&k.as_str()));
+            weaken_etag(&mut modified_headers);
+            modified_headers.push(("Content-Encoding".to_string(), compression.to_string()));
+            context::record_encoding(compression);
+            modified_headers.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+            if !trailers.is_empty() {
+                modified_headers.push(("Trailer".to_string(), trailers));
             }
+            append_vary(&mut modified_headers, "Accept-Encoding");
+        }
+        self.write_head(out, response, modified_headers)
+    }
+
+    /// Writes the head of the backend's representation for HEAD requests whose GET would be
+    /// compressed on the fly. The compressed length is only known once the body is compressed, so
+    /// the backend's own length and encoding are kept, and `Vary` says that GET may differ.
+    fn write_uncompressed_head<W: Write>(
+        &self,
+        out: &mut W,
+        response: &ResponseHead,
+    ) -> io::Result<()> {
+        let mut headers = response.headers.clone();
+        append_vary(&mut headers, "Accept-Encoding");
+        self.write_head(out, response, headers)
+    }
+
+    /// Writes the head of a 304, which stands for the 200 the client has stored. When that 200
+    /// would have been compressed here, its length and framing were not the backend's, so those
+    /// the backend describes its own body with are left out, and `Vary` matches the 200's. A 304
+    /// carrying `Content-Encoding` is for a response the backend compressed itself, which passes
+    /// through unchanged, and so does the 304.
+    fn write_not_modified_head<W: Write>(
+        &self,
+        out: &mut W,
+        response: &ResponseHead,
+    ) -> io::Result<()> {
+        let compressed_here = self.compression != CompressionType::None
+            && !self.bypass
+            && response.header("content-encoding").is_none();
+        if !compressed_here {
+            return self.write_head_as_is(out, response);
+        }
+        let mut headers = response.headers.clone();
+        headers.retain(|(k, _)| !BODY_FRAMING_HEADERS.contains(&k.as_str()));
+        weaken_etag(&mut headers);
+        append_vary(&mut headers, "Accept-Encoding");
+        self.write_head(out, response, headers)
+    }
+
+    /// Writes `headers` after the status line of `response`, with the names of the backend's
+    /// headers spelled as the backend did.
+    fn write_head<W: Write>(
+        &self,
+        out: &mut W,
+        response: &ResponseHead,
+        mut headers: Vec<(String, String)>,
+    ) -> io::Result<()> {
+        headers.extend(self.hint_headers.iter().cloned());
+        out.write_all(format!("{}\r\n", response.status_line).as_bytes())?;
+        for (key, value) in &headers {
+            let key = response.spelling(key).unwrap_or(key);
+            out.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
+        }
+        out.write_all(b"\r\n")
+    }
+
+    /// Writes the response to `out`, reading its body from `server`. `source` is the socket
+    /// `server` reads from, if it buffers nothing, for compressed output to be flushed while the
+    /// backend is quiet.
+    pub(super) fn respond<W: Write, R: Read>(
+        &self,
+        out: &mut W,
+        server: &mut R,
+        source: Option<RawFd>,
+        response: &ResponseHead,
+        forward: &dyn Loggable,
+        truncate_at: Option<f64>,
+    ) -> io::Result<()> {
+        let compression = self.compression;
+        let status = response.status();
+
+        // Bodyless responses end with their head; reading on would wait for the backend to close
+        if status == 304 {
+            log::debug!("Response is 304 Not Modified, forwarding head only");
+            self.write_not_modified_head(out, response)?;
+            return out.flush();
+        }
+        if matches!(status, 100..=199 | 204) {
+            log::debug!("Response has no body, forwarding head only");
+            self.write_head_as_is(out, response)?;
+            return out.flush();
+        }
+
+        // Check compression and encoding properties
+        let current_encoding = response
+            .header("content-encoding")
+            .map(|v| v.to_lowercase());
+
+        let is_already_compressed = current_encoding.is_some();
+        let is_transcoded = self.transcode_gzip
+            && compression == CompressionType::Zstd
+            && current_encoding.as_deref().is_some_and(is_transcodable);
+        let content_type = response.header("content-type").unwrap_or("");
+        let is_chunked = response
+            .header("transfer-encoding")
+            .is_some_and(|v| v.to_lowercase().contains("chunked"));
 
-            // Send modified headers
-            client.write_all(format!("{}\r\n", status_line).as_bytes())?;
-            for (key, value) in &modified_headers {
-                client.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
+        let content_length = response
+            .header("content-length")
+            .and_then(|v| v.parse::<usize>().ok());
+
+        log::debug!(
+            "Response properties - compressed: {}, transcoded: {}, chunked: {}, length: {:?}",
+            is_already_compressed,
+            is_transcoded,
+            is_chunked,
+            content_length
+        );
+
+        let truncate_limit = truncate_at.map(|at| {
+            let length = content_length.unwrap_or(CHAOS_TRUNCATE_UNKNOWN_LENGTH);
+            (at * length as f64) as u64
+        });
+
+        // A compressed range would not be the requested range of the representation
+        let is_partial = status == 206;
+        // gRPC compresses its messages itself, and its status comes in trailers
+        let passes_through = (is_already_compressed && !is_transcoded)
+            || self.bypass
+            || is_partial
+            || grpc::is_grpc(content_type);
+
+        // Answer HEAD without compressing anything, with the length the backend sent
+        if self.head_request {
+            log::debug!("Response to HEAD has no body, forwarding head only");
+            if passes_through {
+                self.write_head_as_is(out, response)?;
+            } else if compression == CompressionType::None {
+                self.write_compressed_head(out, response)?;
+            } else {
+                self.write_uncompressed_head(out, response)?;
             }
-            client.write_all(b"\r\n")?;
-
-            if supports_zstd {
-                let mut encoder = ZstdEncoder::new(Vec::new(), zstd_level)?;
-                if is_chunked {
-                    forward_chunked_body(&mut server.try_clone()?, &mut encoder)?;
-                } else if let Some(length) = content_length {
-                    io::copy(&mut server.take(length as u64), &mut encoder)?;
-                } else {
-                    io::copy(&mut server, &mut encoder)?;
-                }
+            return out.flush();
+        }
 
-                let compressed = encoder.finish()?;
-                log::debug!("Compressed response to {} bytes", compressed.len());
+        let body = if is_chunked {
+            Body::Chunked(Box::new(server))
+        } else {
+            Body::Stream {
+                reader: Box::new(server),
+                length: content_length.map(|length| length as u64),
+            }
+        };
+        if passes_through {
+            forward.log_operation("forward_compressed", || {
+                // Forward headers and body as-is
+                self.write_head_as_is(out, response)?;
+                body.copy_to(&mut TruncatingWriter::new(&mut *out, truncate_limit))
+            })
+        } else {
+            forward.log_operation("forward_with_compression", || {
+                self.write_compressed_head(out, response)?;
 
-                let mut chunked_writer = BufWriter::new(&mut client);
-                for chunk in compressed.chunks(8192) {
-                    write!(chunked_writer, "{:X}\r\n", chunk.len())?;
-                    chunked_writer.write_all(chunk)?;
-                    write!(chunked_writer, "\r\n")?;
+                let mut body_out = TruncatingWriter::new(&mut *out, truncate_limit);
+                if compression == CompressionType::None {
+                    return body.copy_to(&mut body_out);
                 }
-                write!(chunked_writer, "0\r\n\r\n")?;
-                chunked_writer.flush()
-            } else {
-                if is_chunked {
-                    forward_chunked_body(&mut server.try_clone()?, &mut client)
-                } else if let Some(length) = content_length {
-                    io::copy(&mut server.take(length as u64), &mut client)?;
-                    Ok(())
+                // Events are flushed as they come, rather than held until the next one
+                let flush_interval = if response.is_streaming() {
+                    log::debug!("Streaming response, flushing every write");
+                    Duration::ZERO
                 } else {
-                    io::copy(&mut server, &mut client)?;
-                    Ok(())
-                }
-            }
-        })?;
+                    self.flush_interval
+                };
+                let encoding = Encoding {
+                    compression,
+                    options: &self.options,
+                    content_encoding: current_encoding.as_deref(),
+                    content_digest: self.content_digest,
+                    flush_interval: Some(flush_interval),
+                    source,
+                    mime_type: content_type,
+                };
+                body.encode(&mut body_out, &encoding)?;
+                log::debug!("Finished streaming compressed response");
+                Ok(())
+            })
+        }
     }
+}
 
-    log::debug!("← Completed proxy request in {:?}", start_time.elapsed());
+/// Reads the request head so the client sees the injected error rather than a reset.
+fn write_chaos_error(
+    client: &mut ClientStream,
+    limits: &RequestLimits,
+    status: u16,
+) -> io::Result<()> {
+    let mut buf_reader = BufReader::new(&*client);
+    let mut header_lines = HeaderLines::new(limits);
+    while header_lines.next(&mut buf_reader)?.is_some() {}
 
-    Ok(())
+    client.write_all(format!("HTTP/1.1 {} Chaos\r\n", status).as_bytes())?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 15\r\n")?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Injected fault\n")
+}
+
+pub(super) fn write_bad_gateway(client: &mut ClientStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n")?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 11\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Bad Gateway")
+}
+
+pub(super) fn write_content_too_large(client: &mut ClientStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 413 Content Too Large\r\n")?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 17\r\n")?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Content Too Large")
+}
+
+pub(super) fn write_gateway_timeout(client: &mut ClientStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 504 Gateway Timeout\r\n")?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 15\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Gateway Timeout")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(compression: CompressionType) -> Relay {
+        Relay {
+            compression,
+            options: CompressionOptions {
+                zstd: 3,
+                brotli: 5,
+                gzip: 6,
+                zstd_workers: 0,
+                zstd_long: false,
+                zstd_window_log: None,
+                zstd_cpu_budget: None,
+                padding: None,
+            },
+            flush_interval: Duration::from_millis(100),
+            bypass: false,
+            transcode_gzip: false,
+            content_digest: false,
+            head_request: false,
+            hint_headers: Vec::new(),
+            allowed_headers: None,
+        }
+    }
+
+    fn not_modified(relay: &Relay, head: &str) -> Vec<String> {
+        let response = ResponseHead::parse(format!("{}\r\n\r\n", head).into_bytes());
+        let mut out = Vec::new();
+        relay.write_not_modified_head(&mut out, &response).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let head = out
+            .strip_suffix("\r\n\r\n")
+            .expect("head ends the response");
+        head.split("\r\n").map(str::to_string).collect()
+    }
+
+    const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\
+        Cache-Control: max-age=60\r\nVary: Cookie\r\nContent-Location: /a.json\r\n\
+        Content-Length: 120\r\nTransfer-Encoding: chunked\r\nTrailer: Server-Timing";
+
+    #[test]
+    fn not_modified_for_a_compressed_response_keeps_its_metadata() {
+        let lines = not_modified(&relay(CompressionType::Zstd), NOT_MODIFIED);
+        assert_eq!(
+            lines,
+            [
+                "HTTP/1.1 304 Not Modified",
+                "ETag: W/\"v1\"",
+                "Cache-Control: max-age=60",
+                "Vary: Cookie, Accept-Encoding",
+                "Content-Location: /a.json",
+            ]
+        );
+    }
+
+    #[test]
+    fn not_modified_passes_through_when_not_compressed_here() {
+        let expected: Vec<String> = NOT_MODIFIED.split("\r\n").map(str::to_string).collect();
+        assert_eq!(
+            not_modified(&relay(CompressionType::None), NOT_MODIFIED),
+            expected
+        );
+
+        let mut bypassed = relay(CompressionType::Zstd);
+        bypassed.bypass = true;
+        assert_eq!(not_modified(&bypassed, NOT_MODIFIED), expected);
+
+        let encoded = format!("{}\r\nContent-Encoding: br", NOT_MODIFIED);
+        let expected: Vec<String> = encoded.split("\r\n").map(str::to_string).collect();
+        assert_eq!(
+            not_modified(&relay(CompressionType::Zstd), &encoded),
+            expected
+        );
+    }
+
+    fn head_response(relay: &Relay, head: &str) -> Vec<String> {
+        let response = ResponseHead::parse(format!("{}\r\n\r\n", head).into_bytes());
+        let mut out = Vec::new();
+        let mut server: &[u8] = b"body that HEAD never reads";
+        relay
+            .respond(&mut out, &mut server, None, &response, &"backend", None)
+            .unwrap();
+        assert_eq!(server, b"body that HEAD never reads");
+        let out = String::from_utf8(out).unwrap();
+        let head = out
+            .strip_suffix("\r\n\r\n")
+            .expect("head ends the response");
+        head.split("\r\n").map(str::to_string).collect()
+    }
+
+    #[test]
+    fn head_over_a_response_compressed_on_the_fly_keeps_the_backend_length() {
+        let mut relay = relay(CompressionType::Zstd);
+        relay.head_request = true;
+        let lines = head_response(
+            &relay,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 1234\r\nETag: \"v1\"",
+        );
+        assert_eq!(
+            lines,
+            [
+                "HTTP/1.1 200 OK",
+                "Content-Type: text/html",
+                "Content-Length: 1234",
+                "ETag: \"v1\"",
+                "Vary: Accept-Encoding",
+            ]
+        );
+    }
+
+    #[test]
+    fn head_over_an_encoded_response_is_relayed_as_is() {
+        let mut relay = relay(CompressionType::Zstd);
+        relay.head_request = true;
+        let head = "HTTP/1.1 200 OK\r\nContent-Encoding: br\r\nContent-Length: 99";
+        let expected: Vec<String> = head.split("\r\n").map(str::to_string).collect();
+        assert_eq!(head_response(&relay, head), expected);
+    }
 }
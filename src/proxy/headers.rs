@@ -24,3 +24,168 @@ pub fn parse_response_headers(headers: &str) -> (&str, Vec<(String, String)>) {
 
     (status_line, headers)
 }
+
+/// Adds `value` to the `Vary` header, creating it if the backend did not send one.
+pub fn append_vary(headers: &mut Vec<(String, String)>, value: &str) {
+    match headers
+        .iter_mut()
+        .find(|(k, _)| k.eq_ignore_ascii_case("vary"))
+    {
+        Some((_, existing)) => {
+            let already_listed = existing
+                .split(',')
+                .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(value));
+            if !already_listed {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+        }
+        None => headers.push(("Vary".to_string(), value.to_string())),
+    }
+}
+
+/// Makes a strong `ETag` weak, for a body compressed on the fly: the same representation may be
+/// compressed into other bytes another time, with other settings, so the tag can no longer vouch
+/// for the bytes, which `If-Range` relies on.
+pub fn weaken_etag(headers: &mut [(String, String)]) {
+    for (_, value) in headers
+        .iter_mut()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("etag"))
+    {
+        if value.starts_with('"') {
+            *value = format!("W/{}", value);
+        }
+    }
+}
+
+/// Appends headers to a raw response head that ends in an empty line.
+pub fn append_raw_headers(raw: &[u8], extra: &[(String, String)]) -> Vec<u8> {
+    let head = raw.strip_suffix(b"\r\n").unwrap_or(raw);
+    let mut result = head.to_vec();
+    for (key, value) in extra {
+        result.extend_from_slice(format!("{}: {}\r\n", key, value).as_bytes());
+    }
+    result.extend_from_slice(b"\r\n");
+    result
+}
+
+/// Headers that describe the backend's body as sent: they are replaced when the body is
+/// compressed, and always forwarded when it is not.
+pub const BODY_FRAMING_HEADERS: [&str; 4] = [
+    "content-length",
+    "transfer-encoding",
+    "content-encoding",
+    "trailer",
+];
+
+/// Trailer fields that describe the backend's body as encoded, such as its digest: they no longer
+/// hold once the body is compressed, and are dropped then. Other trailers, like gRPC's status,
+/// are forwarded after the compressed body.
+pub const ENCODED_BODY_TRAILERS: [&str; 4] =
+    ["content-digest", "content-md5", "digest", "repr-digest"];
+
+/// Whether a trailer field is forwarded after a body zstdp compresses.
+pub fn survives_compression(name: &str) -> bool {
+    !ENCODED_BODY_TRAILERS
+        .iter()
+        .chain(&BODY_FRAMING_HEADERS)
+        .any(|dropped| name.eq_ignore_ascii_case(dropped))
+}
+
+/// Backend response headers that strict mode forwards unless told otherwise: those that
+/// describe the content, its caching and its security policies.
+const DEFAULT_ALLOWED_HEADERS: &[&str] = &[
+    "accept-ranges",
+    "access-control-allow-credentials",
+    "access-control-allow-headers",
+    "access-control-allow-methods",
+    "access-control-allow-origin",
+    "access-control-expose-headers",
+    "access-control-max-age",
+    "age",
+    "allow",
+    "cache-control",
+    "content-disposition",
+    "content-language",
+    "content-location",
+    "content-range",
+    "content-security-policy",
+    "content-type",
+    "cross-origin-embedder-policy",
+    "cross-origin-opener-policy",
+    "cross-origin-resource-policy",
+    "date",
+    "etag",
+    "expires",
+    "last-modified",
+    "link",
+    "location",
+    "permissions-policy",
+    "referrer-policy",
+    "retry-after",
+    "set-cookie",
+    "strict-transport-security",
+    "vary",
+    "www-authenticate",
+    "x-content-type-options",
+    "x-frame-options",
+];
+
+/// The backend response headers forwarded to clients in strict mode, so that internals such as
+/// `X-Backend-Server` or `X-Runtime` never leave the edge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderAllowList {
+    /// Lowercase names
+    names: Vec<String>,
+}
+
+impl HeaderAllowList {
+    /// The default headers and `extra`.
+    pub fn new(extra: &[String]) -> Self {
+        let names = DEFAULT_ALLOWED_HEADERS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(extra.iter().map(|name| name.trim().to_lowercase()))
+            .filter(|name| !name.is_empty())
+            .collect();
+        HeaderAllowList { names }
+    }
+
+    /// Whether the header `name` is forwarded. The body framing headers always are.
+    pub fn allows(&self, name: &str) -> bool {
+        let name = name.trim();
+        BODY_FRAMING_HEADERS
+            .iter()
+            .any(|framing| framing.eq_ignore_ascii_case(name))
+            || self
+                .names
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }
+
+    /// A raw response head with only the allowed header lines left.
+    pub fn filter_raw(&self, raw: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(raw);
+        let mut lines = text.lines();
+        let mut result = lines.next().unwrap_or("").to_string();
+        result.push_str("\r\n");
+        let mut keep = false;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            // Folded lines continue the header before them
+            if !line.starts_with([' ', '\t']) {
+                keep = line
+                    .split_once(':')
+                    .is_some_and(|(name, _)| self.allows(name));
+                if !keep {
+                    debug!("Dropping response header: {}", line);
+                }
+            }
+            if keep {
+                result.push_str(line);
+                result.push_str("\r\n");
+            }
+        }
+        result.push_str("\r\n");
+        result.into_bytes()
+    }
+}
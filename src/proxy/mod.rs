@@ -1,7 +1,111 @@
-pub mod handlers;
+mod abort;
+pub(crate) mod auth;
+pub mod backend;
+pub mod balancer;
+pub(crate) mod cache_key;
+pub(crate) mod connect;
+pub(crate) mod fastcgi;
+pub(crate) mod forward_auth;
+pub(crate) mod grpc;
+pub(crate) mod handlers;
 pub mod headers;
+pub(crate) mod proxy_protocol;
+pub(crate) mod remote_cache;
+pub(crate) mod shield;
+pub(crate) mod token_auth;
 pub mod transfer;
 
+use std::fmt;
 use std::io::{self, Read, Write};
 use std::net::TcpStream;
-use zstd::stream::write::Encoder as ZstdEncoder;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::client::ClientStream;
+use backend::BackendAddr;
+use transfer::ForwardedRequest;
+
+/// Bounds on how long the backend may take to accept a connection, take the request and respond.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BackendTimeouts {
+    /// Time allowed to establish the connection
+    pub connect: Option<Duration>,
+    /// Time allowed for each write of the request
+    pub write: Option<Duration>,
+    /// Time allowed until the first byte of the response head
+    pub header: Option<Duration>,
+    /// Time allowed for each read after that
+    pub read: Option<Duration>,
+}
+
+impl BackendTimeouts {
+    /// Connects to `addr` within the connect timeout, with writes bounded by the write timeout.
+    pub fn connect(&self, addr: &BackendAddr) -> io::Result<TcpStream> {
+        let stream = match self.connect {
+            Some(timeout) => addr.connect_timeout(timeout)?,
+            None => addr.connect()?,
+        };
+        stream.set_write_timeout(self.write)?;
+        Ok(stream)
+    }
+
+    /// How long the backend may take to connect and start responding, if both are bounded.
+    pub fn until_response(&self) -> Option<Duration> {
+        Some(self.connect? + self.header.or(self.read)?)
+    }
+}
+
+/// The `Host` header backends are sent, given as `preserve`, `backend` or a host name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum HostRewrite {
+    /// The client's, or the backend address for clients that sent none
+    #[default]
+    Preserve,
+    /// The address of the backend, for name-based virtual hosts that expect their own name
+    Backend,
+    /// A fixed host, with an optional port
+    Custom(String),
+}
+
+impl HostRewrite {
+    /// The `Host` to send `request` to `backend` with.
+    pub fn host_for(&self, request: &ForwardedRequest, backend: &BackendAddr) -> String {
+        match self {
+            HostRewrite::Preserve => request
+                .host
+                .clone()
+                .unwrap_or_else(|| backend.host_header()),
+            HostRewrite::Backend => backend.host_header(),
+            HostRewrite::Custom(host) => host.clone(),
+        }
+    }
+}
+
+impl FromStr for HostRewrite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(HostRewrite::Preserve),
+            "backend" => Ok(HostRewrite::Backend),
+            "" => Err("Expected preserve, backend or a host name".to_string()),
+            host if host
+                .bytes()
+                .any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) =>
+            {
+                Err(format!("Invalid host '{}'", host))
+            }
+            host => Ok(HostRewrite::Custom(host.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for HostRewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostRewrite::Preserve => write!(f, "preserve"),
+            HostRewrite::Backend => write!(f, "backend"),
+            HostRewrite::Custom(host) => write!(f, "{}", host),
+        }
+    }
+}
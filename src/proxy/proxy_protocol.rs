@@ -0,0 +1,68 @@
+//! PROXY protocol version 2 headers, sent first on backend connections so that a backend which
+//! understands them sees the address of the client rather than that of zstdp.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::client::ClientStream;
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, relaying a connection on behalf of a client
+const PROXY: u8 = 0x21;
+/// Version 2, a connection zstdp makes on its own, such as a background revalidation
+const LOCAL: u8 = 0x20;
+const UNSPEC: u8 = 0x00;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Writes the header for a connection made on behalf of `client` to `server`. Connections made
+/// without a client, or for one on a Unix socket, carry no addresses.
+pub fn write_header<W: Write>(server: &mut W, client: Option<&ClientStream>) -> io::Result<()> {
+    let addresses = match client {
+        Some(client) if client.as_tcp().is_some() => {
+            Some((client.peer_addr()?, client.local_addr()?))
+        }
+        _ => None,
+    };
+    server.write_all(&header(addresses))
+}
+
+/// The header for a connection from `source` to `destination`, or a local one without addresses.
+fn header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    let Some((source, destination)) = addresses else {
+        header.extend_from_slice(&[LOCAL, UNSPEC, 0, 0]);
+        return header;
+    };
+
+    let mut block = Vec::with_capacity(36);
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            block.extend_from_slice(&source.octets());
+            block.extend_from_slice(&destination.octets());
+            TCP_OVER_IPV4
+        }
+        // Both addresses must be of the same family, so IPv4 ones are mapped into IPv6
+        (source, destination) => {
+            block.extend_from_slice(&ipv6_octets(source));
+            block.extend_from_slice(&ipv6_octets(destination));
+            TCP_OVER_IPV6
+        }
+    };
+    block.extend_from_slice(&source.port().to_be_bytes());
+    block.extend_from_slice(&destination.port().to_be_bytes());
+
+    header.extend_from_slice(&[PROXY, family]);
+    header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+    header.extend_from_slice(&block);
+    header
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::Duration;
+
+use percent_encoding::percent_decode_str;
+
+use super::backend::BackendAddr;
+
+/// Time allowed for connecting to the remote cache and for each read or write. A slow remote
+/// cache is treated as a miss rather than holding up requests.
+const TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A cache shared by several zstdp instances, given as `redis://[:password@]host:port[/db]` or
+/// `memcached://host:port`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCache {
+    Redis {
+        addr: BackendAddr,
+        password: Option<String>,
+        db: u32,
+    },
+    Memcached {
+        addr: BackendAddr,
+    },
+}
+
+impl FromStr for RemoteCache {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").ok_or_else(|| {
+            format!(
+                "Expected redis://host:port or memcached://host:port, got '{}'",
+                s
+            )
+        })?;
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, path),
+            None => (rest, ""),
+        };
+        let (userinfo, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, authority),
+        };
+        let addr = host.parse::<BackendAddr>()?;
+
+        match scheme {
+            "redis" => {
+                // Only the password part of `user:password` is used
+                let password = userinfo
+                    .map(|userinfo| userinfo.split_once(':').map_or(userinfo, |(_, p)| p))
+                    .filter(|password| !password.is_empty())
+                    .map(|password| {
+                        percent_decode_str(password)
+                            .decode_utf8_lossy()
+                            .into_owned()
+                    });
+                let db = match path {
+                    "" => 0,
+                    db => db
+                        .parse::<u32>()
+                        .map_err(|e| format!("Invalid Redis database '{}': {}", db, e))?,
+                };
+                Ok(RemoteCache::Redis { addr, password, db })
+            }
+            "memcached" => {
+                if userinfo.is_some() || !path.is_empty() {
+                    return Err(format!("Expected memcached://host:port, got '{}'", s));
+                }
+                Ok(RemoteCache::Memcached { addr })
+            }
+            _ => Err(format!("Unsupported remote cache scheme '{}'", scheme)),
+        }
+    }
+}
+
+impl fmt::Display for RemoteCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteCache::Redis { addr, db, .. } => write!(f, "redis://{}/{}", addr, db),
+            RemoteCache::Memcached { addr } => write!(f, "memcached://{}", addr),
+        }
+    }
+}
+
+impl RemoteCache {
+    /// The value stored under `key`, if there is one. `key` must not contain whitespace.
+    pub fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut conn = self.connect()?;
+        match self {
+            RemoteCache::Redis { .. } => {
+                conn.get_mut()
+                    .write_all(&resp_command(&[b"GET", key.as_bytes()]))?;
+                read_resp_bulk(&mut conn)
+            }
+            RemoteCache::Memcached { .. } => {
+                conn.get_mut()
+                    .write_all(format!("get {}\r\n", key).as_bytes())?;
+                let line = read_line(&mut conn)?;
+                if line == "END" {
+                    return Ok(None);
+                }
+                // VALUE <key> <flags> <bytes>
+                let len = line
+                    .strip_prefix("VALUE ")
+                    .and_then(|rest| rest.split(' ').nth(2))
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or_else(|| invalid(format!("Unexpected memcached reply '{}'", line)))?;
+                let value = read_exact_crlf(&mut conn, len)?;
+                match read_line(&mut conn)?.as_str() {
+                    "END" => Ok(Some(value)),
+                    line => Err(invalid(format!("Unexpected memcached reply '{}'", line))),
+                }
+            }
+        }
+    }
+
+    /// Stores `value` under `key` for `ttl`, which is rounded up to whole seconds for memcached.
+    pub fn set(&self, key: &str, value: &[u8], ttl: Duration) -> io::Result<()> {
+        let mut conn = self.connect()?;
+        match self {
+            RemoteCache::Redis { .. } => {
+                let ttl = ttl.as_millis().max(1).to_string();
+                conn.get_mut().write_all(&resp_command(&[
+                    b"SET",
+                    key.as_bytes(),
+                    value,
+                    b"PX",
+                    ttl.as_bytes(),
+                ]))?;
+                read_resp_ok(&mut conn)
+            }
+            RemoteCache::Memcached { .. } => {
+                // Expiry times over 30 days would be read as Unix timestamps
+                let ttl = ttl.as_secs_f64().ceil().clamp(1.0, 30.0 * 24.0 * 3600.0) as u64;
+                let mut command = format!("set {} 0 {} {}\r\n", key, ttl, value.len()).into_bytes();
+                command.extend_from_slice(value);
+                command.extend_from_slice(b"\r\n");
+                conn.get_mut().write_all(&command)?;
+                match read_line(&mut conn)?.as_str() {
+                    "STORED" => Ok(()),
+                    line => Err(io::Error::other(format!("memcached: {}", line))),
+                }
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let addr = match self {
+            RemoteCache::Redis { addr, .. } | RemoteCache::Memcached { addr } => addr,
+        };
+        let stream = addr.connect_timeout(TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut conn = BufReader::new(stream);
+
+        if let RemoteCache::Redis { password, db, .. } = self {
+            if let Some(password) = password {
+                conn.get_mut()
+                    .write_all(&resp_command(&[b"AUTH", password.as_bytes()]))?;
+                read_resp_ok(&mut conn)?;
+            }
+            if *db != 0 {
+                conn.get_mut()
+                    .write_all(&resp_command(&[b"SELECT", db.to_string().as_bytes()]))?;
+                read_resp_ok(&mut conn)?;
+            }
+        }
+        Ok(conn)
+    }
+}
+
+/// Encodes a Redis command as an array of bulk strings.
+fn resp_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+fn read_resp_ok(conn: &mut impl BufRead) -> io::Result<()> {
+    let line = read_line(conn)?;
+    match line.strip_prefix('-') {
+        Some(error) => Err(io::Error::other(format!("Redis: {}", error))),
+        None if line.starts_with('+') => Ok(()),
+        None => Err(invalid(format!("Unexpected Redis reply '{}'", line))),
+    }
+}
+
+fn read_resp_bulk(conn: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let line = read_line(conn)?;
+    if let Some(error) = line.strip_prefix('-') {
+        return Err(io::Error::other(format!("Redis: {}", error)));
+    }
+    let len = line
+        .strip_prefix('$')
+        .and_then(|len| len.parse::<i64>().ok())
+        .ok_or_else(|| invalid(format!("Unexpected Redis reply '{}'", line)))?;
+    if len < 0 {
+        return Ok(None);
+    }
+    read_exact_crlf(conn, len as usize).map(Some)
+}
+
+fn read_line(conn: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    if conn.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Remote cache closed the connection",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Reads `len` bytes followed by CRLF.
+fn read_exact_crlf(conn: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut value = vec![0; len + 2];
+    conn.read_exact(&mut value)?;
+    if !value.ends_with(b"\r\n") {
+        return Err(invalid("Remote cache value is not followed by CRLF"));
+    }
+    value.truncate(len);
+    Ok(value)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
@@ -0,0 +1,1012 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::compression::CompressionType;
+use crate::file_serving::conditional::Validators;
+use crate::file_serving::range::{parse_range, RangeRequest};
+
+use super::balancer::Backends;
+use super::cache_key::{CacheKeyTemplate, KeyedRequest};
+use super::headers::append_raw_headers;
+use super::remote_cache::RemoteCache;
+use super::transfer::{decode_chunked_body, ForwardedRequest};
+
+/// Largest response the shield stores, or its whole size if that is smaller; bigger ones are
+/// streamed through uncached.
+const MAX_ENTRY_SIZE: usize = 4 * 1024 * 1024;
+
+/// How long requests for a key whose last response could not be stored skip waiting on each
+/// other and go straight to the backend.
+const HIT_FOR_PASS: Duration = Duration::from_secs(30);
+
+/// How long requests wait for another one fetching the same key when the backend timeouts do not
+/// bound the fetch.
+const COLLAPSED_WAIT: Duration = Duration::from_secs(60);
+
+/// Version of the layout of entries in the remote cache, part of their keys.
+const REMOTE_FORMAT: u8 = 1;
+
+/// Responses stored for the routes whose `ProxyPolicy` has a shield, which they share.
+pub struct Shield {
+    cache: Mutex<Cache>,
+    /// Signalled whenever a fetch that other requests may be waiting on ends
+    fetch_done: Condvar,
+    /// Cache shared with other instances, consulted on local misses
+    remote: Option<RemoteCache>,
+    /// Whether the `Age` of replayed responses counts the time they spent in the shield
+    count_age: bool,
+    /// Largest response stored
+    max_entry_size: usize,
+}
+
+/// How a route uses the shield: the store its responses go to and what they are keyed on.
+#[derive(Clone)]
+pub struct ShieldPolicy {
+    pub store: Arc<Shield>,
+    pub key: CacheKeyTemplate,
+}
+
+struct Cache {
+    slots: HashMap<String, Slot>,
+    /// Size of the `Ready` entries
+    size: usize,
+    /// Most the `Ready` entries may take, in bytes
+    max_size: usize,
+}
+
+enum Slot {
+    Ready(Arc<Entry>),
+    /// A request is fetching this key from the backend and the others wait for it
+    Fetching,
+    /// The last response was not cacheable
+    Pass {
+        until: Instant,
+    },
+}
+
+impl Cache {
+    fn new(max_size: usize) -> Self {
+        Cache {
+            slots: HashMap::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn insert(&mut self, key: &str, slot: Slot) {
+        if let Slot::Ready(entry) = &slot {
+            self.size += entry.size();
+        }
+        if let Some(Slot::Ready(old)) = self.slots.insert(key.to_string(), slot) {
+            self.size -= old.size();
+        }
+        self.evict(key);
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(Slot::Ready(old)) = self.slots.remove(key) {
+            self.size -= old.size();
+        }
+    }
+
+    /// Drops the oldest entries other than `keep` until the cache fits its size limit.
+    fn evict(&mut self, keep: &str) {
+        while self.size > self.max_size {
+            let oldest = self
+                .slots
+                .iter()
+                .filter_map(|(key, slot)| match slot {
+                    Slot::Ready(entry) if key != keep => Some((key, entry.stored)),
+                    _ => None,
+                })
+                .min_by_key(|(_, stored)| *stored)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => self.remove(&key),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Shield {
+    /// A shield keeping up to `max_size` bytes of responses, and sharing them with other
+    /// instances through `remote` if given, so that each response is fetched and compressed once
+    /// for all of them. Unless `count_age` is set, replayed responses carry the backend's `Age`
+    /// unchanged instead of one that adds the time spent in the shield.
+    pub fn new(max_size: usize, count_age: bool, remote: Option<RemoteCache>) -> Self {
+        log::info!("Origin shield enabled ({} bytes)", max_size);
+        if let Some(remote) = &remote {
+            log::info!("Origin shield shared through {}", remote);
+        }
+        Shield {
+            cache: Mutex::new(Cache::new(max_size)),
+            fetch_done: Condvar::new(),
+            remote,
+            count_age,
+            max_entry_size: MAX_ENTRY_SIZE.min(max_size),
+        }
+    }
+
+    /// Largest response the shield stores, in bytes.
+    pub fn max_entry_size(&self) -> usize {
+        self.max_entry_size
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// An entry for a complete response, replayed with the `Age` the shield counts.
+    fn entry(&self, response: Vec<u8>, freshness: Freshness) -> Option<Entry> {
+        Entry::new(response, freshness).map(|entry| Entry {
+            count_age: self.count_age,
+            ..entry
+        })
+    }
+
+    /// Copies a newly stored entry to the remote cache in the background.
+    fn push_remote(&self, key: &str, entry: &Arc<Entry>) {
+        let Some(remote) = self.remote.clone() else {
+            return;
+        };
+        let key = key.to_string();
+        let entry = Arc::clone(entry);
+        thread::spawn(move || {
+            let ttl = entry.remote_ttl();
+            if ttl.is_zero() {
+                return;
+            }
+            if let Err(e) = remote.set(&remote_key(&key), &entry.to_remote(), ttl) {
+                log::warn!("Failed to store {} in {}: {}", key, remote, e);
+            }
+        });
+    }
+}
+
+/// The remote cache key of a shield key, which may be too long or contain spaces.
+fn remote_key(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("zstdp:shield:{}:{}", REMOTE_FORMAT, hex)
+}
+
+/// How long a response may be served from the shield, from its `Cache-Control` header. Durations
+/// count from when the response was generated, so they include the `Age` the backend sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freshness {
+    pub max_age: Duration,
+    /// Time after `max_age` during which the response is served while it is refreshed
+    pub stale_while_revalidate: Duration,
+    /// Time after `max_age` during which the response is served when the backend fails
+    pub stale_if_error: Duration,
+}
+
+impl Freshness {
+    /// Returns `None` for responses that must not be stored under keys made from `template`.
+    /// `headers` names are lowercase.
+    pub fn of_response(
+        status: u16,
+        headers: &[(String, String)],
+        template: &CacheKeyTemplate,
+    ) -> Option<Self> {
+        if status != 200 {
+            return None;
+        }
+        let header = |name: &'static str| {
+            headers
+                .iter()
+                .filter(move |(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        if header("set-cookie").next().is_some() {
+            return None;
+        }
+        // Only the Accept-Encoding variants and the headers named in --cache-key are part of the
+        // key
+        let varies_otherwise = header("vary").flat_map(|v| v.split(',')).any(|v| {
+            let v = v.trim();
+            !v.eq_ignore_ascii_case("accept-encoding") && !template.varies_on(v)
+        });
+        if varies_otherwise {
+            return None;
+        }
+
+        let mut max_age = None;
+        let mut s_maxage = None;
+        let mut stale_while_revalidate = 0;
+        let mut stale_if_error = 0;
+        for directive in header("cache-control").flat_map(|v| v.split(',')) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, value.trim().trim_matches('"').parse::<u64>().ok()),
+                None => (directive, None),
+            };
+            match name.trim().to_lowercase().as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = value,
+                "s-maxage" => s_maxage = value,
+                "stale-while-revalidate" => stale_while_revalidate = value.unwrap_or(0),
+                "stale-if-error" => stale_if_error = value.unwrap_or(0),
+                _ => {}
+            }
+        }
+        let age = header("age")
+            .next()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let max_age = s_maxage.or(max_age)?;
+
+        let freshness = Freshness {
+            max_age: Duration::from_secs(max_age),
+            stale_while_revalidate: Duration::from_secs(stale_while_revalidate),
+            stale_if_error: Duration::from_secs(stale_if_error),
+        };
+        // Lifetimes count from when the backend generated the response, `age` seconds ago
+        if freshness.lifetime() <= Duration::from_secs(age) {
+            return None;
+        }
+        Some(freshness)
+    }
+
+    /// How long the response may be served at all, including its stale windows.
+    fn lifetime(&self) -> Duration {
+        self.max_age + self.stale_while_revalidate.max(self.stale_if_error)
+    }
+
+    fn is_fresh(&self, age: Duration) -> bool {
+        age <= self.max_age
+    }
+
+    /// Whether a response of `age` may be served while it is refreshed in the background.
+    fn serves_while_revalidating(&self, age: Duration) -> bool {
+        age <= self.max_age + self.stale_while_revalidate
+    }
+
+    /// Whether a response of `age` may be served when the backend fails.
+    fn serves_if_error(&self, age: Duration) -> bool {
+        age <= self.max_age + self.stale_if_error
+    }
+}
+
+/// A stored response, replayed as it was sent apart from the `Age` and `X-Cache` headers.
+pub struct Entry {
+    head: Vec<u8>,
+    body: Vec<u8>,
+    /// When the response was stored
+    stored: Instant,
+    /// How old the response already was when it was stored: the `Age` the backend sent
+    age_at_store: Duration,
+    freshness: Freshness,
+    /// A request is refreshing this entry in the background
+    refreshing: AtomicBool,
+    /// Whether the `Age` sent along counts the time spent in the shield
+    count_age: bool,
+}
+
+impl Entry {
+    /// Splits a complete response into an entry, or returns `None` if it has no complete head.
+    fn new(response: Vec<u8>, freshness: Freshness) -> Option<Self> {
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+        let mut age = Duration::ZERO;
+        let head = String::from_utf8_lossy(&response[..head_end])
+            .split_inclusive("\r\n")
+            .filter(|line| match line.split_once(':') {
+                Some((name, value)) if name.trim().eq_ignore_ascii_case("age") => {
+                    age = Duration::from_secs(value.trim().parse().unwrap_or(0));
+                    false
+                }
+                _ => true,
+            })
+            .collect::<String>();
+        Some(Entry {
+            head: head.into_bytes(),
+            body: response[head_end..].to_vec(),
+            stored: Instant::now(),
+            age_at_store: age,
+            freshness,
+            refreshing: AtomicBool::new(false),
+            count_age: true,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
+
+    /// How old the response is: its age when it was stored plus the time spent in the shield.
+    fn age(&self) -> Duration {
+        self.age_at_store + self.stored.elapsed()
+    }
+
+    /// The `Age` to send along with the stored response.
+    fn age_header(&self) -> (String, String) {
+        let age = if self.count_age {
+            self.age()
+        } else {
+            self.age_at_store
+        };
+        ("Age".to_string(), age.as_secs().to_string())
+    }
+
+    /// How long other instances may still use this entry, including its stale windows.
+    fn remote_ttl(&self) -> Duration {
+        self.freshness.lifetime().saturating_sub(self.age())
+    }
+
+    /// Encodes the entry for the remote cache. The time it was stored is kept as wall-clock time,
+    /// so that other instances compute the same age.
+    fn to_remote(&self) -> Vec<u8> {
+        let stored = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(self.age());
+        let mut data = Vec::with_capacity(self.size() + 37);
+        data.push(REMOTE_FORMAT);
+        data.extend_from_slice(&(stored.as_millis() as u64).to_be_bytes());
+        for duration in [
+            self.freshness.max_age,
+            self.freshness.stale_while_revalidate,
+            self.freshness.stale_if_error,
+        ] {
+            data.extend_from_slice(&duration.as_secs().to_be_bytes());
+        }
+        data.extend_from_slice(&(self.head.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.head);
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    /// Decodes an entry written by `to_remote`, or returns `None` if `data` is malformed.
+    fn from_remote(data: &[u8]) -> Option<Self> {
+        let (&format, mut rest) = data.split_first()?;
+        if format != REMOTE_FORMAT {
+            return None;
+        }
+        let mut take = |len: usize| {
+            let (taken, remaining) = rest.split_at_checked(len)?;
+            rest = remaining;
+            Some(taken)
+        };
+        let mut number = |len: usize| {
+            take(len).map(|bytes| bytes.iter().fold(0u64, |n, b| (n << 8) | u64::from(*b)))
+        };
+        let stored = Duration::from_millis(number(8)?);
+        let max_age = Duration::from_secs(number(8)?);
+        let stale_while_revalidate = Duration::from_secs(number(8)?);
+        let stale_if_error = Duration::from_secs(number(8)?);
+        let head_len = number(4)? as usize;
+        let head = take(head_len)?.to_vec();
+        let body = rest.to_vec();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        Some(Entry {
+            head,
+            body,
+            stored: Instant::now(),
+            age_at_store: now.saturating_sub(stored),
+            freshness: Freshness {
+                max_age,
+                stale_while_revalidate,
+                stale_if_error,
+            },
+            refreshing: AtomicBool::new(false),
+            count_age: true,
+        })
+    }
+
+    /// Writes the stored response, labelled with `X-Cache: <status>`. Its `Age` counts the time
+    /// spent in the shield on top of the age the backend reported, as RFC 9111 requires of
+    /// responses served without validation, unless the shield was created without `count_age`.
+    /// `Accept-Ranges` tells clients that they may resume it with `write_range_to`, when they can.
+    pub fn write_to<W: Write>(&self, client: &mut W, status: &str) -> io::Result<()> {
+        log::debug!("Shield {}, age {:?}", status, self.age());
+        let head = self.head_without(&["accept-ranges"], None);
+        let mut extra = Vec::new();
+        if self.serves_ranges() {
+            extra.push(("Accept-Ranges".to_string(), "bytes".to_string()));
+        }
+        extra.push(self.age_header());
+        extra.push(("X-Cache".to_string(), status.to_string()));
+        client.write_all(&append_raw_headers(&head, &extra))?;
+        client.write_all(&self.body)?;
+        client.flush()
+    }
+
+    /// Like `write_to`, but answers a request for a single `range` of the stored body with 206,
+    /// so that interrupted downloads resume from the shield instead of the backend. Ranges are of
+    /// the body as stored, compressed if it was. Requests whose `If-Range` no longer matches the
+    /// stored `ETag` or `Last-Modified`, and those for several ranges, get the whole response.
+    /// So do requests for a range of a compressed body without an `If-Range` naming its strong
+    /// `ETag`, as the bytes the client resumes from may come from another compression.
+    pub fn write_range_to<W: Write>(
+        &self,
+        client: &mut W,
+        status: &str,
+        range: &str,
+        if_range: Option<&str>,
+    ) -> io::Result<()> {
+        let validators = Validators {
+            etag: self.header("etag").unwrap_or_default(),
+            last_modified: self
+                .header("last-modified")
+                .and_then(|date| httpdate::parse_http_date(date.trim()).ok()),
+        };
+        let resumable = match if_range {
+            // Only a strong entity tag vouches for the very bytes of a compressed body; zstdp
+            // weakens those of the bodies it compresses, which never resume
+            Some(value) if self.is_compressed() => {
+                value.trim().starts_with('"') && validators.if_range(value)
+            }
+            Some(value) => validators.if_range(value),
+            None => !self.is_compressed(),
+        };
+        if !resumable {
+            return self.write_to(client, status);
+        }
+        let content = self.content()?;
+        let total = content.len() as u64;
+        let range = match parse_range(range, total) {
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => ranges[0],
+            RangeRequest::Unsatisfiable => {
+                log::debug!("Shield {}, range not satisfiable", status);
+                write!(
+                    client,
+                    "HTTP/1.1 416 Range Not Satisfiable\r\n\
+                     Content-Range: bytes */{}\r\n\
+                     Content-Length: 0\r\n\
+                     X-Cache: {}\r\n\r\n",
+                    total, status
+                )?;
+                return client.flush();
+            }
+            _ => return self.write_to(client, status),
+        };
+
+        log::debug!(
+            "Shield {}, age {:?}, bytes {}-{} of {}",
+            status,
+            self.age(),
+            range.start,
+            range.end,
+            total
+        );
+        let head = self.head_without(
+            &[
+                "accept-ranges",
+                "content-length",
+                "transfer-encoding",
+                "trailer",
+            ],
+            Some("HTTP/1.1 206 Partial Content"),
+        );
+        let extra = [
+            ("Content-Range".to_string(), range.content_range(total)),
+            ("Content-Length".to_string(), range.len().to_string()),
+            ("Accept-Ranges".to_string(), "bytes".to_string()),
+            self.age_header(),
+            ("X-Cache".to_string(), status.to_string()),
+        ];
+        client.write_all(&append_raw_headers(&head, &extra))?;
+        client.write_all(&content[range.start as usize..=range.end as usize])?;
+        client.flush()
+    }
+
+    /// Whether the stored body has a `Content-Encoding`, from the backend or from zstdp.
+    fn is_compressed(&self) -> bool {
+        self.header("content-encoding").is_some()
+    }
+
+    /// Whether `write_range_to` may answer with ranges of the stored body: always for unencoded
+    /// bodies, and with `If-Range` for compressed ones with a strong `ETag`.
+    fn serves_ranges(&self) -> bool {
+        !self.is_compressed()
+            || self
+                .header("etag")
+                .is_some_and(|etag| etag.starts_with('"'))
+    }
+
+    /// The value of the first stored header named `name`, ignoring case.
+    fn header(&self, name: &str) -> Option<String> {
+        String::from_utf8_lossy(&self.head)
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim().to_string())
+    }
+
+    /// The stored head without the headers in `names`, with `status_line` in place of the stored
+    /// one if given.
+    fn head_without(&self, names: &[&str], status_line: Option<&str>) -> Vec<u8> {
+        let head = String::from_utf8_lossy(&self.head);
+        let mut lines = head.split_inclusive("\r\n");
+        let stored_status = lines.next().unwrap_or_default();
+        let mut result = match status_line {
+            Some(status_line) => format!("{}\r\n", status_line),
+            None => stored_status.to_string(),
+        };
+        for line in lines {
+            let name = line.split_once(':').map_or("", |(name, _)| name.trim());
+            if !names
+                .iter()
+                .any(|dropped| name.eq_ignore_ascii_case(dropped))
+            {
+                result.push_str(line);
+            }
+        }
+        result.into_bytes()
+    }
+
+    /// The stored body without its chunked framing, if it was sent chunked.
+    fn content(&self) -> io::Result<Cow<'_, [u8]>> {
+        let chunked = self
+            .header("transfer-encoding")
+            .is_some_and(|v| v.to_lowercase().contains("chunked"));
+        if !chunked {
+            return Ok(Cow::Borrowed(&self.body));
+        }
+        let mut content = Vec::with_capacity(self.body.len());
+        decode_chunked_body(&mut &self.body[..], &mut content)?;
+        Ok(Cow::Owned(content))
+    }
+
+    /// Lets a later request try again after a failed background refresh.
+    pub fn refresh_failed(&self) {
+        self.refreshing.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The shield key of a request to `backends`, made from `template`, if the shield may answer it.
+/// Backends taking turns share their entries; see `cache_key` for the rest of the key.
+pub fn key(
+    request: &ForwardedRequest,
+    backends: &Backends,
+    compression: CompressionType,
+    template: &CacheKeyTemplate,
+) -> Option<String> {
+    if request.method != "GET" || request.body.is_some() {
+        return None;
+    }
+    let authorized = request
+        .headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case("authorization"));
+    if authorized {
+        return None;
+    }
+    let key = template.key(&KeyedRequest {
+        host: request.host.as_deref(),
+        uri: &request.uri,
+        headers: &request.headers,
+        compression,
+        forced: request.forced_encoding.is_some(),
+    })?;
+    Some(format!("{} {}", backends, key))
+}
+
+pub enum Lookup {
+    /// Serve the stored response
+    Fresh(Arc<Entry>),
+    /// Serve the stored response, and refresh it in the background if `revalidate` is set
+    Stale { entry: Arc<Entry>, revalidate: bool },
+    /// Fetch the response from the backend and store it
+    Miss(Fetch),
+    /// Fetch the response from the backend without storing it
+    Pass,
+}
+
+impl Shield {
+    /// Looks `key` up. While another request is fetching the same key, waits for it to finish so
+    /// that concurrent misses only reach the backend once, but no longer than `timeout` (a minute
+    /// if `None`): past that, the request goes to the backend on its own.
+    pub fn lookup(self: &Arc<Self>, key: &str, timeout: Option<Duration>) -> Lookup {
+        let deadline = Instant::now() + timeout.unwrap_or(COLLAPSED_WAIT);
+        let mut cache = self.lock_cache();
+        let mut waited = false;
+        loop {
+            let stale = match cache.slots.get(key) {
+                Some(Slot::Ready(entry)) => {
+                    let age = entry.age();
+                    if entry.freshness.is_fresh(age) {
+                        return Lookup::Fresh(Arc::clone(entry));
+                    }
+                    if entry.freshness.serves_while_revalidating(age) {
+                        let revalidate = !entry.refreshing.swap(true, Ordering::Relaxed);
+                        return Lookup::Stale {
+                            entry: Arc::clone(entry),
+                            revalidate,
+                        };
+                    }
+                    Some(Arc::clone(entry))
+                }
+                Some(Slot::Fetching) => {
+                    let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                        log::debug!("Shield gave up waiting for the fetch of {}", key);
+                        return Lookup::Pass;
+                    };
+                    cache = self
+                        .fetch_done
+                        .wait_timeout(cache, remaining)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                    waited = true;
+                    continue;
+                }
+                Some(Slot::Pass { until }) if *until > Instant::now() => return Lookup::Pass,
+                // The fetch we waited for did not store anything
+                None if waited => return Lookup::Pass,
+                _ => None,
+            };
+
+            let stale = stale.filter(|entry| entry.freshness.serves_if_error(entry.age()));
+            cache.insert(key, Slot::Fetching);
+            drop(cache);
+            return self.lookup_remote(Fetch {
+                shield: Arc::clone(self),
+                key: key.to_string(),
+                stale,
+                done: false,
+            });
+        }
+    }
+
+    /// Looks a local miss up in the remote cache, if there is one, before it goes to the backend.
+    /// Other requests for the key keep waiting on `fetch` meanwhile.
+    fn lookup_remote(&self, mut fetch: Fetch) -> Lookup {
+        let Some(remote) = &self.remote else {
+            return Lookup::Miss(fetch);
+        };
+        let entry = match remote.get(&remote_key(&fetch.key)) {
+            Ok(data) => data
+                .and_then(|data| Entry::from_remote(&data))
+                .filter(|entry| entry.size() <= self.max_entry_size)
+                .map(|entry| Entry {
+                    count_age: self.count_age,
+                    ..entry
+                }),
+            Err(e) => {
+                log::warn!(
+                    "Remote cache lookup of {} in {} failed: {}",
+                    fetch.key,
+                    remote,
+                    e
+                );
+                None
+            }
+        };
+        let Some(entry) = entry.map(Arc::new) else {
+            return Lookup::Miss(fetch);
+        };
+
+        let age = entry.age();
+        let freshness = entry.freshness;
+        if freshness.is_fresh(age) {
+            log::debug!("Shield found {} in {}", fetch.key, remote);
+            fetch.finish(Some(Slot::Ready(Arc::clone(&entry))));
+            return Lookup::Fresh(entry);
+        }
+        if freshness.serves_while_revalidating(age) {
+            entry.refreshing.store(true, Ordering::Relaxed);
+            fetch.finish(Some(Slot::Ready(Arc::clone(&entry))));
+            return Lookup::Stale {
+                entry,
+                revalidate: true,
+            };
+        }
+        let fresher = fetch.stale.as_ref().is_none_or(|stale| stale.age() > age);
+        if freshness.serves_if_error(age) && fresher {
+            fetch.stale = Some(entry);
+        }
+        Lookup::Miss(fetch)
+    }
+
+    /// Replaces the entry for `key` after a background refresh, or drops it if the new response is
+    /// not cacheable.
+    pub fn replace(&self, key: &str, response: Option<(Vec<u8>, Freshness)>) {
+        let mut cache = self.lock_cache();
+        match response.and_then(|(response, freshness)| self.entry(response, freshness)) {
+            Some(entry) => {
+                let entry = Arc::new(entry);
+                self.push_remote(key, &entry);
+                cache.insert(key, Slot::Ready(entry));
+            }
+            None => cache.remove(key),
+        }
+    }
+}
+
+/// A request's claim on fetching a key from the backend. Other requests for the key wait until it
+/// is stored, passed on or dropped; dropping it puts back the stale entry, if there was one.
+pub struct Fetch {
+    shield: Arc<Shield>,
+    key: String,
+    stale: Option<Arc<Entry>>,
+    done: bool,
+}
+
+impl Fetch {
+    /// The stored response to fall back on when the backend fails, within `stale-if-error`.
+    pub fn stale_if_error(&self) -> Option<Arc<Entry>> {
+        self.stale.clone()
+    }
+
+    /// Stores the complete response sent to the client.
+    pub fn store(mut self, response: Vec<u8>, freshness: Freshness) {
+        match self.shield.entry(response, freshness) {
+            Some(entry) => {
+                log::debug!("Shield stored {} ({} bytes)", self.key, entry.size());
+                let entry = Arc::new(entry);
+                self.shield.push_remote(&self.key, &entry);
+                self.finish(Some(Slot::Ready(entry)));
+            }
+            None => self.pass(),
+        }
+    }
+
+    /// Lets requests for this key go to the backend without waiting for a while, since the
+    /// response could not be stored.
+    pub fn pass(mut self) {
+        log::debug!("Shield passing {}", self.key);
+        self.finish(Some(Slot::Pass {
+            until: Instant::now() + HIT_FOR_PASS,
+        }));
+    }
+
+    fn finish(&mut self, slot: Option<Slot>) {
+        self.done = true;
+        let mut cache = self.shield.lock_cache();
+        match slot {
+            Some(slot) => cache.insert(&self.key, slot),
+            None => cache.remove(&self.key),
+        }
+        self.shield.fetch_done.notify_all();
+    }
+}
+
+impl Drop for Fetch {
+    fn drop(&mut self) {
+        if !self.done {
+            let stale = self.stale.take().map(Slot::Ready);
+            self.finish(stale);
+        }
+    }
+}
+
+/// Passes writes through while keeping a copy of up to `max_size` bytes for the shield.
+pub struct Capture<W: Write> {
+    inner: W,
+    copy: Option<Vec<u8>>,
+    max_size: usize,
+}
+
+impl<W: Write> Capture<W> {
+    /// Copies what is written unless `max_size` is `None`.
+    pub fn new(inner: W, max_size: Option<usize>) -> Self {
+        Self {
+            inner,
+            copy: max_size.map(|_| Vec::new()),
+            max_size: max_size.unwrap_or(0),
+        }
+    }
+
+    /// The bytes written, unless there were too many to keep.
+    pub fn into_copy(self) -> Option<Vec<u8>> {
+        self.copy
+    }
+}
+
+impl<W: Write> Write for Capture<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(copy) = &mut self.copy {
+            if copy.len() + written > self.max_size {
+                self.copy = None;
+            } else {
+                copy.extend_from_slice(&buf[..written]);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn freshness(cache_control: &str, age: Option<&str>) -> Option<Freshness> {
+        let mut pairs = vec![("cache-control", cache_control)];
+        pairs.extend(age.map(|age| ("age", age)));
+        Freshness::of_response(200, &headers(&pairs), &CacheKeyTemplate::default())
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn fresh_until_max_age() {
+        let freshness = freshness("public, max-age=60", None).unwrap();
+        assert!(freshness.is_fresh(secs(60)));
+        assert!(!freshness.is_fresh(secs(61)));
+        assert!(!freshness.serves_while_revalidating(secs(61)));
+        assert!(!freshness.serves_if_error(secs(61)));
+    }
+
+    #[test]
+    fn s_maxage_overrides_max_age() {
+        let freshness = freshness("max-age=10, s-maxage=100", None).unwrap();
+        assert_eq!(freshness.max_age, secs(100));
+    }
+
+    #[test]
+    fn stale_windows_follow_max_age() {
+        let freshness = freshness(
+            "max-age=60, stale-while-revalidate=30, stale-if-error=120",
+            None,
+        )
+        .unwrap();
+        assert!(freshness.serves_while_revalidating(secs(90)));
+        assert!(!freshness.serves_while_revalidating(secs(91)));
+        assert!(freshness.serves_if_error(secs(180)));
+        assert!(!freshness.serves_if_error(secs(181)));
+    }
+
+    #[test]
+    fn uncacheable_responses_are_not_stored() {
+        assert_eq!(freshness("no-store, max-age=60", None), None);
+        assert_eq!(freshness("private, max-age=60", None), None);
+        assert_eq!(freshness("public", None), None);
+        let template = CacheKeyTemplate::default();
+        let cookie = headers(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")]);
+        assert_eq!(Freshness::of_response(200, &cookie, &template), None);
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+        assert_eq!(Freshness::of_response(404, &fresh, &template), None);
+    }
+
+    #[test]
+    fn lifetime_counts_the_backend_age() {
+        // max-age plus the longer stale window: 10 + max(5, 20)
+        let cache_control = "max-age=10, stale-while-revalidate=5, stale-if-error=20";
+        assert!(freshness(cache_control, Some("29")).is_some());
+        assert_eq!(freshness(cache_control, Some("30")), None);
+        assert_eq!(freshness("max-age=10", Some("10")), None);
+    }
+
+    fn entry(head: &str, freshness: Freshness) -> Entry {
+        Entry::new(format!("{}\r\n\r\nbody", head).into_bytes(), freshness).unwrap()
+    }
+
+    fn one_minute() -> Freshness {
+        Freshness {
+            max_age: secs(60),
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn age_adds_the_time_in_the_shield_to_the_backend_age() {
+        let entry = entry("HTTP/1.1 200 OK\r\nAge: 100", one_minute());
+        assert_eq!(entry.age_at_store, secs(100));
+        assert!(entry.age() >= secs(100) && entry.age() < secs(101));
+        assert_eq!(entry.remote_ttl(), Duration::ZERO);
+    }
+
+    #[test]
+    fn ages_beyond_the_uptime_are_kept() {
+        // Older than the monotonic clock may go back on a freshly booted machine
+        let entry = entry("HTTP/1.1 200 OK\r\nAge: 315360000", one_minute());
+        assert!(entry.age() >= secs(315360000));
+    }
+
+    #[test]
+    fn remote_entries_keep_their_age() {
+        let entry = entry("HTTP/1.1 200 OK\r\nAge: 30", one_minute());
+        let copy = Entry::from_remote(&entry.to_remote()).unwrap();
+        assert!(copy.age() >= secs(30) && copy.age() < secs(32));
+        assert_eq!(copy.freshness, entry.freshness);
+        assert_eq!((copy.head, copy.body), (entry.head, entry.body));
+    }
+
+    fn response(body: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .into_bytes()
+    }
+
+    fn store(shield: &Arc<Shield>, key: &str, body: &str) {
+        match shield.lookup(key, None) {
+            Lookup::Miss(fetch) => fetch.store(response(body), one_minute()),
+            _ => panic!("expected a miss for {}", key),
+        }
+    }
+
+    #[test]
+    fn stored_responses_answer_later_lookups() {
+        let shield = Arc::new(Shield::new(1024, true, None));
+        store(&shield, "a", "first");
+        match shield.lookup("a", None) {
+            Lookup::Fresh(entry) => assert_eq!(entry.body, b"first"),
+            _ => panic!("expected a hit"),
+        }
+    }
+
+    #[test]
+    fn oldest_entries_make_room_for_new_ones() {
+        let entry_size = response("first").len();
+        let shield = Arc::new(Shield::new(entry_size * 3 / 2, true, None));
+        store(&shield, "a", "first");
+        store(&shield, "b", "other");
+        assert!(matches!(shield.lookup("b", None), Lookup::Fresh(_)));
+        assert!(matches!(shield.lookup("a", None), Lookup::Miss(_)));
+    }
+
+    #[test]
+    fn entries_never_outgrow_a_small_shield() {
+        assert_eq!(
+            Shield::new(1 << 30, true, None).max_entry_size(),
+            MAX_ENTRY_SIZE
+        );
+        let shield = Shield::new(100, true, None);
+        assert_eq!(shield.max_entry_size(), 100);
+
+        let mut capture = Capture::new(io::sink(), Some(shield.max_entry_size()));
+        capture.write_all(&[b'x'; 60]).unwrap();
+        capture.write_all(&[b'x'; 40]).unwrap();
+        assert_eq!(capture.into_copy().map(|copy| copy.len()), Some(100));
+
+        let mut capture = Capture::new(io::sink(), Some(shield.max_entry_size()));
+        capture.write_all(&[b'x'; 60]).unwrap();
+        capture.write_all(&[b'x'; 41]).unwrap();
+        assert!(capture.into_copy().is_none());
+    }
+
+    #[test]
+    fn replays_keep_the_stored_headers() {
+        let entry = entry(
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nCache-Control: max-age=60\r\n\
+             Vary: Accept-Encoding\r\nContent-Length: 4\r\nAge: 5\r\nAccept-Ranges: none",
+            one_minute(),
+        );
+        let mut out = Vec::new();
+        entry.write_to(&mut out, "HIT").unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (head, body) = out.split_once("\r\n\r\n").unwrap();
+        let lines: Vec<&str> = head.split("\r\n").collect();
+        assert_eq!(
+            lines,
+            [
+                "HTTP/1.1 200 OK",
+                "ETag: \"v1\"",
+                "Cache-Control: max-age=60",
+                "Vary: Accept-Encoding",
+                "Content-Length: 4",
+                "Accept-Ranges: bytes",
+                "Age: 5",
+                "X-Cache: HIT",
+            ]
+        );
+        assert_eq!(body, "body");
+    }
+}
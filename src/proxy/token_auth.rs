@@ -0,0 +1,214 @@
+//! Static bearer tokens: only requests carrying one of the tokens listed in a file are served.
+//!
+//! With `--auth-token-file PATH`, every request must send `Authorization: Bearer <token>` with a
+//! token from the file, which holds one token per line; empty lines and lines starting with `#`
+//! are skipped. Other requests are answered with `401 Unauthorized` and a `Bearer` challenge.
+//! Only digests of the tokens are kept, and every digest is compared, so that how long a check
+//! takes does not tell how much of a token was right.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::client::ClientStream;
+use crate::metrics::TOKEN_AUTH_DENIALS;
+
+use super::auth::{AuthProvider, Denied, Subject};
+
+/// The tokens requests may carry.
+pub struct StaticTokens {
+    path: PathBuf,
+    /// SHA-256 digests of the tokens
+    digests: Vec<[u8; 32]>,
+}
+
+impl fmt::Display for StaticTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bearer tokens from {}",
+            self.digests.len(),
+            self.path.display()
+        )
+    }
+}
+
+impl StaticTokens {
+    /// Reads the tokens listed in `path`, which must list at least one.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read tokens from {}: {}", path.display(), e),
+            )
+        })?;
+        let tokens = StaticTokens::parse(path, &content);
+        if tokens.digests.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} lists no tokens", path.display()),
+            ));
+        }
+        Ok(tokens)
+    }
+
+    fn parse(path: &Path, content: &str) -> Self {
+        StaticTokens {
+            path: path.to_path_buf(),
+            digests: content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|token| Sha256::digest(token.as_bytes()).into())
+                .collect(),
+        }
+    }
+
+    /// Whether the `Authorization` header `authorization` carries one of the tokens.
+    fn allows(&self, authorization: Option<&str>) -> bool {
+        let Some((scheme, token)) = authorization.and_then(|value| value.trim().split_once(' '))
+        else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return false;
+        }
+        let digest: [u8; 32] = Sha256::digest(token.trim().as_bytes()).into();
+        self.digests
+            .iter()
+            .fold(false, |found, known| found | (*known == digest))
+    }
+}
+
+impl AuthProvider for StaticTokens {
+    /// Allows requests with a known token, setting no headers, and answers the others with 401.
+    fn check(
+        &self,
+        client: &mut ClientStream,
+        subject: &Subject,
+    ) -> io::Result<Vec<(String, String)>> {
+        let authorization = subject
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+            .map(|(_, v)| v.as_str());
+        if self.allows(authorization) {
+            return Ok(Vec::new());
+        }
+
+        TOKEN_AUTH_DENIALS.increment();
+        log::debug!("Request without a known bearer token");
+        client.write_all(b"HTTP/1.1 401 Unauthorized\r\n")?;
+        client.write_all(b"WWW-Authenticate: Bearer realm=\"zstdp\"\r\n")?;
+        client.write_all(b"Content-Type: text/plain\r\n")?;
+        client.write_all(b"Content-Length: 12\r\n")?;
+        client.write_all(b"Connection: close\r\n")?;
+        client.write_all(b"\r\n")?;
+        client.write_all(b"Unauthorized")?;
+        Err(Denied::error("401 Unauthorized"))
+    }
+
+    fn response_headers(&self) -> &[String] {
+        &[]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::os::unix::net::UnixStream;
+
+    use crate::proxy::auth::denied_status;
+
+    fn tokens() -> StaticTokens {
+        StaticTokens::parse(
+            Path::new("tokens"),
+            "# deploy tokens\n\n  s3cr3t  \nanother-token\n",
+        )
+    }
+
+    fn check(tokens: &StaticTokens, headers: &[(String, String)]) -> (io::Result<()>, String) {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let mut client = ClientStream::Unix(ours);
+        let subject = Subject {
+            method: "GET",
+            uri: "/",
+            host: Some("example.com"),
+            headers,
+            peer: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            scheme: "http",
+        };
+        let result = tokens.check(&mut client, &subject).map(|allowed| {
+            assert!(allowed.is_empty());
+        });
+        drop(client);
+        let mut answer = String::new();
+        theirs.read_to_string(&mut answer).unwrap();
+        (result, answer)
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        assert_eq!(tokens().digests.len(), 2);
+        assert_eq!(
+            tokens().to_string(),
+            "2 bearer tokens from tokens".to_string()
+        );
+    }
+
+    #[test]
+    fn allows_listed_bearer_tokens() {
+        let tokens = tokens();
+        assert!(tokens.allows(Some("Bearer s3cr3t")));
+        assert!(tokens.allows(Some("bearer another-token")));
+        assert!(tokens.allows(Some("  Bearer  s3cr3t ")));
+    }
+
+    #[test]
+    fn refuses_unknown_tokens_and_other_schemes() {
+        let tokens = tokens();
+        assert!(!tokens.allows(None));
+        assert!(!tokens.allows(Some("Bearer")));
+        assert!(!tokens.allows(Some("Bearer s3cr3")));
+        assert!(!tokens.allows(Some("Bearer # deploy tokens")));
+        assert!(!tokens.allows(Some("Basic s3cr3t")));
+    }
+
+    #[test]
+    fn allowed_requests_are_not_answered() {
+        let headers = [("authorization".to_string(), "Bearer s3cr3t".to_string())];
+        let (result, answer) = check(&tokens(), &headers);
+        assert!(result.is_ok());
+        assert!(answer.is_empty());
+    }
+
+    #[test]
+    fn refused_requests_get_a_challenge() {
+        let headers = [("authorization".to_string(), "Bearer wrong".to_string())];
+        let (result, answer) = check(&tokens(), &headers);
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(denied_status(&e), Some("401 Unauthorized"));
+        assert!(answer.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(answer.contains("WWW-Authenticate: Bearer realm=\"zstdp\"\r\n"));
+        assert!(answer.ends_with("\r\n\r\nUnauthorized"));
+    }
+
+    #[test]
+    fn empty_token_files_are_refused() {
+        let path = std::env::temp_dir().join(format!("zstdp-tokens-{}", std::process::id()));
+        fs::write(&path, "# nothing yet\n").unwrap();
+        let result = StaticTokens::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidInput)
+        );
+    }
+}
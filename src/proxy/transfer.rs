@@ -1,34 +1,267 @@
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
 use std::io::{self, BufRead, BufReader, Read, Write};
-use std::net::TcpStream;
-use std::time::Instant;
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::compression::determine_compression;
+use crate::body_log::{self, Sampled};
+use crate::capture::{self, CapturedExchange};
+use crate::client::ClientStream;
+use crate::compression::{
+    determine_compression, parse_forced_encoding, AcceptedCompression, CompressionType,
+    FORCE_ENCODING_HEADER,
+};
+use crate::header_stats::{self, Direction};
 use crate::log_request;
+use crate::request_target::{
+    read_request_line, write_bad_request, write_rejection, HeaderLines, RequestLimits,
+    RequestTarget,
+};
+use crate::slow_clients::{is_body_timeout, ClientReader};
 
-pub fn forward_chunked_body<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+use super::handlers::write_content_too_large;
+
+/// Encodes everything written to it as HTTP/1.1 chunks, one chunk per `write` call.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    /// Hash of the body so far, sent as a `Content-Digest` trailer
+    digest: Option<Sha256>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            digest: None,
+        }
+    }
+
+    /// Like `new`, but ends the body with a `Content-Digest` trailer (RFC 9530) holding the
+    /// SHA-256 of everything written. The response head should announce it with
+    /// `Trailer: Content-Digest`.
+    pub fn with_digest(inner: W) -> Self {
+        Self {
+            inner,
+            digest: Some(Sha256::new()),
+        }
+    }
+
+    /// Writes the terminating zero-length chunk, then `trailers` and the digest if there is one,
+    /// and returns the inner writer.
+    pub fn finish(mut self, trailers: &[(String, String)]) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n")?;
+        for (name, value) in trailers {
+            write!(self.inner, "{}: {}\r\n", name, value)?;
+        }
+        if let Some(digest) = self.digest.take() {
+            let hash = BASE64_STANDARD.encode(digest.finalize());
+            write!(self.inner, "Content-Digest: sha-256=:{}:\r\n", hash)?;
+        }
+        self.inner.write_all(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        if let Some(digest) = &mut self.digest {
+            digest.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Flushes the inner writer whenever `interval` has passed since the last flush, so that
+/// encoders emit what they have buffered instead of holding it until they are finished.
+pub struct IntervalFlushWriter<W: Write> {
+    inner: W,
+    interval: Duration,
+    last_flush: Instant,
+}
+
+impl<W: Write> IntervalFlushWriter<W> {
+    pub fn new(inner: W, interval: Duration) -> Self {
+        Self {
+            inner,
+            interval,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for IntervalFlushWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.last_flush.elapsed() >= self.interval {
+            self.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.inner.flush()
+    }
+}
+
+/// Reads a chunked body and writes only the decoded payload, dropping the chunk framing. Returns
+/// the trailer fields that followed the last chunk, names as sent.
+pub fn decode_chunked_body<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<Vec<(String, String)>> {
     let start_time = Instant::now();
+    let mut reader = BufReader::new(reader);
     let mut total_bytes = 0;
 
     loop {
-        let mut size_buf = [0; 16];
-        let mut size_bytes = 0;
-
-        // Read chunk size
-        loop {
-            let byte = &mut [0; 1];
-            reader.read_exact(byte)?;
-            size_buf[size_bytes] = byte[0];
-            size_bytes += 1;
-            if byte[0] == b'\n' {
-                break;
+        let mut size_line = String::new();
+        if reader.read_line(&mut size_line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Chunked body ended before the last chunk",
+            ));
+        }
+
+        // Chunk extensions follow a ';' and are ignored
+        let size_str = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        if size == 0 {
+            break;
+        }
+
+        total_bytes += size;
+        io::copy(&mut (&mut reader).take(size as u64), writer)?;
+
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+
+    // Trailers run up to the blank line ending the body
+    let mut trailers = Vec::new();
+    loop {
+        let line = read_raw_line(&mut reader)?;
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) => {
+                trailers.push((name.trim().to_string(), value.trim().to_string()))
             }
+            None => log::debug!("Skipping invalid trailer line: {}", line.trim_end()),
         }
+    }
+
+    log::debug!(
+        "Decoded chunked body: {} bytes and {} trailers in {:?}",
+        total_bytes,
+        trailers.len(),
+        start_time.elapsed()
+    );
+
+    Ok(trailers)
+}
+
+/// Largest backend response head (status line and headers) the proxy holds in memory.
+pub const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+
+/// Reads the backend's status line and headers up to and including the empty line.
+///
+/// `header_timeout` only bounds the wait for the first byte and fails with
+/// `ErrorKind::TimedOut`; every later read is bounded by `read_timeout`. Heads larger than
+/// `MAX_RESPONSE_HEAD_SIZE` fail with `ErrorKind::InvalidData`.
+pub fn read_response_head(
+    server: &mut TcpStream,
+    header_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+) -> io::Result<Vec<u8>> {
+    let start_time = Instant::now();
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+
+    server.set_read_timeout(header_timeout.or(read_timeout))?;
+    match server.read(&mut byte) {
+        Ok(1) => head.push(byte[0]),
+        Ok(_) => return Ok(head),
+        Err(e) if is_timeout(&e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "No response header from backend within {:?}",
+                    start_time.elapsed()
+                ),
+            ))
+        }
+        Err(e) => return Err(e),
+    }
+    log::debug!(
+        "First response byte from backend after {:?}",
+        start_time.elapsed()
+    );
+
+    server.set_read_timeout(read_timeout)?;
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Backend response head exceeds {} bytes",
+                    MAX_RESPONSE_HEAD_SIZE
+                ),
+            ));
+        }
+        match server.read(&mut byte)? {
+            1 => head.push(byte[0]),
+            _ => break,
+        }
+    }
+
+    Ok(head)
+}
+
+/// Socket read timeouts surface as `WouldBlock` on Unix and `TimedOut` on Windows.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
 
-        writer.write_all(&size_buf[..size_bytes])?;
+/// Copies a chunked body with its framing, chunk extensions and trailers, and returns the size of
+/// its payload.
+pub fn forward_chunked_body<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<u64> {
+    let start_time = Instant::now();
+    let mut total_bytes = 0;
+
+    loop {
+        let size_line = read_raw_line(reader)?;
+        writer.write_all(&size_line)?;
 
-        let size_str = std::str::from_utf8(&size_buf[..size_bytes - 2])
+        // Chunk extensions follow a ';' and are forwarded untouched
+        let size_str = std::str::from_utf8(&size_line)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        let size = usize::from_str_radix(size_str.trim(), 16)
+        let size_str = size_str.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
         if size == 0 {
@@ -47,10 +280,14 @@ pub fn forward_chunked_body<R: Read, W: Write>(reader: &mut R, writer: &mut W) -
         writer.write_all(&crlf)?;
     }
 
-    // Forward the final CRLF after the last chunk
-    let mut final_crlf = [0; 2];
-    reader.read_exact(&mut final_crlf)?;
-    writer.write_all(&final_crlf)?;
+    // Forward any trailers along with the empty line ending the body
+    loop {
+        let line = read_raw_line(reader)?;
+        writer.write_all(&line)?;
+        if line.trim_ascii().is_empty() {
+            break;
+        }
+    }
 
     log::debug!(
         "Completed chunked body transfer: {} bytes in {:?}",
@@ -58,29 +295,335 @@ pub fn forward_chunked_body<R: Read, W: Write>(reader: &mut R, writer: &mut W) -
         start_time.elapsed()
     );
 
-    Ok(())
+    Ok(total_bytes as u64)
 }
 
-pub fn forward_request(
-    client: &mut TcpStream,
-    server: &mut TcpStream,
-) -> io::Result<(Vec<(String, String)>, bool, String)> {
-    // Add String to return type for URI
+/// Longest chunk size or trailer line accepted from the backend.
+const MAX_CHUNK_LINE: usize = 8 * 1024;
+
+/// Reads one line, including its line ending, a byte at a time so nothing past it is consumed.
+fn read_raw_line<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+        if byte[0] == b'\n' {
+            return Ok(line);
+        }
+        if line.len() > MAX_CHUNK_LINE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Chunk size or trailer line too long",
+            ));
+        }
+    }
+}
+
+/// A client request, read up to the end of its head and ready to be sent to the backend.
+pub struct ForwardedRequest {
+    /// The request head as it is sent to the backend, without `Host` and the final empty line
+    pub head: Vec<u8>,
+    pub method: String,
+    /// Request headers, minus `Host`
+    pub headers: Vec<(String, String)>,
+    /// The `Host` header, if the client sent one
+    pub host: Option<String>,
+    pub accepted_compression: AcceptedCompression,
+    /// Encoding pinned by a trusted client, overriding `accepted_compression`
+    pub forced_encoding: Option<CompressionType>,
+    pub uri: String,
+    /// Bytes of the request and response bodies to log, if this request is sampled
+    pub body_sample: Option<usize>,
+    /// The capture of the exchange, if captures are enabled
+    pub captured: Option<Arc<CapturedExchange>>,
+    /// Request body still to be sent to the backend
+    pub body: Option<PendingBody>,
+}
+
+impl ForwardedRequest {
+    /// The request head for a backend, addressed to `host`.
+    pub fn head_for(&self, host: &str) -> Vec<u8> {
+        let mut head = self.head.clone();
+        head.extend_from_slice(format!("Host: {}\r\n\r\n", host).as_bytes());
+        head
+    }
+
+    /// The value of the first request header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Removes the headers named in `names`, then adds `headers`.
+    pub fn replace_headers(&mut self, names: &[String], headers: Vec<(String, String)>) {
+        let named = |name: &str| names.iter().any(|n| n.eq_ignore_ascii_case(name.trim()));
+        self.headers.retain(|(k, _)| !named(k));
+        let mut head = String::from_utf8_lossy(&self.head)
+            .split_inclusive("\r\n")
+            .enumerate()
+            .filter(|(i, line)| *i == 0 || !line.split_once(':').is_some_and(|(k, _)| named(k)))
+            .map(|(_, line)| line)
+            .collect::<String>();
+        for (name, value) in &headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        self.head = head.into_bytes();
+        self.headers.extend(headers);
+    }
+}
+
+/// Largest chunked request body read into memory for applications that need its length first.
+pub const MAX_BUFFERED_CHUNKED_BODY: usize = 16 * 1024 * 1024;
+
+fn body_too_large(max: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::FileTooLarge,
+        format!("Request body larger than {} bytes", max),
+    )
+}
+
+/// A request body that still has to be copied from the client to the backend.
+pub struct PendingBody {
+    /// Body bytes already read from the client along with the headers
+    buffered: Vec<u8>,
+    /// `None` for a chunked body, which is copied with its framing
+    length: Option<u64>,
+    /// Largest body the route takes, in bytes
+    max_size: u64,
+}
+
+impl PendingBody {
+    /// Copies the whole body from `client` to `out` on the current thread, and returns the size
+    /// of its payload. A chunked body that grows past its maximum size, framing included, fails
+    /// with `ErrorKind::FileTooLarge` once that much has been copied.
+    pub fn copy_to<R: Read, W: Write>(self, client: &mut R, out: &mut W) -> io::Result<u64> {
+        let Some(length) = self.length else {
+            let mut body = LimitedReader {
+                inner: self.buffered.as_slice().chain(client),
+                max: self.max_size,
+                left: self.max_size,
+            };
+            return forward_chunked_body(&mut body, out);
+        };
+        out.write_all(&self.buffered)?;
+        let remaining = length - self.buffered.len() as u64;
+        io::copy(&mut client.take(remaining), out)?;
+        Ok(length)
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.length.is_none()
+    }
+
+    /// The size of the body, unless it is chunked.
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Reads a chunked body into memory without its framing, for applications that need to know
+    /// its length before it is sent. Bodies larger than `MAX_BUFFERED_CHUNKED_BODY` or their
+    /// maximum size fail with `ErrorKind::FileTooLarge`.
+    pub fn into_buffered<R: Read>(self, client: &mut R) -> io::Result<PendingBody> {
+        if self.length.is_some() {
+            return Ok(self);
+        }
+        let mut content = Vec::new();
+        let mut limited = LimitedWriter {
+            content: &mut content,
+            max: self.max_size.min(MAX_BUFFERED_CHUNKED_BODY as u64) as usize,
+        };
+        decode_chunked_body(&mut self.buffered.as_slice().chain(client), &mut limited)?;
+        Ok(PendingBody {
+            length: Some(content.len() as u64),
+            buffered: content,
+            max_size: self.max_size,
+        })
+    }
+
+    /// Uploads the body on its own thread, so that the backend's response can be read while the
+    /// upload is still in progress (e.g. a 413 or 401 sent before the body has been read).
+    pub fn spawn_upload(
+        self,
+        client: &ClientStream,
+        server: &TcpStream,
+        label: String,
+        body_sample: Option<usize>,
+        captured: Option<&Arc<CapturedExchange>>,
+    ) -> io::Result<Upload> {
+        let mut reader = ClientReader::new(client.try_clone()?);
+        let mut writer = Sampled::new(server.try_clone()?, label, body_sample)
+            .capturing(captured, Direction::Request);
+        let aborting = server.try_clone()?;
+        let handle = thread::spawn(move || {
+            let result = self
+                .copy_to(&mut reader, &mut writer)
+                .and_then(|length| writer.flush().map(|()| length));
+            let cut_short =
+                |e: &io::Error| is_body_timeout(e) || e.kind() == io::ErrorKind::FileTooLarge;
+            if result.as_ref().is_err_and(cut_short) {
+                // The backend waits for the rest of the body, which is not coming
+                let _ = aborting.shutdown(Shutdown::Both);
+            }
+            result
+        });
+
+        Ok(Upload {
+            handle: Some(handle),
+            client: client.try_clone()?,
+            server: server.try_clone()?,
+        })
+    }
+}
+
+/// Fails writes that would take the vector past `max` bytes.
+struct LimitedWriter<'a> {
+    content: &'a mut Vec<u8>,
+    max: usize,
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.content.len() + buf.len() > self.max {
+            return Err(body_too_large(self.max as u64));
+        }
+        self.content.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails reads past `left` more bytes, of a body of at most `max`.
+struct LimitedReader<R: Read> {
+    inner: R,
+    max: u64,
+    left: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        match self.left.checked_sub(n as u64) {
+            Some(left) => {
+                self.left = left;
+                Ok(n)
+            }
+            None => Err(body_too_large(self.max)),
+        }
+    }
+}
+
+/// A request body upload running alongside the response.
+///
+/// Dropping it waits for the upload, first cutting it short if it is still running: once the
+/// response is over (or has failed) there is nobody left to read the rest of the body.
+pub struct Upload {
+    handle: Option<JoinHandle<io::Result<u64>>>,
+    client: ClientStream,
+    server: TcpStream,
+}
+
+impl Upload {
+    /// Waits for the upload, cutting it short if it is still running, and returns how it ended:
+    /// with the size of the body, or with why it stopped.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.stop().unwrap_or(Ok(0))
+    }
+
+    fn stop(&mut self) -> Option<io::Result<u64>> {
+        let handle = self.handle.take()?;
+        if !handle.is_finished() {
+            log::debug!("Response finished before the request body, aborting upload");
+            let _ = self.client.shutdown(Shutdown::Read);
+            let _ = self.server.shutdown(Shutdown::Write);
+        }
+        let result = handle
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Request body upload thread panicked")));
+        match &result {
+            Ok(length) => log::debug!("Forwarded request body of {} bytes", length),
+            Err(e) => log::debug!("Request body upload stopped: {}", e),
+        }
+        Some(result)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Reads the request head from the client and rewrites it for a backend, apart from the `Host`
+/// header, which `ForwardedRequest::head_for` adds. The client's `Accept-Encoding` ranks
+/// `encodings`. A request whose target or `Host` is invalid, or whose body is in a transfer coding
+/// other than chunked, is answered with `400 Bad Request`, one whose target is too long with
+/// `414 URI Too Long` and one for a host that is not allowed with `421 Misdirected Request`; all
+/// are returned as `InvalidInput` errors. One whose `Content-Length` is over the `max_body_size` of
+/// `limits` is answered with `413 Content Too Large` and returned as a `FileTooLarge` error.
+/// The body length a request announces. The backend is sent the same `Content-Length` header, so
+/// anything it could read another way, a value that is not a plain number, a list or a second
+/// header, is refused (RFC 9112, section 6.3) rather than taken as no body.
+fn content_length(headers: &[(String, String)]) -> io::Result<Option<u64>> {
+    let mut values = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .map(|(_, v)| v);
+    let Some(value) = values.next() else {
+        return Ok(None);
+    };
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid request Content-Length {:?}", value),
+        )
+    };
+    if values.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Request with more than one Content-Length",
+        ));
+    }
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    value.parse().map(Some).map_err(|_| invalid())
+}
+
+pub fn read_request(
+    client: &mut ClientStream,
+    limits: &RequestLimits,
+    trust_forced_encoding: bool,
+    encodings: &[CompressionType],
+) -> io::Result<ForwardedRequest> {
     let start_time = Instant::now();
     let mut request = Vec::new();
     let mut headers = Vec::new();
-    let mut supports_zstd = false;
-    let mut uri = String::new();
+    let mut accepted_compression = AcceptedCompression::only(CompressionType::None);
+    let mut forced_encoding = None;
     let mut buf_reader = BufReader::new(client);
 
-    // Read and forward request line
-    let mut first_line = String::new();
-    buf_reader.read_line(&mut first_line)?;
+    // Read request line
+    let first_line = match read_request_line(&mut buf_reader, limits) {
+        Ok(line) => line,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            log::debug!("Rejecting request: {}", e);
+            write_rejection(buf_reader.into_inner(), &e)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
 
-    // Extract URI from request line
-    if let Some(uri_part) = first_line.split_whitespace().nth(1) {
-        uri = uri_part.to_string();
-    }
+    // Extract method and URI from request line
+    let mut request_line = first_line.split_whitespace();
+    let method = request_line.next().unwrap_or("").to_string();
+    let target = request_line.next().unwrap_or("").to_string();
+    let version = request_line.next().unwrap_or("HTTP/1.1").to_string();
 
     request.extend_from_slice(first_line.as_bytes());
 
@@ -88,44 +631,311 @@ pub fn forward_request(
     log_request!(&first_line);
 
     // Read headers
-    let mut line = String::new();
-    while {
-        line.clear();
-        buf_reader.read_line(&mut line)?;
-        !line.trim().is_empty()
-    } {
-        request.extend_from_slice(line.as_bytes());
-
-        if line.to_lowercase().starts_with("accept-encoding:") {
+    let mut hosts = Vec::new();
+    let mut accept_encoding_lines = String::new();
+    let mut header_lines = HeaderLines::new(limits);
+    loop {
+        let line = match header_lines.next(&mut buf_reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                log::debug!("Rejecting request: {}", e);
+                write_rejection(buf_reader.into_inner(), &e)?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let lowercase_line = line.to_lowercase();
+
+        // The pin is meant for zstdp alone and never reaches the backend
+        if let Some(value) = lowercase_line
+            .strip_prefix(FORCE_ENCODING_HEADER)
+            .and_then(|rest| rest.strip_prefix(':'))
+        {
+            if trust_forced_encoding {
+                forced_encoding = parse_forced_encoding(value);
+            } else {
+                log::debug!("Ignoring force-encoding header from untrusted client");
+            }
+            continue;
+        }
+
+        // Sent once normalized, below
+        if lowercase_line.starts_with("host:") {
+            hosts.extend(
+                line.split_once(':')
+                    .map(|(_, value)| value.trim().to_string()),
+            );
+            continue;
+        }
+
+        if lowercase_line.starts_with("accept-encoding:") {
             let accept_encoding = line.split(':').map(|s| s.trim()).collect::<Vec<_>>()[1];
-            supports_zstd = determine_compression(accept_encoding).supports_zstd;
-            log::debug!("Client accepts zstd compression: {}", supports_zstd);
+            accepted_compression = determine_compression(accept_encoding, encodings);
+            accept_encoding_lines.push_str(&line);
+        } else {
+            request.extend_from_slice(line.as_bytes());
         }
 
-        if !line.to_lowercase().starts_with("host:") {
-            let parts: Vec<&str> = line.splitn(2, ':').collect();
-            if parts.len() == 2 {
-                headers.push((parts[0].trim().to_string(), parts[1].trim().to_string()));
-            }
+        let parts: Vec<&str> = line.splitn(2, ':').collect();
+        if parts.len() == 2 {
+            headers.push((parts[0].trim().to_string(), parts[1].trim().to_string()));
         }
     }
 
-    // Forward complete request
-    server.write_all(&request)?;
-    server.write_all(b"\r\n")?;
-    server.flush()?;
+    let hosts: Vec<&str> = hosts.iter().map(String::as_str).collect();
+    let RequestTarget { path: uri, host } =
+        match RequestTarget::parse(&target, &hosts, &limits.hosts) {
+            Ok(target) => target,
+            Err(e) => {
+                log::debug!("Rejecting request for {}: {}", target, e);
+                write_rejection(buf_reader.into_inner(), &e)?;
+                return Err(e);
+            }
+        };
+    // Backends get the target in origin form, whatever form the client sent
+    if uri != target {
+        let line = format!("{} {} {}\r\n", method, uri, version);
+        request.splice(..first_line.len(), line.into_bytes());
+    }
+
+    header_stats::record(Direction::Request, &uri, &headers);
 
-    // Forward request body if present
-    if let Some(length) = headers
+    // A chunked body is forwarded with its framing; a `Content-Length` alongside it would let
+    // the backend see a different body than ours (request smuggling), so it is dropped
+    let transfer_coding = headers
         .iter()
-        .find(|(k, _)| k.to_lowercase() == "content-length")
-        .and_then(|(_, v)| v.parse::<u64>().ok())
-    {
-        log::debug!("Forwarding request body of {} bytes", length);
-        io::copy(&mut buf_reader.take(length), server)?;
+        .filter(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .rfind(|coding| !coding.is_empty())
+        .map(str::to_lowercase);
+    let chunked = match transfer_coding.as_deref() {
+        None => false,
+        Some("chunked") => true,
+        Some(coding) => {
+            log::debug!("Rejecting request with a body in {} coding", coding);
+            write_bad_request(buf_reader.into_inner())?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported request transfer coding {}", coding),
+            ));
+        }
+    };
+    if chunked {
+        headers.retain(|(k, _)| !k.eq_ignore_ascii_case("content-length"));
+        request = String::from_utf8_lossy(&request)
+            .split_inclusive("\r\n")
+            .filter(|line| {
+                !line
+                    .split_once(':')
+                    .is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case("content-length"))
+            })
+            .collect::<String>()
+            .into_bytes();
+    }
+
+    // A pinned response is compressed here, so ask the backend for it uncompressed
+    if forced_encoding.is_some() {
+        request.extend_from_slice(b"Accept-Encoding: identity\r\n");
+    } else {
+        request.extend_from_slice(accept_encoding_lines.as_bytes());
     }
 
-    log::debug!("Completed request forwarding in {:?}", start_time.elapsed());
+    // The request and its body, if present, are forwarded by the caller
+    let body_sample = body_log::sample_limit(&uri);
+    let peer = buf_reader.get_ref().peer_addr().ok().map(|peer| peer.ip());
+    let captured = capture::start(peer, &method, &uri, host.as_deref(), &headers);
+    let max_size = limits.max_body_size.unwrap_or(u64::MAX);
+    let body = if chunked {
+        Some(PendingBody {
+            buffered: buf_reader.buffer().to_vec(),
+            length: None,
+            max_size,
+        })
+    } else {
+        let length = if method.eq_ignore_ascii_case("CONNECT") {
+            // What follows belongs to the tunnel; keep what was read along with the head
+            Some(buf_reader.buffer().len() as u64)
+        } else {
+            match content_length(&headers) {
+                Ok(length) => length,
+                Err(e) => {
+                    log::debug!("Rejecting request: {}", e);
+                    write_bad_request(buf_reader.into_inner())?;
+                    return Err(e);
+                }
+            }
+        };
+        if !method.eq_ignore_ascii_case("CONNECT") && length.is_some_and(|length| length > max_size)
+        {
+            log::debug!("Rejecting request with a body of {:?} bytes", length);
+            write_content_too_large(buf_reader.into_inner())?;
+            return Err(body_too_large(max_size));
+        }
+        length.filter(|&length| length > 0).map(|length| {
+            let buffered = buf_reader.buffer();
+            let buffered = &buffered[..buffered.len().min(length as usize)];
+            PendingBody {
+                buffered: buffered.to_vec(),
+                length: Some(length),
+                max_size,
+            }
+        })
+    };
 
-    Ok((headers, supports_zstd, uri))
+    log::debug!("Read request in {:?}", start_time.elapsed());
+
+    Ok(ForwardedRequest {
+        head: request,
+        method,
+        headers,
+        host,
+        accepted_compression,
+        forced_encoding,
+        uri,
+        body_sample,
+        captured,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::net::UnixStream;
+
+    /// Reads `request` as a client would send it, and returns the outcome and what the client
+    /// was answered.
+    fn read(request: &str) -> (io::Result<ForwardedRequest>, String) {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        theirs.write_all(request.as_bytes()).unwrap();
+        theirs.shutdown(Shutdown::Write).unwrap();
+        let mut client = ClientStream::Unix(ours);
+        let result = read_request(
+            &mut client,
+            &RequestLimits::default(),
+            false,
+            &[CompressionType::Zstd],
+        );
+        drop(client);
+        let mut answer = String::new();
+        theirs.read_to_string(&mut answer).unwrap();
+        (result, answer)
+    }
+
+    fn body_length(request: &ForwardedRequest) -> Option<Option<u64>> {
+        request.body.as_ref().map(|body| body.length)
+    }
+
+    #[test]
+    fn chunked_bodies_end_with_trailers_and_their_digest() {
+        let mut chunked = ChunkedWriter::with_digest(Vec::new());
+        chunked.write_all(b"hello ").unwrap();
+        chunked.write_all(b"").unwrap();
+        chunked.write_all(b"world").unwrap();
+        let trailers = [("Grpc-Status".to_string(), "0".to_string())];
+        let encoded = chunked.finish(&trailers).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded.clone()).unwrap(),
+            "6\r\nhello \r\n5\r\nworld\r\n0\r\nGrpc-Status: 0\r\n\
+             Content-Digest: sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:\r\n\r\n"
+        );
+
+        let mut decoded = Vec::new();
+        let trailers = decode_chunked_body(&mut &encoded[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"hello world");
+        assert_eq!(
+            trailers,
+            [
+                ("Grpc-Status".to_string(), "0".to_string()),
+                (
+                    "Content-Digest".to_string(),
+                    "sha-256=:uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=:".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn forwarded_chunked_bodies_keep_their_framing_and_trailers() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\nnext request";
+        let mut reader = &body[..];
+        let mut forwarded = Vec::new();
+        assert_eq!(
+            forward_chunked_body(&mut reader, &mut forwarded).unwrap(),
+            9
+        );
+        assert_eq!(forwarded, &body[..body.len() - b"next request".len()]);
+        assert_eq!(reader, b"next request");
+    }
+
+    #[test]
+    fn broken_chunked_bodies_fail() {
+        let mut out = Vec::new();
+        let e = decode_chunked_body(&mut &b"5\r\nhel"[..], &mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = decode_chunked_body(&mut &b"zz\r\n"[..], &mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let long = format!("{}\r\n", "0".repeat(MAX_CHUNK_LINE + 1));
+        let e = forward_chunked_body(&mut long.as_bytes(), &mut out).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn content_length_announces_the_body() {
+        let (result, answer) =
+            read("POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello");
+        let request = result.unwrap();
+        assert_eq!(body_length(&request), Some(Some(5)));
+        assert_eq!(request.body.unwrap().buffered, b"hello");
+        assert!(answer.is_empty());
+    }
+
+    #[test]
+    fn invalid_or_conflicting_content_lengths_are_refused() {
+        for headers in [
+            "Content-Length: abc\r\n",
+            "Content-Length: 1, 2\r\n",
+            "Content-Length: 5, 5\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length:\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5\r\ncontent-length: 5\r\n",
+        ] {
+            let (result, answer) = read(&format!(
+                "POST /form HTTP/1.1\r\nHost: a\r\n{}\r\nhello",
+                headers
+            ));
+            let e = result.err().unwrap();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "{:?}", headers);
+            assert!(
+                answer.starts_with("HTTP/1.1 400 "),
+                "{:?}: {}",
+                headers,
+                answer
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_bodies_drop_content_length() {
+        let (result, _) = read(
+            "POST /up HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\
+             Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        );
+        let request = result.unwrap();
+        assert_eq!(body_length(&request), Some(None));
+        let head = String::from_utf8(request.head).unwrap();
+        assert!(!head.to_lowercase().contains("content-length"), "{}", head);
+    }
+
+    #[test]
+    fn unknown_transfer_codings_are_refused() {
+        let (result, answer) =
+            read("POST /up HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert!(result.is_err());
+        assert!(answer.starts_with("HTTP/1.1 400 "), "{}", answer);
+    }
 }
@@ -0,0 +1,549 @@
+//! Request targets and `Host` headers, checked and normalized the same way by the router, the
+//! file server and the proxy.
+//!
+//! A target in absolute form (`GET http://example.com/path HTTP/1.1`), which clients send when
+//! they take zstdp for a forward proxy, is reduced to its path and query, and its authority
+//! replaces the `Host` header (RFC 9112, section 3.2.2). Requests with more than one `Host`
+//! header, an invalid one, or an absolute target zstdp cannot serve are answered with
+//! `400 Bad Request`, as are request lines that are not a method, a target and an `HTTP/1.x`
+//! version. Request lines are read no further than `--max-uri-length` allows, and longer targets
+//! are answered with `414 URI Too Long`; header sections larger than `--max-header-size` or with
+//! more lines than `--max-headers` are answered with `431 Request Header Fields Too Large`.
+//! Hostile clients thus cannot make zstdp buffer, match and log requests of any size. Heads that
+//! take longer than `--client-header-timeout` to arrive are answered with `408 Request Timeout`.
+//!
+//! With `--allowed-hosts`, requests for other hosts are answered with `421 Misdirected Request`
+//! and requests without a host with `400 Bad Request`, before they are routed, so that a forged
+//! `Host` never reaches a backend or picks a route. With `--default-host` as well, such requests
+//! are handled as if they were for that host instead.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+use crate::client::ClientStream;
+use crate::slow_clients;
+
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8192;
+
+pub const DEFAULT_MAX_HEADER_SIZE: usize = 32768;
+
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// Room on a request line for the method and version around the target.
+const REQUEST_LINE_SLACK: usize = 64;
+
+/// How large requests may be and which hosts they may be for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestLimits {
+    /// Longest request target, in bytes, answered with 414 beyond
+    pub max_uri_length: usize,
+    /// Largest header section, in bytes, answered with 431 beyond
+    pub max_header_size: usize,
+    /// Most header lines, answered with 431 beyond
+    pub max_headers: usize,
+    /// Largest request body, in bytes, answered with 413 beyond; `None` for any size
+    pub max_body_size: Option<u64>,
+    pub hosts: HostPolicy,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        RequestLimits {
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_body_size: None,
+            hosts: HostPolicy::default(),
+        }
+    }
+}
+
+/// The hosts requests may be for.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HostPolicy {
+    /// Lowercase host names, or `*.` and a domain for the names below it; empty to allow any host
+    allowed: Vec<String>,
+    /// Host given to requests for no allowed host
+    default: Option<String>,
+}
+
+impl HostPolicy {
+    /// Answers requests for hosts other than `allowed` with 421, and those without a host with
+    /// 400, or handles both as requests for `default`. An empty `allowed` lets any host through.
+    pub fn new(allowed: &[String], default: Option<String>) -> Self {
+        HostPolicy {
+            allowed: allowed
+                .iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            default,
+        }
+    }
+
+    /// Whether requests are checked against a list of hosts.
+    pub fn is_restricted(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        let name = strip_port(host).to_ascii_lowercase();
+        self.allowed
+            .iter()
+            .any(|allowed| match allowed.strip_prefix('*') {
+                Some(suffix) => name.len() > suffix.len() && name.ends_with(suffix),
+                None => name == *allowed,
+            })
+    }
+}
+
+/// A request for a host that `--allowed-hosts` does not list.
+#[derive(Debug)]
+pub struct MisdirectedHost {
+    host: String,
+}
+
+impl fmt::Display for MisdirectedHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request for a host not served here: {}", self.host)
+    }
+}
+
+impl Error for MisdirectedHost {}
+
+/// Whether `e` comes from a request for a host that is not allowed.
+pub fn is_misdirected(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<MisdirectedHost>())
+}
+
+/// A request target longer than `--max-uri-length`.
+#[derive(Debug)]
+pub struct UriTooLong {
+    max: usize,
+}
+
+impl fmt::Display for UriTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request target longer than {} bytes", self.max)
+    }
+}
+
+impl Error for UriTooLong {}
+
+/// A header section larger than `--max-header-size`, or with more lines than `--max-headers`.
+#[derive(Debug)]
+pub enum HeaderTooLarge {
+    Size(usize),
+    Count(usize),
+}
+
+impl fmt::Display for HeaderTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderTooLarge::Size(max) => {
+                write!(f, "Request header section larger than {} bytes", max)
+            }
+            HeaderTooLarge::Count(max) => write!(f, "More than {} request header lines", max),
+        }
+    }
+}
+
+impl Error for HeaderTooLarge {}
+
+/// Reads a request line, line ending included. A line whose target is longer than the
+/// `max_uri_length` of `limits` is not read past the limit and fails with an `InvalidInput` error
+/// that `is_uri_too_long` recognizes; a line that is not a request line fails with another
+/// `InvalidInput` error, and a connection closed before sending anything with `UnexpectedEof`.
+pub fn read_request_line<R: BufRead>(reader: &mut R, limits: &RequestLimits) -> io::Result<String> {
+    let max = limits.max_uri_length;
+    let limit = max.saturating_add(REQUEST_LINE_SLACK);
+    let mut line = Vec::new();
+    reader.take(limit as u64 + 1).read_until(b'\n', &mut line)?;
+    let line = String::from_utf8_lossy(&line).into_owned();
+    let too_long = line.len() > limit
+        || line
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|target| target.len() > max);
+    if too_long {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            UriTooLong { max },
+        ));
+    }
+    if line.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed before a request",
+        ));
+    }
+    check_request_line(&line)?;
+    Ok(line)
+}
+
+/// Checks that `line` is a method, a target and an `HTTP/1.x` version, and that the target is in
+/// one of the forms of RFC 9112, section 3.2.
+fn check_request_line(line: &str) -> io::Result<()> {
+    let [method, target, version] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err(bad_request(format!(
+            "Malformed request line {:?}",
+            line.trim_end()
+        )));
+    };
+    let is_tchar = |byte: u8| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte);
+    if !method.bytes().all(is_tchar) {
+        return Err(bad_request(format!("Invalid method {:?}", method)));
+    }
+    let is_http1 = version
+        .strip_prefix("HTTP/1.")
+        .is_some_and(|minor| minor.len() == 1 && minor.as_bytes()[0].is_ascii_digit());
+    if !is_http1 {
+        return Err(bad_request(format!("Unsupported version {:?}", version)));
+    }
+    let valid_target = target.starts_with('/')
+        || target == "*"
+        || target.contains("://")
+        || method.eq_ignore_ascii_case("CONNECT");
+    if !valid_target || target.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(bad_request(format!("Invalid request target {:?}", target)));
+    }
+    Ok(())
+}
+
+/// Whether `e` comes from a request line refused by `read_request_line`.
+pub fn is_uri_too_long(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<UriTooLong>())
+}
+
+/// Reads the header section that follows a request line, one line at a time, no further than
+/// the `max_header_size` and `max_headers` of its limits allow. Reaching the end of the section
+/// ends the time the client has for its request head.
+pub struct HeaderLines {
+    max_size: usize,
+    max_lines: usize,
+    /// Bytes the rest of the section may take
+    left: usize,
+    /// Lines the rest of the section may have
+    lines_left: usize,
+}
+
+impl HeaderLines {
+    pub fn new(limits: &RequestLimits) -> Self {
+        HeaderLines {
+            max_size: limits.max_header_size,
+            max_lines: limits.max_headers,
+            left: limits.max_header_size,
+            lines_left: limits.max_headers,
+        }
+    }
+
+    /// The next header line, line ending included, or `None` once the blank line that ends the
+    /// section or the end of the stream is reached. A section that grows past either limit fails
+    /// with an `InvalidInput` error that `is_header_too_large` recognizes.
+    pub fn next<R: BufRead>(&mut self, reader: &mut R) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        reader
+            .take(self.left as u64 + 1)
+            .read_until(b'\n', &mut line)?;
+        if line.len() > self.left {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                HeaderTooLarge::Size(self.max_size),
+            ));
+        }
+        self.left -= line.len();
+        let line = String::from_utf8_lossy(&line).into_owned();
+        if line.trim().is_empty() {
+            slow_clients::end_head();
+            return Ok(None);
+        }
+        if self.lines_left == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                HeaderTooLarge::Count(self.max_lines),
+            ));
+        }
+        self.lines_left -= 1;
+        Ok(Some(line))
+    }
+}
+
+/// Whether `e` comes from a header section refused by `HeaderLines`.
+pub fn is_header_too_large(e: &io::Error) -> bool {
+    e.get_ref()
+        .is_some_and(|inner| inner.is::<HeaderTooLarge>())
+}
+
+/// The status a request head refused by `read_request_line`, `HeaderLines` or
+/// `RequestTarget::parse`, or a request that arrived too slowly, is answered with.
+pub fn rejection_status(e: &io::Error) -> &'static str {
+    if is_uri_too_long(e) {
+        "414 URI Too Long"
+    } else if is_header_too_large(e) {
+        "431 Request Header Fields Too Large"
+    } else if slow_clients::is_head_timeout(e) || slow_clients::is_body_timeout(e) {
+        "408 Request Timeout"
+    } else if is_misdirected(e) {
+        "421 Misdirected Request"
+    } else {
+        "400 Bad Request"
+    }
+}
+
+/// Where a request goes, once normalized.
+#[derive(Debug)]
+pub struct RequestTarget {
+    /// The target in origin form: a path and an optional query
+    pub path: String,
+    /// Host the request is for, from an absolute target or the `Host` header
+    pub host: Option<String>,
+}
+
+impl RequestTarget {
+    /// Normalizes `target` from the request line, given the values of all the `Host` headers of
+    /// the request, and checks its host against `policy`. Errors are of kind `InvalidInput`, and
+    /// answered with the status of `rejection_status`.
+    pub fn parse(target: &str, hosts: &[&str], policy: &HostPolicy) -> io::Result<Self> {
+        let mut target = Self::normalize(target, hosts)?;
+        if !policy.is_restricted() {
+            return Ok(target);
+        }
+        match target.host.as_deref() {
+            Some(host) if policy.allows(host) => {}
+            _ if policy.default.is_some() => target.host = policy.default.clone(),
+            Some(host) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    MisdirectedHost {
+                        host: host.to_string(),
+                    },
+                ))
+            }
+            None => return Err(bad_request("Missing Host header")),
+        }
+        Ok(target)
+    }
+
+    fn normalize(target: &str, hosts: &[&str]) -> io::Result<Self> {
+        let host = match hosts {
+            [] => None,
+            [host] => Some(valid_host(host.trim())?.to_string()),
+            _ => return Err(bad_request("More than one Host header")),
+        };
+        if target.starts_with('/') || target == "*" || !target.contains("://") {
+            return Ok(RequestTarget {
+                path: target.to_string(),
+                host,
+            });
+        }
+
+        let (scheme, rest) = target.split_once("://").unwrap_or_default();
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(bad_request(format!("Unsupported target scheme {}", scheme)));
+        }
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(end) => rest.split_at(end),
+            None => (rest, ""),
+        };
+        if authority.is_empty() || authority.contains('@') {
+            return Err(bad_request(format!(
+                "Invalid target authority {}",
+                authority
+            )));
+        }
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        Ok(RequestTarget {
+            path,
+            host: Some(valid_host(authority)?.to_string()),
+        })
+    }
+}
+
+/// `host` without its port, if it has one.
+pub fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
+
+/// `host` if it only has the characters of a URI authority without user information.
+fn valid_host(host: &str) -> io::Result<&str> {
+    let valid = host
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:[]%".contains(&byte));
+    if valid {
+        Ok(host)
+    } else {
+        Err(bad_request(format!("Invalid Host {:?}", host)))
+    }
+}
+
+fn bad_request(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason.into())
+}
+
+/// Answers a request that cannot be served as it was sent.
+pub fn write_bad_request(mut client: &ClientStream) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 400 Bad Request\r\n")?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 11\r\n")?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Bad Request")
+}
+
+/// Answers a request whose head `read_request_line` or `HeaderLines` refused with `e`.
+pub fn write_rejection(mut client: impl Write, e: &io::Error) -> io::Result<()> {
+    let status = rejection_status(e);
+    let (_, reason) = status.split_once(' ').unwrap_or_default();
+    client.write_all(format!("HTTP/1.1 {}\r\n", status).as_bytes())?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(format!("Content-Length: {}\r\n", reason.len()).as_bytes())?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(reason.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            max_uri_length: 32,
+            max_header_size: 64,
+            max_headers: 3,
+            ..RequestLimits::default()
+        }
+    }
+
+    fn request_line(line: &str) -> io::Result<String> {
+        read_request_line(&mut line.as_bytes(), &limits())
+    }
+
+    fn status(result: io::Result<impl fmt::Debug>) -> &'static str {
+        rejection_status(&result.unwrap_err())
+    }
+
+    fn target(target: &str, hosts: &[&str], policy: &HostPolicy) -> io::Result<(String, String)> {
+        RequestTarget::parse(target, hosts, policy)
+            .map(|target| (target.path, target.host.unwrap_or_default()))
+    }
+
+    #[test]
+    fn request_lines_are_checked() {
+        assert_eq!(
+            request_line("GET / HTTP/1.1\r\n").unwrap(),
+            "GET / HTTP/1.1\r\n"
+        );
+        assert!(request_line("CONNECT example.com:443 HTTP/1.1\r\n").is_ok());
+        assert!(request_line("OPTIONS * HTTP/1.0\r\n").is_ok());
+        for line in [
+            "GET /\r\n",
+            "GET / HTTP/2.0\r\n",
+            "G(T / HTTP/1.1\r\n",
+            "GET path HTTP/1.1\r\n",
+            "GET /a\tb HTTP/1.1 x\r\n",
+        ] {
+            assert_eq!(status(request_line(line)), "400 Bad Request", "{:?}", line);
+        }
+        let e = request_line("").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn long_targets_are_answered_with_414() {
+        let line = format!("GET /{} HTTP/1.1\r\n", "a".repeat(31));
+        assert!(request_line(&line).is_ok());
+        let line = format!("GET /{} HTTP/1.1\r\n", "a".repeat(32));
+        assert_eq!(status(request_line(&line)), "414 URI Too Long");
+        let line = format!("GET /{} HTTP/1.1\r\n", "a".repeat(1000));
+        assert_eq!(status(request_line(&line)), "414 URI Too Long");
+    }
+
+    #[test]
+    fn header_sections_are_bounded() {
+        let read_all = |section: &str| {
+            let mut reader = section.as_bytes();
+            let mut lines = HeaderLines::new(&limits());
+            let mut count = 0;
+            while lines.next(&mut reader)?.is_some() {
+                count += 1;
+            }
+            Ok::<_, io::Error>(count)
+        };
+        assert_eq!(read_all("A: 1\r\nB: 2\r\nC: 3\r\n\r\nbody").unwrap(), 3);
+        let too_many = "A: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n\r\n";
+        assert_eq!(
+            status(read_all(too_many)),
+            "431 Request Header Fields Too Large"
+        );
+        let too_large = format!("A: {}\r\n\r\n", "x".repeat(64));
+        assert_eq!(
+            status(read_all(&too_large)),
+            "431 Request Header Fields Too Large"
+        );
+    }
+
+    #[test]
+    fn absolute_targets_are_reduced_to_their_path() {
+        let any = HostPolicy::default();
+        assert_eq!(
+            target("http://Example.com:8080/a?b", &["other"], &any).unwrap(),
+            ("/a?b".to_string(), "Example.com:8080".to_string())
+        );
+        assert_eq!(
+            target("https://example.com?q", &[], &any).unwrap(),
+            ("/?q".to_string(), "example.com".to_string())
+        );
+        assert_eq!(
+            target("/path", &[" example.com "], &any).unwrap(),
+            ("/path".to_string(), "example.com".to_string())
+        );
+        for (path, hosts) in [
+            ("/", &["a", "b"][..]),
+            ("/", &["a b"]),
+            ("ftp://example.com/", &[]),
+            ("http://user@example.com/", &[]),
+            ("http:///path", &[]),
+        ] {
+            assert_eq!(
+                status(target(path, hosts, &any)),
+                "400 Bad Request",
+                "{} {:?}",
+                path,
+                hosts
+            );
+        }
+    }
+
+    #[test]
+    fn hosts_are_checked_against_the_allowed_ones() {
+        let allowed = ["example.com".to_string(), "*.example.org".to_string()];
+        let policy = HostPolicy::new(&allowed, None);
+        assert!(target("/", &["EXAMPLE.com:443"], &policy).is_ok());
+        assert!(target("/", &["www.example.org"], &policy).is_ok());
+        assert_eq!(
+            status(target("/", &["example.org"], &policy)),
+            "421 Misdirected Request"
+        );
+        assert_eq!(
+            status(target("/", &["evil.com"], &policy)),
+            "421 Misdirected Request"
+        );
+        assert_eq!(status(target("/", &[], &policy)), "400 Bad Request");
+
+        let policy = HostPolicy::new(&allowed, Some("example.com".to_string()));
+        assert_eq!(
+            target("http://evil.com/x", &[], &policy).unwrap(),
+            ("/x".to_string(), "example.com".to_string())
+        );
+        assert_eq!(target("/", &[], &policy).unwrap().1, "example.com");
+    }
+}
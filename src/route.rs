@@ -0,0 +1,501 @@
+use regex::Regex;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::args::{should_bypass_compression, Args};
+use crate::chaos::Fault;
+use crate::client_hints::{ClientHintPolicy, ClientHints};
+use crate::compression::{CompressionOptions, CompressionType, ZstdLevelPolicy, DEFAULT_ENCODINGS};
+use crate::file_serving::archive::Archive;
+use crate::file_serving::bucket::Bucket;
+use crate::file_serving::dotfiles::DotfilePolicy;
+use crate::file_serving::error_pages::ErrorPages;
+use crate::file_serving::manifest::Manifest;
+use crate::file_serving::spa::SpaConfig;
+use crate::header_case::HeaderCase;
+use crate::metrics::SECRET_COOKIE_BYPASSES;
+use crate::patterns;
+use crate::proxy::auth::AuthProvider;
+use crate::proxy::backend::Upstream;
+use crate::proxy::balancer::{Backends, Ejection};
+use crate::proxy::connect::ConnectRule;
+use crate::proxy::fastcgi::FastCgiAddr;
+use crate::proxy::forward_auth::ForwardAuth;
+use crate::proxy::headers::HeaderAllowList;
+use crate::proxy::shield::{Shield, ShieldPolicy};
+use crate::proxy::token_auth::StaticTokens;
+use crate::proxy::{BackendTimeouts, HostRewrite};
+use crate::request_target::RequestLimits;
+use crate::schedule::{self, Window};
+use crate::tunnel::TunnelLimits;
+
+/// How requests are answered: where they go and the policies applied on the way. Built once from
+/// the command line and shared by both modes.
+pub struct RouteConfig {
+    pub target: Target,
+    pub compression: CompressionPolicy,
+    pub client_hints: ClientHintPolicy,
+    /// Clients allowed to pin response encodings with the force-encoding header
+    pub encoding_pinners: Vec<IpAddr>,
+    /// Windows during which requests are answered with 503 instead
+    pub maintenance: Vec<Window>,
+    /// Provider asked whether each request may be served
+    pub auth: Option<Box<dyn AuthProvider>>,
+    pub requests: RequestLimits,
+    /// How header names are spelled in responses
+    pub header_case: HeaderCase,
+}
+
+/// Where responses come from, along with the policies that only apply there.
+pub enum Target {
+    Backend {
+        backends: Backends,
+        policy: Box<ProxyPolicy>,
+    },
+    Directory(Box<ServeDir>),
+    FastCgi(Box<FastCgiApp>),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Backend { backends, .. } => write!(f, "Proxy → {}", backends),
+            Target::Directory(dir) => write!(f, "File Server → {}", dir.root.display()),
+            Target::FastCgi(app) => write!(
+                f,
+                "FastCGI → {} ({})",
+                app.addr,
+                app.static_files.root.display()
+            ),
+        }
+    }
+}
+
+/// A FastCGI application, such as a PHP site behind PHP-FPM.
+pub struct FastCgiApp {
+    pub addr: FastCgiAddr,
+    /// Script run for directories and for paths that match no file
+    pub index: String,
+    pub policy: ProxyPolicy,
+    /// The document root, served directly for files other than scripts. Both zstdp and the
+    /// FastCGI server see it at `static_files.root`.
+    pub static_files: ServeDir,
+}
+
+/// A directory served in file server mode.
+pub struct ServeDir {
+    /// Canonical path of the served directory
+    pub root: PathBuf,
+    pub spa: Option<SpaConfig>,
+    /// Pre-compressed siblings listed by `zstdp precompress`, instead of probing for them
+    pub manifest: Option<Manifest>,
+    /// Set when `root` is an archive, whose members are served from memory instead
+    pub archive: Option<Archive>,
+    /// Set when objects are fetched from an S3-compatible bucket instead of `root`
+    pub bucket: Option<Bucket>,
+    pub error_pages: ErrorPages,
+    /// Files that directories resolve to, in order of preference
+    pub index_files: Vec<String>,
+    pub dotfiles: DotfilePolicy,
+}
+
+pub struct CompressionPolicy {
+    pub options: CompressionOptions,
+    pub zstd_level: ZstdLevelPolicy,
+    /// URIs whose responses are sent uncompressed
+    pub bypass: Vec<Regex>,
+    /// Cookies whose presence leaves backend responses uncompressed, as the pages of a session may
+    /// carry secrets next to reflected request data
+    pub secret_cookies: Vec<String>,
+    /// How often streamed responses are flushed to the client (proxy mode)
+    pub flush_interval: Duration,
+    /// Re-encode gzip and deflate backend responses as zstd (proxy mode)
+    pub transcode_gzip: bool,
+    /// End compressed responses with a `Content-Digest` trailer
+    pub content_digest: bool,
+    /// Encodings offered to clients, most preferred first, or `None` for the defaults
+    pub encodings: Option<Vec<CompressionType>>,
+}
+
+#[derive(Clone)]
+pub struct ProxyPolicy {
+    pub timeouts: BackendTimeouts,
+    pub ignore_client_abort: bool,
+    /// Open backend connections with a PROXY protocol header
+    pub proxy_protocol: bool,
+    pub host_rewrite: HostRewrite,
+    /// Destinations `CONNECT` requests may open tunnels to
+    pub connect_allow: Vec<ConnectRule>,
+    /// Relay connections that open with the HTTP/2 preface to the backend as they are
+    pub h2c_passthrough: bool,
+    /// How long `CONNECT` tunnels, upgraded connections and HTTP/2 relays stay open
+    pub tunnel: TunnelLimits,
+    pub chaos: Vec<Fault>,
+    /// Backend response headers forwarded to clients, or `None` to forward all of them
+    pub allowed_response_headers: Option<HeaderAllowList>,
+    /// Where responses are stored and what they are keyed on, or `None` when they are not
+    pub shield: Option<ShieldPolicy>,
+}
+
+impl RouteConfig {
+    /// The route of `args`, whose backend responses go to `shield` if it is given and `--shield`
+    /// is set.
+    pub fn from_args(args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<Self> {
+        let index_files: Vec<String> = args
+            .index
+            .iter()
+            .filter(|index| !index.is_empty())
+            .cloned()
+            .collect();
+        if index_files.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--index needs at least one file name",
+            ));
+        }
+        if args.h2c_passthrough {
+            // Relayed connections are never read, so none of these could apply to them
+            let checks = [
+                (
+                    args.auth_token_file.is_some() || args.forward_auth.is_some(),
+                    "authentication",
+                ),
+                (!args.allowed_hosts.is_empty(), "--allowed-hosts"),
+                (args.max_body_size.is_some(), "--max-body-size"),
+            ];
+            if let Some((_, check)) = checks.iter().find(|(set, _)| *set) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "--h2c-passthrough relays connections unchecked, and cannot be combined \
+                         with {}",
+                        check
+                    ),
+                ));
+            }
+        }
+        let policy = || ProxyPolicy {
+            timeouts: args.backend_timeouts(),
+            ignore_client_abort: args.ignore_client_abort,
+            proxy_protocol: args.backend_proxy_protocol,
+            host_rewrite: args.host_rewrite.clone(),
+            connect_allow: args.connect_allow.clone(),
+            h2c_passthrough: args.h2c_passthrough,
+            tunnel: args.tunnel_limits(),
+            chaos: args.chaos.clone(),
+            allowed_response_headers: args
+                .strict_response_headers
+                .then(|| HeaderAllowList::new(&args.allow_response_header)),
+            shield: shield.filter(|_| args.shield).map(|store| ShieldPolicy {
+                store: Arc::clone(store),
+                key: args.cache_key.clone(),
+            }),
+        };
+        let target = match (&args.forward, &args.serve, &args.s3) {
+            (Some(Upstream::Http(backends)), None, None) => Target::Backend {
+                backends: backends
+                    .clone()
+                    .with_strategy(args.lb_strategy)
+                    .with_ejection(Ejection {
+                        max_fails: args.max_fails,
+                        fail_timeout: args.fail_timeout,
+                    }),
+                policy: Box::new(policy()),
+            },
+            (Some(Upstream::FastCgi(addr)), None, None) => {
+                let root = args.fastcgi_root.as_deref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "A FastCGI backend needs its document root in --fastcgi-root",
+                    )
+                })?;
+                let root = std::fs::canonicalize(root)?;
+                let error_pages = error_pages(args, |page| {
+                    root.join(page.trim_start_matches('/')).is_file()
+                });
+                Target::FastCgi(Box::new(FastCgiApp {
+                    addr: addr.clone(),
+                    index: args.fastcgi_index.clone(),
+                    policy: policy(),
+                    static_files: ServeDir {
+                        root,
+                        spa: None,
+                        manifest: args.manifest.as_deref().map(load_manifest).transpose()?,
+                        archive: None,
+                        bucket: None,
+                        error_pages,
+                        index_files,
+                        dotfiles: args.hide_dotfiles,
+                    },
+                }))
+            }
+            (None, Some(serve_dir), None) => {
+                let root = std::fs::canonicalize(serve_dir)?;
+                let archive = if root.is_file() {
+                    if !Archive::is_archive(&root) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "{} is neither a directory nor a .tar or .tar.zst archive",
+                                root.display()
+                            ),
+                        ));
+                    }
+                    Some(Archive::load(&root)?)
+                } else {
+                    None
+                };
+                let error_pages = error_pages(args, |page| match &archive {
+                    Some(archive) => archive.contains(page),
+                    None => root.join(page.trim_start_matches('/')).is_file(),
+                });
+                Target::Directory(Box::new(ServeDir {
+                    root,
+                    spa: args.spa.then(SpaConfig::new),
+                    manifest: args.manifest.as_deref().map(load_manifest).transpose()?,
+                    archive,
+                    bucket: None,
+                    error_pages,
+                    index_files,
+                    dotfiles: args.hide_dotfiles,
+                }))
+            }
+            (None, None, Some(url)) => {
+                let cache = args
+                    .s3_cache_dir
+                    .clone()
+                    .map(|dir| (dir, args.s3_cache_ttl));
+                let bucket = Bucket::new(url, &args.s3_region, cache, args.backend_timeouts())?;
+                Target::Directory(Box::new(ServeDir {
+                    root: PathBuf::from(url),
+                    spa: args.spa.then(SpaConfig::new),
+                    manifest: None,
+                    archive: None,
+                    error_pages: error_pages(args, |page| bucket.contains(page)),
+                    bucket: Some(bucket),
+                    index_files,
+                    dotfiles: args.hide_dotfiles,
+                }))
+            }
+            (None, None, None) => {
+                let archive = Archive::embedded()?.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "--embedded needs a binary built with ZSTDP_EMBED_ARCHIVE set",
+                    )
+                })?;
+                Target::Directory(Box::new(ServeDir {
+                    root: PathBuf::from("<embedded>"),
+                    spa: args.spa.then(SpaConfig::new),
+                    manifest: None,
+                    error_pages: error_pages(args, |page| archive.contains(page)),
+                    archive: Some(archive),
+                    bucket: None,
+                    index_files,
+                    dotfiles: args.hide_dotfiles,
+                }))
+            }
+            _ => unreachable!(),
+        };
+
+        let bypass = patterns::compile(&args.bypass, "--bypass")?;
+        if !bypass.is_empty() {
+            log::info!("Loaded {} bypass patterns for compression", bypass.len());
+        }
+        for window in &args.maintenance {
+            log::info!("Maintenance scheduled {}", window);
+        }
+        let save_data_skip = patterns::compile(&args.save_data_skip, "--save-data-skip")?;
+        if !save_data_skip.is_empty() {
+            log::info!("Loaded {} save-data skip patterns", save_data_skip.len());
+        }
+
+        Ok(RouteConfig {
+            target,
+            compression: CompressionPolicy {
+                options: args.compression_options(),
+                zstd_level: args.zstd_level_policy(),
+                bypass,
+                secret_cookies: args
+                    .secret_cookie
+                    .iter()
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .collect(),
+                flush_interval: args.flush_interval,
+                transcode_gzip: args.transcode_gzip,
+                content_digest: args.content_digest,
+                encodings: args.encodings.as_ref().map(|encodings| {
+                    let mut unique = Vec::new();
+                    for &encoding in encodings {
+                        if !unique.contains(&encoding) {
+                            unique.push(encoding);
+                        }
+                    }
+                    unique
+                }),
+            },
+            client_hints: ClientHintPolicy {
+                request_link_hints: args.adaptive_zstd,
+                save_data_skip,
+            },
+            encoding_pinners: args.trust_force_encoding.clone(),
+            maintenance: args.maintenance.clone(),
+            auth: match (&args.auth_token_file, &args.forward_auth) {
+                (Some(path), _) => {
+                    Some(Box::new(StaticTokens::load(path)?) as Box<dyn AuthProvider>)
+                }
+                (None, Some(url)) => Some(Box::new(ForwardAuth::new(
+                    url,
+                    &args.forward_auth_request_header,
+                    &args.forward_auth_response_header,
+                    args.backend_timeouts(),
+                )?) as Box<dyn AuthProvider>),
+                (None, None) => None,
+            },
+            requests: args.request_limits(),
+            header_case: args.header_case,
+        })
+    }
+
+    /// When the maintenance window the route is in closes, or `None` outside maintenance.
+    pub fn maintenance_until(&self, now: SystemTime) -> Option<SystemTime> {
+        schedule::open_until(&self.maintenance, now)
+    }
+
+    /// Whether `peer` may pin response encodings with the force-encoding header.
+    pub fn trusts_forced_encoding(&self, peer: IpAddr) -> bool {
+        self.encoding_pinners.contains(&peer)
+    }
+}
+
+impl CompressionPolicy {
+    pub fn bypasses(&self, uri: &str) -> bool {
+        let bypass = should_bypass_compression(uri, &self.bypass);
+        if bypass {
+            log::debug!("URI '{}' matches bypass pattern, skipping compression", uri);
+        }
+        bypass
+    }
+
+    /// Whether the request carries one of the secret cookies, in which case the backend response
+    /// is sent uncompressed.
+    pub fn guards_secrets(&self, headers: &[(String, String)]) -> bool {
+        if self.secret_cookies.is_empty() {
+            return false;
+        }
+        let found = headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.split_once('=').map(|(name, _)| name.trim()))
+            .find(|name| self.secret_cookies.iter().any(|secret| secret == name));
+        if let Some(name) = found {
+            log::debug!(
+                "Request carries secret cookie {}, skipping compression",
+                name
+            );
+            SECRET_COOKIE_BYPASSES.increment();
+        }
+        found.is_some()
+    }
+
+    /// The encodings of files, most preferred first.
+    pub fn file_encodings(&self) -> &[CompressionType] {
+        self.encodings.as_deref().unwrap_or(DEFAULT_ENCODINGS)
+    }
+
+    /// The encodings of backend responses, most preferred first. Unless configured otherwise,
+    /// gzip is left to the backend.
+    pub fn backend_encodings(&self) -> &[CompressionType] {
+        self.encodings
+            .as_deref()
+            .unwrap_or(&[CompressionType::Zstd, CompressionType::Brotli])
+    }
+
+    /// The compression options for a client, with the zstd level picked from its hints.
+    pub fn options_for(&self, hints: &ClientHints) -> CompressionOptions {
+        CompressionOptions {
+            zstd: self.zstd_level.level_for(hints),
+            ..self.options
+        }
+    }
+}
+
+fn load_manifest(path: &Path) -> io::Result<Manifest> {
+    let manifest = Manifest::load(path)?;
+    log::info!(
+        "Loaded manifest of {} pre-compressed files from {}",
+        manifest.len(),
+        path.display()
+    );
+    Ok(manifest)
+}
+
+/// The error pages given on the command line, or those found in the served root by `exists`.
+fn error_pages(args: &Args, exists: impl Fn(&str) -> bool) -> ErrorPages {
+    let error_pages = ErrorPages::new(
+        args.not_found_page.as_deref(),
+        args.error_page.as_deref(),
+        exists,
+    );
+    for (status, page) in [
+        (404, &error_pages.not_found),
+        (500, &error_pages.server_error),
+    ] {
+        if let Some(page) = page {
+            log::info!("Serving {} for {} responses", page, status);
+        }
+    }
+    error_pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn route(args: &[&str]) -> io::Result<RouteConfig> {
+        let args = ["zstdp", "-f", "127.0.0.1:9", "--h2c-passthrough"]
+            .iter()
+            .chain(args);
+        RouteConfig::from_args(&Args::try_parse_from(args).unwrap(), None)
+    }
+
+    fn refusal(args: &[&str]) -> String {
+        match route(args) {
+            Ok(_) => panic!("expected {:?} to be refused", args),
+            Err(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+                e.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn h2c_passthrough_alone_is_accepted() {
+        assert!(route(&[]).is_ok());
+    }
+
+    #[test]
+    fn h2c_passthrough_refuses_authentication() {
+        let refusal = refusal(&["--forward-auth", "http://127.0.0.1:9/auth"]);
+        assert!(refusal.ends_with("with authentication"), "{}", refusal);
+    }
+
+    #[test]
+    fn h2c_passthrough_refuses_allowed_hosts() {
+        let refusal = refusal(&["--allowed-hosts", "example.com"]);
+        assert!(refusal.ends_with("with --allowed-hosts"), "{}", refusal);
+    }
+
+    #[test]
+    fn h2c_passthrough_refuses_max_body_size() {
+        let refusal = refusal(&["--max-body-size", "1024"]);
+        assert!(refusal.ends_with("with --max-body-size"), "{}", refusal);
+    }
+}
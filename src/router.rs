@@ -0,0 +1,284 @@
+//! Picks the route of each connection when more than one target is configured, by peeking at the
+//! request head before the handler of the chosen route reads it.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::args::Args;
+use crate::client::ClientStream;
+use crate::discovery;
+use crate::proxy::shield::Shield;
+use crate::request_target::{strip_port, RequestTarget};
+use crate::route::RouteConfig;
+use crate::schedule::{self, Window};
+use crate::slow_clients;
+use crate::vhosts;
+
+/// Most of a request head that is looked at to pick its route.
+const MAX_PEEK: usize = 8192;
+
+/// How long the rest of a request head is waited for when reads have no timeout.
+const HEAD_WAIT: Duration = Duration::from_secs(1);
+
+/// Requests for an optional host and a path prefix, and the route that answers them.
+pub struct Route {
+    pub name: String,
+    host: Option<String>,
+    prefix: String,
+    /// Windows outside which the route is skipped, or none for a route that always applies
+    active: Vec<Window>,
+    pub config: Arc<RouteConfig>,
+}
+
+impl Route {
+    /// `prefix` matches itself and the paths below it; a trailing slash makes no difference.
+    pub fn new(name: &str, host: Option<String>, prefix: &str, config: Arc<RouteConfig>) -> Self {
+        Route {
+            name: name.to_string(),
+            host: host.map(|host| host.to_lowercase()),
+            prefix: format!("/{}", prefix.trim_matches('/')),
+            active: Vec::new(),
+            config,
+        }
+    }
+
+    /// Limits the route to the times `active` windows are open.
+    pub fn with_schedule(mut self, active: Vec<Window>) -> Self {
+        self.active = active;
+        self
+    }
+
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.active.is_empty() || schedule::open_until(&self.active, now).is_some()
+    }
+
+    fn matches(&self, host: Option<&str>, path: &str) -> bool {
+        let host_matches = match &self.host {
+            Some(expected) => host.is_some_and(|host| host.eq_ignore_ascii_case(expected)),
+            None => true,
+        };
+        let path_matches = match path.strip_prefix(self.prefix.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+        host_matches && path_matches
+    }
+
+    fn log(&self) {
+        log::info!(
+            "Route {}: {}{} → {}",
+            self.name,
+            self.host.as_deref().unwrap_or("*"),
+            self.prefix,
+            self.config.target
+        );
+        for window in &self.active {
+            log::info!("Route {} applies {}", self.name, window);
+        }
+    }
+}
+
+/// Orders routes the way they are tried: host-specific ones first, then longer prefixes first,
+/// then scheduled ones, so that a route limited to some times takes over from the one it shares
+/// its host and prefix with.
+pub fn sort(routes: &mut [Arc<Route>]) {
+    routes.sort_by_key(|route| {
+        std::cmp::Reverse((
+            route.host.is_some(),
+            route.prefix.len(),
+            !route.active.is_empty(),
+        ))
+    });
+}
+
+/// Logs `routes`, one line each.
+pub fn log_routes(routes: &[Arc<Route>]) {
+    for route in routes {
+        route.log();
+    }
+}
+
+/// The routes of the command line: with both `--forward` and a file source (`--serve`, `--s3`
+/// or `--embedded`), requests under the `--proxy-path` prefixes go to the backend and the others
+/// to the files. Routes discovered with `--routes-dir` are tried first, then the `--vhosts`
+/// virtual hosts.
+pub struct Router {
+    pub default: Arc<RouteConfig>,
+    proxied: Vec<Arc<Route>>,
+}
+
+impl Router {
+    /// The routes of `args`, whose backend responses go to `shield` if it is given.
+    pub fn from_args(args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<Self> {
+        let has_files = args.serve.is_some() || args.s3.is_some() || args.embedded;
+        if args.forward.is_none() || !has_files {
+            return Ok(Router {
+                default: Arc::new(RouteConfig::from_args(args, shield)?),
+                proxied: Vec::new(),
+            });
+        }
+
+        let mut files = args.clone();
+        files.forward = None;
+        let mut backend = args.clone();
+        backend.serve = None;
+        backend.s3 = None;
+        backend.embedded = false;
+        let backend = Arc::new(RouteConfig::from_args(&backend, shield)?);
+        let mut proxied: Vec<_> = args
+            .proxy_path
+            .iter()
+            .map(|prefix| Arc::new(Route::new("proxy", None, prefix, Arc::clone(&backend))))
+            .collect();
+        sort(&mut proxied);
+        log_routes(&proxied);
+
+        Ok(Router {
+            default: Arc::new(RouteConfig::from_args(&files, shield)?),
+            proxied,
+        })
+    }
+
+    /// The route for the request `client` is about to send.
+    pub fn route_for(&self, client: &ClientStream) -> Arc<RouteConfig> {
+        let discovered = discovery::routes();
+        let hosts = vhosts::hosts();
+        if discovered.is_empty() && hosts.is_empty() && self.proxied.is_empty() {
+            return Arc::clone(&self.default);
+        }
+        let Some(head) = peek_head(client) else {
+            return Arc::clone(&self.default);
+        };
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let Some(uri) = lines.next().and_then(|line| line.split_whitespace().nth(1)) else {
+            return Arc::clone(&self.default);
+        };
+        let host_headers: Vec<&str> = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+            .map(|(_, value)| value)
+            .collect();
+        match self.resolve(uri, &host_headers, SystemTime::now()) {
+            Some(route) => {
+                log::debug!("Request for {} takes route {}", uri, route.name);
+                Arc::clone(&route.config)
+            }
+            None => Arc::clone(&self.default),
+        }
+    }
+
+    /// The route that answers a request for `uri` with `host_headers` at `now`, or `None` for
+    /// the default one.
+    pub fn resolve(&self, uri: &str, host_headers: &[&str], now: SystemTime) -> Option<Arc<Route>> {
+        // The handler of the default route answers requests that cannot be normalized, and its
+        // hosts are the ones requests may be for before they are routed
+        let target = RequestTarget::parse(uri, host_headers, &self.default.requests.hosts).ok()?;
+        let path = target.path.split('?').next().unwrap_or(&target.path);
+        let host = target.host.as_deref().map(strip_port);
+        discovery::routes()
+            .iter()
+            .chain(&vhosts::hosts())
+            .chain(&self.proxied)
+            .find(|route| route.matches(host, path) && route.is_active(now))
+            .cloned()
+    }
+}
+
+/// Waits for the request head, or as much of it as fits in `MAX_PEEK`, leaving it unread.
+fn peek_head(client: &ClientStream) -> Option<Vec<u8>> {
+    let mut buf = vec![0; MAX_PEEK];
+    // Heads usually arrive at once; give slow clients as long as a read would wait for the rest
+    // before deciding on less
+    let wait = client.read_timeout().ok().flatten().unwrap_or(HEAD_WAIT);
+    let deadline = Instant::now() + wait;
+    let mut len = client.peek(&mut buf).ok()?;
+    if len == 0 {
+        return None;
+    }
+    while len < MAX_PEEK && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || !slow_clients::await_more(client, len, left).ok()? {
+            break;
+        }
+        // The client may have closed the connection instead
+        match client.peek(&mut buf).ok()? {
+            more if more > len => len = more,
+            _ => break,
+        }
+    }
+    buf.truncate(len);
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+
+    /// Connects a client that sends `parts` a little apart, and returns the server side.
+    fn trickle(parts: &[&'static str], close: bool) -> (ClientStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let parts = parts.to_vec();
+        let sending = thread::spawn(move || {
+            for part in parts {
+                sender.write_all(part.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+            if close {
+                sender.shutdown(Shutdown::Write).unwrap();
+            }
+            thread::sleep(Duration::from_millis(300));
+        });
+        thread::sleep(Duration::from_millis(10));
+        let client = ClientStream::Tcp(stream);
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (client, sending)
+    }
+
+    #[test]
+    fn heads_sent_in_pieces_are_waited_for() {
+        let (client, sending) = trickle(&["GET / HTTP/1.1\r\n", "Host: a\r\n", "\r\n"], false);
+        let started = Instant::now();
+        let head = peek_head(&client).unwrap();
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        sending.join().unwrap();
+    }
+
+    #[test]
+    fn unfinished_heads_are_given_up_on_after_the_read_timeout() {
+        let (mut client, sending) = trickle(&["GET / HTTP/1.1\r\nHo"], false);
+        client
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert_eq!(peek_head(&client).unwrap(), b"GET / HTTP/1.1\r\nHo");
+        // Reads take what there is again, without waiting for more
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let started = Instant::now();
+        let mut buf = [0; 64];
+        assert_eq!(client.read(&mut buf).unwrap(), 18);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        sending.join().unwrap();
+    }
+
+    #[test]
+    fn clients_closing_mid_head_are_not_waited_for() {
+        let (client, sending) = trickle(&["GET / HTTP/1.1\r\nHo"], true);
+        let started = Instant::now();
+        assert_eq!(peek_head(&client).unwrap(), b"GET / HTTP/1.1\r\nHo");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        sending.join().unwrap();
+    }
+}
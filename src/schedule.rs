@@ -0,0 +1,224 @@
+//! Recurring time windows, for routes that only apply at certain times and for planned
+//! maintenance.
+//!
+//! A window is a cron expression for when it opens and how long it stays open, such as
+//! `0 2 * * sun for 2h`: every Sunday from 02:00 to 04:00. The five fields are the minute, hour,
+//! day of the month, month and day of the week, each `*`, a number, a range `a-b` or a list of
+//! them, optionally with a step (`*/15`, `8-18/2`). Months and weekdays may also be given by
+//! their first three letters, and Sunday is 0 or 7. As in cron, a day matches when either of the
+//! two day fields does if both are restricted. Times are UTC.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A recurring window, given as `CRON for DURATION`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    cron: Cron,
+    duration: Duration,
+    /// The window as it was given, for logs
+    spec: String,
+}
+
+impl Window {
+    /// When the window `now` falls in closes, or `None` if it is closed.
+    pub fn end(&self, now: SystemTime) -> Option<SystemTime> {
+        let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let duration = self.duration.as_secs();
+        // Windows opening at `minute` cover `now` until `minute * 60 + duration`; look for the
+        // latest such opening, skipping whole days and hours that do not match
+        let mut minute = now / 60;
+        while minute * 60 + duration > now {
+            let day = minute / (24 * 60);
+            let hour = minute / 60 % 24;
+            let previous = if !self.cron.matches_day(day) {
+                (day * 24 * 60).checked_sub(1)
+            } else if self.cron.hours & 1 << hour == 0 {
+                (minute - minute % 60).checked_sub(1)
+            } else if self.cron.minutes & 1 << (minute % 60) == 0 {
+                minute.checked_sub(1)
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute * 60 + duration));
+            };
+            minute = previous?;
+        }
+        None
+    }
+}
+
+/// When the latest of `windows` that is open at `now` closes, or `None` if all are closed.
+pub fn open_until(windows: &[Window], now: SystemTime) -> Option<SystemTime> {
+    windows.iter().filter_map(|window| window.end(now)).max()
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cron, duration) = s.split_once(" for ").ok_or_else(|| {
+            format!(
+                "Expected a cron expression and a duration such as '0 2 * * sun for 2h', got '{}'",
+                s
+            )
+        })?;
+        let duration = humantime::parse_duration(duration.trim())
+            .map_err(|e| format!("Invalid window duration '{}': {}", duration.trim(), e))?;
+        if duration.as_secs() == 0 {
+            return Err(format!("Window '{}' lasts less than a second", s));
+        }
+        Ok(Window {
+            cron: cron.parse()?,
+            duration,
+            spec: s.split_whitespace().collect::<Vec<_>>().join(" "),
+        })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} UTC", self.spec)
+    }
+}
+
+/// The times a window opens, as bit sets of the values each field allows.
+#[derive(Debug, Clone, PartialEq)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month or the day of the week is `*`, which leaves the day to the
+    /// other field
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    /// Whether windows may open on `day`, counted from the Unix epoch.
+    fn matches_day(&self, day: u64) -> bool {
+        let (month, day_of_month) = month_and_day(day);
+        // 1970-01-01 was a Thursday
+        let weekday = (day + 4) % 7;
+        if self.months & 1 << month == 0 {
+            return false;
+        }
+        let by_date = self.days & 1 << day_of_month != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => by_weekday,
+            (false, true) => by_date,
+            (false, false) => by_date || by_weekday,
+        }
+    }
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "Expected five cron fields (minute hour day month weekday), got '{}'",
+                s
+            ));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7, &WEEKDAYS, 0)?;
+        // Sunday is both 0 and 7
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            minutes: parse_field(minutes, 0, 59, &[], 0)?,
+            hours: parse_field(hours, 0, 23, &[], 0)?,
+            days: parse_field(days, 1, 31, &[], 0)?,
+            months: parse_field(months, 1, 12, &MONTHS, 1)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+/// The values from `min` to `max` that the cron field `s` allows, as a bit set. `names` stand
+/// for the values from `first_named` on.
+fn parse_field(
+    s: &str,
+    min: u64,
+    max: u64,
+    names: &[&str],
+    first_named: u64,
+) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "Invalid cron field '{}', expected values from {} to {}",
+            s, min, max
+        )
+    };
+    let value = |v: &str| -> Result<u64, String> {
+        let value = match names.iter().position(|name| name.eq_ignore_ascii_case(v)) {
+            Some(index) => index as u64 + first_named,
+            None => v.parse().map_err(|_| invalid())?,
+        };
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(invalid())
+        }
+    };
+
+    let mut bits = 0;
+    for item in s.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (value(start)?, value(end)?)
+        } else if item.contains('/') {
+            // `5/10` runs from 5 to the end
+            (value(range)?, max)
+        } else {
+            let value = value(range)?;
+            (value, value)
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+/// The month (1 to 12) and day of the month of `day`, counted from the Unix epoch.
+fn month_and_day(day: u64) -> (u64, u64) {
+    // Days since 0000-03-01, in 400-year eras of 146097 days (Howard Hinnant's `civil_from_days`)
+    let z = day + 719_468;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (month, day_of_month)
+}
@@ -1,65 +1,148 @@
-use std::io::{self, BufRead, BufReader, ErrorKind};
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufReader, ErrorKind, Write};
 use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
-
-use regex::Regex;
+use std::time::SystemTime;
 
+use crate::admin::start_admin_server;
 use crate::args::Args;
+use crate::capture;
+use crate::client::{ClientStream, Listener};
+use crate::compression::FORCE_ENCODING_HEADER;
+use crate::connections::{self, OverLimit};
+use crate::context::ConnectionContext;
+use crate::discovery;
+use crate::file_serving::bucket;
 use crate::file_serving::handlers::handle_file_request;
-use crate::file_serving::spa::SpaConfig;
+use crate::header_case::{self, HeaderCase};
+use crate::header_stats::{self, Direction, HeaderThresholds};
 use crate::logging::LoggingExt;
+use crate::metrics::MAINTENANCE_RESPONSES;
+use crate::precompress;
+use crate::proxy::auth::{denied_status, Subject};
+use crate::proxy::fastcgi::handle_fastcgi_connection;
 use crate::proxy::handlers::handle_proxy_connection;
+use crate::proxy::shield::Shield;
+use crate::request_target::{
+    read_request_line, rejection_status, write_rejection, HeaderLines, RequestLimits, RequestTarget,
+};
+use crate::route::Target;
+use crate::router::Router;
+use crate::slow_clients;
+use crate::stats;
+use crate::stats_file;
+use crate::tls_passthrough::{self, Terminator};
+use crate::vhosts;
 use crate::{log_error, log_request, log_response};
 
 pub fn start_server(args: Args) -> io::Result<()> {
-    let listener = TcpListener::bind(args.listen_addr())?;
+    // Before any other thread starts, so that they all leave stopping signals to it
+    if let Some(path) = &args.stats_file {
+        stats_file::enable(path.clone())?;
+    }
+    let listener = Listener::bind(&args.listen_addr(), args.socket_mode)?;
     log::info!("Server started on: {}", args.listen_addr());
 
-    // Canonicalize the serve directory at startup if it exists
-    let args = if let Some(serve_dir) = &args.serve {
-        let canonical_dir = std::fs::canonicalize(serve_dir)?;
-        let mut new_args = args.clone();
-        new_args.serve = Some(canonical_dir);
-        new_args
-    } else {
-        args
-    };
-
-    match (&args.forward, &args.serve) {
-        (Some(addr), None) => log::info!("Mode: Proxy → {}", addr),
-        (None, Some(dir)) => log::info!("Mode: File Server → {}", dir.display()),
-        _ => unreachable!(),
+    if let Some(admin_addr) = &args.admin_listen {
+        start_admin_server(admin_addr)?;
     }
+    if let Some(capacity) = args.capture {
+        capture::enable(capacity, args.capture_body_bytes);
+    }
+    if let Some(interval) = args.stats_interval {
+        stats::start_reporter(interval);
+    }
+    header_stats::configure(HeaderThresholds {
+        fields: args.header_warn_fields,
+        bytes: args.header_warn_bytes,
+        field_bytes: args.header_warn_field_bytes,
+    });
+    let client_limits = args.client_limits();
+    let terminator = args.tls_passthrough.clone().map(|addr| {
+        Arc::new(Terminator {
+            addr,
+            tunnel: args.tunnel_limits(),
+        })
+    });
+    let shield = (args.shield
+        && (args.forward.is_some() || args.routes_dir.is_some() || args.vhosts.is_some()))
+    .then(|| {
+        Arc::new(Shield::new(
+            args.shield_max_size,
+            !args.shield_no_age,
+            args.shield_remote.clone(),
+        ))
+    });
 
-    let bypass_patterns = {
-        let patterns: Result<Vec<Regex>, regex::Error> =
-            args.bypass.iter().map(|p| Regex::new(p)).collect();
-
-        match patterns {
-            Ok(patterns) => {
-                if !patterns.is_empty() {
-                    log::info!("Loaded {} bypass patterns for compression", patterns.len());
-                }
-                Arc::new(patterns)
-            }
-            Err(e) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("Invalid bypass pattern: {}", e),
-                ));
-            }
+    // Writes cut short by a crash leave their temporary files behind
+    if let Some(dir) = args.serve.as_deref().filter(|dir| dir.is_dir()) {
+        if let Err(e) = precompress::remove_orphans_under(dir) {
+            log::warn!(
+                "Failed to clean up temporary files under {}: {}",
+                dir.display(),
+                e
+            );
         }
-    };
+    }
+    if let Some(dir) = args.s3_cache_dir.as_deref().filter(|dir| dir.is_dir()) {
+        if let Err(e) = bucket::remove_orphans(dir) {
+            log::warn!(
+                "Failed to clean up temporary files under {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+
+    let router = Arc::new(Router::from_args(&args, shield.as_ref())?);
+    log::info!("Mode: {}", router.default.target);
+    if let Some(dir) = &args.routes_dir {
+        discovery::watch(dir.clone(), &args, shield.as_ref(), args.routes_poll)?;
+    }
+    if let Some(path) = &args.vhosts {
+        vhosts::watch(path.clone(), &args, shield.as_ref(), args.routes_poll)?;
+    }
 
-    for stream in listener.incoming() {
-        match stream {
+    loop {
+        match listener.accept() {
             Ok(stream) => {
-                let args = args.clone();
-                let bypass_patterns = Arc::clone(&bypass_patterns);
+                let peer = match stream.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        log_error!(e, "Failed to get peer address");
+                        continue;
+                    }
+                };
+                let slot = connections::acquire(peer.ip(), args.max_connections_per_client);
+                let Some(slot) = slot else {
+                    match args.max_connections_answer {
+                        OverLimit::Drop => drop(stream),
+                        answer => {
+                            let header_case = router.default.header_case;
+                            thread::spawn(move || reject_connection(stream, answer, header_case));
+                        }
+                    }
+                    continue;
+                };
+
+                let router = Arc::clone(&router);
+                let terminator = terminator.clone();
                 thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream, &args, bypass_patterns) {
+                    let _slot = slot;
+                    if let Err(e) = slow_clients::apply(&stream, &client_limits) {
+                        log::warn!("Failed to limit buffering for a client: {}", e);
+                    }
+                    let Some(started) = slow_clients::await_request(&stream, peer, &client_limits)
+                    else {
+                        return;
+                    };
+                    if tls_passthrough::divert(&stream, terminator.as_deref()) {
+                        return;
+                    }
+                    let route = router.route_for(&stream);
+                    let context = ConnectionContext::new(peer, route, client_limits);
+                    slow_clients::start_head(&context, started);
+                    let _entered = context.enter();
+                    if let Err(e) = handle_connection(stream, &context) {
                         log_error!(e, "Connection handler failed");
                     }
                 });
@@ -69,89 +152,270 @@ pub fn start_server(args: Args) -> io::Result<()> {
             }
         }
     }
+}
 
-    Ok(())
+/// Turns away a client that already holds too many connections, with the status of `answer` and
+/// its header names spelled as `header_case` asks.
+fn reject_connection(mut client: ClientStream, answer: OverLimit, header_case: HeaderCase) {
+    let response: &[u8] = match answer {
+        OverLimit::TooManyRequests => {
+            b"HTTP/1.1 429 Too Many Requests\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Length: 17\r\n\
+            Connection: close\r\n\
+            \r\n\
+            Too Many Requests"
+        }
+        _ => {
+            b"HTTP/1.1 503 Service Unavailable\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Length: 19\r\n\
+            Connection: close\r\n\
+            \r\n\
+            Service Unavailable"
+        }
+    };
+    if let Err(e) = client.write_all(&header_case::rewrite(response, header_case)) {
+        log::debug!("Failed to send {}: {}", answer, e);
+    }
 }
 
-fn handle_connection(
-    client: TcpStream,
-    args: &Args,
-    bypass_patterns: Arc<Vec<Regex>>,
+/// Answers a request that arrived during a maintenance window closing at `until`, once it has
+/// been read within `limits`, so that the client sees the answer rather than a reset connection.
+fn answer_maintenance(
+    mut client: &ClientStream,
+    limits: &RequestLimits,
+    until: SystemTime,
 ) -> io::Result<()> {
-    let start_time = Instant::now();
-    let peer_addr = client.peer_addr()?;
-    log::debug!("→ New connection from {}", peer_addr);
+    let mut buf_reader = BufReader::new(client);
+    let first_line = match read_request_line(&mut buf_reader, limits) {
+        Ok(line) => line,
+        Err(e) if e.kind() == ErrorKind::InvalidInput => return write_rejection(client, &e),
+        Err(e) => return Err(e),
+    };
+    log_request!(&first_line);
+    let mut header_lines = HeaderLines::new(limits);
+    loop {
+        match header_lines.next(&mut buf_reader) {
+            Ok(Some(_)) => {}
+            Ok(None) => break,
+            Err(e) if e.kind() == ErrorKind::InvalidInput => return write_rejection(client, &e),
+            Err(e) => return Err(e),
+        }
+    }
 
-    let result = match (&args.forward, &args.serve) {
-        (Some(forward), None) => forward.log_operation("proxy_request", || {
-            let request_time = Instant::now();
-            let result = handle_proxy_connection(client, forward, args.zstd_level, bypass_patterns);
+    let retry_after = until
+        .duration_since(SystemTime::now())
+        .map_or(0, |left| left.as_secs() + 1);
+    client.write_all(b"HTTP/1.1 503 Service Unavailable\r\n")?;
+    client.write_all(format!("Retry-After: {}\r\n", retry_after).as_bytes())?;
+    client.write_all(b"Content-Type: text/plain\r\n")?;
+    client.write_all(b"Content-Length: 19\r\n")?;
+    client.write_all(b"Connection: close\r\n")?;
+    client.write_all(b"\r\n")?;
+    client.write_all(b"Service Unavailable")
+}
 
-            match &result {
-                Ok(_) => log_response!("200 OK", request_time.elapsed()),
-                Err(_) => log_response!("500 Internal Server Error", request_time.elapsed()),
-            }
+/// Logs the status a proxied request was most likely answered with.
+fn log_proxy_response(result: &io::Result<()>, context: &ConnectionContext) {
+    match result {
+        Ok(_) => log_response!(context, "200 OK"),
+        Err(e) if e.kind() == ErrorKind::TimedOut => {
+            log_response!(context, "504 Gateway Timeout")
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidData => {
+            log_response!(context, "502 Bad Gateway")
+        }
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            log_response!(context, rejection_status(e))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log_response!(context, "404 Not Found")
+        }
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            log_response!(context, denied_status(e).unwrap_or("403 Forbidden"))
+        }
+        Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+            log_response!(context, "413 Content Too Large")
+        }
+        Err(_) => log_response!(context, "500 Internal Server Error"),
+    }
+}
+
+fn handle_connection(mut client: ClientStream, context: &ConnectionContext) -> io::Result<()> {
+    let route = &context.route;
+    let peer_addr = context.peer;
+    log::debug!("→ New connection from {} ({})", peer_addr, route.target);
 
-            result
+    if let Some(until) = route.maintenance_until(SystemTime::now()) {
+        MAINTENANCE_RESPONSES.increment();
+        let result = answer_maintenance(&client, &route.requests, until);
+        log_response!(context, "503 Service Unavailable");
+        return result;
+    }
+
+    let result = match &route.target {
+        Target::Backend { backends, policy } => backends.log_operation("proxy_request", || {
+            let result = handle_proxy_connection(client, route, backends, policy);
+            log_proxy_response(&result, context);
+            match result {
+                // Malformed requests, refused tunnels and bodies too large were answered with 400,
+                // 403 and 413
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::InvalidInput
+                            | ErrorKind::PermissionDenied
+                            | ErrorKind::FileTooLarge
+                    ) =>
+                {
+                    Ok(())
+                }
+                result => result,
+            }
         }),
-        (None, Some(serve)) => serve.log_operation("serve_files", || {
+        Target::FastCgi(app) => app.addr.log_operation("fastcgi_request", || {
+            let result = handle_fastcgi_connection(client, route, app);
+            log_proxy_response(&result, context);
+            match result {
+                // Malformed requests, bodies too large and static files that are missing or hidden
+                // were answered
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::InvalidInput
+                            | ErrorKind::NotFound
+                            | ErrorKind::PermissionDenied
+                            | ErrorKind::FileTooLarge
+                    ) =>
+                {
+                    Ok(())
+                }
+                result => result,
+            }
+        }),
+        Target::Directory(dir) => dir.root.log_operation("serve_files", || {
             let mut buf_reader = BufReader::new(&client);
-            let mut first_line = String::new();
-            buf_reader.read_line(&mut first_line)?;
+            let first_line = match read_request_line(&mut buf_reader, &route.requests) {
+                Ok(line) => line,
+                Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                    log::debug!("Rejecting request: {}", e);
+                    write_rejection(&client, &e)?;
+                    log_response!(context, rejection_status(&e));
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
 
             // Add request logging
             log_request!(&first_line);
-            let request_time = Instant::now();
 
             let mut headers = Vec::new();
-            let mut line = String::new();
-            while {
-                line.clear();
-                buf_reader.read_line(&mut line)?;
-                !line.trim().is_empty()
-            } {
+            let mut header_lines = HeaderLines::new(&route.requests);
+            loop {
+                let line = match header_lines.next(&mut buf_reader) {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                        log::debug!("Rejecting request: {}", e);
+                        write_rejection(&client, &e)?;
+                        log_response!(context, rejection_status(&e));
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                };
                 let parts: Vec<&str> = line.splitn(2, ':').collect();
                 if parts.len() == 2 {
                     headers.push((parts[0].trim().to_string(), parts[1].trim().to_string()));
                 }
             }
 
-            let spa_config = if args.spa {
-                Some(SpaConfig::new())
-            } else {
-                None
+            let mut request_line = first_line.split_whitespace();
+            let method = request_line.next().unwrap_or("GET");
+            let hosts: Vec<&str> = headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("host"))
+                .map(|(_, v)| v.as_str())
+                .collect();
+            let target = request_line.next().unwrap_or("/");
+            let target = match RequestTarget::parse(target, &hosts, &route.requests.hosts) {
+                Ok(target) => target,
+                Err(e) => {
+                    log::debug!("Rejecting request for {}: {}", target, e);
+                    write_rejection(&client, &e)?;
+                    log_response!(context, rejection_status(&e));
+                    return Ok(());
+                }
             };
+            headers.retain(|(k, _)| !k.eq_ignore_ascii_case("host"));
+            if let Some(host) = target.host {
+                headers.push(("Host".to_string(), host));
+            }
+            let request_path = target.path.as_str();
+            header_stats::record(Direction::Request, request_path, &headers);
 
-            let result = handle_file_request(
-                client,
-                serve,
-                &first_line,
-                &headers,
-                args.zstd_level,
-                args.gzip_level,
-                &bypass_patterns,
-                spa_config.as_ref(),
-            );
+            if !route.trusts_forced_encoding(peer_addr.ip()) {
+                headers.retain(|(k, _)| !k.eq_ignore_ascii_case(FORCE_ENCODING_HEADER));
+            }
+
+            if let Some(auth) = &route.auth {
+                let subject = Subject {
+                    method,
+                    uri: request_path,
+                    host: headers
+                        .iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case("host"))
+                        .map(|(_, v)| v.as_str()),
+                    headers: &headers,
+                    peer: peer_addr.ip(),
+                    scheme: client.scheme(),
+                };
+                match auth.check(&mut client, &subject) {
+                    Ok(allowed) => {
+                        headers.retain(|(k, _)| {
+                            !auth
+                                .response_headers()
+                                .iter()
+                                .any(|name| name.eq_ignore_ascii_case(k))
+                        });
+                        headers.extend(allowed);
+                    }
+                    // Answered by the provider, with its own response or with 502 or 504
+                    Err(e) => {
+                        let result = Err(e);
+                        log_proxy_response(&result, context);
+                        return match result {
+                            Err(e) if e.kind() == ErrorKind::PermissionDenied => Ok(()),
+                            result => result,
+                        };
+                    }
+                }
+            }
+
+            let result = handle_file_request(client, route, dir, method, request_path, &headers);
 
             // Add response logging based on file existence
             match &result {
                 Ok(_) => {
-                    log_response!("200 OK", request_time.elapsed());
+                    log_response!(context, "200 OK");
                     Ok(())
                 }
                 Err(e) => match e.kind() {
                     ErrorKind::NotFound => {
-                        log_response!("404 Not Found", request_time.elapsed());
+                        log_response!(context, "404 Not Found");
+                        Ok(())
+                    }
+                    ErrorKind::PermissionDenied => {
+                        log_response!(context, "403 Forbidden");
                         Ok(())
                     }
                     _ => {
-                        log_response!("500 Internal Server Error", request_time.elapsed());
+                        log_response!(context, "500 Internal Server Error");
                         result
                     }
                 },
             }
         }),
-        _ => unreachable!(),
     };
 
     match result {
@@ -159,7 +423,7 @@ fn handle_connection(
             log::debug!(
                 "← Completed connection from {} in {:?}",
                 peer_addr,
-                start_time.elapsed()
+                context.elapsed()
             );
         }
         Err(e) => {
@@ -0,0 +1,300 @@
+//! Limits on what slow clients can hold up.
+//!
+//! Responses are written with blocking writes, so a client that reads slowly already holds back
+//! compression and reading from the backend: nothing is read ahead of what the client takes, and
+//! the buffers in between have fixed sizes. What is left to bound is the socket send buffer, which
+//! the kernel grows to several megabytes per connection on fast links, and how long a client that
+//! stops reading keeps a thread, an encoder and a backend connection.
+//!
+//! Reading is bounded as well, as each connection holds a thread from the moment it is accepted:
+//! a client has `--client-idle-timeout` to start its request, `--client-header-timeout` from then
+//! on to send the whole request head, however it trickles in, and may leave each read of the body
+//! waiting no longer than `--client-body-timeout`. Clients that send nothing are dropped without
+//! an answer; slow request heads are answered with `408 Request Timeout`.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::body::readable_within;
+use crate::client::ClientStream;
+use crate::context::{self, ConnectionContext};
+
+use crate::metrics::{CLIENT_HEADER_TIMEOUTS, CLIENT_IDLE_TIMEOUTS, CLIENT_WRITE_TIMEOUTS};
+use crate::proxy::transfer::is_timeout;
+
+/// Limits on each client connection; `None` for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ClientLimits {
+    /// Size of the socket send buffer in bytes, instead of the kernel's own
+    pub send_buffer: Option<usize>,
+    /// Longest a write to the client may make no progress
+    pub write_timeout: Option<Duration>,
+    /// Longest a new connection may wait before the first byte of its request
+    pub idle_timeout: Option<Duration>,
+    /// Longest the request head may take to arrive, from its first byte
+    pub header_timeout: Option<Duration>,
+    /// Longest a read of the request body may wait for the client
+    pub body_timeout: Option<Duration>,
+}
+
+/// Applies `limits` to a newly accepted client.
+pub fn apply(client: &ClientStream, limits: &ClientLimits) -> io::Result<()> {
+    client.set_read_timeout(limits.idle_timeout)?;
+    if let Some(size) = limits.send_buffer {
+        set_send_buffer(client, size)?;
+    }
+    if let Some(timeout) = limits.write_timeout {
+        // A client that stops reading still takes a few bytes now and then, which restarts the
+        // socket timeout; the TCP user timeout also covers data it leaves unacknowledged
+        client.set_write_timeout(Some(timeout))?;
+        if let Some(client) = client.as_tcp() {
+            set_user_timeout(client, timeout)?;
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the client from `peer` to start its request, for up to the idle timeout of `limits`,
+/// and returns when it did. Connections closed or left silent until then are to be dropped: health
+/// checks and port scanners connect and leave without a request, and idle connections only hold
+/// a thread.
+pub fn await_request(
+    client: &ClientStream,
+    peer: SocketAddr,
+    limits: &ClientLimits,
+) -> Option<Instant> {
+    match client.peek(&mut [0]) {
+        Ok(0) => {
+            log::debug!("Connection from {} closed before a request", peer);
+            None
+        }
+        Ok(_) => match client.set_read_timeout(limits.body_timeout) {
+            Ok(()) => Some(Instant::now()),
+            Err(e) => {
+                log::warn!("Failed to set the read timeout for {}: {}", peer, e);
+                None
+            }
+        },
+        Err(e) if is_timeout(&e) => {
+            CLIENT_IDLE_TIMEOUTS.increment();
+            log::debug!("Dropping connection from {}: no request in time", peer);
+            None
+        }
+        Err(e) => {
+            log::debug!("Connection from {} failed before a request: {}", peer, e);
+            None
+        }
+    }
+}
+
+/// Waits up to `timeout` for the client to send more than the `buffered` bytes it sent that are
+/// not read yet, and returns whether it did or closed the connection. Only TCP sockets can be told
+/// to wake up for more than they hold already; other clients are taken to have sent all they will.
+pub fn await_more(client: &ClientStream, buffered: usize, timeout: Duration) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let Some(client) = client.as_tcp() else {
+        return Ok(false);
+    };
+    let low_mark = libc::c_int::try_from(buffered + 1).unwrap_or(libc::c_int::MAX);
+    set_option(client, libc::SOL_SOCKET, libc::SO_RCVLOWAT, low_mark)?;
+    let more = readable_within(client.as_raw_fd(), timeout);
+    // Reads would otherwise wait for as many bytes too
+    set_option(client, libc::SOL_SOCKET, libc::SO_RCVLOWAT, 1)?;
+    more
+}
+
+/// A request head that did not arrive within `--client-header-timeout`.
+#[derive(Debug)]
+pub struct HeadTimeout {
+    timeout: Duration,
+}
+
+impl fmt::Display for HeadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request head not received within {:?}", self.timeout)
+    }
+}
+
+impl Error for HeadTimeout {}
+
+/// Whether `e` comes from a request head that arrived too slowly.
+pub fn is_head_timeout(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<HeadTimeout>())
+}
+
+/// Starts the time the client of `context` has to send its request head, which it started at
+/// `started`.
+pub fn start_head(context: &ConnectionContext, started: Instant) {
+    if let Some(timeout) = context.client_limits.header_timeout {
+        context.set_head_deadline(Some(started + timeout));
+    }
+}
+
+/// Marks the request head of the current connection as read.
+pub fn end_head() {
+    if let Some(context) = context::current() {
+        context.set_head_deadline(None);
+    }
+}
+
+/// Runs `read`, a read from `client`, within the time left for the request head while the current
+/// connection reads one. A head that takes too long fails with an `InvalidInput` error that
+/// `is_head_timeout` recognizes.
+pub fn read_head(
+    client: &ClientStream,
+    read: impl FnOnce() -> io::Result<usize>,
+) -> io::Result<usize> {
+    let Some(context) = context::current() else {
+        return read();
+    };
+    let Some(deadline) = context.head_deadline() else {
+        return read();
+    };
+    let limits = context.client_limits;
+    let timed_out = || {
+        CLIENT_HEADER_TIMEOUTS.increment();
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            HeadTimeout {
+                timeout: limits.header_timeout.unwrap_or_default(),
+            },
+        )
+    };
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(timed_out());
+    }
+    let timeout = limits.body_timeout.map_or(left, |body| body.min(left));
+    client.set_read_timeout(Some(timeout))?;
+    let result = read();
+    client.set_read_timeout(limits.body_timeout)?;
+    match result {
+        Err(e) if is_timeout(&e) && Instant::now() >= deadline => Err(timed_out()),
+        result => result,
+    }
+}
+
+/// Turns a timed out write to a client into an error saying that the client stopped reading, so
+/// that it is not taken for a slow backend.
+pub fn stalled(e: io::Error) -> io::Error {
+    if !is_timeout(&e) {
+        return e;
+    }
+    CLIENT_WRITE_TIMEOUTS.increment();
+    io::Error::new(
+        ErrorKind::BrokenPipe,
+        format!("Client stopped reading: {}", e),
+    )
+}
+
+/// A client connection that reports timed out writes as `stalled`.
+pub struct ClientWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ClientWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: Write> Write for ClientWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf).map_err(stalled)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(stalled)
+    }
+}
+
+/// A request body that stalled for longer than `--client-body-timeout`.
+#[derive(Debug)]
+pub struct BodyTimeout;
+
+impl fmt::Display for BodyTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Client stopped sending its request body")
+    }
+}
+
+impl Error for BodyTimeout {}
+
+/// Whether `e` comes from a `ClientReader` whose client stopped sending.
+pub fn is_body_timeout(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<BodyTimeout>())
+}
+
+/// A client connection whose timed out reads fail with an error that `is_body_timeout`
+/// recognizes, so that they are not taken for a slow backend.
+pub struct ClientReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> ClientReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for ClientReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            if is_timeout(&e) {
+                io::Error::new(ErrorKind::BrokenPipe, BodyTimeout)
+            } else {
+                e
+            }
+        })
+    }
+}
+
+#[cfg(unix)]
+fn set_send_buffer(client: &ClientStream, size: usize) -> io::Result<()> {
+    let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+    set_option(client, libc::SOL_SOCKET, libc::SO_SNDBUF, size)
+}
+
+#[cfg(not(unix))]
+fn set_send_buffer(_client: &ClientStream, _size: usize) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_user_timeout(client: &TcpStream, timeout: Duration) -> io::Result<()> {
+    let millis = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+    set_option(client, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, millis)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_client: &TcpStream, _timeout: Duration) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_option(
+    socket: &impl std::os::unix::io::AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: the descriptor is owned by `socket`, borrowed for the whole call, and the option
+    // value points to a `c_int` of the given length.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
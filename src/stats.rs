@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics::{
+    FILE_RESPONSES_COMPRESSED, FILE_RESPONSES_IDENTITY, FILE_RESPONSES_PRECOMPRESSED,
+};
+
+/// On-the-fly compression totals for one content type.
+#[derive(Debug, Default, Clone)]
+pub struct CompressionStats {
+    pub responses: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// CPU time of the threads that compressed these responses
+    pub cpu_time: Duration,
+}
+
+static BY_CONTENT_TYPE: Mutex<Option<HashMap<String, CompressionStats>>> = Mutex::new(None);
+
+/// Adds one compressed response to the totals of its media type, ignoring parameters such as
+/// `charset`.
+pub fn record_compression(content_type: &str, bytes_in: u64, bytes_out: u64, cpu_time: Duration) {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase();
+    let mime = if mime.is_empty() {
+        "unknown".to_string()
+    } else {
+        mime
+    };
+
+    let mut stats = BY_CONTENT_TYPE.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = stats
+        .get_or_insert_with(HashMap::new)
+        .entry(mime)
+        .or_default();
+    entry.responses += 1;
+    entry.bytes_in += bytes_in;
+    entry.bytes_out += bytes_out;
+    entry.cpu_time += cpu_time;
+}
+
+/// The compression totals per content type, for saving them.
+pub fn compression_totals() -> Vec<(String, CompressionStats)> {
+    let stats = BY_CONTENT_TYPE.lock().unwrap_or_else(|e| e.into_inner());
+    stats
+        .iter()
+        .flatten()
+        .map(|(mime, s)| (mime.clone(), s.clone()))
+        .collect()
+}
+
+/// Adds totals saved by an earlier run to those of `mime`.
+pub fn add_compression_totals(mime: &str, totals: &CompressionStats) {
+    let mut stats = BY_CONTENT_TYPE.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = stats
+        .get_or_insert_with(HashMap::new)
+        .entry(mime.to_string())
+        .or_default();
+    entry.responses += totals.responses;
+    entry.bytes_in += totals.bytes_in;
+    entry.bytes_out += totals.bytes_out;
+    entry.cpu_time += totals.cpu_time;
+}
+
+/// A table of compression totals per content type, largest input first, followed by where the
+/// bodies of file responses came from.
+pub fn compression_report() -> String {
+    let stats = BY_CONTENT_TYPE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut rows: Vec<_> = stats.iter().flatten().collect();
+    rows.sort_by(|a, b| b.1.bytes_in.cmp(&a.1.bytes_in).then(a.0.cmp(b.0)));
+
+    let mut report = format!(
+        "{:<40} {:>9} {:>14} {:>14} {:>7} {:>12}\n",
+        "content-type", "responses", "bytes-in", "bytes-out", "ratio", "cpu-ms"
+    );
+    for (mime, s) in rows {
+        let ratio = if s.bytes_out > 0 {
+            s.bytes_in as f64 / s.bytes_out as f64
+        } else {
+            0.0
+        };
+        report.push_str(&format!(
+            "{:<40} {:>9} {:>14} {:>14} {:>7.2} {:>12}\n",
+            mime,
+            s.responses,
+            s.bytes_in,
+            s.bytes_out,
+            ratio,
+            s.cpu_time.as_millis()
+        ));
+    }
+    report.push_str(&source_summary());
+    report
+}
+
+/// How many file responses were sent from pre-compressed siblings, compressed on the fly and
+/// uncompressed.
+fn source_summary() -> String {
+    let precompressed = FILE_RESPONSES_PRECOMPRESSED.get();
+    let compressed = FILE_RESPONSES_COMPRESSED.get();
+    let identity = FILE_RESPONSES_IDENTITY.get();
+    let total = precompressed + compressed + identity;
+    if total == 0 {
+        return String::new();
+    }
+    format!(
+        "\nfile responses: {} pre-compressed ({:.1}%), {} compressed on the fly, {} identity\n",
+        precompressed,
+        precompressed as f64 * 100.0 / total as f64,
+        compressed,
+        identity
+    )
+}
+
+/// Logs the compression report every `interval`.
+pub fn start_reporter(interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let has_stats = BY_CONTENT_TYPE
+            .lock()
+            .map(|s| s.as_ref().is_some_and(|s| !s.is_empty()))
+            .unwrap_or(false)
+            || !source_summary().is_empty();
+        if has_stats {
+            log::info!("Compression statistics:\n{}", compression_report());
+        }
+    });
+}
+
+/// CPU time consumed so far by the calling thread.
+#[cfg(unix)]
+pub fn thread_cpu_time() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid, exclusively borrowed timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+#[cfg(not(unix))]
+pub fn thread_cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+/// Counts the bytes written through it.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
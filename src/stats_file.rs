@@ -0,0 +1,146 @@
+//! Cumulative statistics kept across restarts.
+//!
+//! With `--stats-file PATH`, the counters of the admin API and the compression totals per content
+//! type start from the values saved in `PATH`, and are written back every minute and when zstdp
+//! is stopped with SIGTERM or SIGINT, so that long-term figures such as the bytes compression
+//! saved survive deploys. The file is plain text, one value per line:
+//!
+//! ```text
+//! counter requests_served 1843
+//! compression text/html 120 4812304 903411 5120
+//! ```
+//!
+//! where compression lines hold the responses, bytes in, bytes out and CPU time in microseconds.
+//! It is replaced atomically, so a crash never leaves half of it behind. Counters the running
+//! version does not know are dropped.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use crate::metrics;
+use crate::stats::{self, CompressionStats};
+
+/// How often the statistics are saved while zstdp runs.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Adds the statistics saved in `path` to the current ones, and saves them there from now on.
+/// Must be called before any other thread is started, as it leaves SIGTERM and SIGINT to a
+/// thread of its own that saves the statistics before exiting.
+pub fn enable(path: PathBuf) -> io::Result<()> {
+    match fs::read_to_string(&path) {
+        Ok(saved) => {
+            load(&saved).map_err(|e| {
+                io::Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+            })?;
+            log::info!("Statistics restored from {}", path.display());
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::info!("Statistics will be saved to {}", path.display());
+        }
+        Err(e) => return Err(e),
+    }
+
+    let signals = block_stop_signals();
+    let signal_path = path.clone();
+    thread::spawn(move || {
+        let signal = wait_for(&signals);
+        match save(&signal_path) {
+            Ok(()) => log::info!("Received signal {}, statistics saved", signal),
+            Err(e) => log::error!(
+                "Received signal {}, failed to save statistics: {}",
+                signal,
+                e
+            ),
+        }
+        std::process::exit(0);
+    });
+    thread::spawn(move || loop {
+        thread::sleep(SAVE_INTERVAL);
+        if let Err(e) = save(&path) {
+            log::warn!("Failed to save statistics to {}: {}", path.display(), e);
+        }
+    });
+    Ok(())
+}
+
+/// Adds the values of a saved file to the current statistics.
+fn load(saved: &str) -> Result<(), String> {
+    for (number, line) in saved.lines().enumerate() {
+        let invalid = || format!("invalid line {}: '{}'", number + 1, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [] => {}
+            ["counter", name, value] => {
+                let value = value.parse().map_err(|_| invalid())?;
+                match metrics::counter(name) {
+                    Some(counter) => counter.add(value),
+                    None => log::debug!("Dropping saved counter {}", name),
+                }
+            }
+            // Content types are as the backends sent them, which may include spaces
+            ["compression", ref mime @ .., responses, bytes_in, bytes_out, cpu_micros]
+                if !mime.is_empty() =>
+            {
+                let number = |field: &str| field.parse::<u64>().map_err(|_| invalid());
+                stats::add_compression_totals(
+                    &mime.join(" "),
+                    &CompressionStats {
+                        responses: number(responses)?,
+                        bytes_in: number(bytes_in)?,
+                        bytes_out: number(bytes_out)?,
+                        cpu_time: Duration::from_micros(number(cpu_micros)?),
+                    },
+                );
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(())
+}
+
+/// Writes the current statistics to `path`, through a temporary file renamed over it.
+fn save(path: &Path) -> io::Result<()> {
+    let mut saved = String::new();
+    for (name, value) in metrics::counters() {
+        saved.push_str(&format!("counter {} {}\n", name, value));
+    }
+    for (mime, totals) in stats::compression_totals() {
+        saved.push_str(&format!(
+            "compression {} {} {} {} {}\n",
+            mime,
+            totals.responses,
+            totals.bytes_in,
+            totals.bytes_out,
+            totals.cpu_time.as_micros()
+        ));
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, saved)?;
+    fs::rename(&temporary, path)
+}
+
+/// Blocks SIGTERM and SIGINT in the calling thread and the threads it starts from now on, so
+/// that only `wait_for` receives them.
+fn block_stop_signals() -> libc::sigset_t {
+    // SAFETY: the set is initialized by sigemptyset before it is used.
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+/// Waits for one of the blocked `signals`, returning its number.
+fn wait_for(signals: &libc::sigset_t) -> i32 {
+    let mut signal = 0;
+    // SAFETY: both pointers are valid for the whole call.
+    while unsafe { libc::sigwait(signals, &mut signal) } != 0 {}
+    signal
+}
@@ -0,0 +1,221 @@
+//! `zstdp test-config`: how a server started with a given command line would handle example
+//! requests, without starting it or reaching its backends.
+//!
+//! The fixtures file lists requests as they would arrive: a request line, `HTTP/1.1` optional,
+//! then header lines, with a blank line between requests and `#` comments:
+//!
+//! ```text
+//! GET /api/users
+//! Host: api.example.com
+//! Accept-Encoding: zstd, gzip
+//!
+//! GET /assets/app.js
+//! Accept-Encoding: br
+//! ```
+//!
+//! Each request is printed with the route it takes, the decisions made on its way (refusal,
+//! maintenance, authentication, compression) and the headers zstdp adds to a compressible
+//! response, spelled as `--header-case` asks. The output does not depend on the time of day
+//! outside maintenance windows, so that it can be compared with that of a known-good
+//! configuration in CI.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use clap::Parser;
+
+use crate::args::Args;
+use crate::compression::{determine_compression, CompressionType};
+use crate::discovery;
+use crate::header_case;
+use crate::request_target::{rejection_status, RequestTarget};
+use crate::route::{RouteConfig, Target};
+use crate::router::Router;
+use crate::vhosts;
+
+/// An example request from the fixtures file.
+struct Fixture {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+/// Prints what the server started with `server_args` would make of each request in `fixtures`.
+pub fn run(fixtures: &Path, server_args: &[String]) -> io::Result<()> {
+    let args = Args::try_parse_from(
+        std::iter::once("zstdp").chain(server_args.iter().map(String::as_str)),
+    )
+    .unwrap_or_else(|e| e.exit());
+    if args.command.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Expected the command line of a server after --, not another command",
+        ));
+    }
+    let fixtures = parse(fixtures)?;
+
+    // Fixtures never reach a backend, so there is nothing for a shield to store
+    let router = Router::from_args(&args, None)?;
+    if let Some(dir) = &args.routes_dir {
+        discovery::load_dir(dir, &args, None)?;
+    }
+    if let Some(path) = &args.vhosts {
+        vhosts::load_file(path, &args, None)?;
+    }
+
+    let now = SystemTime::now();
+    let mut out = io::stdout().lock();
+    for (i, fixture) in fixtures.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "{} {}", fixture.method, fixture.target)?;
+        let hosts: Vec<&str> = fixture
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("host"))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        let (name, route) = match router.resolve(&fixture.target, &hosts, now) {
+            Some(route) => (route.name.clone(), route.config.clone()),
+            None => ("default".to_string(), router.default.clone()),
+        };
+        writeln!(out, "  route: {} ({})", name, route.target)?;
+        describe(&mut out, fixture, &hosts, &route, now)?;
+    }
+    Ok(())
+}
+
+/// Prints the decisions `route` makes about `fixture`.
+fn describe(
+    out: &mut impl Write,
+    fixture: &Fixture,
+    hosts: &[&str],
+    route: &RouteConfig,
+    now: SystemTime,
+) -> io::Result<()> {
+    let target = match RequestTarget::parse(&fixture.target, hosts, &route.requests.hosts) {
+        Ok(target) => target,
+        Err(e) => return writeln!(out, "  answer: {} ({})", rejection_status(&e), e),
+    };
+    if route.maintenance_until(now).is_some() {
+        return writeln!(out, "  answer: 503 Service Unavailable (maintenance)");
+    }
+    if let Some(auth) = &route.auth {
+        writeln!(out, "  auth: {}", auth)?;
+    }
+
+    let (encodings, vary) = match &route.target {
+        Target::Directory(_) => (
+            route.compression.file_encodings(),
+            match route.client_hints.vary() {
+                Some(hint) => format!("Accept-Encoding, {}", hint),
+                None => "Accept-Encoding".to_string(),
+            },
+        ),
+        Target::Backend { .. } | Target::FastCgi(_) => (
+            route.compression.backend_encodings(),
+            "Accept-Encoding".to_string(),
+        ),
+    };
+    let accept_encoding = fixture
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("accept-encoding"))
+        .map_or("", |(_, v)| v.as_str());
+    let accepted = determine_compression(accept_encoding, encodings);
+    let bypassed = if route.compression.bypasses(&target.path) {
+        Some("bypass pattern")
+    } else if route.compression.guards_secrets(&fixture.headers) {
+        Some("secret cookie")
+    } else {
+        None
+    };
+    let compression = match bypassed {
+        Some(reason) => {
+            writeln!(out, "  compression: none ({})", reason)?;
+            CompressionType::None
+        }
+        None if !accepted.any() => {
+            writeln!(out, "  compression: none (not accepted)")?;
+            CompressionType::None
+        }
+        None => {
+            writeln!(out, "  compression: {}", accepted.best())?;
+            accepted.best()
+        }
+    };
+
+    let mut head = "HTTP/1.1 200 OK\r\n".to_string();
+    if compression != CompressionType::None {
+        head.push_str(&format!("Content-Encoding: {}\r\n", compression));
+    }
+    head.push_str(&format!("Vary: {}\r\n", vary));
+    for (name, value) in route.client_hints.response_headers() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let head = header_case::rewrite(head.as_bytes(), route.header_case);
+    for line in String::from_utf8_lossy(&head)
+        .lines()
+        .skip(1)
+        .filter(|line| !line.is_empty())
+    {
+        writeln!(out, "  > {}", line)?;
+    }
+    Ok(())
+}
+
+/// Reads the requests of a fixtures file.
+fn parse(path: &Path) -> io::Result<Vec<Fixture>> {
+    let invalid = |line: usize, reason: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: line {}: {}", path.display(), line, reason),
+        )
+    };
+    let content = fs::read_to_string(path)?;
+    let mut fixtures: Vec<Fixture> = Vec::new();
+    let mut in_request = false;
+    for (number, line) in content.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.is_empty() {
+            in_request = false;
+            continue;
+        }
+        if in_request {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(number, "expected a Name: value header"))?;
+            if let Some(fixture) = fixtures.last_mut() {
+                fixture
+                    .headers
+                    .push((name.trim().to_string(), value.trim().to_string()));
+            }
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (method, target) = match parts[..] {
+            [method, target] | [method, target, _] => (method, target),
+            _ => return Err(invalid(number, "expected METHOD TARGET [VERSION]")),
+        };
+        fixtures.push(Fixture {
+            method: method.to_string(),
+            target: target.to_string(),
+            headers: Vec::new(),
+        });
+        in_request = true;
+    }
+    if fixtures.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no requests", path.display()),
+        ));
+    }
+    Ok(fixtures)
+}
@@ -0,0 +1,70 @@
+//! TLS and plain HTTP on one port.
+//!
+//! zstdp only speaks plain HTTP. Clients inevitably send `http://` requests to a port that is
+//! meant for HTTPS and the other way round, so with a TLS terminator configured, the first byte
+//! of each connection decides: connections that open with a TLS handshake are relayed byte for
+//! byte to the terminator, which decrypts them and sends the requests back to zstdp, and
+//! everything else is served as usual.
+
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::client::ClientStream;
+use crate::metrics::TLS_PASSTHROUGH_CONNECTIONS;
+use crate::tunnel::{tunnel_connection, TunnelLimits};
+
+/// Content type of a TLS handshake record, the first byte of a ClientHello. HTTP requests start
+/// with a method name instead.
+const TLS_HANDSHAKE: u8 = 0x16;
+/// How long a client may take to send its first byte
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where TLS connections are relayed, and for how long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Terminator {
+    pub addr: String,
+    pub tunnel: TunnelLimits,
+}
+
+/// Relays `client` to `terminator`, if there is one and the client opens with a TLS handshake.
+/// Returns whether the connection was taken care of, and must not be served as HTTP.
+pub fn divert(client: &ClientStream, terminator: Option<&Terminator>) -> bool {
+    let Some(terminator) = terminator else {
+        return false;
+    };
+    match opens_with_tls(client) {
+        Ok(false) => return false,
+        Ok(true) => {}
+        Err(e) => {
+            log::debug!("Client sent nothing to tell TLS from HTTP: {}", e);
+            return true;
+        }
+    }
+
+    TLS_PASSTHROUGH_CONNECTIONS.increment();
+    if let Err(e) = relay(client, terminator) {
+        log::warn!(
+            "Failed to relay TLS connection to {}: {}",
+            terminator.addr,
+            e
+        );
+    }
+    true
+}
+
+/// Whether the first byte the client sent starts a TLS handshake, without consuming it.
+fn opens_with_tls(client: &ClientStream) -> io::Result<bool> {
+    let timeout = client.read_timeout()?;
+    client.set_read_timeout(Some(SNIFF_TIMEOUT))?;
+    let mut first = [0; 1];
+    let peeked = client.peek(&mut first);
+    client.set_read_timeout(timeout)?;
+    Ok(peeked? == 1 && first[0] == TLS_HANDSHAKE)
+}
+
+/// Copies bytes both ways between `client` and `terminator` until the terminator closes.
+fn relay(client: &ClientStream, terminator: &Terminator) -> io::Result<()> {
+    let server = TcpStream::connect(&terminator.addr)?;
+    tunnel_connection(client, server, terminator.tunnel)
+}
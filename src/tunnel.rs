@@ -0,0 +1,226 @@
+//! Byte-for-byte relays between a client and a server, for connections zstdp passes on without
+//! reading them: TLS handed to a terminator, `CONNECT` tunnels and upgraded connections such as
+//! WebSockets.
+//!
+//! Each direction is copied by a blocking thread of its own. When one side closes its end, the
+//! other side's connection is half-closed in turn and the opposite direction carries on, so that
+//! closing handshakes such as the WebSocket one complete. A tunnel in which neither side has sent
+//! anything for `--tunnel-idle-timeout` is closed, so that peers that vanished without closing
+//! their connection do not hold on to two threads forever, and with `--tunnel-max-lifetime` every
+//! tunnel is closed after that long, busy or not. Each tunnel logs how long it lasted and how many
+//! bytes it carried when it closes.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::ClientStream;
+use crate::slow_clients;
+
+/// How long tunnels stay open.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TunnelLimits {
+    /// Longest neither side may send anything, or `None` for no limit
+    pub idle_timeout: Option<Duration>,
+    /// Longest a tunnel stays open, busy or not, or `None` for no limit
+    pub max_lifetime: Option<Duration>,
+}
+
+impl TunnelLimits {
+    /// Limits that close tunnels once they have been idle for `idle_timeout`, and once they have
+    /// been open for `max_lifetime`. Either may be `None` or zero for never.
+    pub fn new(idle_timeout: Option<Duration>, max_lifetime: Option<Duration>) -> Self {
+        TunnelLimits {
+            idle_timeout: idle_timeout.filter(|timeout| !timeout.is_zero()),
+            max_lifetime: max_lifetime.filter(|lifetime| !lifetime.is_zero()),
+        }
+    }
+}
+
+/// Copies bytes both ways between `client` and `server` until both have closed their end, the
+/// tunnel has been idle for too long or it reaches its maximum lifetime.
+pub fn tunnel_connection(
+    client: &ClientStream,
+    server: TcpStream,
+    limits: TunnelLimits,
+) -> io::Result<()> {
+    let TunnelLimits {
+        idle_timeout,
+        max_lifetime,
+    } = limits;
+    // Tunnels idle as the tunnel timeouts allow, whatever is left of the request head
+    slow_clients::end_head();
+    client.set_read_timeout(idle_timeout)?;
+    server.set_read_timeout(idle_timeout)?;
+    let activity = Arc::new(Activity::new());
+    // Dropped when the tunnel closes, which stops the watch
+    let _lifetime = match max_lifetime {
+        Some(lifetime) => Some(watch_lifetime(client, &server, lifetime, &activity)?),
+        None => None,
+    };
+
+    let mut upload_from = client.try_clone()?;
+    let mut upload_to = server.try_clone()?;
+    let upload_activity = Arc::clone(&activity);
+    let upload = thread::spawn(move || {
+        match copy(
+            &mut upload_from,
+            &mut upload_to,
+            &upload_activity,
+            &upload_activity.uploaded,
+            idle_timeout,
+        ) {
+            Ok(End::Closed) => {
+                let _ = upload_to.shutdown(Shutdown::Write);
+            }
+            Ok(End::Idle) => {}
+            Err(e) => {
+                log::debug!("Tunnel from client ended: {}", e);
+                // Also ends the copy from the server
+                let _ = upload_to.shutdown(Shutdown::Both);
+            }
+        }
+    });
+
+    let result = copy(
+        &mut &server,
+        &mut &*client,
+        &activity,
+        &activity.downloaded,
+        idle_timeout,
+    );
+    match result {
+        // The client may still be sending, if only the end of a closing handshake
+        Ok(End::Closed) => {
+            let _ = client.shutdown(Shutdown::Write);
+        }
+        // Also ends the copy from the client, which may still be waiting for it to send something
+        Ok(End::Idle) | Err(_) => {
+            let _ = client.shutdown(Shutdown::Both);
+            let _ = server.shutdown(Shutdown::Both);
+        }
+    }
+    let _ = upload.join();
+
+    let end = if activity.expired.load(Ordering::Relaxed) {
+        "at its maximum lifetime"
+    } else {
+        match result {
+            Ok(End::Closed) => "by the peers",
+            Ok(End::Idle) => "while idle",
+            Err(_) => "on an error",
+        }
+    };
+    log::info!(
+        "Tunnel closed {} after {:?}: {} bytes from the client, {} from the server",
+        end,
+        activity.start.elapsed(),
+        activity.uploaded.load(Ordering::Relaxed),
+        activity.downloaded.load(Ordering::Relaxed)
+    );
+    result.map(drop)
+}
+
+/// Shuts both connections down once the tunnel has been open for `lifetime`, unless the returned
+/// sender is dropped first.
+fn watch_lifetime(
+    client: &ClientStream,
+    server: &TcpStream,
+    lifetime: Duration,
+    activity: &Arc<Activity>,
+) -> io::Result<Sender<()>> {
+    let client = client.try_clone()?;
+    let server = server.try_clone()?;
+    let activity = Arc::clone(activity);
+    let (stop, stopped) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(lifetime) {
+            activity.expired.store(true, Ordering::Relaxed);
+            let _ = client.shutdown(Shutdown::Both);
+            let _ = server.shutdown(Shutdown::Both);
+        }
+    });
+    Ok(stop)
+}
+
+/// Why a direction of a tunnel stopped.
+enum End {
+    /// The sending side closed its end
+    Closed,
+    /// Neither direction carried anything for the idle timeout
+    Idle,
+}
+
+/// When either direction of a tunnel last carried bytes, and how many each carried.
+struct Activity {
+    start: Instant,
+    /// Milliseconds from `start`
+    last: AtomicU64,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    /// The tunnel was closed at its maximum lifetime
+    expired: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    fn record(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// Copies `from` to `to` until `from` ends or the tunnel is idle, adding up the bytes in `copied`.
+/// Reads time out after `idle_timeout`, which only ends the copy if the other direction was idle
+/// as long.
+fn copy<R: Read, W: Write>(
+    from: &mut R,
+    to: &mut W,
+    activity: &Activity,
+    copied: &AtomicU64,
+    idle_timeout: Option<Duration>,
+) -> io::Result<End> {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        match from.read(&mut buf) {
+            Ok(0) => return Ok(End::Closed),
+            Ok(n) => {
+                to.write_all(&buf[..n])?;
+                activity.record();
+                copied.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let idle = activity.idle_for();
+                if idle_timeout.is_some_and(|timeout| idle >= timeout) {
+                    log::debug!("Closing tunnel idle for {:?}", idle);
+                    return Ok(End::Idle);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Virtual hosts read from a file, so that one zstdp process can front several sites.
+//!
+//! Each `[host]` section takes the keys of a route file (see `discovery`) and answers the
+//! requests whose `Host` header names it:
+//!
+//! ```text
+//! [blog.example.com]
+//! serve = /srv/blog
+//!
+//! [api.example.com]
+//! forward = 127.0.0.1:3000
+//! ```
+//!
+//! Requests for other hosts, or without a `Host` header, go to the target given on the command
+//! line. The file is checked for changes while zstdp runs, as soon as inotify reports them on
+//! Linux and every `--routes-poll` regardless; a changed file only replaces the virtual hosts if
+//! all of it is valid.
+
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::args::Args;
+use crate::discovery;
+use crate::proxy::shield::Shield;
+use crate::router::{self, Route};
+
+/// How long after a change is noticed the file is read, so that it is read once written.
+#[cfg(target_os = "linux")]
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+/// Routes in the order they are tried, see `router::sort`.
+static HOSTS: Mutex<Vec<Arc<Route>>> = Mutex::new(Vec::new());
+
+/// The virtual hosts in effect.
+pub fn hosts() -> Vec<Arc<Route>> {
+    HOSTS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The virtual hosts of a file, along with the settings of each host to tell what a later
+/// version of the file changed.
+struct Loaded {
+    routes: Vec<Arc<Route>>,
+    settings: Vec<(String, String)>,
+}
+
+/// Loads the virtual hosts in `path` once, without watching it.
+pub fn load_file(path: &Path, args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<()> {
+    apply(load(path, args, shield)?.routes);
+    Ok(())
+}
+
+/// Loads the virtual hosts in `path`, then checks it for changes when `Watcher` wakes up and
+/// applies them. An invalid file is an error at startup; later, it leaves the current hosts in
+/// place.
+pub fn watch(
+    path: PathBuf,
+    args: &Args,
+    shield: Option<&Arc<Shield>>,
+    interval: Duration,
+) -> io::Result<()> {
+    let mut stamp = stamp(&path)?;
+    let Loaded {
+        routes,
+        mut settings,
+    } = load(&path, args, shield)?;
+    apply(routes);
+    log::info!("Watching {} for virtual host changes", path.display());
+
+    let args = args.clone();
+    let shield = shield.cloned();
+    let watcher = Watcher::new(&path, interval);
+    thread::spawn(move || loop {
+        watcher.wait();
+        match self::stamp(&path) {
+            Ok(current) if current != stamp => {
+                stamp = current;
+                match load(&path, &args, shield.as_ref()) {
+                    Ok(loaded) => {
+                        log_changes(&settings, &loaded.settings);
+                        apply(loaded.routes);
+                        settings = loaded.settings;
+                    }
+                    Err(e) => log::warn!("Keeping the current virtual hosts: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to check {} for changes: {}", path.display(), e),
+        }
+    });
+    Ok(())
+}
+
+/// Wakes the watching thread up when the directory of the file changes, or after `interval`
+/// otherwise. The directory is watched rather than the file, as editors and Kubernetes replace
+/// files by renaming others over them; where inotify is unavailable, only the interval is left.
+struct Watcher {
+    #[cfg(target_os = "linux")]
+    inotify: Option<std::os::fd::OwnedFd>,
+    interval: Duration,
+}
+
+impl Watcher {
+    #[cfg(target_os = "linux")]
+    fn new(path: &Path, interval: Duration) -> Self {
+        use std::ffi::CString;
+        use std::os::fd::{FromRawFd, OwnedFd};
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let inotify = (|| {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let dir = CString::new(dir.as_os_str().as_bytes())?;
+            let mask = libc::IN_CLOSE_WRITE
+                | libc::IN_MOVED_TO
+                | libc::IN_CREATE
+                | libc::IN_DELETE
+                | libc::IN_ATTRIB;
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), mask) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(fd)
+        })();
+        let inotify = inotify
+            .inspect_err(|e| {
+                log::warn!(
+                    "Cannot watch {} with inotify, checking it every {:?}: {}",
+                    dir.display(),
+                    interval,
+                    e
+                )
+            })
+            .ok();
+        Watcher { inotify, interval }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new(_path: &Path, interval: Duration) -> Self {
+        Watcher { interval }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn wait(&self) {
+        let Some(inotify) = &self.inotify else {
+            return thread::sleep(self.interval);
+        };
+        let mut poll = libc::pollfd {
+            fd: inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = self.interval.as_millis().min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut poll, 1, timeout) } <= 0 {
+            return;
+        }
+        // Let a write in several steps finish, then drop the events it caused
+        thread::sleep(SETTLE_TIME);
+        let mut events = [0u8; 4096];
+        while unsafe {
+            libc::read(
+                inotify.as_raw_fd(),
+                events.as_mut_ptr() as *mut libc::c_void,
+                events.len(),
+            )
+        } > 0
+        {}
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wait(&self) {
+        thread::sleep(self.interval);
+    }
+}
+
+/// The modification time and size of `path`, to notice changes.
+fn stamp(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+fn load(path: &Path, args: &Args, shield: Option<&Arc<Shield>>) -> io::Result<Loaded> {
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), reason),
+        )
+    };
+    let content = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to read virtual hosts from {}: {}",
+                path.display(),
+                e
+            ),
+        )
+    })?;
+
+    // Sections as their host and their lines with line numbers
+    let mut sections: Vec<(&str, Vec<(usize, &str)>)> = Vec::new();
+    for (number, line) in content.lines().enumerate().map(|(i, line)| (i + 1, line)) {
+        let trimmed = line.trim();
+        if let Some(host) = trimmed
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            let host = host.trim();
+            if host.is_empty() || host.contains(char::is_whitespace) {
+                return Err(invalid(format!("line {}: invalid host '{}'", number, host)));
+            }
+            if sections
+                .iter()
+                .any(|(other, _)| other.eq_ignore_ascii_case(host))
+            {
+                return Err(invalid(format!(
+                    "line {}: duplicate host '{}'",
+                    number, host
+                )));
+            }
+            sections.push((host, Vec::new()));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            lines.push((number, line));
+        } else if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            return Err(invalid(format!(
+                "line {}: expected a [host] section",
+                number
+            )));
+        }
+    }
+
+    let mut loaded = Loaded {
+        routes: Vec::new(),
+        settings: Vec::new(),
+    };
+    for (host, lines) in sections {
+        let route = discovery::parse(
+            host,
+            Some(host.to_string()),
+            lines.iter().copied(),
+            args,
+            shield,
+        )
+        .map_err(|reason| invalid(format!("[{}]: {}", host, reason)))?;
+        let settings: Vec<&str> = lines
+            .iter()
+            .map(|(_, line)| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        loaded.routes.push(Arc::new(route));
+        loaded
+            .settings
+            .push((host.to_lowercase(), settings.join("\n")));
+    }
+    Ok(loaded)
+}
+
+/// Logs the hosts that were added, removed or changed between two versions of the file.
+fn log_changes(old: &[(String, String)], new: &[(String, String)]) {
+    let find = |hosts: &[(String, String)], host: &str| {
+        hosts
+            .iter()
+            .find(|(other, _)| other == host)
+            .map(|(_, settings)| settings.clone())
+    };
+    for (host, settings) in new {
+        match find(old, host) {
+            None => log::info!("Virtual host {} added", host),
+            Some(previous) if previous != *settings => {
+                log::info!("Virtual host {} changed", host);
+                log::info!("  was: {}", previous.replace('\n', "; "));
+                log::info!("  now: {}", settings.replace('\n', "; "));
+            }
+            Some(_) => {}
+        }
+    }
+    for (host, _) in old {
+        if find(new, host).is_none() {
+            log::info!("Virtual host {} removed", host);
+        }
+    }
+}
+
+fn apply(mut routes: Vec<Arc<Route>>) {
+    router::sort(&mut routes);
+    router::log_routes(&routes);
+    *HOSTS.lock().unwrap_or_else(|e| e.into_inner()) = routes;
+}